@@ -2,11 +2,14 @@
 extern crate criterion;
 
 use criterion::{BatchSize, Benchmark, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{BTreeKvsEngine, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use sled::open;
 use tempfile::TempDir;
 
+const ZIPF_KEYS: usize = 1 << 14;
+const ZIPF_EXPONENT: f64 = 0.99;
+
 fn set_bench(c: &mut Criterion) {
     let bench = Benchmark::new(
         "kvs",
@@ -39,6 +42,38 @@ fn set_bench(c: &mut Criterion) {
             },
             BatchSize::SmallInput,
         )
+    })
+    .with_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(engine, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    engine
+                        .set(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    })
+    .with_function("btree", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (BTreeKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(engine, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    engine
+                        .set(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
     });
     c.bench("set_bench", bench);
 }
@@ -63,6 +98,21 @@ fn get_bench(c: &mut Criterion) {
         },
         vec![8, 12, 16, 20],
     )
+    .with_function("kvs_mmap", |b, i| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().mmap(true)).unwrap();
+        for key_i in 1..(1 << i) {
+            store
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            store
+                .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
+        })
+    })
     .with_function("sled", |b, i| {
         let temp_dir = TempDir::new().unwrap();
         let db = SledKvsEngine::new(open(&temp_dir).unwrap());
@@ -74,9 +124,102 @@ fn get_bench(c: &mut Criterion) {
         b.iter(|| {
             db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
         })
+    })
+    .with_function("lsm", |b, i| {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = LsmKvsEngine::open(temp_dir.path()).unwrap();
+        for key_i in 1..(1 << i) {
+            engine
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            engine
+                .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
+        })
+    })
+    .with_function("btree", |b, i| {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = BTreeKvsEngine::open(temp_dir.path()).unwrap();
+        for key_i in 1..(1 << i) {
+            engine
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            engine
+                .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
+        })
     });
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// Zipf samples key ids in 0..n, the id k drawn with a probability proportional to
+// 1 / (k + 1)^exponent, so a few hot keys take most of the reads.
+struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, exponent: f64) -> Self {
+        let mut sum = 0.0;
+        let mut cdf: Vec<f64> = (1..=n)
+            .map(|k| {
+                sum += 1.0 / (k as f64).powf(exponent);
+                sum
+            })
+            .collect();
+        for p in cdf.iter_mut() {
+            *p /= sum;
+        }
+        Zipf { cdf }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let u: f64 = rng.gen();
+        self.cdf.partition_point(|p| *p < u).min(self.cdf.len() - 1)
+    }
+}
+
+fn open_zipf_store(opts: KvStoreOptions) -> (KvStore, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with(temp_dir.path(), opts).unwrap();
+    for key_i in 0..ZIPF_KEYS {
+        store
+            .set(
+                format!("key{}", key_i),
+                format!("{}{}", key_i, "v".repeat(1000)),
+            )
+            .unwrap();
+    }
+    (store, temp_dir)
+}
+
+// zipf_get_bench reads keys skewed to a hot few, which is where the value cache pays.
+fn zipf_get_bench(c: &mut Criterion) {
+    let bench = Benchmark::new("kvs", |b| {
+        let (store, _temp_dir) = open_zipf_store(KvStoreOptions::new());
+        let zipf = Zipf::new(ZIPF_KEYS, ZIPF_EXPONENT);
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            store.get(format!("key{}", zipf.sample(&mut rng))).unwrap();
+        })
+    })
+    .with_function("kvs_cache", |b| {
+        let opts = KvStoreOptions::new().cache_size(4 * 1024 * 1024);
+        let (store, _temp_dir) = open_zipf_store(opts);
+        let zipf = Zipf::new(ZIPF_KEYS, ZIPF_EXPONENT);
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            store.get(format!("key{}", zipf.sample(&mut rng))).unwrap();
+        })
+    });
+    c.bench("zipf_get_bench", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench, zipf_get_bench);
 criterion_main!(benches);
@@ -1,5 +1,5 @@
 use crate::error::Error::*;
-use crate::{Error, Result, SimpleSerializer};
+use crate::{keyword, Error, Result, SimpleSerializer};
 use core::marker::PhantomData;
 use serde::de::{
     DeserializeOwned, DeserializeSeed, EnumAccess, Expected, SeqAccess, VariantAccess, Visitor,
@@ -11,11 +11,16 @@ use std::io::{BufRead, Cursor, Read};
 pub struct SimpleDeserializer<R> {
     // This string starts empty and JSON is appended as values are serialized.
     reader: R,
+    // whether the last request read is a batch, which ends with EXEC.
+    multi: bool,
 }
 
 impl<R: BufRead> SimpleDeserializer<R> {
     pub fn from_buf_reader(reader: R) -> Self {
-        SimpleDeserializer { reader }
+        SimpleDeserializer {
+            reader,
+            multi: false,
+        }
     }
 
     fn next_byte(&mut self) -> Result<u8> {
@@ -74,28 +79,28 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
@@ -115,49 +120,53 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        let s = self.next_line()?;
+        match s.parse::<u64>() {
+            Ok(x) => visitor.visit_u64(x),
+            Err(_) => Err(Error::Syntax),
+        }
     }
 
     fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
@@ -183,56 +192,75 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        self.deserialize_byte_buf(visitor)
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        // a bulk string, the length line is followed by exactly that many bytes.
+        let line = self.next_line()?;
+        let len = line
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or(Syntax)?;
+        let mut buf = vec![0u8; len + 2];
+        self.reader.read_exact(&mut buf)?;
+        if !buf.ends_with(b"\r\n") {
+            return Err(Syntax);
+        }
+        buf.truncate(len);
+        visitor.visit_byte_buf(buf)
     }
 
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        // an optional is a sequence of no or a single element.
+        match self.next_line()?.as_str() {
+            "0" => visitor.visit_none(),
+            "1" => visitor.visit_some(self),
+            _ => Err(Syntax),
+        }
     }
 
     fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_seq(Seq::new(self))
+        // the elements are counted up front, like the length of an array.
+        let len = self.next_line()?.parse::<usize>().map_err(|_| Syntax)?;
+        visitor.visit_seq(Seq::with_len(self, len))
     }
 
     fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_tuple_struct<V>(
@@ -244,14 +272,14 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_struct<V>(
@@ -263,7 +291,7 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn deserialize_enum<V>(
@@ -291,12 +319,31 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
         let prefix = self.next_byte()?;
         let cmd = match prefix {
             b'*' => {
-                let _args = self.next_line()?;
+                let args = self.next_line()?;
                 let request = self.next_line()?;
                 match request.trim_end() {
                     "GET" => "Get",
+                    // a SET with an expiry carries the EX option.
+                    "SET" if args == "5" => "SetEx",
                     "SET" => "Set",
                     "DEL" => "Remove",
+                    "SCAN" => "Scan",
+                    "SCANPREFIX" => "ScanPrefix",
+                    "MULTI" => {
+                        self.multi = true;
+                        "Batch"
+                    }
+                    "CAS" => "CompareAndSwap",
+                    "SETNX" => "SetIfAbsent",
+                    "SETXX" => "SetIfPresent",
+                    "EXPIRE" => "Expire",
+                    "TTL" => "Ttl",
+                    "PERSIST" => "Persist",
+                    "WATCH" => "Watch",
+                    "SELECT" => "Select",
+                    "DROPKEYSPACE" => "DropKeyspace",
+                    "KEYSPACES" => "Keyspaces",
+                    "CHECKPOINT" | "BGSAVE" => "Checkpoint",
                     _ => {
                         return Err(NotSupport);
                     }
@@ -317,17 +364,36 @@ impl<'de, 'a, R: BufRead> de::Deserializer<'de> for &'a mut SimpleDeserializer<R
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
 struct Seq<'a, R: 'a> {
     deserializer: &'a mut SimpleDeserializer<R>,
+    // elements left, None if the visitor knows when to stop.
+    remaining: Option<usize>,
+    // the fields of a struct variant, a field with a keyword is read behind it.
+    fields: &'static [&'static str],
+    index: usize,
 }
 
 impl<'a, R: 'a> Seq<'a, R> {
-    fn new(de: &'a mut SimpleDeserializer<R>) -> Self {
-        Self { deserializer: de }
+    fn new(de: &'a mut SimpleDeserializer<R>, fields: &'static [&'static str]) -> Self {
+        Self {
+            deserializer: de,
+            remaining: None,
+            fields,
+            index: 0,
+        }
+    }
+
+    fn with_len(de: &'a mut SimpleDeserializer<R>, len: usize) -> Self {
+        Self {
+            deserializer: de,
+            remaining: Some(len),
+            fields: &[],
+            index: 0,
+        }
     }
 }
 
@@ -338,8 +404,23 @@ impl<'de, 'a, R: BufRead + 'a> SeqAccess<'de> for Seq<'a, R> {
     where
         T: DeserializeSeed<'de>,
     {
+        match self.remaining {
+            Some(0) => return Ok(None),
+            Some(n) => self.remaining = Some(n - 1),
+            None => (),
+        }
+        if let Some(keyword) = self.fields.get(self.index).and_then(|field| keyword(field)) {
+            if self.deserializer.next_line()? != keyword {
+                return Err(Syntax);
+            }
+        }
+        self.index += 1;
         seed.deserialize(&mut *self.deserializer).map(Some)
     }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
 }
 
 // a wrapper struct to deal with EnumAccess and VariantAccess
@@ -395,7 +476,16 @@ impl<'de, 'a, R: BufRead + 'a> VariantAccess<'de> for Enum<'a, R> {
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_seq(self.deserializer, visitor)
+        let multi = std::mem::replace(&mut self.deserializer.multi, false);
+        let value = visitor.visit_seq(Seq::new(&mut *self.deserializer, fields))?;
+        if multi {
+            let args = self.deserializer.next_line()?;
+            let exec = self.deserializer.next_line()?;
+            if args != "*1" || exec != "EXEC" {
+                return Err(Syntax);
+            }
+        }
+        Ok(value)
     }
 }
 
@@ -453,7 +543,14 @@ pub fn from_str<T>(s: &str) -> Result<T>
 where
     T: DeserializeOwned,
 {
-    let mut reader = Cursor::new(s);
+    from_bytes(s.as_bytes())
+}
+
+pub fn from_bytes<T>(bytes: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut reader = Cursor::new(bytes);
     from_buf_reader(&mut reader)
 }
 
@@ -470,61 +567,205 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{to_string, Reply, Request};
+    use crate::{to_bytes, Bytes, Reply, Request};
     use std::io::Read;
 
     #[test]
     fn test_request_get() {
-        let old = Request::Get {
-            key: "foo".to_string(),
-        };
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Request>(s.as_str()).unwrap();
+        let old = Request::Get { key: "foo".into() };
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Request>(&s).unwrap();
         assert_eq!(old, new);
     }
 
     #[test]
     fn test_request_set() {
         let old = Request::Set {
-            key: "foo".to_string(),
-            value: "bar".to_string(),
+            key: "foo".into(),
+            value: "bar".into(),
         };
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Request>(s.as_str()).unwrap();
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Request>(&s).unwrap();
         assert_eq!(old, new);
     }
 
     #[test]
     fn test_request_del() {
-        let old = Request::Remove {
-            key: "foo".to_string(),
-        };
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Request>(s.as_str()).unwrap();
+        let old = Request::Remove { key: "foo".into() };
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Request>(&s).unwrap();
         assert_eq!(old, new)
     }
 
+    #[test]
+    fn test_request_scan() {
+        let old = Request::Scan {
+            start: "a".into(),
+            end: "b".into(),
+            limit: 10,
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        let new = from_bytes::<Request>(&s).unwrap();
+        assert_eq!(old, new);
+
+        let old = Request::ScanPrefix {
+            prefix: "a".into(),
+            limit: 0,
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        let new = from_bytes::<Request>(&s).unwrap();
+        assert_eq!(old, new);
+    }
+
+    #[test]
+    fn test_request_batch() {
+        let old = Request::Batch {
+            requests: vec![
+                Request::Set {
+                    key: "foo".into(),
+                    value: "bar".into(),
+                },
+                Request::Remove { key: "foo".into() },
+            ],
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        let mut input = s.clone();
+        input.extend_from_slice(&to_bytes(&Request::Get { key: "foo".into() }).unwrap());
+        let mut reader = Cursor::new(input);
+        let mut requests = SimpleDeserializer::from_buf_reader(&mut reader).into_iter::<Request>();
+        assert_eq!(requests.next().unwrap().unwrap(), old);
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+
+        let broken = String::from_utf8(s).unwrap().replace("EXEC", "DISCARD");
+        assert!(from_str::<Request>(broken.as_str()).is_err());
+    }
+
+    #[test]
+    fn test_request_watch() {
+        let old = Request::Watch {
+            keys: vec!["foo".into(), "bar".into()],
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        let new = from_bytes::<Request>(&s).unwrap();
+        assert_eq!(old, new);
+    }
+
+    #[test]
+    fn test_request_admin() {
+        let requests = vec![
+            Request::Select {
+                keyspace: "foo".into(),
+            },
+            Request::DropKeyspace {
+                keyspace: "foo".into(),
+            },
+            Request::Keyspaces {},
+            Request::Checkpoint {
+                dir: "/tmp/foo".into(),
+            },
+        ];
+        for old in requests {
+            let s = to_bytes(&old).unwrap();
+            assert_eq!(s, old.to_resp());
+            let new = from_bytes::<Request>(&s).unwrap();
+            assert_eq!(old, new);
+        }
+    }
+
+    #[test]
+    fn test_request_cas() {
+        for (expected, new) in [(None, Some("bar".into())), (Some("".into()), None)] {
+            let old = Request::CompareAndSwap {
+                key: "foo".into(),
+                expected,
+                new,
+            };
+            let s = to_bytes(&old).unwrap();
+            assert_eq!(s, old.to_resp());
+            let new = from_bytes::<Request>(&s).unwrap();
+            assert_eq!(old, new);
+        }
+
+        let old = Request::SetIfAbsent {
+            key: "foo".into(),
+            value: "bar".into(),
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        let new = from_bytes::<Request>(&s).unwrap();
+        assert_eq!(old, new);
+    }
+
+    #[test]
+    fn test_request_ttl() {
+        let requests = vec![
+            Request::SetEx {
+                key: "foo".into(),
+                value: "bar".into(),
+                ex: 10,
+            },
+            Request::Set {
+                key: "foo".into(),
+                value: "bar".into(),
+            },
+            Request::Expire {
+                key: "foo".into(),
+                seconds: 10,
+            },
+            Request::Ttl { key: "foo".into() },
+            Request::Persist { key: "foo".into() },
+        ];
+        for old in requests {
+            let s = to_bytes(&old).unwrap();
+            assert_eq!(s, old.to_resp());
+            let new = from_bytes::<Request>(&s).unwrap();
+            assert_eq!(old, new);
+        }
+
+        let broken = "*5\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\nPX\r\n10\r\n";
+        assert!(from_str::<Request>(broken).is_err());
+    }
+
+    #[test]
+    fn test_request_binary() {
+        let old = Request::CompareAndSwap {
+            key: Bytes::new(vec![0xff, 0x00]),
+            expected: Some(Bytes::new(b"\r\n".to_vec())),
+            new: Some(Bytes::new(b"$1\r\n\xfe".to_vec())),
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        assert_eq!(from_bytes::<Request>(&s).unwrap(), old);
+
+        // a bulk string cut short is not read as a shorter one.
+        assert!(from_bytes::<Request>(&s[..s.len() - 1]).is_err());
+    }
+
     #[test]
     fn test_reply_single_line() {
         let old = Reply::SingleLine("OK".to_string());
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Reply>(s.as_str()).unwrap();
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Reply>(&s).unwrap();
         assert_eq!(old, new)
     }
 
     #[test]
     fn test_reply_err() {
         let old = Reply::Err("ERR".to_string());
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Reply>(s.as_str()).unwrap();
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Reply>(&s).unwrap();
         assert_eq!(old, new)
     }
 
     #[test]
     fn test_reply_int() {
         let old = Reply::Int(42);
-        let s = to_string(&old).unwrap();
-        let new = from_str::<Reply>(s.as_str()).unwrap();
+        let s = to_bytes(&old).unwrap();
+        let new = from_bytes::<Reply>(&s).unwrap();
         assert_eq!(old, new)
     }
 }
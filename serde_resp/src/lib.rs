@@ -1,13 +1,15 @@
 // a very simple RESP serde Serializer and Deserializer trait
 #![allow(dead_code, unused_must_use, unused_variables)]
 #![allow(unused_imports)]
+mod bytes;
 mod de;
 mod error;
 mod ser;
 
-pub use de::{from_buf_reader, from_str, SimpleDeserializer};
+pub use bytes::Bytes;
+pub use de::{from_buf_reader, from_bytes, from_str, SimpleDeserializer};
 pub use error::{Error, Result};
-pub use ser::{to_string, to_writer, SimpleSerializer};
+pub use ser::{to_bytes, to_string, to_writer, SimpleSerializer};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::Display;
@@ -15,9 +17,69 @@ use std::fmt::Display;
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 // internal use
 enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Bytes,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+    },
+    Remove {
+        key: Bytes,
+    },
+    Scan {
+        start: Bytes,
+        end: Bytes,
+        limit: u64,
+    },
+    ScanPrefix {
+        prefix: Bytes,
+        limit: u64,
+    },
+    Batch {
+        requests: Vec<Request>,
+    },
+    CompareAndSwap {
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Option<Bytes>,
+    },
+    SetIfAbsent {
+        key: Bytes,
+        value: Bytes,
+    },
+    SetIfPresent {
+        key: Bytes,
+        value: Bytes,
+    },
+    SetEx {
+        key: Bytes,
+        value: Bytes,
+        ex: u64,
+    },
+    Expire {
+        key: Bytes,
+        seconds: u64,
+    },
+    Ttl {
+        key: Bytes,
+    },
+    Persist {
+        key: Bytes,
+    },
+    Watch {
+        keys: Vec<Bytes>,
+    },
+    Select {
+        keyspace: Bytes,
+    },
+    DropKeyspace {
+        keyspace: Bytes,
+    },
+    Keyspaces {},
+    Checkpoint {
+        dir: Bytes,
+    },
 }
 
 impl Display for Request {
@@ -32,6 +94,51 @@ impl Display for Request {
             Request::Remove { key } => {
                 write!(f, "remove {}", key)?;
             }
+            Request::Scan { start, end, limit } => {
+                write!(f, "scan {}..{} limit {}", start, end, limit)?;
+            }
+            Request::ScanPrefix { prefix, limit } => {
+                write!(f, "scan prefix {} limit {}", prefix, limit)?;
+            }
+            Request::Batch { requests } => {
+                write!(f, "batch of {} requests", requests.len())?;
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                write!(f, "cas {}:{:?}->{:?}", key, expected, new)?;
+            }
+            Request::SetIfAbsent { key, value } => {
+                write!(f, "setnx {}:{}", key, value)?;
+            }
+            Request::SetIfPresent { key, value } => {
+                write!(f, "setxx {}:{}", key, value)?;
+            }
+            Request::SetEx { key, value, ex } => {
+                write!(f, "set {}:{} ex {}", key, value, ex)?;
+            }
+            Request::Expire { key, seconds } => {
+                write!(f, "expire {} {}", key, seconds)?;
+            }
+            Request::Ttl { key } => {
+                write!(f, "ttl {}", key)?;
+            }
+            Request::Persist { key } => {
+                write!(f, "persist {}", key)?;
+            }
+            Request::Watch { keys } => {
+                write!(f, "watch {} keys", keys.len())?;
+            }
+            Request::Select { keyspace } => {
+                write!(f, "select {}", keyspace)?;
+            }
+            Request::DropKeyspace { keyspace } => {
+                write!(f, "drop keyspace {}", keyspace)?;
+            }
+            Request::Keyspaces {} => {
+                write!(f, "keyspaces")?;
+            }
+            Request::Checkpoint { dir } => {
+                write!(f, "checkpoint {}", dir)?;
+            }
         }
         Ok(())
     }
@@ -39,15 +146,122 @@ impl Display for Request {
 
 impl Request {
     // simply format using REdis Serialization Protocol
-    pub fn to_resp(&self) -> String {
-        let s = match self {
-            Request::Get { key } => format!("*2\r\n{}\r\n{}\r\n", "GET", key),
+    pub fn to_resp(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Request::Get { key } => {
+                buf.extend_from_slice(b"*2\r\nGET\r\n");
+                bulk(&mut buf, key);
+            }
             Request::Set { key, value } => {
-                format!("*3\r\n{}\r\n{}\r\n{}\r\n", "SET", key, value)
+                buf.extend_from_slice(b"*3\r\nSET\r\n");
+                bulk(&mut buf, key);
+                bulk(&mut buf, value);
             }
-            Request::Remove { key } => format!("*2\r\n{}\r\n{}\r\n", "DEL", key),
-        };
-        s
+            Request::Remove { key } => {
+                buf.extend_from_slice(b"*2\r\nDEL\r\n");
+                bulk(&mut buf, key);
+            }
+            Request::Scan { start, end, limit } => {
+                buf.extend_from_slice(b"*4\r\nSCAN\r\n");
+                bulk(&mut buf, start);
+                bulk(&mut buf, end);
+                buf.extend_from_slice(format!("{}\r\n", limit).as_bytes());
+            }
+            Request::ScanPrefix { prefix, limit } => {
+                buf.extend_from_slice(b"*3\r\nSCANPREFIX\r\n");
+                bulk(&mut buf, prefix);
+                buf.extend_from_slice(format!("{}\r\n", limit).as_bytes());
+            }
+            Request::Batch { requests } => {
+                buf.extend_from_slice(format!("*2\r\nMULTI\r\n{}\r\n", requests.len()).as_bytes());
+                for request in requests {
+                    buf.extend_from_slice(&request.to_resp());
+                }
+                buf.extend_from_slice(b"*1\r\nEXEC\r\n");
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                buf.extend_from_slice(b"*4\r\nCAS\r\n");
+                bulk(&mut buf, key);
+                option_to_resp(&mut buf, expected);
+                option_to_resp(&mut buf, new);
+            }
+            Request::SetIfAbsent { key, value } => {
+                buf.extend_from_slice(b"*3\r\nSETNX\r\n");
+                bulk(&mut buf, key);
+                bulk(&mut buf, value);
+            }
+            Request::SetIfPresent { key, value } => {
+                buf.extend_from_slice(b"*3\r\nSETXX\r\n");
+                bulk(&mut buf, key);
+                bulk(&mut buf, value);
+            }
+            Request::SetEx { key, value, ex } => {
+                buf.extend_from_slice(b"*5\r\nSET\r\n");
+                bulk(&mut buf, key);
+                bulk(&mut buf, value);
+                buf.extend_from_slice(format!("EX\r\n{}\r\n", ex).as_bytes());
+            }
+            Request::Expire { key, seconds } => {
+                buf.extend_from_slice(b"*3\r\nEXPIRE\r\n");
+                bulk(&mut buf, key);
+                buf.extend_from_slice(format!("{}\r\n", seconds).as_bytes());
+            }
+            Request::Ttl { key } => {
+                buf.extend_from_slice(b"*2\r\nTTL\r\n");
+                bulk(&mut buf, key);
+            }
+            Request::Persist { key } => {
+                buf.extend_from_slice(b"*2\r\nPERSIST\r\n");
+                bulk(&mut buf, key);
+            }
+            Request::Watch { keys } => {
+                buf.extend_from_slice(format!("*2\r\nWATCH\r\n{}\r\n", keys.len()).as_bytes());
+                for key in keys {
+                    bulk(&mut buf, key);
+                }
+            }
+            Request::Select { keyspace } => {
+                buf.extend_from_slice(b"*2\r\nSELECT\r\n");
+                bulk(&mut buf, keyspace);
+            }
+            Request::DropKeyspace { keyspace } => {
+                buf.extend_from_slice(b"*2\r\nDROPKEYSPACE\r\n");
+                bulk(&mut buf, keyspace);
+            }
+            Request::Keyspaces {} => {
+                buf.extend_from_slice(b"*1\r\nKEYSPACES\r\n");
+            }
+            Request::Checkpoint { dir } => {
+                buf.extend_from_slice(b"*2\r\nCHECKPOINT\r\n");
+                bulk(&mut buf, dir);
+            }
+        }
+        buf
+    }
+}
+
+// keyword returns the keyword a field is written behind, like the EX of a SET.
+fn keyword(field: &str) -> Option<&'static str> {
+    match field {
+        "ex" => Some("EX"),
+        _ => None,
+    }
+}
+
+fn bulk(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    buf.extend_from_slice(bytes);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn option_to_resp(buf: &mut Vec<u8>, value: &Option<Bytes>) {
+    match value {
+        Some(value) => {
+            buf.extend_from_slice(b"1\r\n");
+            bulk(buf, value);
+        }
+        None => buf.extend_from_slice(b"0\r\n"),
     }
 }
 
@@ -94,3 +308,30 @@ impl Reply {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_round_trip() {
+        let old = Request::Batch {
+            requests: vec![
+                Request::CompareAndSwap {
+                    key: "foo".into(),
+                    expected: None,
+                    new: Some("bar".into()),
+                },
+                Request::CompareAndSwap {
+                    key: "bar".into(),
+                    expected: Some("".into()),
+                    new: None,
+                },
+                Request::Remove { key: "foo".into() },
+            ],
+        };
+        let s = to_bytes(&old).unwrap();
+        assert_eq!(s, old.to_resp());
+        assert_eq!(from_bytes::<Request>(&s).unwrap(), old);
+    }
+}
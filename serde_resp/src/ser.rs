@@ -1,26 +1,37 @@
 use serde::{ser, Serialize, Serializer};
 
 use crate::error::{Error, Result};
+use crate::keyword;
 use std::io::Write;
 
 // TODO: not sure the writer should be buf writer or writer
 pub struct SimpleSerializer<W: Write> {
     // This string starts empty and JSON is appended as values are serialized.
     writer: W,
+    // what closes each struct variant being serialized, the batch request ends with EXEC.
+    ends: Vec<&'static [u8]>,
 }
 
 // By convention, the public API of a Serde serializer is one or more `to_abc`
 // functions such as `to_string`, `to_bytes`, or `to_writer` depending on what
 // Rust types the serializer is able to produce as output.
 //
-// This basic serializer supports only `to_string`.
+// This basic serializer supports `to_string`, `to_bytes` and `to_writer`. A value
+// holding `Bytes` may only be written by the last two, its bytes need not be utf-8.
 pub fn to_string<T>(value: &T) -> Result<String>
+where
+    T: Serialize,
+{
+    Ok(String::from_utf8(to_bytes(value)?)?)
+}
+
+pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>>
 where
     T: Serialize,
 {
     let mut buf: Vec<u8> = Vec::new();
-    to_writer(value, &mut buf);
-    Ok(String::from_utf8(buf)?)
+    to_writer(value, &mut buf)?;
+    Ok(buf)
 }
 
 pub fn to_writer<T, W>(value: &T, writer: &mut W) -> Result<()>
@@ -28,12 +39,15 @@ where
     T: Serialize,
     W: Write,
 {
-    let mut serializer = SimpleSerializer { writer };
+    let mut serializer = SimpleSerializer {
+        writer,
+        ends: Vec::new(),
+    };
     value.serialize(&mut serializer)?;
     Ok(())
 }
 
-impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
+impl<'a, W: Write> ser::Serializer for &'a mut SimpleSerializer<W> {
     // The output type produced by this `Serializer` during successful
     // serialization. Most serializers that produce text or binary output should
     // set `Ok = ()` and serialize into an `io::Write` or buffer contained
@@ -49,7 +63,7 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // compound data structures like sequences and maps. In this case no
     // additional state is required beyond what is already stored in the
     // Serializer struct.
-    type SerializeSeq = Self;
+    type SerializeSeq = SeqSerializer<'a, W>;
     type SerializeTuple = Self;
     type SerializeTupleStruct = Self;
     type SerializeTupleVariant = Self;
@@ -61,7 +75,7 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // of the primitive types of the data model and map it to JSON by appending
     // into the output string.
     fn serialize_bool(self, v: bool) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // JSON does not distinguish between different sizes of integers, so all
@@ -69,15 +83,15 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // will be serialized the same. Other formats, especially compact binary
     // formats, may need independent logic for the different sizes.
     fn serialize_i8(self, v: i8) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_i16(self, v: i16) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_i32(self, v: i32) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // Not particularly efficient but this is example code anyway. A more
@@ -88,27 +102,28 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     }
 
     fn serialize_u8(self, v: u8) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_u16(self, v: u16) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_u32(self, v: u32) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_u64(self, v: u64) -> Result<()> {
-        unimplemented!()
+        self.writer.write_all(format!("{}\r\n", v).as_bytes())?;
+        Ok(())
     }
 
     fn serialize_f32(self, v: f32) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn serialize_f64(self, v: f64) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // Serialize a char as a single-character string. Other formats may
@@ -122,47 +137,47 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // get the idea. For example it would emit invalid JSON if the input string
     // contains a '"' character.
     fn serialize_str(self, v: &str) -> Result<()> {
-        self.serialize_bytes(v.as_bytes())?;
+        self.writer.write_all(v.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
         Ok(())
     }
 
-    // Serialize a byte array as an array of bytes. Could also use a base64
-    // string here. Binary formats will typically represent byte arrays more
-    // compactly.
+    // Serialize a byte array as a bulk string, the length in front of the bytes lets
+    // them hold `\r\n`.
     fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(format!("${}\r\n", v.len()).as_bytes())?;
         self.writer.write_all(v)?;
         self.writer.write_all(b"\r\n")?;
         Ok(())
     }
 
-    // An absent optional is represented as the JSON `null`.
+    // An optional is represented as a sequence of no or a single element, which
+    // tells an absent value from an empty string.
     fn serialize_none(self) -> Result<()> {
+        self.writer.write_all(b"0\r\n")?;
         Ok(())
     }
 
-    // A present optional is represented as just the contained value. Note that
-    // this is a lossy representation. For example the values `Some(())` and
-    // `None` both serialize as just `null`. Unfortunately this is typically
-    // what people expect when working with JSON. Other formats are encouraged
-    // to behave more intelligently if possible.
     fn serialize_some<T>(self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
+        self.writer.write_all(b"1\r\n")?;
         value.serialize(self)
     }
 
     // In Serde, unit means an anonymous value containing no data. Map this to
     // JSON as `null`.
     fn serialize_unit(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // Unit struct means a named value containing no data. Again, since there is
     // no data, map this to JSON as `null`. There is no need to serialize the
     // name in most formats.
     fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // When serializing a unit variant (or any other kind of variant), formats
@@ -233,8 +248,23 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // doesn't make a difference in JSON because the length is not represented
     // explicitly in the serialized form. Some serializers may only be able to
     // support sequences for which the length is known up front.
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        unimplemented!()
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        // the elements are counted up front, like the length of an array. Those of a
+        // sequence of unknown length are held back until it ends.
+        let pending = match len {
+            Some(len) => {
+                self.writer.write_all(format!("{}\r\n", len).as_bytes())?;
+                None
+            }
+            None => Some((
+                SimpleSerializer {
+                    writer: Vec::new(),
+                    ends: Vec::new(),
+                },
+                0,
+            )),
+        };
+        Ok(SeqSerializer { ser: self, pending })
     }
 
     // Tuples look just like sequences in JSON. Some formats may be able to
@@ -242,7 +272,8 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
     // means that the corresponding `Deserialize implementation will know the
     // length without needing to look at the serialized data.
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
-        self.serialize_seq(Some(len))
+        self.writer.write_all(format!("{}\r\n", len).as_bytes())?;
+        Ok(self)
     }
 
     // Tuple structs look just like sequences in JSON.
@@ -251,7 +282,7 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleStruct> {
-        self.serialize_seq(Some(len))
+        self.serialize_tuple(len)
     }
 
     // Tuple variants are represented in JSON as `{ NAME: [DATA...] }`. Again
@@ -263,12 +294,12 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
         variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // Maps are represented in JSON as `{ K: V, K: V, ... }`.
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // Structs look just like maps in JSON. In particular, JSON requires that we
@@ -302,10 +333,58 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
             2 => {
                 self.writer.write_all(b"*2\r\nDEL\r\n")?;
             }
+            3 => {
+                self.writer.write_all(b"*4\r\nSCAN\r\n")?;
+            }
+            4 => {
+                self.writer.write_all(b"*3\r\nSCANPREFIX\r\n")?;
+            }
+            5 => {
+                self.writer.write_all(b"*2\r\nMULTI\r\n")?;
+                self.ends.push(b"*1\r\nEXEC\r\n");
+                return Ok(self);
+            }
+            6 => {
+                self.writer.write_all(b"*4\r\nCAS\r\n")?;
+            }
+            7 => {
+                self.writer.write_all(b"*3\r\nSETNX\r\n")?;
+            }
+            8 => {
+                self.writer.write_all(b"*3\r\nSETXX\r\n")?;
+            }
+            9 => {
+                self.writer.write_all(b"*5\r\nSET\r\n")?;
+            }
+            10 => {
+                self.writer.write_all(b"*3\r\nEXPIRE\r\n")?;
+            }
+            11 => {
+                self.writer.write_all(b"*2\r\nTTL\r\n")?;
+            }
+            12 => {
+                self.writer.write_all(b"*2\r\nPERSIST\r\n")?;
+            }
+            13 => {
+                self.writer.write_all(b"*2\r\nWATCH\r\n")?;
+            }
+            14 => {
+                self.writer.write_all(b"*2\r\nSELECT\r\n")?;
+            }
+            15 => {
+                self.writer.write_all(b"*2\r\nDROPKEYSPACE\r\n")?;
+            }
+            16 => {
+                self.writer.write_all(b"*1\r\nKEYSPACES\r\n")?;
+            }
+            17 => {
+                self.writer.write_all(b"*2\r\nCHECKPOINT\r\n")?;
+            }
             _ => {
                 return Err(Error::NotSupport);
             }
         }
+        self.ends.push(b"");
         Ok(self)
     }
 }
@@ -315,9 +394,17 @@ impl<W: Write> ser::Serializer for &mut SimpleSerializer<W> {
 // method and followed by zero or more calls to serialize individual elements of
 // the compound type and one call to end the compound type.
 //
+// SeqSerializer writes the elements of a sequence. Those of a sequence of unknown
+// length are written into a buffer of their own and counted, the count goes in front
+// of them once the sequence ends.
+pub struct SeqSerializer<'a, W: Write> {
+    ser: &'a mut SimpleSerializer<W>,
+    pending: Option<(SimpleSerializer<Vec<u8>>, usize)>,
+}
+
 // This impl is SerializeSeq so these methods are called after `serialize_seq`
 // is called on the Serializer.
-impl<W: Write> ser::SerializeSeq for &mut SimpleSerializer<W> {
+impl<'a, W: Write> ser::SerializeSeq for SeqSerializer<'a, W> {
     // Must match the `Ok` type of the serializer.
     type Ok = ();
     // Must match the `Error` type of the serializer.
@@ -328,12 +415,23 @@ impl<W: Write> ser::SerializeSeq for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        match &mut self.pending {
+            Some((buf, count)) => {
+                *count += 1;
+                value.serialize(buf)
+            }
+            None => value.serialize(&mut *self.ser),
+        }
     }
 
     // Close the sequence.
     fn end(self) -> Result<()> {
-        unimplemented!()
+        if let Some((buf, count)) = self.pending {
+            let writer = &mut self.ser.writer;
+            writer.write_all(format!("{}\r\n", count).as_bytes())?;
+            writer.write_all(&buf.writer)?;
+        }
+        Ok(())
     }
 }
 
@@ -346,11 +444,11 @@ impl<W: Write> ser::SerializeTuple for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn end(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
@@ -363,11 +461,11 @@ impl<W: Write> ser::SerializeTupleStruct for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn end(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
@@ -388,11 +486,11 @@ impl<W: Write> ser::SerializeTupleVariant for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn end(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
@@ -420,7 +518,7 @@ impl<W: Write> ser::SerializeMap for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     // It doesn't make a difference whether the colon is printed at the end of
@@ -430,11 +528,11 @@ impl<W: Write> ser::SerializeMap for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn end(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
@@ -448,11 +546,11 @@ impl<W: Write> ser::SerializeStruct for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 
     fn end(self) -> Result<()> {
-        unimplemented!()
+        Err(Error::NotSupport)
     }
 }
 
@@ -466,12 +564,18 @@ impl<W: Write> ser::SerializeStructVariant for &mut SimpleSerializer<W> {
     where
         T: ?Sized + Serialize,
     {
+        if let Some(keyword) = keyword(key) {
+            self.writer.write_all(keyword.as_bytes())?;
+            self.writer.write_all(b"\r\n")?;
+        }
         value.serialize(&mut **self)?;
         Ok(())
     }
 
     fn end(self) -> Result<()> {
-        // not thing to do
+        if let Some(end) = self.ends.pop() {
+            self.writer.write_all(end)?;
+        }
         Ok(())
     }
 }
@@ -479,23 +583,21 @@ impl<W: Write> ser::SerializeStructVariant for &mut SimpleSerializer<W> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Reply, Request};
+    use crate::{Bytes, Reply, Request};
 
     #[test]
     fn test_request_get() {
-        let expect = b"*2\r\nGET\r\nfoo\r\n";
-        let request = Request::Get {
-            key: "foo".to_string(),
-        };
+        let expect = b"*2\r\nGET\r\n$3\r\nfoo\r\n";
+        let request = Request::Get { key: "foo".into() };
         assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
     }
 
     #[test]
     fn test_request_set() {
-        let expect = b"*3\r\nSET\r\nfoo\r\nbar\r\n";
+        let expect = b"*3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
         let request = Request::Set {
-            key: "foo".to_string(),
-            value: "bar".to_string(),
+            key: "foo".into(),
+            value: "bar".into(),
         };
         let s = to_string(&request).unwrap();
         assert_eq!(s.as_bytes(), expect);
@@ -503,13 +605,93 @@ mod tests {
 
     #[test]
     fn test_request_del() {
-        let expect = b"*2\r\nDEL\r\nfoo\r\n";
-        let request = Request::Remove {
-            key: "foo".to_string(),
+        let expect = b"*2\r\nDEL\r\n$3\r\nfoo\r\n";
+        let request = Request::Remove { key: "foo".into() };
+        assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
+    }
+
+    #[test]
+    fn test_request_batch() {
+        let expect =
+            b"*2\r\nMULTI\r\n2\r\n*3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*2\r\nDEL\r\n$3\r\nfoo\r\n*1\r\nEXEC\r\n";
+        let request = Request::Batch {
+            requests: vec![
+                Request::Set {
+                    key: "foo".into(),
+                    value: "bar".into(),
+                },
+                Request::Remove { key: "foo".into() },
+            ],
+        };
+        assert_eq!(to_string(&request).unwrap().as_bytes(), &expect[..]);
+    }
+
+    #[test]
+    fn test_request_watch() {
+        let expect = b"*2\r\nWATCH\r\n2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let request = Request::Watch {
+            keys: vec!["foo".into(), "bar".into()],
+        };
+        assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
+    }
+
+    #[test]
+    fn test_request_cas() {
+        let expect = b"*4\r\nCAS\r\n$3\r\nfoo\r\n0\r\n1\r\n$3\r\nbar\r\n";
+        let request = Request::CompareAndSwap {
+            key: "foo".into(),
+            expected: None,
+            new: Some("bar".into()),
         };
         assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
     }
 
+    #[test]
+    fn test_seq_of_unknown_length() {
+        // a filtered iterator does not tell its length up front.
+        struct NonEmpty(Vec<Bytes>);
+
+        impl Serialize for NonEmpty {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.iter().filter(|key| !key.is_empty()))
+            }
+        }
+
+        let keys = NonEmpty(vec!["foo".into(), "".into(), "baz".into()]);
+        let expect = b"2\r\n$3\r\nfoo\r\n$3\r\nbaz\r\n";
+        assert_eq!(to_bytes(&keys).unwrap(), expect);
+        assert_eq!(to_bytes(&()), Err(Error::NotSupport));
+    }
+
+    #[test]
+    fn test_request_ttl() {
+        let expect = b"*5\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\nEX\r\n10\r\n";
+        let request = Request::SetEx {
+            key: "foo".into(),
+            value: "bar".into(),
+            ex: 10,
+        };
+        assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
+
+        let expect = b"*2\r\nTTL\r\n$3\r\nfoo\r\n";
+        let request = Request::Ttl { key: "foo".into() };
+        assert_eq!(to_string(&request).unwrap().as_bytes(), expect);
+    }
+
+    #[test]
+    fn test_request_binary() {
+        let expect = b"*3\r\nSET\r\n$2\r\n\xff\x00\r\n$3\r\n\r\n\xfe\r\n";
+        let request = Request::Set {
+            key: Bytes::new(vec![0xff, 0x00]),
+            value: Bytes::new(b"\r\n\xfe".to_vec()),
+        };
+        assert_eq!(to_bytes(&request).unwrap(), &expect[..]);
+        assert!(to_string(&request).is_err());
+    }
+
     #[test]
     fn test_reply_single_line() {
         let expect = b"+OK\r\n";
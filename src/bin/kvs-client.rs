@@ -2,7 +2,8 @@
 extern crate log;
 
 use env_logger::Target;
-use kvs::{KvsClient, Reply, Request, Result};
+use kvs::{Bytes, KvsClient, Reply, Request, Result};
+use std::io;
 use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
@@ -21,7 +22,7 @@ enum Command {
     #[structopt(name = "get")]
     Get {
         #[structopt(name = "key")]
-        key: String,
+        key: Bytes,
         #[structopt(
             long,
             value_name = "IP:PORT",
@@ -33,9 +34,12 @@ enum Command {
     #[structopt(name = "set")]
     Set {
         #[structopt(name = "key")]
-        key: String,
+        key: Bytes,
         #[structopt(name = "value")]
-        value: String,
+        value: Bytes,
+        /// Make KEY expire after SECONDS
+        #[structopt(long, value_name = "SECONDS")]
+        ex: Option<u64>,
         #[structopt(
             long,
             value_name = "IP:PORT",
@@ -47,7 +51,129 @@ enum Command {
     #[structopt(name = "rm")]
     Remove {
         #[structopt(name = "key")]
-        key: String,
+        key: Bytes,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Print the keys from START up to END excluded and their values, ordered by key
+    #[structopt(name = "scan")]
+    Scan {
+        #[structopt(name = "start", default_value = "")]
+        start: Bytes,
+        #[structopt(name = "end", default_value = "")]
+        end: Bytes,
+        /// Only print the keys starting with PREFIX
+        #[structopt(long, value_name = "PREFIX", conflicts_with_all = &["start", "end"])]
+        prefix: Option<Bytes>,
+        /// Print at most LIMIT keys, 0 is no limit
+        #[structopt(long, value_name = "LIMIT", default_value = "0")]
+        limit: u64,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Set KEY to NEW only if its value is EXPECTED, print 1 if it is set and 0 if not.
+    /// A missing EXPECTED stands for a missing key, a missing NEW removes the key
+    #[structopt(name = "cas")]
+    CompareAndSwap {
+        #[structopt(name = "key")]
+        key: Bytes,
+        #[structopt(long, value_name = "EXPECTED")]
+        expected: Option<Bytes>,
+        #[structopt(long, value_name = "NEW")]
+        new: Option<Bytes>,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Set KEY only if it does not exist, print 1 if it is set and 0 if not
+    #[structopt(name = "setnx")]
+    SetIfAbsent {
+        #[structopt(name = "key")]
+        key: Bytes,
+        #[structopt(name = "value")]
+        value: Bytes,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Set KEY only if it exists, print 1 if it is set and 0 if not
+    #[structopt(name = "setxx")]
+    SetIfPresent {
+        #[structopt(name = "key")]
+        key: Bytes,
+        #[structopt(name = "value")]
+        value: Bytes,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Apply the set and rm requests read from stdin, one per line, as one batch
+    #[structopt(name = "batch")]
+    Batch {
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Make KEY expire after SECONDS, print 1 if it is set and 0 if KEY does not exist
+    #[structopt(name = "expire")]
+    Expire {
+        #[structopt(name = "key")]
+        key: Bytes,
+        #[structopt(name = "seconds")]
+        seconds: u64,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Print the seconds left until KEY expires, -1 if it never does and -2 if it does
+    /// not exist
+    #[structopt(name = "ttl")]
+    Ttl {
+        #[structopt(name = "key")]
+        key: Bytes,
+        #[structopt(
+            long,
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    /// Make KEY never expire, print 1 if its expiry is dropped and 0 if not
+    #[structopt(name = "persist")]
+    Persist {
+        #[structopt(name = "key")]
+        key: Bytes,
         #[structopt(
             long,
             value_name = "IP:PORT",
@@ -59,33 +185,107 @@ enum Command {
 }
 
 impl Command {
-    pub fn to_request(&self) -> (Request, SocketAddr) {
-        match self {
-            Command::Get { key, addr } => (
-                Request::Get {
-                    key: key.to_string(),
+    pub fn to_request(&self) -> Result<(Request, SocketAddr)> {
+        let ret = match self {
+            Command::Get { key, addr } => (Request::Get { key: key.clone() }, *addr),
+            Command::Set {
+                key,
+                value,
+                ex: Some(ex),
+                addr,
+            } => (
+                Request::SetEx {
+                    key: key.clone(),
+                    value: value.clone(),
+                    ex: *ex,
                 },
                 *addr,
             ),
-            Command::Set { key, value, addr } => (
+            Command::Set {
+                key, value, addr, ..
+            } => (
                 Request::Set {
-                    key: key.to_string(),
-                    value: value.to_string(),
+                    key: key.clone(),
+                    value: value.clone(),
                 },
                 *addr,
             ),
-            Command::Remove { key, addr } => (
-                Request::Remove {
-                    key: key.to_string(),
+            Command::Remove { key, addr } => (Request::Remove { key: key.clone() }, *addr),
+            Command::Scan {
+                prefix: Some(prefix),
+                limit,
+                addr,
+                ..
+            } => (
+                Request::ScanPrefix {
+                    prefix: prefix.clone(),
+                    limit: *limit,
                 },
                 *addr,
             ),
-        }
+            Command::Scan {
+                start,
+                end,
+                limit,
+                addr,
+                ..
+            } => (
+                Request::Scan {
+                    start: start.clone(),
+                    end: end.clone(),
+                    limit: *limit,
+                },
+                *addr,
+            ),
+            Command::CompareAndSwap {
+                key,
+                expected,
+                new,
+                addr,
+            } => (
+                Request::CompareAndSwap {
+                    key: key.clone(),
+                    expected: expected.clone(),
+                    new: new.clone(),
+                },
+                *addr,
+            ),
+            Command::SetIfAbsent { key, value, addr } => (
+                Request::SetIfAbsent {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                *addr,
+            ),
+            Command::SetIfPresent { key, value, addr } => (
+                Request::SetIfPresent {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                *addr,
+            ),
+            Command::Batch { addr } => (
+                Request::Batch {
+                    requests: Request::read_batch(io::stdin().lock())?,
+                },
+                *addr,
+            ),
+            Command::Expire { key, seconds, addr } => (
+                Request::Expire {
+                    key: key.clone(),
+                    seconds: *seconds,
+                },
+                *addr,
+            ),
+            Command::Ttl { key, addr } => (Request::Ttl { key: key.clone() }, *addr),
+            Command::Persist { key, addr } => (Request::Persist { key: key.clone() }, *addr),
+        };
+        Ok(ret)
     }
 }
 
 fn run(client: &mut Client) -> Result<Reply> {
-    let (req, addr) = client.cmd.to_request();
+    let (req, addr) = client.cmd.to_request()?;
     let mut kv_client = KvsClient::new(addr)?;
     info!("connect to {}", addr);
     kv_client.process(&req)
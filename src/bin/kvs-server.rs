@@ -9,6 +9,7 @@ use kvs::*;
 use std::env::current_dir;
 use std::fs::OpenOptions;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 use thread_pool::SharedQueueThreadPool;
@@ -18,13 +19,18 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum EngineOpt {
         kvs,
-        sled
+        sled,
+        memory,
+        lsm,
+        btree
     }
 }
 
 const DEFAULT_ENGINE: EngineOpt = EngineOpt::kvs;
 const KVS_ENGINE_FILE: &str = "kvs.engine";
 const SLED_ENGINE_FILE: &str = "sled.engine";
+const LSM_ENGINE_FILE: &str = "lsm.engine";
+const BTREE_ENGINE_FILE: &str = "btree.engine";
 
 #[derive(StructOpt, Debug)]
 #[structopt(version = env!("CARGO_PKG_VERSION"))]
@@ -41,21 +47,120 @@ struct Server {
 
     #[structopt(long, value_name = "ENGINE-NAME", possible_values=&EngineOpt::variants())]
     pub engine: Option<EngineOpt>,
+
+    /// Compact the kvs log once its stale data grows over the given bytes
+    #[structopt(long, value_name = "BYTES", conflicts_with = "compact-ratio")]
+    pub compact_threshold: Option<u64>,
+
+    /// Compact the kvs log once its stale data grows over the given multiple of the live data
+    #[structopt(long, value_name = "RATIO")]
+    pub compact_ratio: Option<f64>,
+
+    /// Seal a kvs log segment once it grows over the given bytes
+    #[structopt(long, value_name = "BYTES")]
+    pub segment_size: Option<u64>,
+
+    /// When to sync kvs and lsm writes to disk: never, every-write, every-<N>ms or
+    /// every-<N>bytes. Without it writes are never synced, and the ones acknowledged
+    /// last may be lost on a power failure
+    #[structopt(long, value_name = "POLICY", parse(try_from_str))]
+    pub sync: Option<SyncPolicy>,
+
+    /// Compress the kvs values with the given codec: none, lz4 or zstd
+    #[structopt(long, value_name = "CODEC", parse(try_from_str))]
+    pub compression: Option<Compression>,
+
+    /// Cache the kvs values read last in the given bytes of memory
+    #[structopt(long, value_name = "BYTES")]
+    pub cache_size: Option<u64>,
+
+    /// Serve the kvs store read-only
+    #[structopt(long)]
+    pub read_only: bool,
+
+    /// Evict the least recently used keys of the memory engine once they take over the
+    /// given bytes
+    #[structopt(long, value_name = "BYTES")]
+    pub capacity: Option<u64>,
+
+    /// Let clients write checkpoints of the store into directories under the given one
+    #[structopt(long, value_name = "DIR", parse(from_os_str))]
+    pub checkpoint_dir: Option<PathBuf>,
 }
 
 impl Server {
     fn validate(&mut self) {
-        let old_engine = check_old_engine().unwrap();
-        match (self.engine, old_engine) {
-            (None, old) => self.engine = old,
-            (Some(curr), Some(old)) => {
-                if curr != old {
-                    error!("Wrong engine!");
+        // the memory engine keeps nothing in the directory, whatever it is pinned to.
+        if self.engine != Some(EngineOpt::memory) {
+            let old_engine = check_old_engine().unwrap();
+            match (self.engine, old_engine) {
+                (None, old) => self.engine = old,
+                (Some(curr), Some(old)) if curr != old => {
+                    error!("Wrong engine! `kvs migrate` moves the store to another engine");
                     exit(1);
                 }
+                _ => {}
             }
-            _ => {}
         }
+        let engine = self.engine.unwrap_or(DEFAULT_ENGINE);
+        if let Some(flag) = self.unsupported_flag(engine) {
+            error!("--{} is not supported by the {} engine", flag, engine);
+            exit(1);
+        }
+    }
+
+    // `unsupported_flag` returns the first flag given which `engine` has no use for.
+    fn unsupported_flag(&self, engine: EngineOpt) -> Option<&'static str> {
+        let kvs = engine == EngineOpt::kvs;
+        let flags = [
+            ("compact-threshold", self.compact_threshold.is_some(), kvs),
+            ("compact-ratio", self.compact_ratio.is_some(), kvs),
+            ("segment-size", self.segment_size.is_some(), kvs),
+            ("sync", self.sync.is_some(), kvs || engine == EngineOpt::lsm),
+            ("compression", self.compression.is_some(), kvs),
+            ("cache-size", self.cache_size.is_some(), kvs),
+            ("read-only", self.read_only, kvs),
+            (
+                "capacity",
+                self.capacity.is_some(),
+                engine == EngineOpt::memory,
+            ),
+        ];
+        flags
+            .iter()
+            .find(|(_, given, supported)| *given && !supported)
+            .map(|(flag, _, _)| *flag)
+    }
+
+    fn kvs_options(&self) -> KvStoreOptions {
+        let mut opts = KvStoreOptions::new().read_only(self.read_only);
+        if let Some(bytes) = self.compact_threshold {
+            opts = opts.compaction(CompactionTrigger::DanglingBytes(bytes));
+        }
+        if let Some(ratio) = self.compact_ratio {
+            opts = opts.compaction(CompactionTrigger::DanglingRatio(ratio));
+        }
+        if let Some(bytes) = self.segment_size {
+            opts = opts.segment_size(bytes);
+        }
+        if let Some(policy) = self.sync {
+            opts = opts.sync(policy);
+        }
+        if let Some(compression) = self.compression {
+            opts = opts.compression(compression);
+        }
+        if let Some(bytes) = self.cache_size {
+            opts = opts.cache_size(bytes);
+        }
+        opts
+    }
+
+    fn lsm_options(&self) -> LsmOptions {
+        let mut opts = LsmOptions::new();
+        if let Some(policy) = self.sync {
+            opts = opts.sync(policy);
+        }
+        opts
     }
 }
 
@@ -64,29 +169,49 @@ fn run(srv: &mut Server) -> Result<()> {
     let opt = srv.engine.unwrap_or(DEFAULT_ENGINE);
     info!("version {}", env!("CARGO_PKG_VERSION"));
     info!("engine: {}", opt);
-    info!("listening on {}", srv.addr);
+    let dir = current_dir()?;
     match opt {
         EngineOpt::kvs => {
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(KVS_ENGINE_FILE)?;
-            let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-            let storage = KvsServer::new(KvStore::open(current_dir()?)?, pool)?;
-            storage.run(srv.addr)
+            // a read-only server leaves the directory as it is.
+            let engine_file = Some(KVS_ENGINE_FILE).filter(|_| !srv.read_only);
+            let store = KvStore::open_with(dir, srv.kvs_options())?;
+            serve(store, engine_file, srv)
         }
-        EngineOpt::sled => {
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(SLED_ENGINE_FILE)?;
-            let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-            let storage = KvsServer::new(SledKvsEngine::open(current_dir()?)?, pool)?;
-            storage.run(srv.addr)
+        EngineOpt::sled => serve(SledKvsEngine::open(dir)?, Some(SLED_ENGINE_FILE), srv),
+        EngineOpt::lsm => serve(
+            LsmKvsEngine::open_with(dir, srv.lsm_options())?,
+            Some(LSM_ENGINE_FILE),
+            srv,
+        ),
+        EngineOpt::btree => serve(BTreeKvsEngine::open(dir)?, Some(BTREE_ENGINE_FILE), srv),
+        EngineOpt::memory => {
+            let engine = match srv.capacity {
+                Some(bytes) => MemoryKvsEngine::with_capacity(bytes),
+                None => MemoryKvsEngine::new(),
+            };
+            serve(engine, None, srv)
         }
     }
 }
 
+// serve records the engine in `engine_file` and serves it as `srv` asks for. The engine
+// is opened before it is recorded, so a store locked by another process is left as it is.
+fn serve<E: KvsEngine>(engine: E, engine_file: Option<&str>, srv: &Server) -> Result<()> {
+    if let Some(engine_file) = engine_file {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(engine_file)?;
+    }
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let mut server = KvsServer::new(engine, pool)?;
+    if let Some(dir) = &srv.checkpoint_dir {
+        server = server.checkpoint_root(dir);
+    }
+    server.run(srv.addr)
+}
+
 fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
@@ -94,7 +219,7 @@ fn main() {
         .init();
     let mut srv = Server::from_args();
     if let Err(e) = run(&mut srv) {
-        error!("run err {:?}", e);
+        error!("{}", e);
         exit(1)
     }
 }
@@ -106,6 +231,12 @@ fn check_old_engine() -> Result<Option<EngineOpt>> {
     if current_dir()?.join(SLED_ENGINE_FILE).exists() {
         return Ok(Some(EngineOpt::sled));
     }
+    if current_dir()?.join(LSM_ENGINE_FILE).exists() {
+        return Ok(Some(EngineOpt::lsm));
+    }
+    if current_dir()?.join(BTREE_ENGINE_FILE).exists() {
+        return Ok(Some(EngineOpt::btree));
+    }
     Ok(None)
 }
 
@@ -1,15 +1,58 @@
-use kvs::{KvStore, KvsEngine, KvsError, Request, Result};
+use kvs::{
+    BTreeKvsEngine, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, Reply, Request,
+    Result, SledKvsEngine,
+};
 use std::env::current_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 use structopt::StructOpt;
 
+// the files kvs-server pins a directory to an engine with.
+const KVS_ENGINE_FILE: &str = "kvs.engine";
+const SLED_ENGINE_FILE: &str = "sled.engine";
+const LSM_ENGINE_FILE: &str = "lsm.engine";
+const BTREE_ENGINE_FILE: &str = "btree.engine";
+// the engines a store can be migrated between.
+const ENGINES: [&str; 4] = ["kvs", "sled", "lsm", "btree"];
+// the dump a migration goes through, under the directory migrated.
+const MIGRATE_DUMP: &str = "migrate.dump";
+
 #[derive(StructOpt, Debug)]
 #[structopt(version = env!("CARGO_PKG_VERSION"))]
 #[structopt(author = env!("CARGO_PKG_AUTHORS"))]
 #[structopt(about = "cli for in memory kv store")]
 struct Cli {
     #[structopt(subcommand)] // Note that we mark a field as a subcommand
-    pub cmd: Option<Request>,
+    pub cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(flatten)]
+    Request(Request),
+
+    /// Write a checkpoint of the store into DIR and verify it
+    #[structopt(name = "backup")]
+    Backup { dir: PathBuf },
+
+    /// Restore the store from the checkpoint in DIR, the current directory must not hold
+    /// a store
+    #[structopt(name = "restore")]
+    Restore { dir: PathBuf },
+
+    /// Move the store in DIR from one engine to another and pin DIR to the new one, no
+    /// server may use DIR meanwhile
+    #[structopt(name = "migrate")]
+    Migrate {
+        #[structopt(long, value_name = "ENGINE-NAME", possible_values = &ENGINES)]
+        from: String,
+        #[structopt(long, value_name = "ENGINE-NAME", possible_values = &ENGINES)]
+        to: String,
+        dir: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
@@ -26,27 +69,180 @@ fn main() -> Result<()> {
     }
 }
 
-fn run(cmd: &Option<Request>) -> Result<()> {
+fn run(cmd: &Option<Command>) -> Result<()> {
     match cmd {
         None => process::exit(1),
-        Some(c) => {
+        // a backup only reads the store, it shares the lock with other readers.
+        Some(Command::Backup { dir }) => {
+            let store = KvStore::open_with(current_dir()?, KvStoreOptions::new().read_only(true))?;
+            store.checkpoint(dir)?;
+            KvStore::verify(dir)?;
+        }
+        Some(Command::Restore { dir }) => KvStore::restore(dir, current_dir()?)?,
+        Some(Command::Migrate { from, to, dir }) => {
+            println!("{}", migrate(from, to, dir)?);
+        }
+        Some(Command::Request(c)) => {
             let store = KvStore::open(current_dir()?)?;
             match c {
                 Request::Set { key: k, value: v } => {
-                    store.set(k.to_string(), v.to_string())?;
+                    store.set_bytes(k.to_vec(), v.to_vec())?;
                 }
                 Request::Get { key: k } => {
-                    if let Some(s) = store.get(k.to_string())? {
-                        println!("{}", s);
+                    if let Some(value) = store.get_bytes(k.to_vec())? {
+                        print_bytes(&value)?;
                     } else {
                         println!("Key not found");
                     }
                 }
                 Request::Remove { key: k } => {
-                    store.remove(k.to_string())?;
+                    store.remove_bytes(k.to_vec())?;
+                }
+                Request::Scan { start, end, limit } => {
+                    let range = Request::scan_range(start.to_vec(), end.to_vec());
+                    print_pairs(store.scan_bytes(range, Request::scan_limit(*limit))?)?;
+                }
+                Request::ScanPrefix { prefix, limit } => {
+                    let limit = Request::scan_limit(*limit);
+                    print_pairs(store.scan_prefix_bytes(prefix.to_vec(), limit)?)?;
+                }
+                Request::Batch { .. } => {
+                    let requests = Request::read_batch(io::stdin().lock())?;
+                    store.write_batch(Request::into_batch(requests)?)?;
+                }
+                Request::CompareAndSwap { key, expected, new } => {
+                    let expected = expected.as_ref().map(|value| value.to_vec());
+                    let new = new.as_ref().map(|value| value.to_vec());
+                    let swapped = store.compare_and_swap_bytes(key.to_vec(), expected, new)?;
+                    println!("{}", swapped as i64);
+                }
+                Request::SetIfAbsent { key, value } => {
+                    let swapped = store.set_if_absent_bytes(key.to_vec(), value.to_vec())?;
+                    println!("{}", swapped as i64);
+                }
+                Request::SetIfPresent { key, value } => {
+                    let swapped = store.set_if_present_bytes(key.to_vec(), value.to_vec())?;
+                    println!("{}", swapped as i64);
+                }
+                Request::SetEx { key, value, ex } => {
+                    let ttl = Duration::from_secs(*ex);
+                    store.set_with_ttl_bytes(key.to_vec(), value.to_vec(), ttl)?;
+                }
+                Request::Expire { key, seconds } => {
+                    let ttl = Duration::from_secs(*seconds);
+                    let expired = store.expire_bytes(key.to_vec(), ttl)?;
+                    println!("{}", expired as i64);
+                }
+                Request::Ttl { key } => {
+                    println!("{}", Reply::ttl(store.ttl_bytes(key.to_vec())));
+                }
+                Request::Persist { key } => {
+                    let persisted = store.persist_bytes(key.to_vec())?;
+                    println!("{}", persisted as i64);
+                }
+                Request::DropKeyspace { keyspace } => {
+                    let dropped = store.drop_keyspace(&Request::keyspace(keyspace.clone())?)?;
+                    println!("{}", dropped as i64);
+                }
+                Request::Keyspaces {} => {
+                    for name in store.keyspaces()? {
+                        println!("{}", name);
+                    }
+                }
+                // not commands of the command line, see `Request::Watch`.
+                Request::Watch { .. } | Request::Select { .. } | Request::Checkpoint { .. } => {
+                    return Err(KvsError::InvalidCommandError)
                 }
             }
         }
     }
     Ok(())
 }
+
+// migrate exports the store in `dir` into a dump, imports the dump into the engine `to`
+// and pins `dir` to it before the store of the engine `from` is removed. Returns the
+// number of keys migrated.
+fn migrate(from: &str, to: &str, dir: &Path) -> Result<u64> {
+    if from == to {
+        return Err(KvsError::InvalidOptionError(format!(
+            "migrate from {} to {}",
+            from, to
+        )));
+    }
+    if dir.join(engine_file(to)).exists() {
+        let msg = format!("{} is pinned to {} already", dir.display(), to);
+        return Err(KvsError::InvalidOptionError(msg));
+    }
+    let dump = dir.join(MIGRATE_DUMP);
+    let mut writer = BufWriter::new(File::create(&dump)?);
+    let count = match from {
+        "kvs" => {
+            KvStore::open_with(dir, KvStoreOptions::new().read_only(true))?.export(&mut writer)?
+        }
+        "sled" => SledKvsEngine::open(dir)?.export(&mut writer)?,
+        "lsm" => LsmKvsEngine::open(dir)?.export(&mut writer)?,
+        _ => BTreeKvsEngine::open(dir)?.export(&mut writer)?,
+    };
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    match to {
+        "kvs" => import(KvStore::open(dir)?, &dump)?,
+        "sled" => import(SledKvsEngine::open(dir)?, &dump)?,
+        "lsm" => import(LsmKvsEngine::open(dir)?, &dump)?,
+        _ => import(BTreeKvsEngine::open(dir)?, &dump)?,
+    };
+    // pinned to the new engine first, a crash from here on leaves the old store behind
+    // as garbage only.
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.join(engine_file(to)))?;
+    match fs::remove_file(dir.join(engine_file(from))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    match from {
+        "kvs" => KvStore::destroy(dir)?,
+        "sled" => SledKvsEngine::destroy(dir)?,
+        "lsm" => LsmKvsEngine::destroy(dir)?,
+        _ => BTreeKvsEngine::destroy(dir)?,
+    }
+    fs::remove_file(&dump)?;
+    Ok(count)
+}
+
+// import imports the dump into `engine`, which must hold no keys yet.
+fn import<E: KvsEngine>(engine: E, dump: &Path) -> Result<u64> {
+    if !engine.scan_bytes(.., 1)?.is_empty() || !engine.keyspaces()?.is_empty() {
+        let msg = "the store to migrate to is not empty";
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    engine.import(&mut BufReader::new(File::open(dump)?))
+}
+
+fn engine_file(engine: &str) -> &'static str {
+    match engine {
+        "kvs" => KVS_ENGINE_FILE,
+        "sled" => SLED_ENGINE_FILE,
+        "lsm" => LSM_ENGINE_FILE,
+        _ => BTREE_ENGINE_FILE,
+    }
+}
+
+// print_bytes prints a key or value as it is, which need not be utf-8.
+fn print_bytes(bytes: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(bytes)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+fn print_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+    for (key, value) in pairs {
+        print_bytes(&key)?;
+        print_bytes(&value)?;
+    }
+    Ok(())
+}
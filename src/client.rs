@@ -1,6 +1,6 @@
 use crate::{parse_reply, KvsError, Reply, Request, Result};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::time::Duration;
 
@@ -25,15 +25,40 @@ impl KvsClient {
     }
 
     pub fn process(&mut self, req: &Request) -> Result<Reply> {
-        self.writer.write_all(req.to_resp().as_ref())?;
+        self.writer.write_all(&req.to_resp())?;
         self.writer.flush()?;
-        let mut buffer = String::new();
-        let cnt = self.reader.read_line(&mut buffer)?;
-        debug!("cnt {}", cnt);
-        let reply = parse_reply(buffer.as_str());
+        let mut buffer = Vec::new();
+        self.read_reply(&mut buffer)?;
+        let reply = parse_reply(&buffer);
         match reply {
             Err(_) => Err(KvsError::InvalidCommandError),
             Ok((_, reply)) => Ok(reply),
         }
     }
+
+    // `read_reply` appends the lines of the next reply to `buffer`, an array reply spans
+    // the lines of all its elements and a bulk reply the bytes its length tells.
+    fn read_reply(&mut self, buffer: &mut Vec<u8>) -> Result<()> {
+        let start = buffer.len();
+        let cnt = self.reader.read_until(b'\n', buffer)?;
+        debug!("cnt {}", cnt);
+        let line = String::from_utf8_lossy(&buffer[start..]).into_owned();
+        if let Some(len) = line.strip_prefix('*') {
+            for _ in 0..parse_len(len)? {
+                self.read_reply(buffer)?;
+            }
+        } else if let Some(len) = line.strip_prefix('$') {
+            // the bytes may hold `\r\n` themselves, so read them by their length.
+            let bytes_start = buffer.len();
+            buffer.resize(bytes_start + parse_len(len)? + 2, 0);
+            self.reader.read_exact(&mut buffer[bytes_start..])?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_len(len: &str) -> Result<usize> {
+    len.trim_end()
+        .parse::<usize>()
+        .map_err(|_| KvsError::InvalidCommandError)
 }
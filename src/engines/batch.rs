@@ -0,0 +1,61 @@
+use std::vec;
+
+/// WriteBatch collects sets and removes to be applied as one unit by
+/// `KvsEngine::write_batch`. The operations are applied in the order they are added.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// let store = KvStore::open("db")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1".to_owned(), "value1".to_owned());
+/// batch.remove("key2".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// BatchOp is a single operation of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// `new` returns an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `set` adds setting `key` to `value`, either of them may be a string or bytes.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        let (key, value) = (key.into(), value.into());
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// `remove` adds removing `key`. Unlike `KvsEngine::remove`, a key which does not
+    /// exist is not an error.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
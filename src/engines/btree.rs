@@ -0,0 +1,1095 @@
+//! # B+tree engine
+//!
+//! `BTreeKvsEngine` keeps its keys in a B+tree of pages in the file `btree.db`, see
+//! the pager for the page formats. Only the pages in the buffer pool are kept in
+//! memory, so the memory it takes is bounded by `BTreeOptions::cache_size` however
+//! many keys it holds.
+//!
+//! Pages are copied on write: a commit writes the pages it changes to free pages, and
+//! only then the meta page pointing to the new root. A crash before that leaves the
+//! last commit as it is, there is no log to replay. The pages the commit replaces go to
+//! the free list once no reader reads a commit older than it, readers never wait for
+//! the writer.
+//!
+//! The keys of all keyspaces share the tree, behind the 4 byte id of their keyspace.
+//! The names of the keyspaces are kept in the tree as well, under the id `u32::MAX`.
+//! A node is dropped once it is empty, but nodes are never merged.
+
+use super::lock::DirLock;
+use super::pager::{
+    self, Entry, Meta, Node, PageId, Pager, Value, FREE_IDS_PER_PAGE, MAX_KEY_LEN,
+    OVERFLOW_DATA_LEN, PAGE_SIZE,
+};
+use super::transaction::Transaction;
+use super::{check_keyspace, expiry, DEFAULT_KEYSPACE};
+use crate::{BTreeOptions, BatchOp, KvsEngine, KvsError, Result, WriteBatch};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DATA_FILE: &str = "btree.db";
+// the id of the default keyspace, the named ones count up from 1.
+const DEFAULT_KEYSPACE_ID: u32 = 0;
+// the keyspace mapping the names of the other keyspaces to their ids.
+const KEYSPACES_ID: u32 = u32::MAX;
+// how many keys of a dropped keyspace are removed per commit.
+const DROP_PAGE: usize = 1024;
+
+// the first key and the page of the right half of a node split in two.
+type Split = Option<(Vec<u8>, PageId)>;
+
+/// BTreeKvsEngine keeps its keys in a B+tree of copy on write pages, see the module
+/// documentation. Every keyspace is a handle of its own on the same tree.
+#[derive(Clone)]
+pub struct BTreeKvsEngine {
+    keyspace: u32,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    opts: BTreeOptions,
+    pager: Pager,
+    readers: Mutex<Readers>,
+    writer: Mutex<Writer>,
+    _lock: DirLock,
+}
+
+// Readers holds the last commit along with the commits being read, and how many
+// readers read each of them.
+struct Readers {
+    meta: Meta,
+    active: BTreeMap<u64, usize>,
+}
+
+// Writer is the state the commits go through one at a time.
+struct Writer {
+    // the handle of the file pages are written through.
+    file: File,
+    meta: Meta,
+    // pages no reader reaches.
+    free: Vec<PageId>,
+    // pages replaced by a commit, which are free once no reader reads an older commit.
+    pending: VecDeque<(u64, Vec<PageId>)>,
+    // the pages the free list of the last commit is written to.
+    free_list: Vec<PageId>,
+}
+
+impl BTreeKvsEngine {
+    /// `open` opens the engine in `path` with the default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, BTreeOptions::default())
+    }
+
+    /// `open_with` opens the engine in `path`, which is locked like the directory of a
+    /// `KvStore`.
+    pub fn open_with(path: impl Into<PathBuf>, opts: BTreeOptions) -> Result<Self> {
+        let dir: PathBuf = path.into();
+        fs::create_dir_all(&dir)?;
+        let lock = DirLock::acquire(&dir, true)?;
+        let path = dir.join(DATA_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut write_file = file.try_clone()?;
+        if file.metadata()?.len() == 0 {
+            // the root is an empty leaf, the meta page goes last.
+            let meta = Meta {
+                txid: 1,
+                root: 2,
+                pages: 3,
+                free_list: 0,
+                next_keyspace: DEFAULT_KEYSPACE_ID + 1,
+            };
+            pager::write_page(&mut write_file, meta.root, &Node::Leaf(Vec::new()).encode())?;
+            pager::write_page(&mut write_file, meta.txid % 2, &meta.encode())?;
+            write_file.sync_all()?;
+        }
+        let cache_pages = (opts.cache_size / PAGE_SIZE as u64) as usize;
+        let pager = Pager::new(path.clone(), file, cache_pages);
+        let meta = pager.meta()?;
+        let (mut free, mut free_list) = (Vec::new(), Vec::new());
+        let mut id = meta.free_list;
+        while id != 0 {
+            if free_list.len() as u64 > meta.pages {
+                let reason = format!("{}: the free list runs in circles", path.display());
+                return Err(KvsError::CorruptedRecordError { offset: 0, reason });
+            }
+            let (next, ids) = pager.read_free(id)?;
+            free_list.push(id);
+            free.extend(ids);
+            id = next;
+        }
+        // what a commit failing halfway leaves behind.
+        if write_file.metadata()?.len() > meta.pages * PAGE_SIZE as u64 {
+            write_file.set_len(meta.pages * PAGE_SIZE as u64)?;
+        }
+        let writer = Writer {
+            file: write_file,
+            meta,
+            free,
+            pending: VecDeque::new(),
+            free_list,
+        };
+        let readers = Readers {
+            meta,
+            active: BTreeMap::new(),
+        };
+        let inner = Inner {
+            path,
+            opts,
+            pager,
+            readers: Mutex::new(readers),
+            writer: Mutex::new(writer),
+            _lock: lock,
+        };
+        Ok(BTreeKvsEngine {
+            keyspace: DEFAULT_KEYSPACE_ID,
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// `destroy` removes the engine in `path`, the directory is locked meanwhile.
+    pub fn destroy(path: impl Into<PathBuf>) -> Result<()> {
+        let dir: PathBuf = path.into();
+        let _lock = DirLock::acquire(&dir, true)?;
+        match fs::remove_file(dir.join(DATA_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // `internal_key` puts the id of the keyspace in front of `key`.
+    fn internal_key(&self, key: &[u8]) -> Vec<u8> {
+        internal_key(self.keyspace, key)
+    }
+
+    // `live` returns the entry of `key` if it is not expired, along with its value.
+    fn live(&self, key: &[u8]) -> Result<Option<(Entry, Vec<u8>)>> {
+        let snapshot = self.inner.snapshot();
+        match snapshot.get(&self.internal_key(key))? {
+            Some(entry) if !expiry::is_expired(entry.expire_at, expiry::now()) => {
+                let value = self.inner.read_value(&entry.value)?;
+                Ok(Some((entry, value)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // `write` runs `f` in a commit of its own, which is dropped if `f` fails.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut WriteTxn) -> Result<T>,
+    {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let mut txn = self.inner.begin(&mut writer);
+        let ret = f(&mut txn)?;
+        txn.commit()?;
+        Ok(ret)
+    }
+
+    // `set_expiry` makes the live `key` expire at `expire_at`, None makes it never
+    // expire.
+    fn set_expiry(&self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool> {
+        let key = self.internal_key(&key);
+        self.write(|txn| match txn.get(&key)? {
+            Some(entry) if expire_at.is_some() || entry.expire_at.is_some() => {
+                txn.insert(Entry {
+                    expire_at,
+                    seq: txn.meta.txid,
+                    ..entry
+                })?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+
+    // `collect` returns at most `limit` live pairs of this keyspace from `start` on, as
+    // long as `in_range` holds for their keys.
+    fn collect<F>(
+        &self,
+        start: Bound<&[u8]>,
+        in_range: F,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let snapshot = self.inner.snapshot();
+        let mut cursor = snapshot.seek(start)?;
+        let now = expiry::now();
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let entry = match cursor.next()? {
+                Some(entry) => entry,
+                None => break,
+            };
+            if !in_range(&entry.key) {
+                break;
+            }
+            if !expiry::is_expired(entry.expire_at, now) {
+                let value = self.inner.read_value(&entry.value)?;
+                pairs.push((entry.key[4..].to_vec(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    // `keyspace_id` returns the id of the keyspace `name`, if it exists.
+    fn keyspace_id(&self, name: &str) -> Result<Option<u32>> {
+        let snapshot = self.inner.snapshot();
+        let entry = snapshot.get(&internal_key(KEYSPACES_ID, name.as_bytes()))?;
+        entry
+            .map(|entry| self.inner.read_keyspace_id(&entry))
+            .transpose()
+    }
+}
+
+impl Inner {
+    // `snapshot` pins the last commit until the snapshot is dropped.
+    fn snapshot(&self) -> Snapshot<'_> {
+        let mut readers = self.readers.lock().unwrap();
+        let meta = readers.meta;
+        *readers.active.entry(meta.txid).or_default() += 1;
+        Snapshot { inner: self, meta }
+    }
+
+    // `begin` starts the next commit. The pages of the commits no reader reads any
+    // more are free from now on.
+    fn begin<'a>(&'a self, writer: &'a mut Writer) -> WriteTxn<'a> {
+        let oldest = self.readers.lock().unwrap().active.keys().next().copied();
+        while let Some(&(txid, _)) = writer.pending.front() {
+            if oldest.map_or(false, |oldest| oldest < txid) {
+                break;
+            }
+            let (_, ids) = writer.pending.pop_front().unwrap();
+            writer.free.extend(ids);
+        }
+        let mut meta = writer.meta;
+        meta.txid += 1;
+        WriteTxn {
+            inner: self,
+            writer,
+            meta,
+            dirty: HashMap::new(),
+            overflow: HashMap::new(),
+            freed: Vec::new(),
+        }
+    }
+
+    // `read_value` reads a value of a committed entry.
+    fn read_value(&self, value: &Value) -> Result<Vec<u8>> {
+        match value {
+            Value::Inline(value) => Ok(value.clone()),
+            Value::Overflow { page, len } => {
+                let mut value = Vec::with_capacity(*len as usize);
+                let mut id = *page;
+                while value.len() < *len as usize {
+                    let (next, data) = self.pager.read_overflow(id)?;
+                    let n = (*len as usize - value.len()).min(data.len());
+                    value.extend_from_slice(&data[..n]);
+                    id = next;
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    fn read_keyspace_id(&self, entry: &Entry) -> Result<u32> {
+        let value = self.read_value(&entry.value)?;
+        let mut id = [0u8; 4];
+        if value.len() != id.len() {
+            let reason = format!("{}: broken keyspace id", self.path.display());
+            return Err(KvsError::CorruptedRecordError { offset: 0, reason });
+        }
+        id.copy_from_slice(&value);
+        Ok(u32::from_be_bytes(id))
+    }
+}
+
+// Snapshot reads the tree as of a commit, whose pages are kept from being reused.
+struct Snapshot<'a> {
+    inner: &'a Inner,
+    meta: Meta,
+}
+
+impl<'a> Snapshot<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        lookup(self.meta.root, key, |id| self.inner.pager.node(id))
+    }
+
+    // `seek` returns a cursor on the first key from `start` on.
+    fn seek(&self, start: Bound<&[u8]>) -> Result<Cursor<'_>> {
+        let pager = &self.inner.pager;
+        let mut stack = Vec::new();
+        let mut id = self.meta.root;
+        loop {
+            let node = pager.node(id)?;
+            let i = match (&*node, start) {
+                (_, Bound::Unbounded) => 0,
+                (Node::Branch { keys, .. }, Bound::Included(key))
+                | (Node::Branch { keys, .. }, Bound::Excluded(key)) => child_index(keys, key),
+                (Node::Leaf(entries), Bound::Included(key)) => {
+                    entries.partition_point(|entry| entry.key.as_slice() < key)
+                }
+                (Node::Leaf(entries), Bound::Excluded(key)) => {
+                    entries.partition_point(|entry| entry.key.as_slice() <= key)
+                }
+            };
+            let child = match &*node {
+                Node::Branch { children, .. } => Some(children[i]),
+                Node::Leaf(_) => None,
+            };
+            stack.push((node, i));
+            match child {
+                Some(child) => id = child,
+                None => return Ok(Cursor { pager, stack }),
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        let mut readers = self.inner.readers.lock().unwrap();
+        if let Some(count) = readers.active.get_mut(&self.meta.txid) {
+            *count -= 1;
+            if *count == 0 {
+                readers.active.remove(&self.meta.txid);
+            }
+        }
+    }
+}
+
+// Cursor walks the leaves of a snapshot in key order. It holds the path from the root
+// to the current leaf, with the child or entry it is at in every node.
+struct Cursor<'a> {
+    pager: &'a Pager,
+    stack: Vec<(Arc<Node>, usize)>,
+}
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self) -> Result<Option<Entry>> {
+        loop {
+            let (node, i) = match self.stack.last_mut() {
+                Some(top) => top,
+                None => return Ok(None),
+            };
+            match &**node {
+                Node::Leaf(entries) => {
+                    if let Some(entry) = entries.get(*i) {
+                        *i += 1;
+                        return Ok(Some(entry.clone()));
+                    }
+                    self.stack.pop();
+                }
+                Node::Branch { children, .. } => {
+                    *i += 1;
+                    match children.get(*i) {
+                        Some(&child) => self.descend(child)?,
+                        None => {
+                            self.stack.pop();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // `descend` goes down to the first leaf under the node `id`.
+    fn descend(&mut self, mut id: PageId) -> Result<()> {
+        loop {
+            let node = self.pager.node(id)?;
+            let child = match &*node {
+                Node::Branch { children, .. } => Some(children[0]),
+                Node::Leaf(_) => None,
+            };
+            self.stack.push((node, 0));
+            match child {
+                Some(child) => id = child,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+// WriteTxn builds a commit on top of the last one. A page of the last commit is copied
+// to a free page before it is changed, once per commit.
+struct WriteTxn<'a> {
+    inner: &'a Inner,
+    writer: &'a mut Writer,
+    meta: Meta,
+    // the nodes written by the commit, by the page they go to.
+    dirty: HashMap<PageId, Arc<Node>>,
+    // the overflow pages written by the commit, along with the page they lead to.
+    overflow: HashMap<PageId, (PageId, Vec<u8>)>,
+    // the pages of the last commit the commit replaces.
+    freed: Vec<PageId>,
+}
+
+impl<'a> WriteTxn<'a> {
+    // `get` returns the entry of `key` if it is not expired.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let entry = lookup(self.meta.root, key, |id| self.node(id))?;
+        Ok(entry.filter(|entry| !expiry::is_expired(entry.expire_at, expiry::now())))
+    }
+
+    // `set` sets `key` to `value`, which expires at `expire_at`.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLargeError(MAX_KEY_LEN - 4));
+        }
+        let value = self.write_value(&key, value);
+        let seq = self.meta.txid;
+        self.insert(Entry {
+            key,
+            value,
+            expire_at,
+            seq,
+        })
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<()> {
+        let (root, split) = self.insert_at(self.meta.root, entry)?;
+        self.meta.root = match split {
+            Some((key, right)) => {
+                let id = self.alloc();
+                let keys = vec![key];
+                let children = vec![root, right];
+                self.dirty
+                    .insert(id, Arc::new(Node::Branch { keys, children }));
+                id
+            }
+            None => root,
+        };
+        Ok(())
+    }
+
+    // `insert_at` inserts `entry` under the node `id`. Returns the page the node is
+    // written to, and the first key and the page of its right half if it is split.
+    fn insert_at(&mut self, id: PageId, entry: Entry) -> Result<(PageId, Split)> {
+        let (id, mut node) = self.take(id)?;
+        match &mut node {
+            Node::Leaf(entries) => {
+                self.purge(entries)?;
+                match entries.binary_search_by(|e| e.key.cmp(&entry.key)) {
+                    Ok(i) => {
+                        let old = std::mem::replace(&mut entries[i], entry);
+                        if !same_chain(&old.value, &entries[i].value) {
+                            self.free_value(&old.value)?;
+                        }
+                    }
+                    Err(i) => entries.insert(i, entry),
+                }
+            }
+            Node::Branch { keys, children } => {
+                let i = child_index(keys, &entry.key);
+                let (child, split) = self.insert_at(children[i], entry)?;
+                children[i] = child;
+                if let Some((key, right)) = split {
+                    keys.insert(i, key);
+                    children.insert(i + 1, right);
+                }
+            }
+        }
+        Ok(self.split(id, node))
+    }
+
+    // `split` writes `node` to page `id`, in two halves if it does not fit in a page.
+    // Every entry takes less than a third of a page, so the halves always fit.
+    fn split(&mut self, id: PageId, node: Node) -> (PageId, Split) {
+        let len = node.encoded_len();
+        if len <= PAGE_SIZE {
+            self.dirty.insert(id, Arc::new(node));
+            return (id, None);
+        }
+        let (left, key, right) = match node {
+            Node::Leaf(mut entries) => {
+                let at = split_point(entries.iter().map(|entry| entry.encoded_len()), len / 2);
+                let right = entries.split_off(at.clamp(1, entries.len() - 1));
+                let key = right[0].key.clone();
+                (Node::Leaf(entries), key, Node::Leaf(right))
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let at = split_point(keys.iter().map(|key| key.len() + 10), len / 2);
+                let at = at.min(keys.len() - 1);
+                // the key at the split moves up to the parent.
+                let right_keys = keys.split_off(at + 1);
+                let key = keys.pop().unwrap();
+                let right_children = children.split_off(at + 1);
+                let right = Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                };
+                (Node::Branch { keys, children }, key, right)
+            }
+        };
+        let right_id = self.alloc();
+        self.dirty.insert(id, Arc::new(left));
+        self.dirty.insert(right_id, Arc::new(right));
+        (id, Some((key, right_id)))
+    }
+
+    // `remove` removes `key`. Returns false if it does not exist.
+    fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        if lookup(self.meta.root, key, |id| self.node(id))?.is_none() {
+            return Ok(false);
+        }
+        let (mut root, empty) = self.remove_at(self.meta.root, key)?;
+        if empty && matches!(*self.node(root)?, Node::Branch { .. }) {
+            self.release(root);
+            root = self.alloc();
+            self.dirty.insert(root, Arc::new(Node::Leaf(Vec::new())));
+        }
+        // a root left with a single child gives way to it.
+        while let Node::Branch { children, .. } = &*self.node(root)? {
+            if children.len() > 1 {
+                break;
+            }
+            let child = children[0];
+            self.release(root);
+            root = child;
+        }
+        self.meta.root = root;
+        Ok(true)
+    }
+
+    // `remove_at` removes `key` under the node `id`. Returns the page the node is
+    // written to and whether it is empty now.
+    fn remove_at(&mut self, id: PageId, key: &[u8]) -> Result<(PageId, bool)> {
+        let (id, mut node) = self.take(id)?;
+        let empty = match &mut node {
+            Node::Leaf(entries) => {
+                if let Ok(i) = entries.binary_search_by(|e| e.key.as_slice().cmp(key)) {
+                    let old = entries.remove(i);
+                    self.free_value(&old.value)?;
+                }
+                self.purge(entries)?;
+                entries.is_empty()
+            }
+            Node::Branch { keys, children } => {
+                let i = child_index(keys, key);
+                let (child, empty) = self.remove_at(children[i], key)?;
+                if empty {
+                    self.release(child);
+                    children.remove(i);
+                    if !keys.is_empty() {
+                        keys.remove(i.saturating_sub(1));
+                    }
+                } else {
+                    children[i] = child;
+                }
+                children.is_empty()
+            }
+        };
+        self.dirty.insert(id, Arc::new(node));
+        Ok((id, empty))
+    }
+
+    // `purge` drops the expired entries of a leaf being written anyway.
+    fn purge(&mut self, entries: &mut Vec<Entry>) -> Result<()> {
+        let now = expiry::now();
+        if !entries
+            .iter()
+            .any(|entry| expiry::is_expired(entry.expire_at, now))
+        {
+            return Ok(());
+        }
+        for entry in std::mem::take(entries) {
+            if expiry::is_expired(entry.expire_at, now) {
+                self.free_value(&entry.value)?;
+            } else {
+                entries.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn node(&self, id: PageId) -> Result<Arc<Node>> {
+        match self.dirty.get(&id) {
+            Some(node) => Ok(node.clone()),
+            None => self.inner.pager.node(id),
+        }
+    }
+
+    // `take` returns the node `id` to be changed, along with the page it goes to. A page
+    // of the last commit is replaced by a free one.
+    fn take(&mut self, id: PageId) -> Result<(PageId, Node)> {
+        if let Some(node) = self.dirty.remove(&id) {
+            return Ok((
+                id,
+                Arc::try_unwrap(node).unwrap_or_else(|node| (*node).clone()),
+            ));
+        }
+        let node = (*self.inner.pager.node(id)?).clone();
+        self.freed.push(id);
+        Ok((self.alloc(), node))
+    }
+
+    fn alloc(&mut self) -> PageId {
+        self.writer.free.pop().unwrap_or_else(|| {
+            self.meta.pages += 1;
+            self.meta.pages - 1
+        })
+    }
+
+    // `release` frees the page `id`, right away if the commit wrote it.
+    fn release(&mut self, id: PageId) {
+        if self.dirty.remove(&id).is_some() || self.overflow.remove(&id).is_some() {
+            self.writer.free.push(id);
+        } else {
+            self.freed.push(id);
+        }
+    }
+
+    // `write_value` keeps `value` in its leaf if it fits, in overflow pages otherwise.
+    fn write_value(&mut self, key: &[u8], value: Vec<u8>) -> Value {
+        if Entry::fits_inline(key, value.len()) {
+            return Value::Inline(value);
+        }
+        let ids: Vec<PageId> = value
+            .chunks(OVERFLOW_DATA_LEN)
+            .map(|_| self.alloc())
+            .collect();
+        for (i, chunk) in value.chunks(OVERFLOW_DATA_LEN).enumerate() {
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            self.overflow
+                .insert(ids[i], (next, pager::overflow_page(next, chunk)));
+        }
+        Value::Overflow {
+            page: ids.first().copied().unwrap_or(0),
+            len: value.len() as u32,
+        }
+    }
+
+    // `free_value` frees the overflow pages of a value replaced or removed.
+    fn free_value(&mut self, value: &Value) -> Result<()> {
+        let (mut id, len) = match value {
+            Value::Overflow { page, len } => (*page, *len as usize),
+            Value::Inline(_) => return Ok(()),
+        };
+        for _ in 0..(len + OVERFLOW_DATA_LEN - 1) / OVERFLOW_DATA_LEN {
+            let next = match self.overflow.get(&id) {
+                Some(&(next, _)) => next,
+                None => self.inner.pager.read_overflow(id)?.0,
+            };
+            self.release(id);
+            id = next;
+        }
+        Ok(())
+    }
+
+    // `commit` writes the pages of the commit and the free list, then the meta page
+    // which makes them the last commit.
+    fn commit(mut self) -> Result<()> {
+        if self.dirty.is_empty() && self.freed.is_empty() && self.overflow.is_empty() {
+            return Ok(());
+        }
+        for (&id, node) in &self.dirty {
+            pager::write_page(&mut self.writer.file, id, &node.encode())?;
+        }
+        for (&id, (_, page)) in &self.overflow {
+            pager::write_page(&mut self.writer.file, id, page)?;
+        }
+        // once the commit is the last one, the pages it replaces are free along with
+        // the pages of the old free list.
+        let mut chain = Vec::new();
+        loop {
+            let count = self.writer.free.len()
+                + self
+                    .writer
+                    .pending
+                    .iter()
+                    .map(|(_, ids)| ids.len())
+                    .sum::<usize>()
+                + self.freed.len()
+                + self.writer.free_list.len();
+            if chain.len() * FREE_IDS_PER_PAGE >= count {
+                break;
+            }
+            chain.push(self.alloc());
+        }
+        let ids: Vec<PageId> = self
+            .writer
+            .free
+            .iter()
+            .chain(self.writer.pending.iter().flat_map(|(_, ids)| ids))
+            .chain(&self.freed)
+            .chain(&self.writer.free_list)
+            .copied()
+            .collect();
+        let mut chunks: Vec<&[PageId]> = ids.chunks(FREE_IDS_PER_PAGE).collect();
+        // taking a page of the free list may leave a page of it with nothing to hold.
+        chunks.resize(chain.len(), &[]);
+        let mut next = 0;
+        for (&id, chunk) in chain.iter().zip(chunks).rev() {
+            pager::write_page(&mut self.writer.file, id, &pager::free_page(next, chunk))?;
+            next = id;
+        }
+        self.meta.free_list = next;
+        if self.inner.opts.sync {
+            self.writer.file.sync_data()?;
+        }
+        let meta = self.meta;
+        pager::write_page(&mut self.writer.file, meta.txid % 2, &meta.encode())?;
+        if self.inner.opts.sync {
+            self.writer.file.sync_data()?;
+        }
+
+        for (id, node) in self.dirty.drain() {
+            self.inner.pager.cache(id, node);
+        }
+        let mut released = std::mem::take(&mut self.freed);
+        released.extend(std::mem::replace(&mut self.writer.free_list, chain));
+        self.writer.pending.push_back((meta.txid, released));
+        self.writer.meta = meta;
+        self.inner.readers.lock().unwrap().meta = meta;
+        Ok(())
+    }
+}
+
+impl KvsEngine for BTreeKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let key = self.internal_key(&key);
+        self.write(|txn| txn.set(key, value, None))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live(&key)?.map(|(_, value)| value))
+    }
+
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self.live(&key)?.map(|(entry, _)| entry.seq))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let key = self.internal_key(&key);
+        self.write(|txn| {
+            if txn.get(&key)?.is_none() {
+                return Err(KvsError::KeyNotFoundError);
+            }
+            txn.remove(&key)?;
+            Ok(())
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => self.internal_key(key),
+            Bound::Unbounded => self.internal_key(&[]),
+        };
+        let start = match range.start_bound() {
+            Bound::Excluded(_) => Bound::Excluded(start.as_slice()),
+            _ => Bound::Included(start.as_slice()),
+        };
+        let prefix = self.keyspace.to_be_bytes();
+        self.collect(
+            start,
+            |key| key.starts_with(&prefix) && range.contains(&key[4..].to_vec()),
+            limit,
+        )
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = self.internal_key(&prefix);
+        self.collect(
+            Bound::Included(&prefix),
+            |key| key.starts_with(&prefix),
+            limit,
+        )
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|txn| {
+            for op in batch {
+                match op {
+                    BatchOp::Set { key, value } => txn.set(self.internal_key(&key), value, None)?,
+                    BatchOp::Remove { key } => {
+                        txn.remove(&self.internal_key(&key))?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = self.internal_key(&key);
+        self.write(|txn| {
+            let current = match txn.get(&key)? {
+                Some(entry) => Some(self.inner.read_value(&entry.value)?),
+                None => None,
+            };
+            if current != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => txn.set(key, value, None)?,
+                None if current.is_some() => {
+                    txn.remove(&key)?;
+                }
+                None => (),
+            }
+            Ok(true)
+        })
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let key = self.internal_key(&key);
+        let expire_at = Some(expiry::expire_at(ttl));
+        self.write(|txn| txn.set(key, value, expire_at))
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.set_expiry(key, Some(expiry::expire_at(ttl)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let (entry, _) = self.live(&key)?.ok_or(KvsError::KeyNotFoundError)?;
+        Ok(entry
+            .expire_at
+            .map(|expire_at| expiry::time_left(expire_at, expiry::now())))
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.set_expiry(key, None)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>,
+    {
+        let mut txn = BTreeTransaction {
+            engine: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let ret = f(&mut txn)?;
+        let BTreeTransaction { reads, writes, .. } = txn;
+        self.write(|txn| {
+            for (key, seq) in reads {
+                let current = txn.get(&self.internal_key(&key))?;
+                if current.map(|entry| entry.seq) != seq {
+                    return Err(KvsError::TransactionConflict);
+                }
+            }
+            for (key, value) in writes {
+                match value {
+                    Some(value) => txn.set(self.internal_key(&key), value, None)?,
+                    None => {
+                        txn.remove(&self.internal_key(&key))?;
+                    }
+                }
+            }
+            Ok(ret)
+        })
+    }
+
+    fn open_keyspace(&self, name: &str) -> Result<Self> {
+        let keyspace = if name == DEFAULT_KEYSPACE {
+            DEFAULT_KEYSPACE_ID
+        } else {
+            check_keyspace(name)?;
+            match self.keyspace_id(name)? {
+                Some(id) => id,
+                None => {
+                    let key = internal_key(KEYSPACES_ID, name.as_bytes());
+                    self.write(|txn| match txn.get(&key)? {
+                        // created meanwhile.
+                        Some(entry) => self.inner.read_keyspace_id(&entry),
+                        None => {
+                            let id = txn.meta.next_keyspace;
+                            txn.meta.next_keyspace += 1;
+                            txn.set(key, id.to_be_bytes().to_vec(), None)?;
+                            Ok(id)
+                        }
+                    })?
+                }
+            }
+        };
+        Ok(BTreeKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    // the name goes first, then the keys a commit at a time. Ids are never reused, so
+    // the keys a crash leaves behind are unreachable.
+    fn drop_keyspace(&self, name: &str) -> Result<bool> {
+        check_keyspace(name)?;
+        let key = internal_key(KEYSPACES_ID, name.as_bytes());
+        let id = self.write(|txn| match txn.get(&key)? {
+            Some(entry) => {
+                txn.remove(&key)?;
+                self.inner.read_keyspace_id(&entry).map(Some)
+            }
+            None => Ok(None),
+        })?;
+        let prefix = match id {
+            Some(id) => id.to_be_bytes(),
+            None => return Ok(false),
+        };
+        loop {
+            let keys = {
+                let snapshot = self.inner.snapshot();
+                let mut cursor = snapshot.seek(Bound::Included(&prefix))?;
+                let mut keys = Vec::new();
+                while keys.len() < DROP_PAGE {
+                    match cursor.next()? {
+                        Some(entry) if entry.key.starts_with(&prefix) => keys.push(entry.key),
+                        _ => break,
+                    }
+                }
+                keys
+            };
+            if keys.is_empty() {
+                return Ok(true);
+            }
+            self.write(|txn| {
+                for key in &keys {
+                    txn.remove(key)?;
+                }
+                Ok(())
+            })?;
+        }
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let prefix = KEYSPACES_ID.to_be_bytes();
+        let snapshot = self.inner.snapshot();
+        let mut cursor = snapshot.seek(Bound::Included(&prefix))?;
+        let mut names = Vec::new();
+        while let Some(entry) = cursor.next()? {
+            if !entry.key.starts_with(&prefix) {
+                break;
+            }
+            names.push(super::into_string(entry.key[4..].to_vec())?);
+        }
+        Ok(names)
+    }
+
+    // the pages of the last commit are copied as they are, free ones included, while
+    // a snapshot keeps them from being reused.
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let _lock = DirLock::acquire(dir, true)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(DATA_FILE))?;
+        let snapshot = self.inner.snapshot();
+        self.inner.pager.copy(&mut file, 2..snapshot.meta.pages)?;
+        let meta = snapshot.meta;
+        pager::write_page(&mut file, meta.txid % 2, &meta.encode())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+// BTreeTransaction reads through snapshots and keeps the writes until it commits. The
+// commit a key read is written by tells whether it changes meanwhile.
+struct BTreeTransaction<'a> {
+    engine: &'a BTreeKvsEngine,
+    // the commit every key read is written by, None if it does not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // the last write of every key written, None for a remove.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction for BTreeTransaction<'a> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let live = self.engine.live(&key)?;
+        // a key read again keeps the commit it is read first with, so a change in
+        // between is a conflict as well.
+        self.reads
+            .entry(key)
+            .or_insert_with(|| live.as_ref().map(|(entry, _)| entry.seq));
+        Ok(live.map(|(_, value)| value))
+    }
+
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        let seq = self.engine.live(&key)?.map(|(entry, _)| entry.seq);
+        Ok(*self.reads.entry(key).or_insert(seq))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFoundError);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+}
+
+// lookup finds `key` in the tree under `root`, reading the nodes through `node`.
+fn lookup<F>(root: PageId, key: &[u8], node: F) -> Result<Option<Entry>>
+where
+    F: Fn(PageId) -> Result<Arc<Node>>,
+{
+    let mut id = root;
+    loop {
+        match &*node(id)? {
+            Node::Branch { keys, children } => id = children[child_index(keys, key)],
+            Node::Leaf(entries) => {
+                let i = entries.binary_search_by(|entry| entry.key.as_slice().cmp(key));
+                return Ok(i.ok().map(|i| entries[i].clone()));
+            }
+        }
+    }
+}
+
+// child_index returns the child of a branch `key` belongs under.
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|k| k.as_slice() <= key)
+}
+
+// split_point returns the index the running total of `sizes` first grows over `half` at.
+fn split_point(sizes: impl Iterator<Item = usize>, half: usize) -> usize {
+    let mut total = 0;
+    for (i, size) in sizes.enumerate() {
+        total += size;
+        if total > half {
+            return i;
+        }
+    }
+    0
+}
+
+// same_chain tells whether two values share their overflow pages.
+fn same_chain(a: &Value, b: &Value) -> bool {
+    matches!((a, b), (Value::Overflow { page: a, .. }, Value::Overflow { page: b, .. }) if a == b)
+}
+
+fn internal_key(keyspace: u32, key: &[u8]) -> Vec<u8> {
+    let mut internal = keyspace.to_be_bytes().to_vec();
+    internal.extend_from_slice(key);
+    internal
+}
@@ -0,0 +1,222 @@
+//! # Value cache
+//!
+//! `KvStore` keeps the values read last in a cache bounded in bytes, which saves the
+//! read of the record and its decoding for a hot key. A value is cached by where its
+//! record lives, the gen of the segment and the position in it, rather than by its
+//! key. A record never changes once written, so a reader racing with a write can at
+//! worst cache a value nobody asks for any more, never hand out a stale one. Writes
+//! still drop the value they replace to free its room early.
+//!
+//! The cache is split into shards, each of them a CLOCK under its own lock, so
+//! concurrent readers rarely wait on each other.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const SHARDS: usize = 16;
+
+// Location is the gen of the segment a record lives in and its position in it.
+type Location = (u64, u64);
+
+/// CacheStats counts the reads of a `KvStore` served from its value cache, and the
+/// ones which went to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads which went to disk.
+    pub misses: u64,
+}
+
+/// ValueCache keeps the values of at most `capacity` bytes of records.
+pub(crate) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> Self {
+        let capacity = (capacity / SHARDS as u64) as usize;
+        ValueCache {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(Shard::new(capacity)))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// `get` returns the cached value of the record at `pos` of segment `gen`.
+    pub fn get(&self, gen: u64, pos: u64) -> Option<Vec<u8>> {
+        let value = self.shard(gen, pos).lock().unwrap().get((gen, pos));
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// `insert` caches the value of the record at `pos` of segment `gen`, a value
+    /// larger than a shard is left out.
+    pub fn insert(&self, gen: u64, pos: u64, value: &[u8]) {
+        self.shard(gen, pos)
+            .lock()
+            .unwrap()
+            .insert((gen, pos), value);
+    }
+
+    /// `remove` drops the value of the record at `pos` of segment `gen`.
+    pub fn remove(&self, gen: u64, pos: u64) {
+        self.shard(gen, pos).lock().unwrap().remove((gen, pos));
+    }
+
+    /// `clear` drops every value, the hit and miss counters are kept.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    /// `stats` returns the hits and misses counted so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn shard(&self, gen: u64, pos: u64) -> &Mutex<Shard> {
+        // records sit at uneven offsets, a multiplicative hash spreads them well enough.
+        let hash = (gen.rotate_left(32) ^ pos).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(hash >> 32) as usize % SHARDS]
+    }
+}
+
+struct Shard {
+    capacity: usize,
+    // bytes of the values cached.
+    size: usize,
+    slots: Vec<Slot>,
+    // location to its slot.
+    map: HashMap<Location, usize>,
+    hand: usize,
+}
+
+struct Slot {
+    location: Location,
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Self {
+        Shard {
+            capacity,
+            size: 0,
+            slots: Vec::new(),
+            map: HashMap::new(),
+            hand: 0,
+        }
+    }
+
+    fn get(&mut self, location: Location) -> Option<Vec<u8>> {
+        let slot = &mut self.slots[*self.map.get(&location)?];
+        slot.referenced = true;
+        Some(slot.value.clone())
+    }
+
+    fn insert(&mut self, location: Location, value: &[u8]) {
+        if value.len() > self.capacity || self.map.contains_key(&location) {
+            return;
+        }
+        // the hand skips the values read since it last passed, and evicts the first
+        // one which is not.
+        while self.size + value.len() > self.capacity {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = &mut self.slots[self.hand];
+            if slot.referenced {
+                slot.referenced = false;
+                self.hand += 1;
+            } else {
+                self.evict(self.hand);
+            }
+        }
+        self.map.insert(location, self.slots.len());
+        self.size += value.len();
+        self.slots.push(Slot {
+            location,
+            value: value.to_vec(),
+            referenced: false,
+        });
+    }
+
+    fn remove(&mut self, location: Location) {
+        if let Some(&i) = self.map.get(&location) {
+            self.evict(i);
+        }
+    }
+
+    // `evict` drops the slot `i` and moves the last slot into its place.
+    fn evict(&mut self, i: usize) {
+        let slot = self.slots.swap_remove(i);
+        self.map.remove(&slot.location);
+        self.size -= slot.value.len();
+        if let Some(moved) = self.slots.get(i) {
+            self.map.insert(moved.location, i);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.map.clear();
+        self.size = 0;
+        self.hand = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_unreferenced() {
+        let mut shard = Shard::new(30);
+        for i in 0..3 {
+            shard.insert((1, i), &[i as u8; 10]);
+        }
+        assert_eq!(shard.get((1, 0)), Some(vec![0; 10]));
+        // the value read is spared, the next one goes.
+        shard.insert((1, 3), &[3; 10]);
+        assert!(shard.get((1, 0)).is_some());
+        assert!(shard.get((1, 1)).is_none());
+        assert!(shard.get((1, 2)).is_some());
+        assert_eq!(shard.size, 30);
+
+        shard.remove((1, 2));
+        assert!(shard.get((1, 2)).is_none());
+        assert_eq!(shard.get((1, 3)), Some(vec![3; 10]));
+        assert_eq!(shard.size, 20);
+        // a value larger than the shard is not cached.
+        shard.insert((1, 4), &[4; 31]);
+        assert!(shard.get((1, 4)).is_none());
+        shard.clear();
+        assert!(shard.get((1, 0)).is_none());
+    }
+
+    #[test]
+    fn count_hits() {
+        let cache = ValueCache::new(1024 * SHARDS as u64);
+        assert!(cache.get(1, 0).is_none());
+        cache.insert(1, 0, b"value");
+        assert_eq!(cache.get(1, 0), Some(b"value".to_vec()));
+        cache.remove(1, 0);
+        assert!(cache.get(1, 0).is_none());
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+    }
+}
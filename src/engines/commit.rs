@@ -0,0 +1,218 @@
+//! # Group commit
+//!
+//! Writers append their records to the active segment under the writer lock and get a
+//! `Ticket` for the end of their record. Waiting on the ticket happens after the lock
+//! is released: the first waiter becomes the leader and calls `fdatasync` once for
+//! everything appended so far, the others wait for it and only sync themselves if
+//! their record was appended after the leader started.
+
+use crate::Result;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// SyncData is the part of a segment file the group commit syncs through.
+pub(crate) trait SyncData {
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+pub(crate) struct GroupCommit<F = File> {
+    state: Mutex<State<F>>,
+    synced: Condvar,
+}
+
+struct State<F> {
+    // the active segment.
+    file: Arc<F>,
+    // bytes appended and bytes known to be on disk, counted over all segments.
+    written: u64,
+    synced: u64,
+    // whether a leader is syncing right now.
+    syncing: bool,
+}
+
+impl<F: SyncData> GroupCommit<F> {
+    pub fn new(file: Arc<F>) -> Self {
+        GroupCommit {
+            state: Mutex::new(State {
+                file,
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// `appended` accounts `len` bytes appended to the active segment and returns the
+    /// ticket for them.
+    pub fn appended(self: &Arc<Self>, len: u64) -> Ticket<F> {
+        let mut state = self.state.lock().unwrap();
+        state.written += len;
+        Ticket {
+            commit: self.clone(),
+            pos: state.written,
+        }
+    }
+
+    /// `unsynced` returns the bytes appended but not synced yet.
+    pub fn unsynced(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.written - state.synced
+    }
+
+    /// `ticket` returns the ticket for everything appended so far, to be waited on
+    /// once the writer lock is released.
+    pub fn ticket(self: &Arc<Self>) -> Ticket<F> {
+        self.appended(0)
+    }
+
+    /// `rolled` switches to a new active segment. The caller has to sync the old one
+    /// first, and holds the writer lock so nothing is appended meanwhile.
+    pub fn rolled(&self, file: Arc<F>) {
+        self.state.lock().unwrap().file = file;
+    }
+
+    fn wait(&self, pos: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= pos {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // become the leader and sync the records of everyone waiting.
+            state.syncing = true;
+            let target = state.written;
+            let file = state.file.clone();
+            drop(state);
+            let result = file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && state.synced < target {
+                state.synced = target;
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
+
+/// Ticket stands for a record appended to the log.
+pub(crate) struct Ticket<F = File> {
+    commit: Arc<GroupCommit<F>>,
+    pos: u64,
+}
+
+impl<F: SyncData> Ticket<F> {
+    /// `wait` returns once the record is on disk.
+    pub fn wait(self) -> Result<()> {
+        self.commit.wait(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn concurrent_wait() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = Arc::new(File::create(temp_dir.path().join("1.log")).unwrap());
+        let commit = Arc::new(GroupCommit::new(file.clone()));
+        let lock = Arc::new(Mutex::new(()));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let commit = commit.clone();
+                let file = file.clone();
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let ticket = {
+                            let _guard = lock.lock().unwrap();
+                            (&*file).write_all(b"record").unwrap();
+                            commit.appended(6)
+                        };
+                        ticket.wait().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(commit.unsynced(), 0);
+        assert_eq!(file.metadata().unwrap().len(), 8 * 100 * 6);
+    }
+
+    // CountingFile stands for a segment, it knows how many bytes are appended to it
+    // and how many of them a `sync_data` call covered.
+    #[derive(Default)]
+    struct CountingFile {
+        state: Mutex<(u64, u64)>,
+        syncs: AtomicUsize,
+    }
+
+    impl CountingFile {
+        fn append(&self, len: u64) {
+            self.state.lock().unwrap().0 += len;
+        }
+
+        fn synced(&self) -> u64 {
+            self.state.lock().unwrap().1
+        }
+    }
+
+    impl SyncData for CountingFile {
+        fn sync_data(&self) -> io::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.1 = state.0;
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn acknowledged_tickets_are_synced() {
+        let file = Arc::new(CountingFile::default());
+        let commit = Arc::new(GroupCommit::new(file.clone()));
+        let lock = Arc::new(Mutex::new(()));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let commit = commit.clone();
+                let file = file.clone();
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let ticket = {
+                            let _guard = lock.lock().unwrap();
+                            file.append(6);
+                            commit.appended(6)
+                        };
+                        let pos = ticket.pos;
+                        ticket.wait().unwrap();
+                        assert!(file.synced() >= pos);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(file.synced(), 8 * 100 * 6);
+        let syncs = file.syncs.load(Ordering::SeqCst);
+        assert!(syncs > 0 && syncs <= 8 * 100);
+    }
+}
@@ -0,0 +1,251 @@
+//! # Dump format
+//!
+//! `KvsEngine::export` writes the keys of a store as a stream any engine reads back
+//! with `KvsEngine::import`, which makes it the way to move a store between engines:
+//!
+//! ```text
+//! header:   | magic 2B | version 1B |
+//! keyspace: | kind 1B = 1 | name len 4B | name |
+//! entry:    | kind 1B = 2 | key len 4B | value len 4B | ttl 8B | key | value |
+//! end:      | kind 1B = 0 | count 8B | crc32 4B |
+//! ```
+//!
+//! Integers are little endian. Every keyspace record is followed by the entries of
+//! that keyspace, the default keyspace first. The ttl is the time left in milliseconds
+//! when the entry is exported, 0 means the key never expires. The end record counts
+//! the entries and its crc32 covers everything before it, so a truncated or damaged
+//! dump fails the import.
+
+use super::DEFAULT_KEYSPACE;
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
+
+const MAGIC: [u8; 2] = [0xb7, 0x44];
+const VERSION: u8 = 1;
+const KIND_END: u8 = 0;
+const KIND_KEYSPACE: u8 = 1;
+const KIND_ENTRY: u8 = 2;
+// how many keys are read per scan on export, and written per batch on import.
+const PAGE: usize = 1024;
+
+/// `export` writes every keyspace of the store `engine` belongs to into `writer`.
+/// Returns the number of entries written.
+pub(crate) fn export<E: KvsEngine>(engine: &E, writer: &mut dyn Write) -> Result<u64> {
+    let mut writer = DumpWriter::new(writer);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    let mut names = vec![DEFAULT_KEYSPACE.to_owned()];
+    names.extend(engine.keyspaces()?);
+    for name in names {
+        let keyspace = engine.open_keyspace(&name)?;
+        writer.write_all(&[KIND_KEYSPACE])?;
+        writer.write_all(&(name.len() as u32).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+        export_keyspace(&keyspace, &mut writer)?;
+    }
+    let count = writer.count;
+    writer.write_all(&[KIND_END])?;
+    writer.write_all(&count.to_le_bytes())?;
+    let crc = writer.hasher.clone().finalize();
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.inner.flush()?;
+    Ok(count)
+}
+
+// export_keyspace writes the entries of a single keyspace, page by page.
+fn export_keyspace<E: KvsEngine>(engine: &E, writer: &mut DumpWriter<'_>) -> Result<()> {
+    let mut start = Bound::Unbounded;
+    loop {
+        let pairs = engine.scan_bytes((start, Bound::Unbounded), PAGE)?;
+        let last = match pairs.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        for (key, value) in pairs {
+            let ttl = match engine.ttl_bytes(key.clone()) {
+                // at least a millisecond, 0 stands for no expiry.
+                Ok(Some(ttl)) => (ttl.as_millis() as u64).max(1),
+                Ok(None) => 0,
+                // expired since the scan.
+                Err(KvsError::KeyNotFoundError) => continue,
+                Err(e) => return Err(e),
+            };
+            writer.write_all(&[KIND_ENTRY])?;
+            writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            writer.write_all(&(value.len() as u32).to_le_bytes())?;
+            writer.write_all(&ttl.to_le_bytes())?;
+            writer.write_all(&key)?;
+            writer.write_all(&value)?;
+            writer.count += 1;
+        }
+        start = Bound::Excluded(last);
+    }
+}
+
+/// `import` writes the entries of the dump in `reader` into the store `engine` belongs
+/// to, creating the keyspaces as needed. Keys of the store missing from the dump are
+/// left as they are. Returns the number of entries read. A broken dump is only found
+/// out once it is read up to the damage, the entries in front of it are written.
+pub(crate) fn import<E: KvsEngine>(engine: &E, reader: &mut dyn Read) -> Result<u64> {
+    let mut reader = DumpReader::new(reader);
+    let mut header = [0u8; 3];
+    reader.read_exact(&mut header)?;
+    if header[..2] != MAGIC {
+        return Err(corrupted(0, "not a dump"));
+    }
+    if header[2] != VERSION {
+        return Err(corrupted(2, &format!("unknown version {}", header[2])));
+    }
+    let mut keyspace: Option<E> = None;
+    let mut batch = WriteBatch::new();
+    loop {
+        let offset = reader.offset;
+        match reader.read_u8()? {
+            KIND_KEYSPACE => {
+                let len = reader.read_u32()? as usize;
+                let name = String::from_utf8(reader.read_vec(len)?)
+                    .map_err(|_| corrupted(offset, "keyspace name is not utf-8"))?;
+                flush(keyspace.as_ref(), &mut batch)?;
+                keyspace = Some(engine.open_keyspace(&name)?);
+            }
+            KIND_ENTRY => {
+                let engine = keyspace
+                    .as_ref()
+                    .ok_or_else(|| corrupted(offset, "entry outside of a keyspace"))?;
+                let key_len = reader.read_u32()? as usize;
+                let value_len = reader.read_u32()? as usize;
+                let ttl = reader.read_u64()?;
+                let key = reader.read_vec(key_len)?;
+                let value = reader.read_vec(value_len)?;
+                reader.count += 1;
+                if ttl == 0 {
+                    batch.set(key, value);
+                    if batch.len() >= PAGE {
+                        flush(Some(engine), &mut batch)?;
+                    }
+                } else {
+                    engine.set_with_ttl_bytes(key, value, Duration::from_millis(ttl))?;
+                }
+            }
+            KIND_END => {
+                let count = reader.read_u64()?;
+                let crc = reader.hasher.clone().finalize();
+                let mut expected = [0u8; 4];
+                reader.read_exact(&mut expected)?;
+                if count != reader.count {
+                    return Err(corrupted(offset, "wrong entry count"));
+                }
+                if crc != u32::from_le_bytes(expected) {
+                    return Err(corrupted(offset, "checksum mismatch"));
+                }
+                flush(keyspace.as_ref(), &mut batch)?;
+                return Ok(count);
+            }
+            kind => return Err(corrupted(offset, &format!("unknown record kind {}", kind))),
+        }
+    }
+}
+
+// flush writes the sets collected in `batch` into `engine`.
+fn flush<E: KvsEngine>(engine: Option<&E>, batch: &mut WriteBatch) -> Result<()> {
+    match engine {
+        Some(engine) if !batch.is_empty() => engine.write_batch(std::mem::take(batch)),
+        _ => Ok(()),
+    }
+}
+
+fn corrupted(offset: u64, reason: &str) -> KvsError {
+    KvsError::CorruptedRecordError {
+        offset,
+        reason: format!("dump: {}", reason),
+    }
+}
+
+// DumpWriter counts the entries written and checksums the bytes.
+struct DumpWriter<'a> {
+    inner: &'a mut dyn Write,
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl<'a> DumpWriter<'a> {
+    fn new(inner: &'a mut dyn Write) -> Self {
+        DumpWriter {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.inner.write_all(buf)?;
+        Ok(())
+    }
+}
+
+// DumpReader counts the entries read and checksums the bytes, it knows its offset to
+// report where a dump is broken.
+struct DumpReader<'a> {
+    inner: &'a mut dyn Read,
+    hasher: crc32fast::Hasher,
+    count: u64,
+    offset: u64,
+}
+
+impl<'a> DumpReader<'a> {
+    fn new(inner: &'a mut dyn Read) -> Self {
+        DumpReader {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+            offset: 0,
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.inner.read_exact(buf) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(corrupted(self.offset, "unexpected end"))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.hasher.update(buf);
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // read_vec reads `len` bytes, through `take` so a broken length does not allocate
+    // them up front.
+    fn read_vec(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(corrupted(self.offset, "unexpected end"));
+        }
+        self.hasher.update(&buf);
+        self.offset += len as u64;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,29 @@
+//! Keys with a time to live carry the time they expire at, in milliseconds since the
+//! unix epoch, so an expiry survives a restart as it is.
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `now` returns the current time in milliseconds since the unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// `expire_at` returns when a key set now with the time to live `ttl` expires.
+pub(crate) fn expire_at(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now().saturating_add(ttl)
+}
+
+/// `is_expired` tells whether a key expiring at `expire_at` is expired at `now`.
+pub(crate) fn is_expired(expire_at: Option<u64>, now: u64) -> bool {
+    matches!(expire_at, Some(at) if at <= now)
+}
+
+/// `time_left` returns the time to live left at `now` of a key expiring at `expire_at`.
+pub(crate) fn time_left(expire_at: u64, now: u64) -> Duration {
+    Duration::from_millis(expire_at.saturating_sub(now))
+}
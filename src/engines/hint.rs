@@ -0,0 +1,251 @@
+//! # Hint files
+//!
+//! A hint file `<gen>.hint` is written by compaction next to the segment it produces.
+//! It lists where every record of the segment lives, so `KvStore::open` can rebuild
+//! the index without reading the values:
+//!
+//! ```text
+//! header:  | magic 2B | version 1B | count 4B | seq 8B |
+//! entry:   | key len 4B | pos 8B | len 8B | expire at 8B | seq 8B | key |   (repeated count times)
+//! trailer: | crc32 4B |
+//! ```
+//!
+//! Integers are little endian and the crc32 covers everything before it. An expire
+//! time of 0 means the key never expires. The sequence number in the header is the
+//! highest one of the merged segments, the records of dropped keys included. Version 1
+//! entries have no expire time, versions before 3 have no sequence numbers at all.
+
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 2] = [0xb7, 0x48];
+const VERSION: u8 = 3;
+const V2_HEADER_LEN: usize = 7;
+const HEADER_LEN: usize = 15;
+const V1_ENTRY_HEADER_LEN: usize = 20;
+const V2_ENTRY_HEADER_LEN: usize = 28;
+const ENTRY_HEADER_LEN: usize = 36;
+
+/// Hint is what a hint file holds.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Hint {
+    pub seq: u64,
+    pub entries: Vec<HintEntry>,
+}
+
+/// HintEntry locates a record in the segment a hint file belongs to.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HintEntry {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: u64,
+    pub expire_at: Option<u64>,
+    pub seq: u64,
+}
+
+/// `write_hint` writes the hint to `path` and syncs it to disk.
+pub(crate) fn write_hint(path: &Path, hint: &Hint) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.extend_from_slice(&(hint.entries.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&hint.seq.to_le_bytes());
+    for entry in &hint.entries {
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.pos.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.expire_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// `read_hint` loads the hint file at `path`. It returns None if the file does not
+/// exist or is broken, the segment has to be replayed then.
+pub(crate) fn read_hint(path: &Path) -> Result<Option<Hint>> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint = parse(&buf);
+    if hint.is_none() {
+        warn!("ignore broken hint file {}", path.display());
+    }
+    Ok(hint)
+}
+
+/// `remove_hint` removes the hint file at `path` if there is one.
+pub(crate) fn remove_hint(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn parse(buf: &[u8]) -> Option<Hint> {
+    if buf.len() < V2_HEADER_LEN + 4 {
+        return None;
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return None;
+    }
+    if body[0..2] != MAGIC {
+        return None;
+    }
+    let (header_len, entry_header_len) = match body[2] {
+        1 => (V2_HEADER_LEN, V1_ENTRY_HEADER_LEN),
+        2 => (V2_HEADER_LEN, V2_ENTRY_HEADER_LEN),
+        VERSION => (HEADER_LEN, ENTRY_HEADER_LEN),
+        _ => return None,
+    };
+    if body.len() < header_len {
+        return None;
+    }
+    let count = u32::from_le_bytes([body[3], body[4], body[5], body[6]]);
+    let mut seq = [0u8; 8];
+    if header_len == HEADER_LEN {
+        seq.copy_from_slice(&body[V2_HEADER_LEN..HEADER_LEN]);
+    }
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut rest = &body[header_len..];
+    for _ in 0..count {
+        if rest.len() < entry_header_len {
+            return None;
+        }
+        let (header, tail) = rest.split_at(entry_header_len);
+        let key_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut pos = [0u8; 8];
+        pos.copy_from_slice(&header[4..12]);
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&header[12..20]);
+        let mut expire_at = [0u8; 8];
+        if entry_header_len >= V2_ENTRY_HEADER_LEN {
+            expire_at.copy_from_slice(&header[20..28]);
+        }
+        let mut entry_seq = [0u8; 8];
+        if entry_header_len == ENTRY_HEADER_LEN {
+            entry_seq.copy_from_slice(&header[28..36]);
+        }
+        if tail.len() < key_len {
+            return None;
+        }
+        let (key, tail) = tail.split_at(key_len);
+        entries.push(HintEntry {
+            key: key.to_vec(),
+            pos: u64::from_le_bytes(pos),
+            len: u64::from_le_bytes(len),
+            expire_at: Some(u64::from_le_bytes(expire_at)).filter(|at| *at != 0),
+            seq: u64::from_le_bytes(entry_seq),
+        });
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(Hint {
+        seq: u64::from_le_bytes(seq),
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn hint() -> Hint {
+        Hint {
+            seq: 9,
+            entries: vec![
+                HintEntry {
+                    key: b"key1".to_vec(),
+                    pos: 0,
+                    len: 30,
+                    expire_at: None,
+                    seq: 3,
+                },
+                HintEntry {
+                    key: b"key2".to_vec(),
+                    pos: 30,
+                    len: 42,
+                    expire_at: Some(1_600_000_000_000),
+                    seq: 7,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn write_read() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.hint");
+        assert_eq!(read_hint(&path).unwrap(), None);
+        write_hint(&path, &hint()).unwrap();
+        assert_eq!(read_hint(&path).unwrap(), Some(hint()));
+        remove_hint(&path).unwrap();
+        remove_hint(&path).unwrap();
+        assert_eq!(read_hint(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn broken_hint() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.hint");
+        write_hint(&path, &hint()).unwrap();
+        let data = fs::read(&path).unwrap();
+        for i in 0..data.len() {
+            let mut broken = data.clone();
+            broken[i] ^= 0x01;
+            fs::write(&path, &broken).unwrap();
+            assert_eq!(
+                read_hint(&path).unwrap(),
+                None,
+                "flip at {} not detected",
+                i
+            );
+        }
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(read_hint(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn read_v1_hint() {
+        let mut buf = MAGIC.to_vec();
+        buf.push(1);
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&30u64.to_le_bytes());
+        buf.extend_from_slice(&42u64.to_le_bytes());
+        buf.extend_from_slice(b"key2");
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        let entry = HintEntry {
+            key: b"key2".to_vec(),
+            pos: 30,
+            len: 42,
+            expire_at: None,
+            seq: 0,
+        };
+        let hint = Hint {
+            seq: 0,
+            entries: vec![entry],
+        };
+        assert_eq!(parse(&buf), Some(hint));
+    }
+}
@@ -1,528 +1,2043 @@
 //! # KvStore Crate
+//!
+//! The log is split into numbered segments `<gen>.log`. New records are appended to
+//! the active segment (the one with the highest gen), which is sealed and replaced
+//! by a new one once it grows over `KvStoreOptions::segment_size`. Sealed segments are never
+//! modified again, compaction merges them into a new segment and removes them.
+//!
+//! Compaction runs on a background thread, writes go on to the active segment while
+//! it copies the live records and the writer lock is only taken to start and finish it.
+//!
+//! A key with an expiry is hidden from reads once it expires. The background thread
+//! drops expired keys from the index every `KvStoreOptions::sweep_interval`, and
+//! compaction leaves their records behind. No tombstone is written for them, an
+//! expired record stays expired when the log is replayed.
+//!
+//! Every write takes the next sequence number, a batch takes a single one. A snapshot
+//! pins the sequence number of the last write. While there are snapshots, a write
+//! keeps the version of the key it replaces in the history, keyed by the key and its
+//! own sequence number. A snapshot looks for the first version replaced after it and
+//! falls back to the index. The segments merged by a compaction are only removed once
+//! the snapshots taken before it are dropped. If the store is closed before that, they
+//! are replayed next to the merged segment on open, which yields the same index.
+//!
+//! Values of at least `KvStoreOptions::compress_threshold` bytes are compressed with
+//! the codec of `KvStoreOptions::compression`, which each record names on its own, see
+//! `record`. Reads decompress whatever codec they find. Switching the codec leaves the
+//! records written so far as they are, compaction rewrites the ones it copies.
+//!
+//! Given `KvStoreOptions::cache_size`, the values read last are kept in a cache, see
+//! `cache`. A write drops the value it replaces from it, and a compaction clears it
+//! once the readers switch over to the new segment.
+//!
+//! Given `KvStoreOptions::mmap`, the sealed segments are read through memory maps
+//! rather than with a pread per read, see `segment`.
+//!
+//! A named keyspace is a store of its own in `keyspaces/<name>` under the directory of
+//! the store, with its own index, segments and compaction. The keyspaces opened are
+//! kept open by the store, so every handle of a keyspace shares the same writer.
+//!
+//! The store takes the lock file of its directory on open, see `lock`, and releases
+//! it once every handle is dropped. Its keyspaces are covered by the same lock.
+//!
+//! A checkpoint hard links the sealed segments and their hint files into another
+//! directory and copies the active segment up to the end of the last write, all of it
+//! taken under the writer lock, so it sees the store as of a single write. Only the
+//! copy of the active segment is made after the lock is released, from a file opened
+//! under it.
 
 pub use crate::{KvsError, Result};
 
+use super::batch::{BatchOp, WriteBatch};
+use super::cache::{CacheStats, ValueCache};
+use super::commit::{GroupCommit, Ticket};
+use super::expiry;
+use super::hint::{self, Hint, HintEntry};
+use super::lock::DirLock;
+use super::options::{Compression, KvStoreOptions, SyncPolicy};
+use super::record::{self, Command, RecordReader};
+use super::segment::Segment;
+use super::transaction::Transaction;
+use super::{check_keyspace, into_string, into_string_pairs, to_bytes_bound, DEFAULT_KEYSPACE};
 use crate::KvsEngine;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender};
+use crossbeam::epoch::{self, Atomic, Owned};
+use crossbeam_skiplist::SkipMap;
 use positioned_io::ReadAt;
-use serde::{Deserialize, Serialize};
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
-use std::fmt::{self, Display};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock, Mutex};
-use crossbeam_skiplist::SkipMap;
-use crossbeam::atomic::AtomicCell;
+use std::ptr;
 use std::sync::atomic::*;
-use std::cell::RefCell;
-use crate::error::KvsError::IOError;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+// how many times `get` follows the index again when the segment it points to is gone.
+const READ_RETRIES: usize = 3;
+// the directory the named keyspaces are kept in, under the directory of the store.
+const KEYSPACES_DIR: &str = "keyspaces";
 
-const COMPACT_THRESHOLD_BYTES: u64 = 1024 * 1024;
-
-/// KvStore store data in memory, without read write lock-free
 #[derive(Clone)]
-pub struct KvOldStore {
+pub struct KvStore {
     path: Arc<PathBuf>,
-    // index stores key to the position in file and length.
-    // TODO: a simple RwLock doesn't make it lock free.
-    db: Arc<RwLock<KvDB>>,
-}
-
-struct KvDB {
-    // what we need to protect is the index along with the internal meta data.
-    // for read and write.
-    index: HashMap<String, Meta>,
-    // for reading, since we use pread here, we not need to track cursor for reader, we don't even need to protect reader.
-    // what we what to achieve is using some lock-free struct to save index. Thus reader is allowed to access the index without any locking.
-    // considering use the crossbeam::skip_list::SkipMap here.
-    reader: File,
-    // for write.
-    cursor: u64,
-    dangling_bytes: u64,
-    writer: File,   // for writing
+    reader: Arc<IndexReader>,
+    // None if the store is opened read-only.
+    writer: Option<Arc<Mutex<IndexWriter>>>,
+    worker: Option<Arc<Worker>>,
+    history: Arc<History>,
+    // None in the stores kept by `Keyspaces`, which never leave it.
+    keyspaces: Option<Arc<Keyspaces>>,
 }
 
-impl KvOldStore {
+impl KvStore {
     /// open read a file with the given path
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// `open_with_recovery` opens the store and handles a broken log record according to `mode`.
+    pub fn open_with_recovery(path: impl Into<PathBuf>, mode: RecoveryMode) -> Result<Self> {
+        Self::open_with(path, KvStoreOptions::new().recovery(mode))
+    }
+
+    /// `open_with` opens the store with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, opts: KvStoreOptions) -> Result<Self> {
         let path: PathBuf = path.into();
-        fs::create_dir_all(&path)?;
-        let mut index: HashMap<String, Meta> = HashMap::new();
-        let mut reader = open_for_read(&data_path(&path), 0)?;
-        let decoder = serde_json::Deserializer::from_reader(&mut reader);
-        let mut iterator = decoder.into_iter::<Command>();
-        let mut dangling_bytes: u64 = 0;
-        // TODO: can use better op than match
+        if !opts.read_only {
+            fs::create_dir_all(&path)?;
+        }
+        let lock = DirLock::acquire(&path, !opts.read_only)?;
+        let store = Self::open_store(path.clone(), opts.clone())?;
+        let keyspaces = Keyspaces {
+            dir: path.join(KEYSPACES_DIR),
+            opts,
+            default: store.clone(),
+            open: Mutex::new(HashMap::new()),
+            _lock: lock,
+        };
+        Ok(Self {
+            keyspaces: Some(Arc::new(keyspaces)),
+            ..store
+        })
+    }
+
+    // `open_store` opens the store of a single keyspace in `path`.
+    fn open_store(path: PathBuf, opts: KvStoreOptions) -> Result<Self> {
+        if !opts.read_only {
+            fs::create_dir_all(&path)?;
+        }
+        upgrade_single_file_log(&path, opts.read_only)?;
+        let gens = segment_gens(&path, !opts.read_only)?;
+        let index: Index = SkipMap::new();
+        // stale bytes of every segment.
+        let mut segments: BTreeMap<u64, u64> = BTreeMap::new();
         let mut cursor: u64 = 0;
-        while let Some(cmd) = iterator.next() {
-            let new_cursor = iterator.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    if let Some(meta) = index.insert(key, Meta(cursor, new_cursor as u64 - cursor))
-                    {
-                        dangling_bytes += meta.1
-                    }
-                }
-                Command::Remove { key } => {
-                    index.remove(&key);
+        // the highest sequence number in the log.
+        let mut seq: u64 = 0;
+        let now = expiry::now();
+        for (i, &gen) in gens.iter().enumerate() {
+            segments.insert(gen, 0);
+            // a compacted segment comes with a hint file, which saves reading the values.
+            if let Some(hint) = hint::read_hint(&hint_path(&path, gen))? {
+                seq = seq.max(hint.seq);
+                for entry in hint.entries {
+                    seq = seq.max(entry.seq);
+                    let meta = Meta::new(gen, entry.pos, entry.len, entry.expire_at, entry.seq);
+                    index_set(&index, &mut segments, entry.key, meta, now);
                 }
-                _ => (),
+                cursor = fs::metadata(segment_path(&path, gen))?.len();
+                continue;
             }
-            cursor = new_cursor as u64;
+            let last = i + 1 == gens.len();
+            cursor = replay_log(
+                &segment_path(&path, gen),
+                &opts,
+                last,
+                |pos, len, record_seq, cmd| {
+                    seq = seq.max(record_seq);
+                    match cmd {
+                        Command::Set { key, expire_at, .. } => {
+                            let meta = Meta::new(gen, pos, len, expire_at, record_seq);
+                            index_set(&index, &mut segments, key, meta, now);
+                        }
+                        Command::Remove { key } => {
+                            if let Some(entry) = index.remove(&key) {
+                                let meta = entry.value().load();
+                                *segments.entry(meta.gen).or_insert(0) += meta.len;
+                            }
+                            *segments.entry(gen).or_insert(0) += len;
+                        }
+                        _ => (),
+                    }
+                },
+            )?;
         }
-        let file = open_for_append(&data_path(&path))?;
+        let live_bytes = index.iter().map(|entry| entry.value().load().len).sum();
+        let expiring = index
+            .iter()
+            .filter_map(|entry| Some((entry.value().load().expire_at?, entry.key().clone())))
+            .collect();
+        let gen = gens.last().copied().unwrap_or(1);
+        let writer = if opts.read_only {
+            None
+        } else {
+            segments.entry(gen).or_insert(0);
+            Some(Arc::new(open_for_append(&segment_path(&path, gen))?))
+        };
+
+        let arc_index = Arc::new(index);
+        let cache = match opts.cache_size {
+            0 => None,
+            bytes => Some(Arc::new(ValueCache::new(bytes))),
+        };
+        let reader = IndexReader::new(path.clone(), arc_index.clone(), cache, opts.mmap);
+        reader.reopen(segments.keys())?;
+        let reader = Arc::new(reader);
+        let history = Arc::new(History::default());
+        let writer = match writer {
+            Some(writer) => writer,
+            None => {
+                return Ok(Self {
+                    path: Arc::new(path),
+                    reader,
+                    writer: None,
+                    worker: None,
+                    history,
+                    keyspaces: None,
+                })
+            }
+        };
+        let index_writer = IndexWriter {
+            dir: path.clone(),
+            reader: reader.clone(),
+            index: arc_index,
+            gen,
+            cursor,
+            seq,
+            dangling_bytes: segments.values().sum(),
+            live_bytes,
+            expiring,
+            segments,
+            commit: Arc::new(GroupCommit::new(writer.clone())),
+            writer,
+            compacting: false,
+            history: history.clone(),
+            replaced: BTreeSet::new(),
+            retired: Vec::new(),
+            opts,
+        };
+        let sync_interval = index_writer.opts.sync.interval();
+        let sweep_interval = index_writer.opts.sweep_interval;
+        let writer = Arc::new(Mutex::new(index_writer));
+        let worker = Worker::spawn(writer.clone(), sync_interval, sweep_interval)?;
+        // the stale data left by the last run may already be worth a compaction.
+        worker.trigger();
         Ok(Self {
             path: Arc::new(path),
-            db: Arc::new(RwLock::new(KvDB {
-                index,
-                cursor,
-                dangling_bytes,
-                reader,
-                writer: file,
-            })),
+            reader,
+            writer: Some(writer),
+            worker: Some(Arc::new(worker)),
+            history,
+            keyspaces: None,
         })
     }
-}
 
-impl KvDB {
-    fn compact(&mut self, path: &Path) -> Result<()> {
-        // nothing can do if threshold not match.
-        if self.dangling_bytes <= COMPACT_THRESHOLD_BYTES {
-            return Ok(());
+    /// `snapshot` takes a point-in-time view of the store, which does not see the
+    /// writes made after it.
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = match &self.writer {
+            // the writer lock keeps a write from going on while the snapshot is pinned.
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                self.history.pin(writer.seq);
+                writer.seq
+            }
+            // nothing is written to a read-only store, the index is all there is.
+            None => {
+                self.history.pin(0);
+                0
+            }
+        };
+        Snapshot {
+            seq,
+            taken_at: expiry::now(),
+            reader: self.reader.clone(),
+            history: self.history.clone(),
+            worker: self.worker.as_ref().map(Arc::downgrade),
         }
-        // do real compaction
-        let compact_path = compact_path(path);
-        let mut compact_file: File = open_for_append(&compact_path)?;
-        compact_file.seek(SeekFrom::Start(0))?;
-        let mut cursor = 0;
-        for meta in self.index.values_mut() {
-            let mut buf = vec![0u8; meta.1 as usize];
-            self.reader.read_exact_at(meta.0, buf.as_mut())?;
-            let l = io::copy(&mut &buf[..], compact_file.borrow_mut())?;
-            *meta = Meta(cursor, l);
-            cursor += l;
-        }
-        compact_file.flush()?;
-        // update file and reader
-        // todo: this swap need lock which break the lock free.
-        self.writer = compact_file;
-        self.reader = open_for_read(&compact_path, 0)?;
-        let data_path = data_path(path);
-        fs::remove_file(&data_path)?;
-        fs::rename(compact_path, data_path)?;
-        self.dangling_bytes = 0;
-        Ok(())
     }
-}
 
-#[allow(clippy::new_without_default)]
-impl KvsEngine for KvOldStore {
-    /// `set` append the given key value into log file and update the index map.
-    /// the content will be flush to disk immediately.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        // todo: the RwLock is not the solution to lock-free
-        // consider evmap??
-        let mut db = self.db.write().unwrap();
-        let cmd = Command::Set { key, value };
-        let vec = serde_json::to_vec(&cmd)?;
-        let buf = vec.as_ref();
-        db.writer.write_all(buf)?;
-        // update the cursor
-        db.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            let cursor = db.cursor;
-            if let Some(meta) = db.index.insert(key, Meta::new(cursor, buf.len() as u64)) {
-                db.dangling_bytes += meta.1;
+    /// `cache_stats` returns how many reads are served from the value cache so far,
+    /// and how many went to disk. Both are 0 without a cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.reader
+            .cache()
+            .map(|cache| cache.stats())
+            .unwrap_or_default()
+    }
+
+    /// `verify` reads every record of the store in `path`, its keyspaces included, and
+    /// checks it against its checksum, a broken hint file fails it too. Meant for a
+    /// checkpoint, it fails with `KvsError::CorruptedRecordError` also on a torn write
+    /// at the end of the log.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<()> {
+        let path: PathBuf = path.into();
+        let opts = KvStoreOptions::new()
+            .read_only(true)
+            .recovery(RecoveryMode::Strict);
+        for gen in segment_gens(&path, false)? {
+            replay_log(&segment_path(&path, gen), &opts, false, |_, _, _, _| ())?;
+            let hint_path = hint_path(&path, gen);
+            if hint_path.exists() && hint::read_hint(&hint_path)?.is_none() {
+                let reason = format!("{}: broken hint file", hint_path.display());
+                return Err(KvsError::CorruptedRecordError { offset: 0, reason });
             }
-        };
-        db.cursor += buf.len() as u64;
-        db.compact(self.path.as_path())?;
+        }
+        let keyspaces = path.join(KEYSPACES_DIR);
+        for name in keyspace_names(&keyspaces)? {
+            Self::verify(keyspaces.join(name))?;
+        }
         Ok(())
     }
 
-    /// `get` use internal index to find the meta data and fetch kv from disk, if no key should return None
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let db = self.db.read().unwrap();
-        if let Some(meta) = db.index.get(&key) {
-            // fetch kv form disk using the meta
-            let mut buf = vec![0u8; meta.1 as usize];
-            // the read_exact_at call pread under the hood.
-            db.reader.read_exact_at(meta.0, buf.as_mut())?;
-            return if let Command::Set { value, .. } = serde_json::from_slice(buf.as_ref())?
-            {
-                Ok(Some(value))
+    /// `restore` verifies the checkpoint in `backup`, copies it into `path` and verifies
+    /// the copy. The directory `path` must not hold a store, it is locked meanwhile.
+    pub fn restore(backup: impl Into<PathBuf>, path: impl Into<PathBuf>) -> Result<()> {
+        let (backup, path): (PathBuf, PathBuf) = (backup.into(), path.into());
+        Self::verify(&backup)?;
+        fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path, true)?;
+        check_empty(&path)?;
+        copy_store(&backup, &path)?;
+        drop(lock);
+        Self::verify(&path)
+    }
+
+    /// `destroy` removes the store in `path`, its keyspaces included, and leaves what
+    /// other engines keep in the directory alone. The directory is locked meanwhile.
+    pub fn destroy(path: impl Into<PathBuf>) -> Result<()> {
+        let path: PathBuf = path.into();
+        let _lock = DirLock::acquire(&path, true)?;
+        remove_segments(&path, &segment_gens(&path, true)?);
+        for path in &[path.join("data"), path.join(KEYSPACES_DIR)] {
+            let ret = if path.is_dir() {
+                fs::remove_dir_all(path)
             } else {
-                Err(KvsError::InvalidCommandError)
+                fs::remove_file(path)
             };
+            match ret {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
         }
-        Ok(None)
+        Ok(())
     }
 
-    /// `remove` call internal HashMap remove api to remove data
-    fn remove(&self, key: String) -> Result<()> {
-        let mut db = self.db.write().unwrap();
-        match db.index.remove(&key) {
-            Some(meta) => {
-                let cmd = Command::Remove { key };
-                serde_json::to_writer(&mut db.writer, &cmd)?;
-                db.writer.flush()?;
-                db.dangling_bytes += meta.1;
-                Ok(())
+    // `checkpoint_store` writes the checkpoint of this keyspace alone into `dir`.
+    fn checkpoint_store(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        check_empty(dir)?;
+        let copies = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                let active = Some((writer.gen, writer.cursor));
+                link_segments(&writer.dir, dir, writer.segments.keys(), active)?
             }
-            None => Err(KvsError::KeyNotFoundError),
+            // nothing is written to a read-only store, every segment can be linked.
+            None => link_segments(
+                &self.path,
+                dir,
+                segment_gens(&self.path, false)?.iter(),
+                None,
+            )?,
+        };
+        for copy in copies {
+            copy.run()?;
         }
+        Ok(())
     }
-}
 
-#[derive(Clone)]
-pub struct KvStore {
-    path: Arc<PathBuf>,
-    reader: Arc<LeftRight>, // since LeftRight impl Send and !Sync, we can not use Arc<LeftRight>, Arc<LeftRight> impl !Send and !Sync
-    writer: Arc<Mutex<IndexWriter>>,
+    fn registry(&self) -> &Arc<Keyspaces> {
+        self.keyspaces
+            .as_ref()
+            .expect("a store handed out knows its keyspaces")
+    }
+
+    // `write` runs `f` under the writer lock and triggers a compaction if it is due. The
+    // record appended by `f` is waited for after the lock is released, if the sync
+    // policy asks for it.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut IndexWriter) -> Result<(T, Option<Ticket>)>,
+    {
+        let (writer, worker) = match (&self.writer, &self.worker) {
+            (Some(writer), Some(worker)) => (writer, worker),
+            _ => return Err(KvsError::ReadOnlyError),
+        };
+        let mut writer = writer.lock().unwrap();
+        writer.release();
+        let (ret, ticket) = f(&mut writer)?;
+        if writer.should_compact() {
+            worker.trigger();
+        }
+        drop(writer);
+        wait_synced(ticket)?;
+        Ok(ret)
+    }
 }
 
-impl KvStore {
-    /// open read a file with the given path
-    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let path: PathBuf = path.into();
-        fs::create_dir_all(&path)?;
-        let index: SkipMap<String, Meta> = SkipMap::new();
-        let mut reader = open_for_read(&data_path(&path), 0)?;
-        let decoder = serde_json::Deserializer::from_reader(&mut reader);
-        let mut iterator = decoder.into_iter::<Command>();
-        let mut dangling_bytes: u64 = 0;
-        // TODO: can use better op than match
-        let mut cursor: u64 = 0;
-        while let Some(cmd) = iterator.next() {
-            let new_cursor = iterator.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    let entry = index.insert(key, Meta(cursor, new_cursor as u64 - cursor));
-                    let meta = entry.value();
-                    dangling_bytes += meta.1;
-                }
-                Command::Remove { key } => {
-                    index.remove(&key);
-                }
-                _ => (),
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|writer| Ok(((), writer.set(key, value, None)?)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.reader.get(&key)
+    }
+
+    // the version of a key is the sequence number of its record.
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self.reader.seq(&key))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.reader.scan(range, limit)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.reader.scan_prefix(prefix, limit)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|writer| Ok(((), writer.remove(key)?)))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| Ok(((), writer.write_batch(batch)?)))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(|writer| writer.compare_and_swap(key, expected, new))
+    }
+
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.write(|writer| {
+            if writer.live_meta(&key).is_none() {
+                return Ok((false, None));
             }
-            cursor = new_cursor as u64;
-        }
+            Ok((true, writer.set(key, value, None)?))
+        })
+    }
 
-        let arc_index = Arc::new(index);
-        let left = IndexReader::new(path.clone(),open_for_read(&data_path(&path), 0)?, arc_index.clone());
-        let right = IndexReader::new(path.clone(),open_for_read(&data_path(&path), 0)?, arc_index.clone());
-        let left_right_reader = LeftRight{
-            cnt: Arc::new(AtomicU32::new(0)),
-            left: left,
-            right: right,
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = expiry::expire_at(ttl);
+        self.write(|writer| Ok(((), writer.set(key, value, Some(expire_at))?)))
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        let expire_at = expiry::expire_at(ttl);
+        self.write(|writer| writer.set_expiry(key, Some(expire_at)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.reader.ttl(&key)
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.write(|writer| writer.set_expiry(key, None))
+    }
+
+    fn open_keyspace(&self, name: &str) -> Result<Self> {
+        let keyspaces = self.registry();
+        let store = keyspaces.open(name)?;
+        Ok(Self {
+            keyspaces: Some(keyspaces.clone()),
+            ..store
+        })
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<bool> {
+        self.registry().remove(name)
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        self.registry().names()
+    }
+
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        self.registry().checkpoint(dir)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>,
+    {
+        let mut txn = KvsTransaction {
+            reader: &self.reader,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
         };
-        let arc_left_right_reader = Arc::new(left_right_reader);
-        let index_writer = IndexWriter{
-            dir: path.clone(),
-            left_right_reader: arc_left_right_reader.clone(),
-            index : arc_index,
-            cursor,
-            dangling_bytes,
-            writer: open_for_append(&data_path(&path))?,
+        let ret = f(&mut txn)?;
+        let KvsTransaction { reads, writes, .. } = txn;
+        self.write(|writer| Ok((ret, writer.commit(reads, writes)?)))
+    }
+}
+
+// KvsTransaction reads through the index and keeps the writes until it commits. The
+// sequence number of the record a key is read from tells whether it changes meanwhile.
+struct KvsTransaction<'a> {
+    reader: &'a IndexReader,
+    // the sequence number of every key read, None if it does not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // the last write of every key written, None for a remove.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction for KvsTransaction<'a> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.reader.get_with_meta(&key)?;
+        // a key read again keeps the version it is read first with, so a change in
+        // between is a conflict as well.
+        self.reads
+            .entry(key)
+            .or_insert_with(|| found.as_ref().map(|(meta, _)| meta.seq));
+        Ok(found.map(|(_, value)| value))
+    }
+
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        let seq = self.reader.seq(&key);
+        Ok(*self.reads.entry(key).or_insert(seq))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFoundError);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+}
+
+// Keyspaces keeps the keyspaces of a store open. The stores it keeps know nothing of it,
+// so they do not keep it alive.
+struct Keyspaces {
+    dir: PathBuf,
+    opts: KvStoreOptions,
+    default: KvStore,
+    open: Mutex<HashMap<String, KvStore>>,
+    // dropped last, once the stores above are closed.
+    _lock: DirLock,
+}
+
+impl Keyspaces {
+    fn open(&self, name: &str) -> Result<KvStore> {
+        if name == DEFAULT_KEYSPACE {
+            return Ok(self.default.clone());
+        }
+        check_keyspace(name)?;
+        let mut open = self.open.lock().unwrap();
+        if let Some(store) = open.get(name) {
+            return Ok(store.clone());
+        }
+        let store = KvStore::open_store(self.dir.join(name), self.opts.clone())?;
+        open.insert(name.to_owned(), store.clone());
+        Ok(store)
+    }
+
+    fn remove(&self, name: &str) -> Result<bool> {
+        check_keyspace(name)?;
+        if self.opts.read_only {
+            return Err(KvsError::ReadOnlyError);
+        }
+        let mut open = self.open.lock().unwrap();
+        // the background thread of the keyspace stops here unless a handle is left.
+        open.remove(name);
+        match fs::remove_dir_all(self.dir.join(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        keyspace_names(&self.dir)
+    }
+
+    // `checkpoint` writes the checkpoint of every keyspace into `dir`, one after
+    // another. Each of them is consistent on its own.
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        self.default.checkpoint_store(dir)?;
+        for name in self.names()? {
+            let store = self.open(&name)?;
+            store.checkpoint_store(&dir.join(KEYSPACES_DIR).join(name))?;
+        }
+        Ok(())
+    }
+}
+
+// keyspace_names returns the names of the keyspaces kept in `dir`, ordered by name.
+fn keyspace_names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+// PendingCopy is a file of a checkpoint to be copied once the writer lock is released.
+// The file is opened under the lock, so it can be read even if it is removed meanwhile.
+struct PendingCopy {
+    from: File,
+    to: PathBuf,
+    len: u64,
+}
+
+impl PendingCopy {
+    fn open(from: &Path, to: PathBuf, len: Option<u64>) -> Result<Self> {
+        let from = File::open(from)?;
+        let len = match len {
+            Some(len) => len,
+            None => from.metadata()?.len(),
         };
-        Ok(Self {
-            path: Arc::new(path),
-            reader: arc_left_right_reader,
-            writer: Arc::new(Mutex::new(index_writer)),
-            })
+        Ok(PendingCopy { from, to, len })
+    }
+
+    fn run(self) -> Result<()> {
+        let mut to = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.to)?;
+        io::copy(&mut (&self.from).take(self.len), &mut to)?;
+        to.sync_all()?;
+        Ok(())
+    }
+}
+
+// link_segments hard links the segments `gens` of the store in `from` and their hint
+// files into `to`. A file that can not be linked, like one on another file system, is
+// copied instead, and so is the `active` segment up to the given length.
+fn link_segments<'a>(
+    from: &Path,
+    to: &Path,
+    gens: impl Iterator<Item = &'a u64>,
+    active: Option<(u64, u64)>,
+) -> Result<Vec<PendingCopy>> {
+    let mut copies = Vec::new();
+    for &gen in gens {
+        match active {
+            Some((active, len)) if active == gen => {
+                let copy =
+                    PendingCopy::open(&segment_path(from, gen), segment_path(to, gen), Some(len))?;
+                copies.push(copy);
+                continue;
+            }
+            _ => (),
+        }
+        let mut paths = vec![(segment_path(from, gen), segment_path(to, gen))];
+        if hint_path(from, gen).exists() {
+            paths.push((hint_path(from, gen), hint_path(to, gen)));
         }
+        for (from, to) in paths {
+            if let Err(e) = fs::hard_link(&from, &to) {
+                trace!("copy {} instead of linking it: {}", from.display(), e);
+                copies.push(PendingCopy::open(&from, to, None)?);
+            }
+        }
+    }
+    Ok(copies)
 }
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        writer.set(key, value)
+// copy_store copies the segments and hint files of the store in `from`, its keyspaces
+// included, into `to`. Nothing is linked, the copy gets written to on its own.
+fn copy_store(from: &Path, to: &Path) -> Result<()> {
+    for gen in segment_gens(from, false)? {
+        PendingCopy::open(&segment_path(from, gen), segment_path(to, gen), None)?.run()?;
+        if hint_path(from, gen).exists() {
+            PendingCopy::open(&hint_path(from, gen), hint_path(to, gen), None)?.run()?;
+        }
     }
+    for name in keyspace_names(&from.join(KEYSPACES_DIR))? {
+        let to = to.join(KEYSPACES_DIR).join(&name);
+        fs::create_dir_all(&to)?;
+        copy_store(&from.join(KEYSPACES_DIR).join(&name), &to)?;
+    }
+    Ok(())
+}
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.reader.get(key)
+// check_empty fails if there is a store in `dir` already.
+fn check_empty(dir: &Path) -> Result<()> {
+    if segment_gens(dir, false)?.is_empty() && !dir.join(KEYSPACES_DIR).exists() {
+        return Ok(());
     }
+    let msg = format!("{} holds a store already", dir.display());
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into())
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        writer.remove(key)
+// wait_synced waits for the record of a write to be synced, if the sync policy asks for
+// it. It is called without holding the writer lock, so the waiting writers share a sync.
+fn wait_synced(ticket: Option<Ticket>) -> Result<()> {
+    match ticket {
+        Some(ticket) => ticket.wait(),
+        None => Ok(()),
     }
 }
 
-// since the SkipMap is a lock-free struct, and we use pread to access the fd underline. No lock is need here.
-// if we use RefCell on reader then impl IndexReader impl Send and !Sync
-struct IndexReader {
-    dir: PathBuf,
-    reader: AtomicCell<File>,
-    // we may need to replace the map in compact.
-    index : Arc<SkipMap<String, Meta>>,
+// Worker owns the background thread compacting the log, which also sweeps the expired
+// keys and syncs the log when the sync policy is time based. Dropping the last KvStore
+// drops it, which lets the thread finish a pending compaction and waits for it.
+struct Worker {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
 }
 
-impl Clone for IndexReader {
-    fn clone(&self) -> Self {
-        Self {
-            dir: self.dir.clone(),
-            reader: AtomicCell::new(open_for_read(self.dir.as_path(), 0).expect("data file broken")),
-            index: self.index.clone(),
+impl Worker {
+    fn spawn(
+        writer: Arc<Mutex<IndexWriter>>,
+        sync_interval: Option<Duration>,
+        sweep_interval: Duration,
+    ) -> Result<Self> {
+        // a single pending request is enough, the compaction checks the state anyway.
+        let (sender, receiver) = bounded::<()>(1);
+        let handle = thread::Builder::new()
+            .name("kvs-worker".to_owned())
+            .spawn(move || {
+                let mut next_sync = sync_interval.map(|interval| Instant::now() + interval);
+                let mut next_sweep = Instant::now() + sweep_interval;
+                loop {
+                    let deadline = next_sync.map_or(next_sweep, |at| at.min(next_sweep));
+                    match receiver.recv_deadline(deadline) {
+                        Ok(()) => {
+                            if let Err(e) = compact(&writer) {
+                                error!("compaction failed: {}", e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            let now = Instant::now();
+                            if let (Some(at), Some(interval)) = (next_sync, sync_interval) {
+                                if at <= now {
+                                    if let Err(e) = sync_unlocked(&writer) {
+                                        error!("sync failed: {}", e);
+                                    }
+                                    next_sync = Some(now + interval);
+                                }
+                            }
+                            if next_sweep <= now {
+                                if let Err(e) = sweep(&writer) {
+                                    error!("sweep failed: {}", e);
+                                }
+                                next_sweep = now + sweep_interval;
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            if let Err(e) = sync_unlocked(&writer) {
+                                error!("sync failed: {}", e);
+                            }
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(Worker {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    fn trigger(&self) {
+        if let Some(sender) = &self.sender {
+            // a full channel means a compaction is already requested.
+            let _ = sender.try_send(());
         }
     }
 }
 
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // closing the channel stops the thread once the pending request is handled.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background thread panicked");
+            }
+        }
+    }
+}
+
+// compact removes the retired segments no snapshot reads any more, and runs a
+// compaction if there is one due. The writer lock is released while the live records
+// are copied.
+fn compact(writer: &Mutex<IndexWriter>) -> Result<()> {
+    let compaction = {
+        let mut writer = writer.lock().unwrap();
+        writer.release();
+        match writer.start_compaction()? {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        }
+    };
+    let result = compaction.run();
+    writer.lock().unwrap().finish_compaction(compaction, result)
+}
+
+// sweep drops the expired keys from the index, and compacts the log if it is due. The
+// keys swept, a snapshot dropped or a write racing with the last compaction may all
+// leave a compaction due which no write comes to trigger.
+fn sweep(writer: &Mutex<IndexWriter>) -> Result<()> {
+    let swept = writer.lock().unwrap().sweep(expiry::now());
+    if swept > 0 {
+        debug!("swept {} expired keys", swept);
+    }
+    compact(writer)
+}
+
+// sync_unlocked syncs what is appended to the log so far. Only the flush point is taken
+// under the writer lock, writers go on while the log is synced.
+fn sync_unlocked(writer: &Mutex<IndexWriter>) -> Result<()> {
+    let ticket = writer.lock().unwrap().flush_point();
+    match ticket {
+        Some(ticket) => ticket.wait(),
+        None => Ok(()),
+    }
+}
+
+// Segments are the open segments keyed by gen.
+type Segments = BTreeMap<u64, Arc<Segment>>;
+
+// IndexReader reads the values the index points to, without taking a lock. The open
+// segments are kept in a map swapped as a whole by `reopen`. A read pins the current
+// epoch while it uses the map, and a map swapped out is only destroyed once every
+// thread pinned before the swap moves on, so a segment is never closed or unmapped
+// under a read.
+struct IndexReader {
+    dir: PathBuf,
+    // never null, see `new`.
+    segments: Atomic<Segments>,
+    // whether the sealed segments are read through memory maps.
+    mmap: bool,
+    // the index is shared with the writer, a Meta always names the segment it lives in.
+    index: Arc<Index>,
+    // None if the store has no cache.
+    cache: Option<Arc<ValueCache>>,
+}
+
 impl IndexReader {
-    pub fn new(dir: PathBuf, reader: File, index: Arc<SkipMap<String, Meta>>) -> Self {
-        return IndexReader{
+    pub fn new(
+        dir: PathBuf,
+        index: Arc<Index>,
+        cache: Option<Arc<ValueCache>>,
+        mmap: bool,
+    ) -> Self {
+        IndexReader {
             dir,
-            reader: AtomicCell::new( reader),
-            index
+            segments: Atomic::new(BTreeMap::new()),
+            mmap,
+            index,
+            cache,
         }
     }
 
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(entry) = self.index.get(&key) {
-            let meta = entry.value();
-            // fetch kv form disk using the meta
-            let mut buf = vec![0u8; meta.1 as usize];
-            // the read_exact_at call pread under the hood.
-            // TODO: what is the safety here?
-            let reader = unsafe {
-                self.reader.as_ptr().as_ref().ok_or(io::Error::new(io::ErrorKind::NotFound, "broken file in atomic cell"))
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_with_meta(key)?.map(|(_, value)| value))
+    }
+
+    // `get_with_meta` returns the live value of `key` along with the Meta it is read
+    // through.
+    fn get_with_meta(&self, key: &[u8]) -> Result<Option<(Meta, Vec<u8>)>> {
+        let mut retries = 0;
+        loop {
+            let meta = match self.index.get(key).map(|entry| entry.value().load()) {
+                Some(meta) if !meta.is_expired(expiry::now()) => meta,
+                _ => return Ok(None),
             };
-            let reader = reader?;
-            reader.read_exact_at(meta.0, buf.as_mut())?;
-            return if let Command::Set { value, .. } = serde_json::from_slice(buf.as_ref())?
-            {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::InvalidCommandError)
+            return match self.read_value(&meta) {
+                Ok(value) => Ok(Some((meta, value))),
+                // the segment is merged away by a compaction after we read the index,
+                // the index points to the new place now.
+                Err(KvsError::IOError(e))
+                    if e.kind() == io::ErrorKind::NotFound && retries < READ_RETRIES =>
+                {
+                    retries += 1;
+                    continue;
+                }
+                Err(e) => Err(e),
             };
         }
-        Ok(None)
     }
 
-    pub fn reopen(&self)->Result<()> {
-        let reader = open_for_read(data_path( self.dir.as_path()).as_path(), 0)?;
-        // calling store will drop the old reader.
-        self.reader.store(reader);
+    // `seq` returns the sequence number of the live record of `key`.
+    fn seq(&self, key: &[u8]) -> Option<u64> {
+        let meta = self.index.get(key).map(|entry| entry.value().load())?;
+        Some(meta.seq).filter(|_| !meta.is_expired(expiry::now()))
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let now = expiry::now();
+        match self.index.get(key).map(|entry| entry.value().load()) {
+            Some(meta) if !meta.is_expired(now) => {
+                Ok(meta.expire_at.map(|at| expiry::time_left(at, now)))
+            }
+            _ => Err(KvsError::KeyNotFoundError),
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self.index.range(range).map(|entry| entry.key().clone());
+        self.get_all(keys, limit)
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self
+            .index
+            .range(prefix.clone()..)
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(&prefix));
+        self.get_all(keys, limit)
+    }
+
+    fn cache(&self) -> Option<&ValueCache> {
+        self.cache.as_deref()
+    }
+
+    // `read_value` fetches the value of the record at `meta` from the cache, or from
+    // disk if it is not there.
+    fn read_value(&self, meta: &Meta) -> Result<Vec<u8>> {
+        if let Some(value) = self.cache.as_ref().and_then(|c| c.get(meta.gen, meta.pos)) {
+            return Ok(value);
+        }
+        let guard = epoch::pin();
+        // Safety: the map is never null, and it is not destroyed before the guard goes,
+        // see `reopen`.
+        let segments = unsafe { self.segments.load(Ordering::Acquire, &guard).deref() };
+        let opened;
+        let segment = match segments.get(&meta.gen) {
+            Some(segment) => segment,
+            // a segment created after the last reopen.
+            None => {
+                opened = self.open_missing(segments, meta.gen, &guard)?;
+                &opened
+            }
+        };
+        let buf = segment.read(meta.pos, meta.len)?;
+        if let Command::Set { value, .. } = record::decode(meta.pos, &buf)? {
+            if let Some(cache) = &self.cache {
+                cache.insert(meta.gen, meta.pos, &value);
+            }
+            Ok(value)
+        } else {
+            Err(KvsError::InvalidCommandError)
+        }
+    }
+
+    // `open_missing` opens the segment `gen` missing from `segments`, the map loaded
+    // under `guard`, and adds it to the map unless the map is swapped meanwhile. The
+    // reads after it find the segment open, until the next reopen.
+    fn open_missing(
+        &self,
+        segments: &Segments,
+        gen: u64,
+        guard: &epoch::Guard,
+    ) -> Result<Arc<Segment>> {
+        let segment = Arc::new(Segment::open(&segment_path(&self.dir, gen), false, false)?);
+        let mut grown = segments.clone();
+        grown.insert(gen, segment.clone());
+        let current = self.segments.load(Ordering::Acquire, guard);
+        if !ptr::eq(current.as_raw(), segments) {
+            return Ok(segment);
+        }
+        let swapped = self.segments.compare_exchange(
+            current,
+            Owned::new(grown),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            guard,
+        );
+        if swapped.is_ok() {
+            // Safety: as in `reopen`, it is the map swapped out that goes.
+            unsafe { guard.defer_destroy(current) };
+        }
+        Ok(segment)
+    }
+
+    // `get_all` reads the values of `keys` until `limit` pairs are found. A key removed
+    // meanwhile is skipped.
+    fn get_all(
+        &self,
+        keys: impl Iterator<Item = Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit {
+                break;
+            }
+            if let Some(value) = self.get(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    // `reopen` swaps in the segments `gens`, the last of them is the active one. A
+    // segment already open is kept, unless it is to be mapped now that it is sealed.
+    // It is only called under the writer lock, so two swaps never race.
+    pub fn reopen<'a>(&self, gens: impl Iterator<Item = &'a u64>) -> Result<()> {
+        let guard = epoch::pin();
+        // Safety: as in `read_value`.
+        let current = unsafe { self.segments.load(Ordering::Acquire, &guard).deref() };
+        let gens: Vec<u64> = gens.copied().collect();
+        let mut segments = BTreeMap::new();
+        for (i, &gen) in gens.iter().enumerate() {
+            let sealed = i + 1 < gens.len();
+            let kept = current
+                .get(&gen)
+                .filter(|segment| !(self.mmap && sealed) || segment.is_mapped());
+            let segment = match kept {
+                Some(segment) => segment.clone(),
+                None => Arc::new(Segment::open(
+                    &segment_path(&self.dir, gen),
+                    self.mmap,
+                    sealed,
+                )?),
+            };
+            segments.insert(gen, segment);
+        }
+        let old = self
+            .segments
+            .swap(Owned::new(segments), Ordering::AcqRel, &guard);
+        // Safety: the old map is out of reach once swapped, only the reads pinned
+        // before may still use it, and it outlives them.
+        unsafe { guard.defer_destroy(old) };
+        // hand the old map over to be collected soon, the merged segments it holds
+        // should not stay open for long.
+        guard.flush();
         Ok(())
     }
+}
 
-    pub fn get_index(&self) -> Arc<SkipMap<String, Meta>> {
-        self.index.clone()
+impl Drop for IndexReader {
+    fn drop(&mut self) {
+        // Safety: a read borrows the reader, so none is left once it is dropped.
+        unsafe {
+            let segments = self.segments.load(Ordering::Relaxed, epoch::unprotected());
+            drop(segments.into_owned());
+        }
     }
 }
 
-struct LeftRight {
-    cnt: Arc<AtomicU32>, // Atomic impl Send and Sync
-    left: IndexReader, // RefCall impl Send and !Sync
-    right: IndexReader, // RefCall impl Send and !Sync
+/// Snapshot is a point-in-time view of a `KvStore`, taken by `KvStore::snapshot`. It
+/// does not see the writes made after it, and judges expiry by the time it is taken.
+/// The data it reads stays on disk until it is dropped, so a long lived snapshot holds
+/// back the space freed by compaction.
+pub struct Snapshot {
+    seq: u64,
+    taken_at: u64,
+    reader: Arc<IndexReader>,
+    history: Arc<History>,
+    // the worker of the store, woken once the snapshot is dropped.
+    worker: Option<Weak<Worker>>,
 }
 
-impl Clone for LeftRight {
-    fn clone(&self) -> Self {
-        Self {
-            cnt: self.cnt.clone(),
-            left: self.left.clone(),
-            right: self.right.clone(),
+impl Snapshot {
+    /// `get_bytes` returns the value `key` has in the snapshot.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.lookup(&key) {
+            Some(meta) => Ok(Some(self.reader.read_value(&meta)?)),
+            None => Ok(None),
         }
     }
-}
 
-impl LeftRight{
-    fn get(&self, key: String) -> Result<Option<String>> {
-        return match self.cnt.load(Ordering::Acquire) {
-            0 => {
-                self.left.get(key)
-            }
-            1 => {
-                self.right.get(key)
+    /// `scan_bytes` returns at most `limit` pairs with keys in `range`, ordered by key.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // a key removed after the snapshot is only found in the history.
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let index = &self.reader.index;
+        let keys = merge_keys(
+            index.range(range).map(|entry| entry.key().clone()),
+            self.history
+                .versions
+                .range((start, end))
+                .map(|entry| entry.key().0.clone()),
+        );
+        self.get_all(keys, limit)
+    }
+
+    /// `scan_prefix_bytes` returns at most `limit` pairs with keys starting with
+    /// `prefix`, ordered by key.
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let index = &self.reader.index;
+        let keys = merge_keys(
+            index
+                .range(prefix.clone()..)
+                .map(|entry| entry.key().clone()),
+            self.history
+                .versions
+                .range((prefix.clone(), 0)..)
+                .map(|entry| entry.key().0.clone()),
+        )
+        .take_while(|key| key.starts_with(&prefix));
+        self.get_all(keys, limit)
+    }
+
+    /// `get` is `get_bytes` for string keys and values.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(into_string)
+            .transpose()
+    }
+
+    /// `scan` is `scan_bytes` for string keys and values.
+    pub fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            to_bytes_bound(range.start_bound()),
+            to_bytes_bound(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+
+    /// `scan_prefix` is `scan_prefix_bytes` for string keys and values.
+    pub fn scan_prefix(&self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit)?)
+    }
+
+    // `lookup` returns where the value `key` has in the snapshot lives, None if the key
+    // does not exist in it.
+    fn lookup(&self, key: &[u8]) -> Option<Meta> {
+        // the index goes first, a write keeps the version it replaces in the history
+        // before it updates the index.
+        let current = self.reader.index.get(key).map(|entry| entry.value().load());
+        let replaced = (key.to_vec(), self.seq + 1)..=(key.to_vec(), u64::MAX);
+        let meta = match self.history.versions.range(replaced).next() {
+            Some(entry) => *entry.value(),
+            None => current,
+        };
+        meta.filter(|meta| !meta.is_expired(self.taken_at))
+    }
+
+    // `get_all` reads the values `keys` have in the snapshot until `limit` pairs are found.
+    fn get_all(
+        &self,
+        keys: impl Iterator<Item = Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let reader = &self.reader;
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit {
+                break;
             }
-            _ => {
-                unreachable!()
+            if let Some(meta) = self.lookup(&key) {
+                let value = reader.read_value(&meta)?;
+                pairs.push((key, value));
             }
         }
+        Ok(pairs)
     }
+}
 
-    fn compact_index(&self) -> Arc<SkipMap<String, Meta>> {
-        return match self.cnt.load(Ordering::Acquire) {
-            0 => {
-                self.right.get_index()
-            },
-            1 => {
-                self.left.get_index()
-            }
-            _ => {
-                unreachable!()
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.history.unpin(self.seq);
+        // the segments and the stale data the snapshot kept may be due for a compaction
+        // now, which no write may come to trigger.
+        if let Some(worker) = self.worker.as_ref().and_then(Weak::upgrade) {
+            worker.trigger();
+        }
+    }
+}
+
+// History is shared by the store and its snapshots. It keeps the versions of keys
+// replaced while there are snapshots, along with the snapshots pinning them.
+#[derive(Default)]
+struct History {
+    // the version a key has right before the write with the sequence number, None if
+    // the key does not exist by then.
+    versions: SkipMap<(Vec<u8>, u64), Option<Meta>>,
+    // count of live snapshots by the sequence number they pin.
+    pinned: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl History {
+    fn pin(&self, seq: u64) {
+        *self.pinned.lock().unwrap().entry(seq).or_insert(0) += 1;
+    }
+
+    fn unpin(&self, seq: u64) {
+        let mut pinned = self.pinned.lock().unwrap();
+        if let Some(count) = pinned.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(&seq);
             }
         }
     }
-    // `compact_reopen` must call after the compact_index updated
-    fn compact_reopen(&self) -> Result<()>  {
-       match self.cnt.load(Ordering::Acquire) {
-           0 => {
-               self.right.reopen()?;
-               self.cnt.store(1, Ordering::Release);
-           },
-           1 => {
-               self.left.reopen()?;
-               self.cnt.store(0, Ordering::Release);
-           }
-           _ => {
-               unreachable!()
-           }
-       }
-        Ok(())
+
+    // `oldest` returns the sequence number pinned by the oldest snapshot, None if there
+    // is no snapshot.
+    fn oldest(&self) -> Option<u64> {
+        self.pinned.lock().unwrap().keys().next().copied()
     }
 }
 
-struct IndexWriter{
-    // we may mut the index_reader
+// merge_keys merges two iterators of ascending keys, a key found more than once is
+// yielded once.
+fn merge_keys<A, B>(a: A, b: B) -> impl Iterator<Item = Vec<u8>>
+where
+    A: Iterator<Item = Vec<u8>>,
+    B: Iterator<Item = Vec<u8>>,
+{
+    let (mut a, mut b) = (a.peekable(), b.peekable());
+    std::iter::from_fn(move || {
+        let key = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) if y < x => b.next()?,
+            (Some(_), _) => a.next()?,
+            (None, _) => b.next()?,
+        };
+        while a.peek() == Some(&key) {
+            a.next();
+        }
+        while b.peek() == Some(&key) {
+            b.next();
+        }
+        Some(key)
+    })
+}
+
+struct IndexWriter {
+    // reopens the segments of reader after a compaction.
     dir: PathBuf,
-    left_right_reader: Arc<LeftRight>, // use Arc<LeftRight> here
-    index : Arc<SkipMap<String, Meta>>,
+    reader: Arc<IndexReader>,
+    index: Arc<Index>,
+    // gen of the active segment and the end of it.
+    gen: u64,
     cursor: u64,
+    // sequence number of the last write.
+    seq: u64,
+    // stale bytes of every segment on disk keyed by gen.
+    segments: BTreeMap<u64, u64>,
     dangling_bytes: u64,
-    writer: File
+    // bytes of the records the index points to.
+    live_bytes: u64,
+    // keys in the index with an expiry, ordered by the time they expire at.
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+    writer: Arc<File>,
+    commit: Arc<GroupCommit>,
+    // whether a compaction is running in the background.
+    compacting: bool,
+    history: Arc<History>,
+    // versions in the history ordered by the sequence number of the write replacing them.
+    replaced: BTreeSet<(u64, Vec<u8>)>,
+    // segments merged by a compaction which snapshots may still read, along with the
+    // sequence number of the last write before they are merged.
+    retired: Vec<(u64, Vec<u64>)>,
+    opts: KvStoreOptions,
 }
 
 impl IndexWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let vec = serde_json::to_vec(&cmd)?;
-        let buf = vec.as_ref();
-        self.writer.write_all(buf)?;
-        // update the cursor
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            let cursor = self.cursor;
-            let entry =  self.index.insert(key, Meta::new(cursor, buf.len() as u64));
-            let meta = entry.value();
-            self.dangling_bytes += meta.1;
+    fn set(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Ticket>> {
+        let cmd = Command::Set {
+            key,
+            value,
+            expire_at,
         };
-        self.cursor += buf.len() as u64;
-        let path = self.dir.clone();
-        self.compact(path.as_path())?;
-        Ok(())
+        let seq = self.next_seq();
+        let buf = self.encode(seq, &cmd)?;
+        (&*self.writer).write_all(&buf)?;
+        self.apply(self.cursor, buf.len() as u64, seq, cmd);
+        self.written(buf.len() as u64)
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<Option<Ticket>> {
+        if self.live_meta(&key).is_none() {
+            return Err(KvsError::KeyNotFoundError);
+        }
+        let cmd = Command::Remove { key };
+        let seq = self.next_seq();
+        let buf = self.encode(seq, &cmd)?;
+        (&*self.writer).write_all(&buf)?;
+        self.apply(self.cursor, buf.len() as u64, seq, cmd);
+        self.written(buf.len() as u64)
+    }
+
+    // `write_batch` appends the operations of the batch as a single batch record. A
+    // remove of a key which does not exist by then is left out.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<Option<Ticket>> {
+        // whether a key touched by the batch exists after the operations so far.
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut cmds = Vec::with_capacity(batch.len());
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => {
+                    exists.insert(key.clone(), true);
+                    cmds.push(Command::Set {
+                        key,
+                        value,
+                        expire_at: None,
+                    });
+                }
+                BatchOp::Remove { key } => {
+                    let found = match exists.get(&key) {
+                        Some(found) => *found,
+                        None => self.live_meta(&key).is_some(),
+                    };
+                    if found {
+                        exists.insert(key.clone(), false);
+                        cmds.push(Command::Remove { key });
+                    }
+                }
+            }
+        }
+        if cmds.is_empty() {
+            return Ok(None);
+        }
+        // the records of a batch share a sequence number, a snapshot sees all or none.
+        let seq = self.next_seq();
+        let records = cmds
+            .iter()
+            .map(|cmd| self.encode(seq, cmd))
+            .collect::<Result<Vec<_>>>()?;
+        let buf = record::encode_batch(seq, &records)?;
+        (&*self.writer).write_all(&buf)?;
+        // the header of the batch record is neither live nor stale, it goes away with
+        // the first compaction of the segment.
+        let mut pos = self.cursor + record::HEADER_LEN as u64;
+        for (cmd, record) in cmds.into_iter().zip(&records) {
+            self.apply(pos, record.len() as u64, seq, cmd);
+            pos += record.len() as u64;
+        }
+        self.written(buf.len() as u64)
+    }
+
+    // `compare_and_swap` swaps the value of `key` if it is `expected`, the writer lock
+    // keeps it from changing in between.
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(bool, Option<Ticket>)> {
+        let current = self.reader.get(&key)?;
+        if current != expected {
+            return Ok((false, None));
+        }
+        let ticket = match (new, current) {
+            (Some(value), _) => self.set(key, value, None)?,
+            (None, Some(_)) => self.remove(key)?,
+            (None, None) => None,
+        };
+        Ok((true, ticket))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.index.remove(&key) {
-            Some(entry) => {
-                let meta = entry.value();
-                let cmd = Command::Remove { key };
-                serde_json::to_writer(&mut self.writer, &cmd)?;
-                self.writer.flush()?;
-                self.dangling_bytes += meta.1;
-                Ok(())
+    // `commit` writes the writes of a transaction as a single batch record, if none of
+    // the keys it read is changed since.
+    fn commit(
+        &mut self,
+        reads: HashMap<Vec<u8>, Option<u64>>,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Option<Ticket>> {
+        for (key, seq) in reads {
+            if self.live_meta(&key).map(|meta| meta.seq) != seq {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
             }
-            None => Err(KvsError::KeyNotFoundError),
         }
+        self.write_batch(batch)
     }
 
-    // the hard part is how to update IndexReader???
-    fn compact(&mut self, dir: &Path) -> Result<()> {
-        // nothing can do if dangling_bytes not excess the threshold.
-        if self.dangling_bytes <= COMPACT_THRESHOLD_BYTES {
-            return Ok(());
+    // `set_expiry` rewrites the value of `key` to expire at `expire_at`, None makes it
+    // never expire. Returns false if the key does not exist, or if it already never
+    // expires when asked for None.
+    fn set_expiry(
+        &mut self,
+        key: Vec<u8>,
+        expire_at: Option<u64>,
+    ) -> Result<(bool, Option<Ticket>)> {
+        match self.live_meta(&key) {
+            Some(meta) if expire_at.is_some() || meta.expire_at.is_some() => (),
+            _ => return Ok((false, None)),
         }
-        // do real compaction
-        let compact_to_path = compact_path(dir);
-        let compact_from_path = data_path(dir);
-        let mut compact_file: File = open_for_append(&compact_to_path)?;
-        compact_file.seek(SeekFrom::Start(0))?;
-        let mut cursor = 0;
-        // do the in place modify
-        {
-            let compact_index = self.left_right_reader.compact_index();
-            let data_file = open_for_read(&compact_from_path, 0)?;
-            for meta in self.index.iter() {
-                let length = meta.value().1 as usize;
-                let mut buf = vec![0u8; length];
-                data_file.read_exact_at(meta.value().0, buf.as_mut())?;
-                let l = io::copy(&mut &buf[..], compact_file.borrow_mut())?;
-                compact_index.insert(meta.key().to_string(), Meta(cursor, l));
-                cursor += l;
+        let value = match self.reader.get(&key)? {
+            Some(value) => value,
+            None => return Ok((false, None)),
+        };
+        Ok((true, self.set(key, value, expire_at)?))
+    }
+
+    // `live_meta` returns where the value of `key` lives, None if the key does not
+    // exist or is expired.
+    fn live_meta(&self, key: &[u8]) -> Option<Meta> {
+        let meta = self.index.get(key)?.value().load();
+        if meta.is_expired(expiry::now()) {
+            return None;
+        }
+        Some(meta)
+    }
+
+    // `sweep` drops the keys expired by `now` from the index and returns how many.
+    fn sweep(&mut self, now: u64) -> usize {
+        let mut swept = 0;
+        while let Some((at, key)) = self.expiring.iter().next().cloned() {
+            if at > now {
+                break;
+            }
+            let meta = match self.index.get(&key).map(|entry| entry.value().load()) {
+                Some(meta) if meta.expire_at == Some(at) => meta,
+                _ => {
+                    self.expiring.remove(&(at, key));
+                    continue;
+                }
+            };
+            // the keys swept at once go away with a single sequence number.
+            if swept == 0 {
+                self.next_seq();
+            }
+            self.remember(&key, self.seq);
+            self.index.remove(&key);
+            self.forget(&key, meta);
+            swept += 1;
+        }
+        swept
+    }
+
+    // `encode` turns `cmd` into a record, its value compressed as the options ask for.
+    fn encode(&self, seq: u64, cmd: &Command) -> Result<Vec<u8>> {
+        let opts = &self.opts;
+        record::encode_compressed(seq, cmd, opts.compression, opts.compress_threshold)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // `remember` keeps the version of `key` replaced by the write `seq` in the history,
+    // if there is a snapshot to see it. It is called before the index is updated, so a
+    // snapshot reading the new version from the index finds the old one in the history.
+    fn remember(&mut self, key: &[u8], seq: u64) {
+        if self.history.oldest().is_none() {
+            return;
+        }
+        let version = (key.to_vec(), seq);
+        // a batch writing a key twice keeps the version from before the batch.
+        if self.history.versions.contains_key(&version) {
+            return;
+        }
+        let current = self.index.get(key).map(|entry| entry.value().load());
+        self.history.versions.insert(version, current);
+        self.replaced.insert((seq, key.to_vec()));
+    }
+
+    // `release` drops the versions and removes the retired segments no snapshot can
+    // read any more.
+    fn release(&mut self) {
+        let oldest = self.history.oldest();
+        // a snapshot only looks for versions replaced after it.
+        while let Some((seq, key)) = self.replaced.iter().next().cloned() {
+            if oldest.map_or(false, |oldest| seq > oldest) {
+                break;
+            }
+            self.history.versions.remove(&(key.clone(), seq));
+            self.replaced.remove(&(seq, key));
+        }
+        let (released, retired) = self
+            .retired
+            .drain(..)
+            .partition(|(seq, _)| oldest.map_or(true, |oldest| oldest > *seq));
+        self.retired = retired;
+        for (_, gens) in released {
+            remove_segments(&self.dir, &gens);
+        }
+    }
+
+    // `apply` updates the index for a record of `len` bytes appended at `pos` of the
+    // active segment.
+    fn apply(&mut self, pos: u64, len: u64, seq: u64, cmd: Command) {
+        match cmd {
+            Command::Set { key, expire_at, .. } => {
+                self.remember(&key, seq);
+                if let Some(meta) = self.index.get(&key).map(|entry| entry.value().load()) {
+                    self.forget(&key, meta);
+                }
+                if let Some(at) = expire_at {
+                    self.expiring.insert((at, key.clone()));
+                }
+                put(
+                    &self.index,
+                    key,
+                    Meta::new(self.gen, pos, len, expire_at, seq),
+                );
+                self.live_bytes += len;
+            }
+            Command::Remove { key } => {
+                self.remember(&key, seq);
+                if let Some(meta) = self.index.remove(&key).map(|entry| entry.value().load()) {
+                    self.forget(&key, meta);
+                }
+                self.mark_stale(self.gen, len);
             }
+            Command::Get { .. } => (),
+        }
+    }
+
+    // `forget` accounts the record of `key` at `meta` as stale once the index no longer
+    // points to it.
+    fn forget(&mut self, key: &[u8], meta: Meta) {
+        if let Some(cache) = self.reader.cache() {
+            cache.remove(meta.gen, meta.pos);
+        }
+        self.mark_stale(meta.gen, meta.len);
+        self.live_bytes -= meta.len;
+        if let Some(at) = meta.expire_at {
+            self.expiring.remove(&(at, key.to_vec()));
+        }
+    }
+
+    fn mark_stale(&mut self, gen: u64, len: u64) {
+        *self.segments.entry(gen).or_insert(0) += len;
+        self.dangling_bytes += len;
+    }
+
+    // `written` accounts a record appended to the active segment, syncs it as the sync
+    // policy asks for and seals the segment once it is full. It returns the ticket to
+    // wait on if the writer has to wait for the record to be synced.
+    fn written(&mut self, len: u64) -> Result<Option<Ticket>> {
+        self.cursor += len;
+        let ticket = self.commit.appended(len);
+        let ticket = match self.opts.sync {
+            SyncPolicy::EveryWrite => Some(ticket),
+            SyncPolicy::EveryNBytes(bytes) if self.commit.unsynced() >= bytes => Some(ticket),
+            _ => None,
+        };
+        if self.cursor >= self.opts.segment_size {
+            self.roll(self.gen + 1)?;
+        }
+        Ok(ticket)
+    }
+
+    // `sync` syncs what is appended to the log since the last sync.
+    fn sync(&mut self) -> Result<()> {
+        match self.flush_point() {
+            Some(ticket) => ticket.wait(),
+            None => Ok(()),
         }
-        compact_file.flush()?;
+    }
+
+    // `flush_point` returns the ticket for what is appended to the log so far, so it
+    // can be synced without holding the writer lock.
+    fn flush_point(&self) -> Option<Ticket> {
+        if self.opts.sync == SyncPolicy::Never {
+            return None;
+        }
+        Some(self.commit.ticket())
+    }
+
+    // `roll` seals the active segment and continues writing in a new segment `gen`.
+    fn roll(&mut self, gen: u64) -> Result<()> {
+        self.sync()?;
+        self.writer = Arc::new(open_for_append(&segment_path(&self.dir, gen))?);
+        self.commit.rolled(self.writer.clone());
+        self.gen = gen;
+        self.cursor = 0;
+        self.segments.insert(gen, 0);
+        self.reader.reopen(self.segments.keys())
+    }
+
+    // `should_compact` tells whether the sealed segments hold enough stale data for a
+    // compaction. The stale data of the active segment can not be compacted yet.
+    fn should_compact(&self) -> bool {
+        let active = self.segments.get(&self.gen).copied().unwrap_or(0);
+        let dangling = self.dangling_bytes - active;
+        !self.compacting && self.opts.compaction.is_due(dangling, self.live_bytes)
+    }
 
-        // update index_writer.
-        self.writer = compact_file;
-        self.dangling_bytes = 0;
+    // `start_compaction` picks the oldest segments, up to the last sealed one holding
+    // stale data, to be merged into a new segment. Every record of a key outside of the
+    // merged segments is newer than the ones inside, so their tombstones and overwritten
+    // values can be dropped. The new segment gets the gen right below the new active
+    // segment, keys in it have no record in any segment between.
+    fn start_compaction(&mut self) -> Result<Option<Compaction>> {
+        if !self.should_compact() {
+            return Ok(None);
+        }
+        let last_stale = self
+            .segments
+            .range(..self.gen)
+            .rev()
+            .find(|(_, stale)| **stale > 0)
+            .map(|(gen, _)| *gen);
+        let merged: Vec<u64> = match last_stale {
+            Some(last) => self.segments.range(..=last).map(|(gen, _)| *gen).collect(),
+            None => return Ok(None),
+        };
+        let gen = self.gen + 1;
+        self.roll(self.gen + 2)?;
+        self.compacting = true;
+        Ok(Some(Compaction {
+            dir: self.dir.clone(),
+            index: self.index.clone(),
+            gen,
+            merged,
+            seq: self.seq,
+            compression: self.opts.compression,
+            compress_threshold: self.opts.compress_threshold,
+        }))
+    }
+
+    // `finish_compaction` installs the new segment and removes the merged ones. Keys
+    // written while the compaction ran keep their new records, the copies of them in
+    // the new segment are stale already. Keys left behind as expired are dropped.
+    fn finish_compaction(
+        &mut self,
+        compaction: Compaction,
+        result: Result<Compacted>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let Compacted { moved, expired } = match result {
+            Ok(compacted) => compacted,
+            Err(e) => {
+                compaction.clean_up();
+                return Err(e);
+            }
+        };
+        self.segments.insert(compaction.gen, 0);
+        // the readers switch over only once the new segment is complete.
+        self.reader.reopen(self.segments.keys())?;
+        for (entry, from) in moved {
+            let current = self.index.get(&entry.key).map(|entry| entry.value().load());
+            match current {
+                Some(meta) if meta.gen == from.gen && meta.pos == from.pos => {
+                    // a recompressed record takes another length.
+                    self.live_bytes = self.live_bytes - from.len + entry.len;
+                    let meta = Meta::new(
+                        compaction.gen,
+                        entry.pos,
+                        entry.len,
+                        entry.expire_at,
+                        entry.seq,
+                    );
+                    put(&self.index, entry.key, meta);
+                }
+                _ => self.mark_stale(compaction.gen, entry.len),
+            }
+        }
+        let mut dropped = false;
+        for (key, from) in expired {
+            let current = self.index.get(&key).map(|entry| entry.value().load());
+            if let Some(meta) = current.filter(|meta| meta.gen == from.gen && meta.pos == from.pos)
+            {
+                if !dropped {
+                    self.next_seq();
+                    dropped = true;
+                }
+                self.remember(&key, self.seq);
+                // the record goes away with its segment, it is not stale in any other.
+                self.index.remove(&key);
+                self.live_bytes -= meta.len;
+                if let Some(at) = meta.expire_at {
+                    self.expiring.remove(&(at, key));
+                }
+            }
+        }
 
         // epilogue for clear
-        let data_path = data_path(dir);
-        fs::remove_file(&data_path)?;
-        fs::rename(compact_to_path, data_path)?;
-        // reopen the left_right_reader.
-        self.left_right_reader.compact_reopen()?;
+        for gen in &compaction.merged {
+            if let Some(stale) = self.segments.remove(gen) {
+                self.dangling_bytes -= stale;
+            }
+        }
+        self.reader.reopen(self.segments.keys())?;
+        // nothing reads the merged segments through the index any more.
+        if let Some(cache) = self.reader.cache() {
+            cache.clear();
+        }
+        // the snapshots may still read the merged segments, they go once those are dropped.
+        if self.history.oldest().is_some() {
+            self.retired.push((self.seq, compaction.merged));
+        } else {
+            for gen in compaction.merged {
+                fs::remove_file(segment_path(&self.dir, gen))?;
+                hint::remove_hint(&hint_path(&self.dir, gen))?;
+            }
+        }
         Ok(())
     }
 }
 
-// data_path is the path to the current data file
-fn data_path(path: &Path) -> PathBuf {
-    path.join("data")
+// Compacted is what a compaction run did with the records the index points to in the
+// merged segments.
+struct Compacted {
+    // the new place of a record along with where it is moved from.
+    moved: Vec<(HintEntry, Meta)>,
+    // expired records left behind.
+    expired: Vec<(Vec<u8>, Meta)>,
 }
 
-// compact_path is the path to the compact target file
-fn compact_path(path: &Path) -> PathBuf {
-    path.join("data.compact")
+// Compaction is the part of a compaction done without holding the writer lock.
+struct Compaction {
+    dir: PathBuf,
+    index: Arc<Index>,
+    // gen of the new segment and of the segments merged into it.
+    gen: u64,
+    merged: Vec<u64>,
+    // sequence number of the last write before the merged segments are sealed.
+    seq: u64,
+    // the codec the copied records are rewritten with, if they have another one.
+    compression: Compression,
+    compress_threshold: usize,
 }
 
-fn open_for_read(path: &Path, pos: u64) -> Result<File> {
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)?;
-    file.seek(SeekFrom::Start(pos))?;
-    Ok(file)
+impl Compaction {
+    // `run` copies the records the index points to in the merged segments into the new
+    // segment, and returns where each of them is moved from. The merged segments are
+    // sealed, so their records only go stale meanwhile. Expired records are not copied,
+    // and the ones compressed with another codec are rewritten.
+    fn run(&self) -> Result<Compacted> {
+        let mut sources = BTreeMap::new();
+        for &gen in &self.merged {
+            sources.insert(gen, File::open(segment_path(&self.dir, gen))?);
+        }
+        let compact_to_path = compact_path(&self.dir, self.gen);
+        let mut compact_file = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&compact_to_path)?,
+        );
+        let mut cursor = 0;
+        let mut entries = Vec::new();
+        let mut from = Vec::new();
+        let mut expired = Vec::new();
+        let now = expiry::now();
+        for entry in self.index.iter() {
+            let meta = entry.value().load();
+            if let Some(file) = sources.get(&meta.gen) {
+                if meta.is_expired(now) {
+                    expired.push((entry.key().clone(), meta));
+                    continue;
+                }
+                let mut buf = vec![0u8; meta.len as usize];
+                file.read_exact_at(meta.pos, buf.as_mut())?;
+                let recoded =
+                    record::recode(meta.pos, &buf, self.compression, self.compress_threshold)?;
+                let buf = recoded.unwrap_or(buf);
+                compact_file.write_all(&buf)?;
+                entries.push(HintEntry {
+                    key: entry.key().clone(),
+                    pos: cursor,
+                    len: buf.len() as u64,
+                    expire_at: meta.expire_at,
+                    seq: meta.seq,
+                });
+                from.push(meta);
+                cursor += buf.len() as u64;
+            }
+        }
+        let compact_file = compact_file.into_inner().map_err(|e| e.into_error())?;
+        compact_file.sync_all()?;
+
+        // the new segment must be complete before it gets its name.
+        fs::rename(&compact_to_path, segment_path(&self.dir, self.gen))?;
+        let hint = Hint {
+            seq: self.seq,
+            entries,
+        };
+        hint::write_hint(&hint_path(&self.dir, self.gen), &hint)?;
+        Ok(Compacted {
+            moved: hint.entries.into_iter().zip(from).collect(),
+            expired,
+        })
+    }
+
+    // `clean_up` removes what a failed run left behind.
+    fn clean_up(&self) {
+        for path in &[
+            compact_path(&self.dir, self.gen),
+            segment_path(&self.dir, self.gen),
+            hint_path(&self.dir, self.gen),
+        ] {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("fail to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
 }
 
-fn open_for_append(path: &Path) -> Result<File> {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(path)?;
-    Ok(file)
+/// RecoveryMode decides what `KvStore::open` does with a broken record in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Truncate a segment at its first broken record and open with everything else.
+    /// This covers the torn tail left by a crash in the middle of a write.
+    TruncateCorrupted,
+    /// Only a broken trailing record of the active segment is truncated. Any other
+    /// corruption fails the open with `KvsError::CorruptedRecordError`.
+    Strict,
+}
+
+impl Default for RecoveryMode {
+    fn default() -> Self {
+        RecoveryMode::TruncateCorrupted
+    }
 }
 
-/// Command defines command
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+// index_set points `key` to `meta` and accounts the record it replaces as stale. A
+// record expired by `now` removes the key like a tombstone, and is stale itself.
+fn index_set(index: &Index, segments: &mut BTreeMap<u64, u64>, key: Vec<u8>, meta: Meta, now: u64) {
+    if let Some(entry) = index.get(&key) {
+        let old = entry.value().load();
+        *segments.entry(old.gen).or_insert(0) += old.len;
+    }
+    if meta.is_expired(now) {
+        index.remove(&key);
+        *segments.entry(meta.gen).or_insert(0) += meta.len;
+        return;
+    }
+    put(index, key, meta);
+}
+
+// put points `key` to `meta`. The entry of a key in the index is updated in place, the
+// skiplist would otherwise unlink the old entry before it links the new one, and a
+// reader could find no entry for the key in between. Only the writer calls it.
+fn put(index: &Index, key: Vec<u8>, meta: Meta) {
+    match index.get(&key) {
+        Some(entry) => entry.value().store(meta),
+        None => {
+            index.insert(key, AtomicCell::new(meta));
+        }
+    }
 }
 
-impl Display for Command {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Command::Get { key } => {
-                write!(f, "get {}", key)?;
+// replay_log feeds every good record of a segment to `apply` and returns the offset right
+// after the last good record. The segment is truncated there if a broken record is found,
+// unless the store is opened read-only.
+fn replay_log<F>(path: &Path, opts: &KvStoreOptions, last: bool, mut apply: F) -> Result<u64>
+where
+    F: FnMut(u64, u64, u64, Command),
+{
+    let file = open_for_read(path, opts.read_only)?;
+    let mut cursor = 0;
+    for item in RecordReader::new(BufReader::new(&file)) {
+        match item {
+            Ok((pos, len, seq, cmd)) => {
+                apply(pos, len, seq, cmd);
+                cursor = pos + len;
             }
-            Command::Set { key, value } => {
-                write!(f, "set {}:{}", key, value)?;
+            Err(KvsError::CorruptedRecordError { offset, reason }) => {
+                let file_len = file.metadata()?.len();
+                if opts.recovery == RecoveryMode::Strict
+                    && !(last && record::is_trailing(&file, offset, file_len)?)
+                {
+                    let reason = format!("{}: {}", path.display(), reason);
+                    return Err(KvsError::CorruptedRecordError { offset, reason });
+                }
+                warn!(
+                    "drop {} bytes of {} from offset {}: {}",
+                    file_len - offset,
+                    path.display(),
+                    offset,
+                    reason
+                );
+                if !opts.read_only {
+                    file.set_len(offset)?;
+                    file.sync_all()?;
+                }
+                break;
             }
-            Command::Remove { key } => {
-                write!(f, "rm {}", key)?;
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(cursor)
+}
+
+// upgrade_single_file_log turns the `data` file written before the log was split into
+// segments into the first segment.
+fn upgrade_single_file_log(dir: &Path, read_only: bool) -> Result<()> {
+    let data_path = dir.join("data");
+    if !data_path.exists() {
+        return Ok(());
+    }
+    if read_only {
+        error!("{} has to be upgraded first", data_path.display());
+        return Err(KvsError::ReadOnlyError);
+    }
+    record::migrate_json_log(&data_path)?;
+    if !segment_gens(dir, true)?.is_empty() {
+        warn!(
+            "ignore {}, the log is already split into segments",
+            data_path.display()
+        );
+        return Ok(());
+    }
+    let compact_path = dir.join("data.compact");
+    if compact_path.exists() {
+        fs::remove_file(compact_path)?;
+    }
+    fs::rename(&data_path, segment_path(dir, 1))?;
+    info!("moved {} into the first segment", data_path.display());
+    Ok(())
+}
+
+// segment_gens lists the gens of the segments in `dir` in ascending order, and cleans
+// up what an interrupted compaction left behind if `clean` is set.
+fn segment_gens(dir: &Path, clean: bool) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    let mut hints = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let gen = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        match (gen, path.extension().and_then(|s| s.to_str())) {
+            (Some(gen), Some("log")) => gens.push(gen),
+            (Some(gen), Some("hint")) => hints.push(gen),
+            (Some(_), Some("compact")) if clean => fs::remove_file(&path)?,
+            _ => (),
+        }
+    }
+    gens.sort_unstable();
+    for gen in hints.into_iter().filter(|_| clean) {
+        if gens.binary_search(&gen).is_err() {
+            hint::remove_hint(&hint_path(dir, gen))?;
+        }
+    }
+    Ok(gens)
+}
+
+// remove_segments removes the segments `gens` along with their hint files.
+fn remove_segments(dir: &Path, gens: &[u64]) {
+    for &gen in gens {
+        for path in &[segment_path(dir, gen), hint_path(dir, gen)] {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("fail to remove {}: {}", path.display(), e);
+                }
             }
         }
-        Ok(())
     }
 }
 
-// Meta store position and length for a Set Command
-#[derive(Debug)]
-struct Meta(u64, u64); // position and length
+// segment_path is the path to the segment `gen`
+fn segment_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+// hint_path is the path to the hint file of the segment `gen`
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+// compact_path is the path a compaction writes the segment `gen` to before it is complete
+fn compact_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.compact", gen))
+}
+
+fn open_for_read(path: &Path, read_only: bool) -> Result<File> {
+    let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+    Ok(file)
+}
+
+fn open_for_append(path: &Path) -> Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(file)
+}
+
+// Index maps every live key to where its record is.
+type Index = SkipMap<Vec<u8>, AtomicCell<Meta>>;
+
+// Meta store the segment, position, length, expire time and sequence number for a Set Command
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expire_at: Option<u64>,
+    seq: u64,
+}
 
 impl Meta {
-    pub fn new(p: u64, l: u64) -> Self {
-        Meta(p, l)
+    pub fn new(gen: u64, pos: u64, len: u64, expire_at: Option<u64>, seq: u64) -> Self {
+        Meta {
+            gen,
+            pos,
+            len,
+            expire_at,
+            seq,
+        }
     }
-}
\ No newline at end of file
+
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expire_at, now)
+    }
+}
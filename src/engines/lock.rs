@@ -0,0 +1,106 @@
+//! # Lock file
+//!
+//! A store is locked by the process that opens it, through `flock` on the `LOCK` file
+//! in its directory. A writer takes an exclusive lock and leaves its pid in the file,
+//! readers share a lock and leave nothing. The kernel releases the lock along with the
+//! file, so a process that dies leaves no stale lock behind, only a stale pid, which
+//! the next writer overwrites.
+
+use crate::{KvsError, Result};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::str;
+use std::thread;
+use std::time::Duration;
+
+const LOCK_FILE: &str = "LOCK";
+// how often and how long to wait for the pid of a writer that just took the lock.
+const PID_RETRIES: u32 = 10;
+const PID_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+pub(crate) struct DirLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl DirLock {
+    /// `acquire` locks the store in `dir`, exclusively for a writer and shared for a
+    /// reader. It fails with `KvsError::Locked` if another process holds a lock in the
+    /// way, the same process opening the store twice included.
+    pub fn acquire(dir: &Path, exclusive: bool) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(exclusive)
+            .create(exclusive)
+            // the pid in the file is only replaced once the lock is held.
+            .truncate(false)
+            .open(&path)
+        {
+            // a reader of a store written before the lock file creates it all the same.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?,
+            ret => ret?,
+        };
+        let arg = if exclusive {
+            FlockArg::LockExclusiveNonblock
+        } else {
+            FlockArg::LockSharedNonblock
+        };
+        match flock(file.as_raw_fd(), arg) {
+            Ok(()) => (),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                return Err(KvsError::Locked {
+                    pid: wait_pid(&file),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if exclusive {
+            file.set_len(0)?;
+            (&file).write_all(format!("{}\n", process::id()).as_bytes())?;
+        }
+        Ok(DirLock { file, exclusive })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // a lock held by readers only leaves no pid to report.
+        if self.exclusive {
+            if let Err(e) = self.file.set_len(0) {
+                warn!("failed to clear the lock file: {}", e);
+            }
+        }
+    }
+}
+
+// wait_pid reads the pid of the writer holding the lock, None if the lock is held by
+// readers. A writer leaves its pid only after it takes the lock, so an empty file is
+// read again for a while before the lock is taken for one held by readers.
+fn wait_pid(file: &File) -> Option<u32> {
+    for _ in 0..PID_RETRIES {
+        if let Some(pid) = read_pid(file) {
+            return Some(pid);
+        }
+        thread::sleep(PID_RETRY_INTERVAL);
+    }
+    read_pid(file)
+}
+
+fn read_pid(file: &File) -> Option<u32> {
+    // read from the start every time, a pid is a line of a few digits.
+    let mut buf = [0u8; 16];
+    let n = file.read_at(&mut buf, 0).ok()?;
+    str::from_utf8(&buf[..n]).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,1180 @@
+//! # LSM engine
+//!
+//! `LsmKvsEngine` keeps the recent writes in a memtable and everything else in sorted
+//! tables on disk, so only the block index and the bloom filter of every table have to
+//! fit in memory, not the keys:
+//!
+//! - A write is appended to the write ahead log `<id>.wal`, in the record format of
+//!   `KvStore`, and inserted into the memtable. Once the memtable grows over
+//!   `LsmOptions::memtable_size` it is flushed into a table of tier 0 and a new log is
+//!   started.
+//! - Compaction is size tiered. Once a tier gathers `LsmOptions::tier_width` tables, the
+//!   background thread merges them into a single table of the next tier. A delete, or a
+//!   key expired meanwhile, is kept as a delete unless no table older than the merged
+//!   ones is left.
+//! - `MANIFEST` lists the tables with their tier along with the keyspaces. It is
+//!   rewritten as a whole through a temporary file on every change.
+//!
+//! A lookup goes through the memtable first and then through the tables, newest first:
+//! every tier holds newer keys than the tiers after it, and within a tier the table with
+//! the higher id is the newer one.
+//!
+//! The keys of all keyspaces share the tables, behind the 4 byte id of their keyspace.
+//! Dropping a keyspace forgets its id, the compactions drop its keys later on.
+
+use super::lock::DirLock;
+use super::record::{self, Command, RecordReader};
+use super::sstable::{Slot, Table, TableBuilder};
+use super::transaction::Transaction;
+use super::{check_keyspace, expiry, DEFAULT_KEYSPACE};
+use crate::{BatchOp, KvsEngine, KvsError, LsmOptions, Result, SyncPolicy, WriteBatch};
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender};
+use crossbeam_skiplist::SkipMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_MAGIC: [u8; 2] = [0xb7, 0x4d];
+const MANIFEST_VERSION: u8 = 1;
+// the id of the default keyspace, the named ones count up from 1.
+const DEFAULT_KEYSPACE_ID: u32 = 0;
+// what a key takes in the memtable next to its key and value bytes.
+const SLOT_OVERHEAD: u64 = 32;
+
+// a write to apply: the key with its keyspace id in front, the value, None for a
+// delete, and the expire time.
+type Op = (Vec<u8>, Option<Vec<u8>>, Option<u64>);
+
+/// LsmKvsEngine keeps its keys in a log structured merge tree, see the module
+/// documentation. Every keyspace is a handle of its own on the same tree.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    keyspace: u32,
+    inner: Arc<Inner>,
+    worker: Arc<Worker>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    opts: LsmOptions,
+    // swapped as a whole by a flush or a compaction, readers hold on to the one they
+    // start with.
+    version: RwLock<Arc<Version>>,
+    writer: Mutex<Writer>,
+    _lock: DirLock,
+}
+
+struct Version {
+    mem: Arc<MemTable>,
+    // newest first, see `sort_tables`.
+    tables: Vec<TableRef>,
+}
+
+#[derive(Clone)]
+struct TableRef {
+    id: u64,
+    tier: u32,
+    table: Arc<Table>,
+}
+
+// MemTable keeps every version of a key written since the last flush, newest first.
+// A write never replaces an entry, so a reader never misses a key being overwritten.
+#[derive(Default)]
+struct MemTable {
+    map: SkipMap<(Vec<u8>, Reverse<u64>), Slot>,
+}
+
+impl MemTable {
+    fn get(&self, key: &[u8]) -> Option<Slot> {
+        let entry = self.map.range((key.to_vec(), Reverse(u64::MAX))..).next()?;
+        Some(entry.value().clone()).filter(|_| entry.key().0 == key)
+    }
+
+    fn insert(&self, key: Vec<u8>, slot: Slot) {
+        self.map.insert((key, Reverse(slot.seq)), slot);
+    }
+}
+
+// Writer is the state the writes go through one at a time.
+struct Writer {
+    manifest: Manifest,
+    wal: BufWriter<File>,
+    wal_id: u64,
+    seq: u64,
+    // the bytes taken by the memtable, roughly.
+    mem_size: u64,
+    // the bytes written to the log since it is last synced.
+    unsynced: u64,
+}
+
+impl Writer {
+    // `sync` syncs the log to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // `flush_point` flushes the log and returns a handle to sync it with, so the sync
+    // does not have to hold the writer lock.
+    fn flush_point(&mut self) -> Result<File> {
+        self.wal.flush()?;
+        self.unsynced = 0;
+        Ok(self.wal.get_ref().try_clone()?)
+    }
+}
+
+impl LsmKvsEngine {
+    /// `open` opens the engine in `path` with the default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, LsmOptions::default())
+    }
+
+    /// `open_with` opens the engine in `path`, which is locked like the directory of a
+    /// `KvStore`. The logs left by the last run are replayed and flushed into a table.
+    pub fn open_with(path: impl Into<PathBuf>, opts: LsmOptions) -> Result<Self> {
+        let dir: PathBuf = path.into();
+        fs::create_dir_all(&dir)?;
+        let lock = DirLock::acquire(&dir, true)?;
+        let mut manifest = Manifest::load(&dir)?.unwrap_or_default();
+        let (tables, wals) = list_files(&dir)?;
+        // a table not listed is what a flush or a compaction interrupted left behind.
+        let listed: HashSet<u64> = manifest.tables.iter().map(|&(id, _)| id).collect();
+        for &id in tables.iter().filter(|id| !listed.contains(id)) {
+            fs::remove_file(table_path(&dir, id))?;
+        }
+        let max_id = tables.iter().chain(wals.iter()).max().copied().unwrap_or(0);
+        manifest.next_id = manifest.next_id.max(max_id + 1);
+
+        let mut seq = 0;
+        let mut refs = Vec::new();
+        for &(id, tier) in &manifest.tables {
+            let table = Table::open(&table_path(&dir, id))?;
+            seq = seq.max(table.max_seq());
+            refs.push(TableRef {
+                id,
+                tier,
+                table: Arc::new(table),
+            });
+        }
+        sort_tables(&mut refs);
+        let mem = MemTable::default();
+        let mut mem_size = 0;
+        for &id in &wals {
+            let path = wal_path(&dir, id);
+            for item in RecordReader::new(BufReader::new(File::open(&path)?)) {
+                let (_, _, item_seq, cmd) = match item {
+                    Ok(item) => item,
+                    Err(e) => {
+                        warn!(
+                            "stop replaying {} at a broken record: {}",
+                            path.display(),
+                            e
+                        );
+                        break;
+                    }
+                };
+                let (key, slot) = match cmd {
+                    Command::Set {
+                        key,
+                        value,
+                        expire_at,
+                    } => (key, Slot::put(value, expire_at, item_seq)),
+                    Command::Remove { key } => (key, Slot::delete(item_seq)),
+                    Command::Get { .. } => continue,
+                };
+                mem_size += slot_size(&key, &slot);
+                mem.insert(key, slot);
+                seq = seq.max(item_seq);
+            }
+        }
+
+        let wal_id = manifest.alloc_id();
+        let wal = create_wal(&dir, wal_id)?;
+        manifest.save(&dir)?;
+        let writer = Writer {
+            manifest,
+            wal,
+            wal_id,
+            seq,
+            mem_size,
+            unsynced: 0,
+        };
+        let version = Version {
+            mem: Arc::new(mem),
+            tables: refs,
+        };
+        let inner = Arc::new(Inner {
+            dir,
+            opts,
+            version: RwLock::new(Arc::new(version)),
+            writer: Mutex::new(writer),
+            _lock: lock,
+        });
+        {
+            let mut writer = inner.writer.lock().unwrap();
+            inner.flush(&mut writer)?;
+        }
+        for &id in &wals {
+            fs::remove_file(wal_path(&inner.dir, id))?;
+        }
+        let worker = Worker::spawn(inner.clone())?;
+        worker.trigger();
+        Ok(LsmKvsEngine {
+            keyspace: DEFAULT_KEYSPACE_ID,
+            inner,
+            worker: Arc::new(worker),
+        })
+    }
+
+    /// `destroy` removes the engine in `path`, the directory is locked meanwhile.
+    pub fn destroy(path: impl Into<PathBuf>) -> Result<()> {
+        let dir: PathBuf = path.into();
+        let _lock = DirLock::acquire(&dir, true)?;
+        let (tables, wals) = list_files(&dir)?;
+        for id in tables {
+            fs::remove_file(table_path(&dir, id))?;
+        }
+        for id in wals {
+            fs::remove_file(wal_path(&dir, id))?;
+        }
+        match fs::remove_file(dir.join(MANIFEST)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // `internal_key` puts the id of the keyspace in front of `key`.
+    fn internal_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut internal = self.keyspace.to_be_bytes().to_vec();
+        internal.extend_from_slice(key);
+        internal
+    }
+
+    // `live` returns the version of `key` if it is neither deleted nor expired.
+    fn live(&self, key: &[u8]) -> Result<Option<Slot>> {
+        let slot = self.inner.get(&self.internal_key(key))?;
+        Ok(slot.filter(|slot| slot.is_live(expiry::now())))
+    }
+
+    // `write` runs `f` under the writer lock and triggers a compaction if it is due.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Writer) -> Result<T>,
+    {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let ret = f(&mut writer)?;
+        drop(writer);
+        if self.inner.compaction_due() {
+            self.worker.trigger();
+        }
+        Ok(ret)
+    }
+
+    fn put(
+        &self,
+        writer: &mut Writer,
+        key: &[u8],
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    ) -> Result<()> {
+        let op = (self.internal_key(key), Some(value), expire_at);
+        self.inner.apply(writer, vec![op])
+    }
+
+    fn delete(&self, writer: &mut Writer, key: &[u8]) -> Result<()> {
+        self.inner
+            .apply(writer, vec![(self.internal_key(key), None, None)])
+    }
+
+    // `set_expiry` rewrites the live `key` to expire at `expire_at`, None makes it never
+    // expire.
+    fn set_expiry(&self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool> {
+        self.write(|writer| match self.live(&key)? {
+            Some(slot) if expire_at.is_some() || slot.expire_at.is_some() => {
+                self.put(writer, &key, slot.value.unwrap_or_default(), expire_at)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+
+    // `collect` returns at most `limit` live pairs of this keyspace from `start` on, as
+    // long as `in_range` holds for their keys.
+    fn collect<F>(
+        &self,
+        start: Bound<Vec<u8>>,
+        in_range: F,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let version = self.inner.current();
+        let now = expiry::now();
+        let mut pairs = Vec::new();
+        for item in version.iter(start) {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, slot) = item?;
+            if !in_range(&key) {
+                break;
+            }
+            if let (true, Some(value)) = (slot.is_live(now), slot.value) {
+                pairs.push((key[4..].to_vec(), value));
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+impl Inner {
+    fn current(&self) -> Arc<Version> {
+        self.version.read().unwrap().clone()
+    }
+
+    // `get` returns the newest version of the internal key `key`.
+    fn get(&self, key: &[u8]) -> Result<Option<Slot>> {
+        let version = self.current();
+        if let Some(slot) = version.mem.get(key) {
+            return Ok(Some(slot));
+        }
+        for table in &version.tables {
+            if let Some(slot) = table.table.get(key)? {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    // `apply` logs `ops` as a whole and inserts them into the memtable, which is flushed
+    // once it is full.
+    fn apply(&self, writer: &mut Writer, ops: Vec<Op>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::with_capacity(ops.len());
+        let mut slots = Vec::with_capacity(ops.len());
+        for (key, value, expire_at) in ops {
+            writer.seq += 1;
+            let (cmd, slot) = match value {
+                Some(value) => (
+                    Command::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        expire_at,
+                    },
+                    Slot::put(value, expire_at, writer.seq),
+                ),
+                None => (
+                    Command::Remove { key: key.clone() },
+                    Slot::delete(writer.seq),
+                ),
+            };
+            records.push(record::encode(writer.seq, &cmd)?);
+            slots.push((key, slot));
+        }
+        let buf = if records.len() == 1 {
+            records.pop().unwrap()
+        } else {
+            record::encode_batch(writer.seq, &records)?
+        };
+        writer.wal.write_all(&buf)?;
+        writer.wal.flush()?;
+        writer.unsynced += buf.len() as u64;
+        match self.opts.sync {
+            SyncPolicy::EveryWrite => writer.sync()?,
+            SyncPolicy::EveryNBytes(bytes) if writer.unsynced >= bytes => writer.sync()?,
+            _ => {}
+        }
+        let version = self.current();
+        for (key, slot) in slots {
+            writer.mem_size += slot_size(&key, &slot);
+            version.mem.insert(key, slot);
+        }
+        if writer.mem_size >= self.opts.memtable_size {
+            self.flush(writer)?;
+        }
+        Ok(())
+    }
+
+    // `flush` writes the memtable into a table of tier 0 and starts a new log.
+    fn flush(&self, writer: &mut Writer) -> Result<()> {
+        let version = self.current();
+        if version.mem.map.is_empty() {
+            return Ok(());
+        }
+        let id = writer.manifest.alloc_id();
+        let path = table_path(&self.dir, id);
+        let mut builder = TableBuilder::create(&path, self.opts.block_size)?;
+        for item in MemIter::new(version.mem.clone(), Bound::Unbounded) {
+            let (key, slot) = item?;
+            builder.add(&key, &slot)?;
+        }
+        builder.finish()?;
+        let table = Arc::new(Table::open(&path)?);
+
+        let wal_id = writer.manifest.alloc_id();
+        let wal = create_wal(&self.dir, wal_id)?;
+        writer.manifest.tables.push((id, 0));
+        writer.manifest.save(&self.dir)?;
+        let old_wal = std::mem::replace(&mut writer.wal_id, wal_id);
+        writer.wal = wal;
+        writer.mem_size = 0;
+        // the writes of the old log are in the table, which is synced.
+        writer.unsynced = 0;
+        let mut tables = version.tables.clone();
+        tables.push(TableRef { id, tier: 0, table });
+        sort_tables(&mut tables);
+        *self.version.write().unwrap() = Arc::new(Version {
+            mem: Arc::new(MemTable::default()),
+            tables,
+        });
+        fs::remove_file(wal_path(&self.dir, old_wal))?;
+        Ok(())
+    }
+
+    // `due_tier` returns the first tier with enough tables to be merged.
+    fn due_tier(&self, version: &Version) -> Option<u32> {
+        let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+        for table in &version.tables {
+            *counts.entry(table.tier).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .find(|&(_, count)| count >= self.opts.tier_width)
+            .map(|(tier, _)| tier)
+    }
+
+    fn compaction_due(&self) -> bool {
+        self.due_tier(&self.current()).is_some()
+    }
+
+    // `compact` merges the tables of every tier which is due, one tier at a time. The
+    // writer lock is released while the tables are merged.
+    fn compact(&self) -> Result<()> {
+        loop {
+            let (tier, inputs, bottom, live, id) = {
+                let mut writer = self.writer.lock().unwrap();
+                let version = self.current();
+                let tier = match self.due_tier(&version) {
+                    Some(tier) => tier,
+                    None => return Ok(()),
+                };
+                let inputs: Vec<TableRef> = version
+                    .tables
+                    .iter()
+                    .filter(|table| table.tier == tier)
+                    .cloned()
+                    .collect();
+                let bottom = !version.tables.iter().any(|table| table.tier > tier);
+                let mut live: HashSet<u32> = writer.manifest.keyspaces.values().copied().collect();
+                live.insert(DEFAULT_KEYSPACE_ID);
+                (tier, inputs, bottom, live, writer.manifest.alloc_id())
+            };
+            let path = table_path(&self.dir, id);
+            let mut builder = TableBuilder::create(&path, self.opts.block_size)?;
+            let now = expiry::now();
+            let sources = inputs
+                .iter()
+                .map(|table| Box::new(table.table.iter(Bound::Unbounded)) as Source)
+                .collect();
+            for item in MergeIter::new(sources) {
+                let (key, mut slot) = item?;
+                if !live.contains(&keyspace_of(&key)) {
+                    continue;
+                }
+                if !slot.is_live(now) {
+                    slot = Slot::delete(slot.seq);
+                }
+                // nothing older is left for a delete to hide.
+                if bottom && slot.value.is_none() {
+                    continue;
+                }
+                builder.add(&key, &slot)?;
+            }
+            let empty = builder.len() == 0;
+            builder.finish()?;
+            let table = if empty {
+                fs::remove_file(&path)?;
+                None
+            } else {
+                Some(Arc::new(Table::open(&path)?))
+            };
+
+            let ids: HashSet<u64> = inputs.iter().map(|table| table.id).collect();
+            {
+                let mut writer = self.writer.lock().unwrap();
+                writer.manifest.tables.retain(|(id, _)| !ids.contains(id));
+                if table.is_some() {
+                    writer.manifest.tables.push((id, tier + 1));
+                }
+                writer.manifest.save(&self.dir)?;
+                let version = self.current();
+                let mut tables: Vec<TableRef> = version
+                    .tables
+                    .iter()
+                    .filter(|table| !ids.contains(&table.id))
+                    .cloned()
+                    .collect();
+                if let Some(table) = table {
+                    debug!(
+                        "merge {} tables of tier {} into table {} of {} keys",
+                        ids.len(),
+                        tier,
+                        id,
+                        table.count()
+                    );
+                    tables.push(TableRef {
+                        id,
+                        tier: tier + 1,
+                        table,
+                    });
+                }
+                sort_tables(&mut tables);
+                *self.version.write().unwrap() = Arc::new(Version {
+                    mem: version.mem.clone(),
+                    tables,
+                });
+            }
+            // the readers still holding them keep the files open.
+            for id in ids {
+                if let Err(e) = fs::remove_file(table_path(&self.dir, id)) {
+                    warn!("fail to remove table {}: {}", id, e);
+                }
+            }
+        }
+    }
+}
+
+impl Version {
+    // `iter` iterates the newest version of every internal key from `start` on.
+    fn iter(&self, start: Bound<Vec<u8>>) -> MergeIter {
+        let mut sources: Vec<Source> =
+            vec![Box::new(MemIter::new(self.mem.clone(), start.clone()))];
+        for table in &self.tables {
+            sources.push(Box::new(table.table.iter(start.clone())));
+        }
+        MergeIter::new(sources)
+    }
+}
+
+impl Slot {
+    fn put(value: Vec<u8>, expire_at: Option<u64>, seq: u64) -> Self {
+        Slot {
+            value: Some(value),
+            expire_at,
+            seq,
+        }
+    }
+
+    fn delete(seq: u64) -> Self {
+        Slot {
+            value: None,
+            expire_at: None,
+            seq,
+        }
+    }
+
+    fn is_live(&self, now: u64) -> bool {
+        self.value.is_some() && !expiry::is_expired(self.expire_at, now)
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|writer| self.put(writer, &key, value, None))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live(&key)?.and_then(|slot| slot.value))
+    }
+
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self.live(&key)?.map(|slot| slot.seq))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|writer| {
+            if self.live(&key)?.is_none() {
+                return Err(KvsError::KeyNotFoundError);
+            }
+            self.delete(writer, &key)
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.internal_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.internal_key(key)),
+            Bound::Unbounded => Bound::Included(self.internal_key(&[])),
+        };
+        let prefix = self.keyspace.to_be_bytes();
+        self.collect(
+            start,
+            |key| key.starts_with(&prefix) && range.contains(&key[4..].to_vec()),
+            limit,
+        )
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = self.internal_key(&prefix);
+        self.collect(
+            Bound::Included(prefix.clone()),
+            |key| key.starts_with(&prefix),
+            limit,
+        )
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let ops = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => (self.internal_key(&key), Some(value), None),
+                BatchOp::Remove { key } => (self.internal_key(&key), None, None),
+            })
+            .collect();
+        self.write(|writer| self.inner.apply(writer, ops))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(|writer| {
+            let current = self.live(&key)?.and_then(|slot| slot.value);
+            if current != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => self.put(writer, &key, value, None)?,
+                None if current.is_some() => self.delete(writer, &key)?,
+                None => (),
+            }
+            Ok(true)
+        })
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = Some(expiry::expire_at(ttl));
+        self.write(|writer| self.put(writer, &key, value, expire_at))
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.set_expiry(key, Some(expiry::expire_at(ttl)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let slot = self.live(&key)?.ok_or(KvsError::KeyNotFoundError)?;
+        Ok(slot
+            .expire_at
+            .map(|expire_at| expiry::time_left(expire_at, expiry::now())))
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.set_expiry(key, None)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>,
+    {
+        let mut txn = LsmTransaction {
+            engine: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let ret = f(&mut txn)?;
+        let LsmTransaction { reads, writes, .. } = txn;
+        self.write(|writer| {
+            for (key, seq) in reads {
+                if self.live(&key)?.map(|slot| slot.seq) != seq {
+                    return Err(KvsError::TransactionConflict);
+                }
+            }
+            let ops = writes
+                .into_iter()
+                .map(|(key, value)| (self.internal_key(&key), value, None))
+                .collect();
+            self.inner.apply(writer, ops)?;
+            Ok(ret)
+        })
+    }
+
+    fn open_keyspace(&self, name: &str) -> Result<Self> {
+        let keyspace = if name == DEFAULT_KEYSPACE {
+            DEFAULT_KEYSPACE_ID
+        } else {
+            check_keyspace(name)?;
+            let mut writer = self.inner.writer.lock().unwrap();
+            match writer.manifest.keyspaces.get(name) {
+                Some(&id) => id,
+                None => {
+                    let id = writer.manifest.next_keyspace;
+                    writer.manifest.next_keyspace += 1;
+                    writer.manifest.keyspaces.insert(name.to_owned(), id);
+                    writer.manifest.save(&self.inner.dir)?;
+                    id
+                }
+            }
+        };
+        Ok(LsmKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<bool> {
+        check_keyspace(name)?;
+        let mut writer = self.inner.writer.lock().unwrap();
+        if writer.manifest.keyspaces.remove(name).is_none() {
+            return Ok(false);
+        }
+        writer.manifest.save(&self.inner.dir)?;
+        Ok(true)
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let writer = self.inner.writer.lock().unwrap();
+        Ok(writer.manifest.keyspaces.keys().cloned().collect())
+    }
+
+    // the memtable is flushed first, so the checkpoint is made of tables only. Tables
+    // never change, they are linked, or copied once the writer lock is released.
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let _lock = DirLock::acquire(dir, true)?;
+        if dir.join(MANIFEST).exists() {
+            let msg = format!("{} holds a store already", dir.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+        let (manifest, copies) = self.write(|writer| {
+            self.inner.flush(writer)?;
+            let mut copies = Vec::new();
+            for &(id, _) in &writer.manifest.tables {
+                let (from, to) = (table_path(&self.inner.dir, id), table_path(dir, id));
+                if let Err(e) = fs::hard_link(&from, &to) {
+                    debug!("copy {} instead of linking it: {}", from.display(), e);
+                    copies.push((File::open(&from)?, to));
+                }
+            }
+            Ok((writer.manifest.clone(), copies))
+        })?;
+        for (mut from, to) in copies {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(&to)?;
+            io::copy(&mut from, &mut file)?;
+            file.sync_all()?;
+        }
+        manifest.save(dir)
+    }
+}
+
+// LsmTransaction reads through the tree and keeps the writes until it commits. The
+// sequence number of the version a key is read from tells whether it changes meanwhile.
+struct LsmTransaction<'a> {
+    engine: &'a LsmKvsEngine,
+    // the sequence number of every key read, None if it does not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // the last write of every key written, None for a remove.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction for LsmTransaction<'a> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let slot = self.engine.live(&key)?;
+        // a key read again keeps the version it is read first with, so a change in
+        // between is a conflict as well.
+        self.reads
+            .entry(key)
+            .or_insert_with(|| slot.as_ref().map(|slot| slot.seq));
+        Ok(slot.and_then(|slot| slot.value))
+    }
+
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        let seq = self.engine.live(&key)?.map(|slot| slot.seq);
+        Ok(*self.reads.entry(key).or_insert(seq))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFoundError);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<(Vec<u8>, Slot)>>>;
+
+// MemIter iterates the newest version of every key of a memtable. It looks the next key
+// up on every step, so it holds no reference into the memtable.
+struct MemIter {
+    mem: Arc<MemTable>,
+    next: Bound<(Vec<u8>, Reverse<u64>)>,
+}
+
+impl MemIter {
+    fn new(mem: Arc<MemTable>, start: Bound<Vec<u8>>) -> Self {
+        let next = match start {
+            Bound::Included(key) => Bound::Included((key, Reverse(u64::MAX))),
+            Bound::Excluded(key) => Bound::Excluded((key, Reverse(0))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        MemIter { mem, next }
+    }
+}
+
+impl Iterator for MemIter {
+    type Item = Result<(Vec<u8>, Slot)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = (self.next.clone(), Bound::Unbounded);
+        let entry = self.mem.map.range(range).next()?;
+        let key = entry.key().0.clone();
+        // the older versions of the key come right after it.
+        self.next = Bound::Excluded((key.clone(), Reverse(0)));
+        Some(Ok((key, entry.value().clone())))
+    }
+}
+
+// MergeIter merges sources ordered by key into the newest version of every key, where
+// a source comes before the older ones.
+struct MergeIter {
+    sources: Vec<Source>,
+    heads: Vec<Option<Slot>>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    failed: Option<KvsError>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>) -> Self {
+        let mut iter = MergeIter {
+            heads: vec![None; sources.len()],
+            sources,
+            heap: BinaryHeap::new(),
+            failed: None,
+        };
+        for i in 0..iter.sources.len() {
+            iter.advance(i);
+        }
+        iter
+    }
+
+    fn advance(&mut self, i: usize) {
+        match self.sources[i].next() {
+            Some(Ok((key, slot))) => {
+                self.heads[i] = Some(slot);
+                self.heap.push(Reverse((key, i)));
+            }
+            Some(Err(e)) => self.failed = Some(e),
+            None => (),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Slot)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.failed.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+        let Reverse((key, i)) = self.heap.pop()?;
+        let slot = self.heads[i]
+            .take()
+            .expect("a source in the heap has a head");
+        self.advance(i);
+        // the same key in the older sources is shadowed.
+        while let Some(Reverse((next, j))) = self.heap.peek() {
+            if *next != key {
+                break;
+            }
+            let j = *j;
+            self.heap.pop();
+            self.heads[j] = None;
+            self.advance(j);
+        }
+        match self.failed.take() {
+            Some(e) => {
+                self.heap.clear();
+                Some(Err(e))
+            }
+            None => Some(Ok((key, slot))),
+        }
+    }
+}
+
+// Manifest is what `MANIFEST` holds:
+//
+// | magic 2B | version 1B | next id 8B | next keyspace 4B |
+// | keyspace count 4B | (id 4B | name len 4B | name) * count |
+// | table count 4B | (id 8B | tier 4B) * count | crc32 4B |
+#[derive(Debug, Clone)]
+struct Manifest {
+    // the next id of a table or a log.
+    next_id: u64,
+    next_keyspace: u32,
+    keyspaces: BTreeMap<String, u32>,
+    tables: Vec<(u64, u32)>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            next_id: 1,
+            next_keyspace: DEFAULT_KEYSPACE_ID + 1,
+            keyspaces: BTreeMap::new(),
+            tables: Vec::new(),
+        }
+    }
+}
+
+impl Manifest {
+    fn alloc_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn load(dir: &Path) -> Result<Option<Self>> {
+        let mut buf = Vec::new();
+        match File::open(dir.join(MANIFEST)) {
+            Ok(mut file) => file.read_to_end(&mut buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Self::decode(&buf)
+            .map(Some)
+            .ok_or_else(|| KvsError::CorruptedRecordError {
+                offset: 0,
+                reason: format!("{}: broken manifest", dir.join(MANIFEST).display()),
+            })
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 4 || buf[..2] != MANIFEST_MAGIC || buf[2] != MANIFEST_VERSION {
+            return None;
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return None;
+        }
+        let mut reader = &body[3..];
+        let mut manifest = Manifest {
+            next_id: read_u64(&mut reader)?,
+            next_keyspace: read_u32(&mut reader)?,
+            ..Manifest::default()
+        };
+        for _ in 0..read_u32(&mut reader)? {
+            let id = read_u32(&mut reader)?;
+            let len = read_u32(&mut reader)? as usize;
+            if reader.len() < len {
+                return None;
+            }
+            let (name, rest) = reader.split_at(len);
+            reader = rest;
+            let name = String::from_utf8(name.to_vec()).ok()?;
+            manifest.keyspaces.insert(name, id);
+        }
+        for _ in 0..read_u32(&mut reader)? {
+            let id = read_u64(&mut reader)?;
+            manifest.tables.push((id, read_u32(&mut reader)?));
+        }
+        Some(manifest)
+    }
+
+    // `save` replaces the manifest in `dir` through a temporary file, so a crash leaves
+    // either the old or the new one.
+    fn save(&self, dir: &Path) -> Result<()> {
+        let mut buf = MANIFEST_MAGIC.to_vec();
+        buf.push(MANIFEST_VERSION);
+        buf.extend_from_slice(&self.next_id.to_le_bytes());
+        buf.extend_from_slice(&self.next_keyspace.to_le_bytes());
+        buf.extend_from_slice(&(self.keyspaces.len() as u32).to_le_bytes());
+        for (name, id) in &self.keyspaces {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        buf.extend_from_slice(&(self.tables.len() as u32).to_le_bytes());
+        for (id, tier) in &self.tables {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&tier.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
+// sync_unlocked syncs the log of `inner` without holding the writer lock over the sync.
+// A log replaced meanwhile is synced all the same, its writes are in a synced table.
+fn sync_unlocked(inner: &Inner) -> Result<()> {
+    let wal = inner.writer.lock().unwrap().flush_point()?;
+    wal.sync_data()?;
+    Ok(())
+}
+
+// Worker owns the background thread merging the tables, which also syncs the log when
+// the sync policy is time based. Dropping the last handle of the engine drops it, which
+// lets the thread finish a pending compaction and waits for it.
+struct Worker {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(inner: Arc<Inner>) -> Result<Self> {
+        // a single pending request is enough, the compaction checks the tiers anyway.
+        let (sender, receiver) = bounded::<()>(1);
+        let sync_interval = inner.opts.sync.interval();
+        let handle = thread::Builder::new()
+            .name("lsm-worker".to_owned())
+            .spawn(move || {
+                let mut next_sync = sync_interval.map(|interval| Instant::now() + interval);
+                loop {
+                    let result = match next_sync {
+                        Some(at) => receiver.recv_deadline(at),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match result {
+                        Ok(()) => {
+                            if let Err(e) = inner.compact() {
+                                error!("compaction failed: {}", e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if let Err(e) = sync_unlocked(&inner) {
+                                error!("sync failed: {}", e);
+                            }
+                            next_sync = sync_interval.map(|interval| Instant::now() + interval);
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            if let Err(e) = sync_unlocked(&inner) {
+                                error!("sync failed: {}", e);
+                            }
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(Worker {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    fn trigger(&self) {
+        if let Some(sender) = &self.sender {
+            // a full channel means a compaction is already requested.
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // closing the channel stops the thread once the pending request is handled.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background thread panicked");
+            }
+        }
+    }
+}
+
+// sort_tables orders the tables newest first: by tier, and by id within a tier.
+fn sort_tables(tables: &mut [TableRef]) {
+    tables.sort_by_key(|table| (table.tier, Reverse(table.id)));
+}
+
+// keyspace_of returns the id of the keyspace an internal key belongs to.
+fn keyspace_of(key: &[u8]) -> u32 {
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&key[..4]);
+    u32::from_be_bytes(id)
+}
+
+fn slot_size(key: &[u8], slot: &Slot) -> u64 {
+    let value_len = slot.value.as_ref().map_or(0, Vec::len);
+    (key.len() + value_len) as u64 + SLOT_OVERHEAD
+}
+
+// list_files lists the ids of the tables and of the logs in `dir`, in ascending order.
+fn list_files(dir: &Path) -> Result<(Vec<u64>, Vec<u64>)> {
+    let (mut tables, mut wals) = (Vec::new(), Vec::new());
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        match (id, path.extension().and_then(|s| s.to_str())) {
+            (Some(id), Some("sst")) => tables.push(id),
+            (Some(id), Some("wal")) => wals.push(id),
+            _ => (),
+        }
+    }
+    tables.sort_unstable();
+    wals.sort_unstable();
+    Ok((tables, wals))
+}
+
+fn create_wal(dir: &Path, id: u64) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(wal_path(dir, id))?;
+    Ok(BufWriter::new(file))
+}
+
+// table_path is the path to the table `id`
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+// wal_path is the path to the log `id`
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+fn read_u32(reader: &mut &[u8]) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut &[u8]) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
@@ -0,0 +1,548 @@
+use super::transaction::Transaction;
+use super::{check_keyspace, expiry, DEFAULT_KEYSPACE};
+use crate::{BatchOp, KvsEngine, KvsError, Result, WriteBatch};
+use crossbeam_skiplist::SkipMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// the dump a checkpoint is written to, under the directory given to `checkpoint`.
+const CHECKPOINT_DUMP: &str = "memory.dump";
+
+/// MemoryKvsEngine keeps its keys in memory only, they are gone once the last handle is
+/// dropped. Given a capacity, it evicts the least recently used keys of all keyspaces
+/// to keep their keys and values within that many bytes.
+///
+/// ```
+/// # use kvs::{KvsEngine, MemoryKvsEngine};
+/// let cache = MemoryKvsEngine::with_capacity(64 * 1024 * 1024);
+/// cache.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(cache.get("key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    space: Arc<Space>,
+    shared: Arc<Shared>,
+}
+
+// Space holds the keys of a keyspace. Readers go through the map without a lock, an
+// entry is updated in place so a reader never misses a key being overwritten.
+#[derive(Default)]
+struct Space {
+    map: SkipMap<Vec<u8>, Entry>,
+}
+
+struct Entry {
+    item: RwLock<Item>,
+    // the tick of the last read or write of the key.
+    used: AtomicU64,
+    // the tick of the record of the key in the eviction queue, see `Shared::evict`.
+    queued: AtomicU64,
+}
+
+#[derive(Clone)]
+struct Item {
+    value: Vec<u8>,
+    expire_at: Option<u64>,
+    // bumped by every write, which tells a transaction whether the key changes.
+    seq: u64,
+}
+
+impl Item {
+    fn is_live(&self, now: u64) -> bool {
+        !expiry::is_expired(self.expire_at, now)
+    }
+}
+
+// Shared is what the handles of all keyspaces of an engine share.
+struct Shared {
+    default: Arc<Space>,
+    // None if keys are never evicted.
+    capacity: Option<u64>,
+    // ticks the reads and writes of the keys, for the least recently used to be found.
+    clock: AtomicU64,
+    writer: Mutex<Writer>,
+}
+
+// Writer is the state the writes go through one at a time.
+#[derive(Default)]
+struct Writer {
+    keyspaces: HashMap<String, Arc<Space>>,
+    seq: u64,
+    // the bytes taken by the keys and values of all keyspaces.
+    size: u64,
+    // the keys by the tick they are queued at, only kept with a capacity.
+    queue: BTreeMap<u64, (Arc<Space>, Vec<u8>)>,
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryKvsEngine {
+    /// `new` returns an empty engine which never evicts a key.
+    pub fn new() -> Self {
+        Self::with_shared(None)
+    }
+
+    /// `with_capacity` returns an empty engine which evicts the least recently used keys
+    /// once the keys and values take more than `bytes`. A read counts as a use, a scan
+    /// does not.
+    pub fn with_capacity(bytes: u64) -> Self {
+        Self::with_shared(Some(bytes))
+    }
+
+    fn with_shared(capacity: Option<u64>) -> Self {
+        let space = Arc::new(Space::default());
+        let shared = Shared {
+            default: space.clone(),
+            capacity,
+            clock: AtomicU64::new(0),
+            writer: Mutex::new(Writer::default()),
+        };
+        MemoryKvsEngine {
+            space,
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// `size` returns the bytes taken by the keys and values of all keyspaces, the
+    /// expired keys not evicted yet included.
+    pub fn size(&self) -> u64 {
+        self.shared.writer.lock().unwrap().size
+    }
+
+    // `load` reads the live item of `key`, which counts as a use of it.
+    fn load(&self, key: &[u8]) -> Option<Item> {
+        let entry = self.space.map.get(key)?;
+        let item = entry.value().item.read().unwrap().clone();
+        if !item.is_live(expiry::now()) {
+            return None;
+        }
+        if self.shared.capacity.is_some() {
+            entry
+                .value()
+                .used
+                .store(self.shared.tick(), Ordering::Release);
+        }
+        Some(item)
+    }
+
+    // `write` runs `f` under the writer lock and evicts keys once it is over capacity.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Writer) -> Result<T>,
+    {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let ret = f(&mut writer)?;
+        self.shared.evict(&mut writer);
+        Ok(ret)
+    }
+
+    fn put(&self, writer: &mut Writer, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) {
+        self.shared.put(writer, &self.space, key, value, expire_at)
+    }
+
+    // `live` reads the live item of `key` under the writer lock, which is no use of it.
+    fn live(&self, key: &[u8]) -> Option<Item> {
+        let entry = self.space.map.get(key)?;
+        let item = entry.value().item.read().unwrap().clone();
+        Some(item).filter(|item| item.is_live(expiry::now()))
+    }
+
+    // `set_expiry` rewrites the live `key` to expire at `expire_at`, None makes it never
+    // expire.
+    fn set_expiry(&self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool> {
+        self.write(|writer| match self.live(&key) {
+            Some(item) if expire_at.is_some() || item.expire_at.is_some() => {
+                self.put(writer, key, item.value, expire_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+}
+
+impl Shared {
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    // `put` writes `key` into `space` in place, or inserts it if it does not exist.
+    fn put(
+        &self,
+        writer: &mut Writer,
+        space: &Arc<Space>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    ) {
+        writer.seq += 1;
+        writer.size += (key.len() + value.len()) as u64;
+        let tick = self.tick();
+        let item = Item {
+            value,
+            expire_at,
+            seq: writer.seq,
+        };
+        let entry = match space.map.get(&key) {
+            Some(entry) => {
+                let mut current = entry.value().item.write().unwrap();
+                writer.size -= (key.len() + current.value.len()) as u64;
+                *current = item;
+                drop(current);
+                entry
+            }
+            None => space.map.insert(
+                key.clone(),
+                Entry {
+                    item: RwLock::new(item),
+                    used: AtomicU64::new(tick),
+                    queued: AtomicU64::new(tick),
+                },
+            ),
+        };
+        if self.capacity.is_some() {
+            // a key has a single record in the queue, the one of its last write.
+            let queued = entry.value().queued.swap(tick, Ordering::AcqRel);
+            writer.queue.remove(&queued);
+            entry.value().used.store(tick, Ordering::Release);
+            writer.queue.insert(tick, (space.clone(), key));
+        }
+    }
+
+    // `delete` removes `key` from `space`, returns its item even if it is expired.
+    fn delete(&self, writer: &mut Writer, space: &Space, key: &[u8]) -> Option<Item> {
+        let entry = space.map.remove(key)?;
+        let item = entry.value().item.read().unwrap().clone();
+        writer.size -= (key.len() + item.value.len()) as u64;
+        if self.capacity.is_some() {
+            writer
+                .queue
+                .remove(&entry.value().queued.load(Ordering::Acquire));
+        }
+        Some(item)
+    }
+
+    // `evict` removes the least recently used keys until the size is within capacity.
+    // Every key has a single record in the queue, and a key read since it is queued is
+    // queued again at the tick of the read, so the first record in the queue whose key
+    // is not used since is the least recently used one.
+    fn evict(&self, writer: &mut Writer) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while writer.size > capacity {
+            let tick = match writer.queue.keys().next() {
+                Some(&tick) => tick,
+                None => return,
+            };
+            let (space, key) = writer.queue.remove(&tick).unwrap();
+            let entry = match space.map.get(&key) {
+                Some(entry) if entry.value().queued.load(Ordering::Acquire) == tick => entry,
+                _ => continue,
+            };
+            let used = entry.value().used.load(Ordering::Acquire);
+            if used > tick {
+                entry.value().queued.store(used, Ordering::Release);
+                writer.queue.insert(used, (space.clone(), key));
+                continue;
+            }
+            drop(entry);
+            self.delete(writer, &space, &key);
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|writer| {
+            self.put(writer, key, value, None);
+            Ok(())
+        })
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(item) = self.load(&key) {
+            return Ok(Some(item.value));
+        }
+        // drop the key if it is expired, unless it is written meanwhile.
+        if self.space.map.contains_key(&key) {
+            self.write(|writer| {
+                if self.live(&key).is_none() {
+                    self.shared.delete(writer, &self.space, &key);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(None)
+    }
+
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self.live(&key).map(|item| item.seq))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(
+            |writer| match self.shared.delete(writer, &self.space, &key) {
+                Some(item) if item.is_live(expiry::now()) => Ok(()),
+                _ => Err(KvsError::KeyNotFoundError),
+            },
+        )
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(collect_pairs(self.space.map.range(range), limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self
+            .space
+            .map
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix));
+        Ok(collect_pairs(entries, limit))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| {
+            for op in batch {
+                match op {
+                    BatchOp::Set { key, value } => self.put(writer, key, value, None),
+                    BatchOp::Remove { key } => {
+                        self.shared.delete(writer, &self.space, &key);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(|writer| {
+            if self.live(&key).map(|item| item.value) != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => self.put(writer, key, value, None),
+                None => {
+                    self.shared.delete(writer, &self.space, &key);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|writer| {
+            self.put(writer, key, value, Some(expiry::expire_at(ttl)));
+            Ok(())
+        })
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.set_expiry(key, Some(expiry::expire_at(ttl)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let item = self.load(&key).ok_or(KvsError::KeyNotFoundError)?;
+        Ok(item
+            .expire_at
+            .map(|expire_at| expiry::time_left(expire_at, expiry::now())))
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.set_expiry(key, None)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>,
+    {
+        let mut txn = MemoryTransaction {
+            engine: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let ret = f(&mut txn)?;
+        let MemoryTransaction { reads, writes, .. } = txn;
+        self.write(|writer| {
+            for (key, seq) in reads {
+                if self.live(&key).map(|item| item.seq) != seq {
+                    return Err(KvsError::TransactionConflict);
+                }
+            }
+            for (key, value) in writes {
+                match value {
+                    Some(value) => self.put(writer, key, value, None),
+                    None => {
+                        self.shared.delete(writer, &self.space, &key);
+                    }
+                }
+            }
+            Ok(ret)
+        })
+    }
+
+    fn open_keyspace(&self, name: &str) -> Result<Self> {
+        let space = if name == DEFAULT_KEYSPACE {
+            self.shared.default.clone()
+        } else {
+            check_keyspace(name)?;
+            let mut writer = self.shared.writer.lock().unwrap();
+            writer
+                .keyspaces
+                .entry(name.to_owned())
+                .or_insert_with(|| Arc::new(Space::default()))
+                .clone()
+        };
+        Ok(MemoryKvsEngine {
+            space,
+            shared: self.shared.clone(),
+        })
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<bool> {
+        check_keyspace(name)?;
+        let mut writer = self.shared.writer.lock().unwrap();
+        let space = match writer.keyspaces.remove(name) {
+            Some(space) => space,
+            None => return Ok(false),
+        };
+        for entry in space.map.iter() {
+            self.shared.delete(&mut writer, &space, entry.key());
+        }
+        Ok(true)
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let writer = self.shared.writer.lock().unwrap();
+        let mut names: Vec<String> = writer.keyspaces.keys().cloned().collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // there are no files to open the engine from, the checkpoint is a dump `import`
+    // reads back.
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(CHECKPOINT_DUMP))?;
+        let mut writer = BufWriter::new(file);
+        self.export(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+// collect_pairs collects at most `limit` live pairs of `entries`.
+fn collect_pairs<'a>(
+    entries: impl Iterator<Item = crossbeam_skiplist::map::Entry<'a, Vec<u8>, Entry>>,
+    limit: usize,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let now = expiry::now();
+    entries
+        .filter_map(|entry| {
+            let item = entry.value().item.read().unwrap();
+            Some((entry.key().clone(), item.value.clone())).filter(|_| item.is_live(now))
+        })
+        .take(limit)
+        .collect()
+}
+
+// MemoryTransaction reads through the map and keeps the writes until it commits. The
+// sequence number of the item a key is read from tells whether it changes meanwhile.
+struct MemoryTransaction<'a> {
+    engine: &'a MemoryKvsEngine,
+    // the sequence number of every key read, None if it does not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // the last write of every key written, None for a remove.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction for MemoryTransaction<'a> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let item = self.engine.load(&key);
+        // a key read again keeps the version it is read first with, so a change in
+        // between is a conflict as well.
+        self.reads
+            .entry(key)
+            .or_insert_with(|| item.as_ref().map(|item| item.seq));
+        Ok(item.map(|item| item.value))
+    }
+
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        let seq = self.engine.live(&key).map(|item| item.seq);
+        Ok(*self.reads.entry(key).or_insert(seq))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFoundError);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_one_record_per_key() -> Result<()> {
+        let cache = MemoryKvsEngine::with_capacity(1024 * 1024);
+        let queued = || cache.shared.writer.lock().unwrap().queue.len();
+        for i in 0..1000 {
+            cache.set("key".to_owned(), format!("value{}", i))?;
+        }
+        cache.set("other".to_owned(), "value".to_owned())?;
+        assert_eq!(queued(), 2);
+
+        for _ in 0..1000 {
+            cache.set("gone".to_owned(), "value".to_owned())?;
+            cache.remove("gone".to_owned())?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set("gone", "value");
+        cache.write_batch(batch)?;
+        cache.compare_and_swap("gone".to_owned(), Some("value".to_owned()), None)?;
+        assert_eq!(queued(), 2);
+
+        let space = cache.open_keyspace("space")?;
+        space.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(queued(), 3);
+        cache.drop_keyspace("space")?;
+        assert_eq!(queued(), 2);
+        Ok(())
+    }
+}
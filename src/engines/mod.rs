@@ -1,13 +1,248 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
 
+/// DEFAULT_KEYSPACE names the keyspace an engine is opened with.
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+/// KvsEngine stores keys and values as raw bytes. The methods taking and returning
+/// `String` sit on top of the byte oriented ones, they fail with `KvsError::Utf8Error`
+/// on a key or value that is not valid utf-8.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// `version_bytes` returns the version of `key`, None if it does not exist. Every
+    /// write of the key gives it another version, even one writing the value it has.
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// `scan_bytes` returns at most `limit` pairs with keys in `range`, ordered by key.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// `scan_prefix_bytes` returns at most `limit` pairs with keys starting with
+    /// `prefix`, ordered by key.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// `write_batch` applies all operations of `batch` or none of them, also across a crash.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// `compare_and_swap_bytes` sets `key` to `new` only if its value is `expected`,
+    /// where None stands for a missing key. A `new` of None removes the key. Returns
+    /// whether the swap happened.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+    /// `set_with_ttl_bytes` sets `key` to `value`, which expires once `ttl` has passed.
+    /// Any other write of the key drops the expiry again.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// `expire_bytes` makes `key` expire once `ttl` has passed. Returns false if the key
+    /// does not exist.
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool>;
+    /// `ttl_bytes` returns the time left until `key` expires, None if it never does. It
+    /// fails with `KvsError::KeyNotFoundError` if the key does not exist.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+    /// `persist_bytes` makes `key` never expire. Returns false if the key does not exist
+    /// or has no expiry.
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool>;
+    /// `transaction` runs `f` as an optimistic transaction. Its writes are applied as a
+    /// whole once `f` returns, only if none of the keys it read has changed meanwhile.
+    /// Otherwise it fails with `KvsError::TransactionConflict` and nothing is written.
+    /// An error returned by `f` aborts the transaction. `f` may be called more than once
+    /// if the engine retries a conflict on its own.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>;
+    /// `open_keyspace` opens the keyspace `name` of the store, creating it if it does
+    /// not exist. Its keys are apart from the ones of every other keyspace, and
+    /// `DEFAULT_KEYSPACE` names the keyspace the store is opened with. A name is made
+    /// of ascii letters, digits, `-` and `_`.
+    fn open_keyspace(&self, name: &str) -> Result<Self>;
+    /// `drop_keyspace` removes the keyspace `name` along with all its keys. Returns
+    /// false if it does not exist. The handles of a dropped keyspace must not be used
+    /// any more, the default keyspace can not be dropped.
+    fn drop_keyspace(&self, name: &str) -> Result<bool>;
+    /// `keyspaces` returns the names of the keyspaces next to the default one, ordered
+    /// by name.
+    fn keyspaces(&self) -> Result<Vec<String>>;
+    /// `checkpoint` writes a copy of the store, every keyspace included, into `dir`
+    /// while it goes on serving. The copy is opened like any store of the engine, `dir`
+    /// must not hold a store already.
+    fn checkpoint(&self, dir: &Path) -> Result<()>;
+
+    /// `export` writes every key of the store, every keyspace included, into `writer`
+    /// along with its value and time to live, in a format `import` of any engine reads.
+    /// Writes going on meanwhile may or may not make it into the dump. Returns the
+    /// number of keys written.
+    fn export(&self, writer: &mut dyn Write) -> Result<u64> {
+        dump::export(self, writer)
+    }
+
+    /// `import` sets the keys of the dump in `reader`, written by `export`, creating the
+    /// keyspaces it names. Returns the number of keys read.
+    fn import(&self, reader: &mut dyn Read) -> Result<u64> {
+        dump::import(self, reader)
+    }
+
+    /// `set_if_absent_bytes` sets `key` only if it does not exist. Returns whether it
+    /// is set.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// `set_if_present_bytes` sets `key` only if it exists. Returns whether it is set.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        loop {
+            let current = match self.get_bytes(key.clone())? {
+                Some(current) => current,
+                None => return Ok(false),
+            };
+            // the value may change between the get and the swap, then look again.
+            if self.compare_and_swap_bytes(key.clone(), Some(current), Some(value.clone()))? {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(into_string)
+            .transpose()
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// `scan` returns at most `limit` pairs with keys in `range`, ordered by key.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            to_bytes_bound(range.start_bound()),
+            to_bytes_bound(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+
+    /// `scan_prefix` returns at most `limit` pairs with keys starting with `prefix`,
+    /// ordered by key.
+    fn scan_prefix(&self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit)?)
+    }
+
+    /// `compare_and_swap` is `compare_and_swap_bytes` for string values.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// `set_if_absent` sets `key` only if it does not exist. Returns whether it is set.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// `set_if_present` sets `key` only if it exists. Returns whether it is set.
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// `set_with_ttl` sets `key` to `value`, which expires once `ttl` has passed.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// `expire` makes `key` expire once `ttl` has passed.
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        self.expire_bytes(key.into_bytes(), ttl)
+    }
+
+    /// `ttl` returns the time left until `key` expires, None if it never does.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// `persist` makes `key` never expire.
+    fn persist(&self, key: String) -> Result<bool> {
+        self.persist_bytes(key.into_bytes())
+    }
+}
+
+// into_string turns a key or value read by the byte oriented methods into a string.
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| KvsError::Utf8Error(e.utf8_error()))
+}
+
+fn into_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+        .collect()
+}
+
+// check_keyspace checks `name` can name a keyspace other than the default one, which
+// also keeps it from reaching out of the directory of the store.
+fn check_keyspace(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || name == DEFAULT_KEYSPACE || !name.chars().all(valid) {
+        return Err(KvsError::InvalidKeyspaceError(name.to_owned()));
+    }
+    Ok(())
+}
+
+fn to_bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
 }
 
-pub use self::kvs::KvStore;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::btree::BTreeKvsEngine;
+pub use self::cache::CacheStats;
+pub use self::kvs::{KvStore, RecoveryMode, Snapshot};
+pub use self::lsm::LsmKvsEngine;
+pub use self::memory::MemoryKvsEngine;
+pub use self::options::{
+    BTreeOptions, CompactionTrigger, Compression, KvStoreOptions, LsmOptions, SyncPolicy,
+};
 pub use self::sled::SledKvsEngine;
+pub use self::transaction::Transaction;
 
+mod batch;
+mod btree;
+mod cache;
+mod commit;
+mod dump;
+mod expiry;
+mod hint;
 mod kvs;
+mod lock;
+mod lsm;
+mod memory;
+mod options;
+mod pager;
+mod record;
+mod segment;
 mod sled;
+mod sstable;
+mod transaction;
@@ -0,0 +1,388 @@
+use super::kvs::RecoveryMode;
+use crate::{KvsError, Result};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_COMPACT_THRESHOLD_BYTES: u64 = 1024 * 1024;
+const DEFAULT_SEGMENT_SIZE_BYTES: u64 = 1024 * 1024;
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_COMPRESS_THRESHOLD_BYTES: usize = 512;
+const DEFAULT_MEMTABLE_SIZE_BYTES: u64 = 4 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE_BYTES: usize = 4 * 1024;
+const DEFAULT_TIER_WIDTH: usize = 4;
+const DEFAULT_CACHE_SIZE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// KvStoreOptions tunes how `KvStore::open_with` opens a store.
+///
+/// ```no_run
+/// # use kvs::{CompactionTrigger, KvStore, KvStoreOptions, SyncPolicy};
+/// let opts = KvStoreOptions::new()
+///     .compaction(CompactionTrigger::DanglingRatio(1.0))
+///     .sync(SyncPolicy::EveryNms(100));
+/// let store = KvStore::open_with("db", opts)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) compaction: CompactionTrigger,
+    pub(crate) segment_size: u64,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) recovery: RecoveryMode,
+    pub(crate) sweep_interval: Duration,
+    pub(crate) compression: Compression,
+    pub(crate) compress_threshold: usize,
+    pub(crate) cache_size: u64,
+    pub(crate) mmap: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction: CompactionTrigger::DanglingBytes(DEFAULT_COMPACT_THRESHOLD_BYTES),
+            segment_size: DEFAULT_SEGMENT_SIZE_BYTES,
+            sync: SyncPolicy::Never,
+            read_only: false,
+            recovery: RecoveryMode::default(),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            compression: Compression::None,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD_BYTES,
+            cache_size: 0,
+            mmap: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// `new` returns the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `compaction` sets when the sealed segments are compacted.
+    pub fn compaction(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction = trigger;
+        self
+    }
+
+    /// `segment_size` sets the size in bytes a segment is sealed at.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes.max(1);
+        self
+    }
+
+    /// `sync` sets when writes are synced to disk.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// `read_only` opens the store without touching the files, writes fail with
+    /// `KvsError::ReadOnlyError`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// `recovery` sets how a broken record found on open is handled.
+    pub fn recovery(mut self, mode: RecoveryMode) -> Self {
+        self.recovery = mode;
+        self
+    }
+
+    /// `sweep_interval` sets how often the expired keys are dropped in the background.
+    /// Until then they are hidden from reads already.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// `compression` sets the codec new values are compressed with. The records
+    /// written with another codec are read as they are, and compaction rewrites them
+    /// with this one.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// `compress_threshold` sets the size in bytes a value is compressed from, a
+    /// shorter one is written as it is.
+    pub fn compress_threshold(mut self, bytes: usize) -> Self {
+        self.compress_threshold = bytes;
+        self
+    }
+
+    /// `cache_size` sets the size in bytes of the cache the values read last are kept
+    /// in. 0, the default, reads every value from disk. Every keyspace has a cache of
+    /// its own.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// `mmap` reads the sealed segments through memory maps rather than with a pread
+    /// per read. The active segment is always read with pread.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+}
+
+/// LsmOptions tunes how `LsmKvsEngine::open_with` opens an engine.
+///
+/// ```no_run
+/// # use kvs::{LsmKvsEngine, LsmOptions};
+/// let opts = LsmOptions::new().memtable_size(64 * 1024 * 1024).tier_width(8);
+/// let engine = LsmKvsEngine::open_with("db", opts)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    pub(crate) memtable_size: u64,
+    pub(crate) block_size: usize,
+    pub(crate) tier_width: usize,
+    pub(crate) sync: SyncPolicy,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE_BYTES,
+            block_size: DEFAULT_BLOCK_SIZE_BYTES,
+            tier_width: DEFAULT_TIER_WIDTH,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// `new` returns the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `memtable_size` sets the size in bytes the memtable is flushed into a table at.
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes.max(1);
+        self
+    }
+
+    /// `block_size` sets the size in bytes a data block of a table is cut at, which is
+    /// what a lookup reads from disk.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes.max(1);
+        self
+    }
+
+    /// `tier_width` sets how many tables a tier gathers before they are merged into a
+    /// table of the next tier.
+    pub fn tier_width(mut self, tables: usize) -> Self {
+        self.tier_width = tables.max(2);
+        self
+    }
+
+    /// `sync` sets when the write ahead log is synced to disk. Unlike `KvStore`, a
+    /// write syncs the log under the writer lock, concurrent writes do not share a sync.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+}
+
+/// BTreeOptions tunes how `BTreeKvsEngine::open_with` opens an engine.
+///
+/// ```no_run
+/// # use kvs::{BTreeKvsEngine, BTreeOptions};
+/// let opts = BTreeOptions::new().cache_size(256 * 1024 * 1024).sync(true);
+/// let engine = BTreeKvsEngine::open_with("db", opts)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    pub(crate) cache_size: u64,
+    pub(crate) sync: bool,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        BTreeOptions {
+            cache_size: DEFAULT_CACHE_SIZE_BYTES,
+            sync: false,
+        }
+    }
+}
+
+impl BTreeOptions {
+    /// `new` returns the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `cache_size` sets the size in bytes of the buffer pool, which keeps the pages
+    /// read last in memory. It is at least a page.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// `sync` makes every commit sync the file before it returns, otherwise it is left
+    /// to the OS.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+/// CompactionTrigger decides when the stale data of the sealed segments is compacted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compact once the stale data grows over the given bytes.
+    DanglingBytes(u64),
+    /// Compact once the stale data grows over the given multiple of the live data.
+    DanglingRatio(f64),
+}
+
+impl CompactionTrigger {
+    pub(crate) fn is_due(&self, dangling: u64, live: u64) -> bool {
+        match *self {
+            CompactionTrigger::DanglingBytes(bytes) => dangling > bytes,
+            CompactionTrigger::DanglingRatio(ratio) => {
+                dangling > 0 && dangling as f64 > live as f64 * ratio
+            }
+        }
+    }
+}
+
+/// Compression is the codec the values of a `KvStore` are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Write the values as they are.
+    None,
+    /// LZ4, which is fast but compresses less.
+    Lz4,
+    /// Zstandard, which compresses better at a higher cost.
+    Zstd,
+}
+
+/// Parses `none`, `lz4` and `zstd`.
+impl FromStr for Compression {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvsError::InvalidOptionError(format!(
+                "invalid compression: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// SyncPolicy decides when writes are synced to disk. Whatever is not synced yet
+/// may be lost on a power failure, but not on a crash of the process.
+///
+/// The options of every engine default to `Never`, and so does `kvs-server` without
+/// `--sync`. A write acknowledged to a client is only sure to survive a power failure
+/// with `EveryWrite`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS.
+    Never,
+    /// Sync before a write returns. Concurrent writes wait for a single sync.
+    EveryWrite,
+    /// Sync the writes of the last given milliseconds in the background.
+    EveryNms(u64),
+    /// Sync once the given bytes are written since the last sync.
+    EveryNBytes(u64),
+}
+
+impl SyncPolicy {
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        match *self {
+            SyncPolicy::EveryNms(ms) => Some(Duration::from_millis(ms.max(1))),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `never`, `every-write`, `every-<N>ms` and `every-<N>bytes`.
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvsError::InvalidOptionError(format!("invalid sync policy: {}", s));
+        match s {
+            "never" => return Ok(SyncPolicy::Never),
+            "every-write" => return Ok(SyncPolicy::EveryWrite),
+            _ => (),
+        }
+        let every = s.strip_prefix("every-").ok_or_else(invalid)?;
+        if let Some(ms) = every.strip_suffix("ms") {
+            return ms.parse().map(SyncPolicy::EveryNms).map_err(|_| invalid());
+        }
+        if let Some(bytes) = every.strip_suffix("bytes") {
+            return bytes
+                .parse()
+                .map(SyncPolicy::EveryNBytes)
+                .map_err(|_| invalid());
+        }
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!(
+            "every-write".parse::<SyncPolicy>().unwrap(),
+            SyncPolicy::EveryWrite
+        );
+        assert_eq!(
+            "every-100ms".parse::<SyncPolicy>().unwrap(),
+            SyncPolicy::EveryNms(100)
+        );
+        assert_eq!(
+            "every-4096bytes".parse::<SyncPolicy>().unwrap(),
+            SyncPolicy::EveryNBytes(4096)
+        );
+        for s in &[
+            "",
+            "always",
+            "every-",
+            "every-ms",
+            "every-10s",
+            "every--1bytes",
+        ] {
+            assert!(s.parse::<SyncPolicy>().is_err(), "{} is accepted", s);
+        }
+    }
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!("lz4".parse::<Compression>().unwrap(), Compression::Lz4);
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        for s in &["", "LZ4", "gzip", "zstd-3"] {
+            assert!(s.parse::<Compression>().is_err(), "{} is accepted", s);
+        }
+    }
+
+    #[test]
+    fn compaction_trigger() {
+        let bytes = CompactionTrigger::DanglingBytes(100);
+        assert!(!bytes.is_due(100, 0));
+        assert!(bytes.is_due(101, 0));
+        let ratio = CompactionTrigger::DanglingRatio(0.5);
+        assert!(!ratio.is_due(0, 0));
+        assert!(ratio.is_due(1, 0));
+        assert!(!ratio.is_due(50, 100));
+        assert!(ratio.is_due(51, 100));
+    }
+}
@@ -0,0 +1,508 @@
+//! # Page file
+//!
+//! The B+tree engine keeps everything in a single file of fixed size pages. Every page
+//! starts with the same header:
+//!
+//! ```text
+//! header:   | crc32 4B | kind 1B | count 2B |
+//! meta:     | header | magic 2B | version 1B | txid 8B | root 8B | pages 8B | free list 8B |
+//!           | next keyspace 4B |
+//! leaf:     | header | (key len 2B | flags 1B | value len 4B | expire at 8B | seq 8B | key |
+//!           | value or first overflow page 8B) * count |
+//! branch:   | header | child 8B | (key len 2B | key | child 8B) * count |
+//! overflow: | header | next 8B | value bytes |
+//! free:     | header | next 8B | page 8B * count |
+//! ```
+//!
+//! Integers are little endian and the crc32 covers the rest of the page. Pages 0 and 1
+//! are the meta pages, written in turns by the commits, and the one with the higher
+//! txid wins on open. A value too large to sit in its leaf goes to a chain of overflow
+//! pages, flag 1 marks it. An expire time of 0 means the key never expires. The free
+//! list is a chain of free pages, 0 ends every chain since page 0 is a meta page.
+
+use crate::{KvsError, Result};
+use positioned_io::{ReadAt, WriteAt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// PAGE_SIZE is the size in bytes of every page of the file.
+pub(crate) const PAGE_SIZE: usize = 4096;
+/// MAX_KEY_LEN is the longest key a leaf takes. It keeps three entries in a page, so a
+/// split always leaves two pages holding their entries.
+pub(crate) const MAX_KEY_LEN: usize = 1024;
+const MAX_INLINE_LEN: usize = 1300;
+const MAGIC: [u8; 2] = [0xb7, 0x42];
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 7;
+const CHAIN_HEADER_LEN: usize = HEADER_LEN + 8;
+const LEAF_ENTRY_HEADER_LEN: usize = 23;
+const KIND_META: u8 = 1;
+const KIND_LEAF: u8 = 2;
+const KIND_BRANCH: u8 = 3;
+const KIND_OVERFLOW: u8 = 4;
+const KIND_FREE: u8 = 5;
+const FLAG_OVERFLOW: u8 = 1;
+// how many pages a checkpoint copies at a time.
+const COPY_PAGES: usize = 64;
+/// OVERFLOW_DATA_LEN is the value bytes an overflow page holds.
+pub(crate) const OVERFLOW_DATA_LEN: usize = PAGE_SIZE - CHAIN_HEADER_LEN;
+/// FREE_IDS_PER_PAGE is the page ids a page of the free list holds.
+pub(crate) const FREE_IDS_PER_PAGE: usize = (PAGE_SIZE - CHAIN_HEADER_LEN) / 8;
+
+pub(crate) type PageId = u64;
+
+/// Meta is what a meta page holds: the state of the tree as of the commit `txid`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Meta {
+    pub txid: u64,
+    pub root: PageId,
+    // the pages of the file in use, free ones included.
+    pub pages: u64,
+    // the first page of the free list, 0 if it is empty.
+    pub free_list: PageId,
+    pub next_keyspace: u32,
+}
+
+/// Value is the value of a leaf entry, in the leaf or in a chain of overflow pages.
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Inline(Vec<u8>),
+    Overflow { page: PageId, len: u32 },
+}
+
+/// Entry is a key of a leaf.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub key: Vec<u8>,
+    pub value: Value,
+    pub expire_at: Option<u64>,
+    // the txid of the commit which wrote the entry.
+    pub seq: u64,
+}
+
+impl Entry {
+    /// `fits_inline` tells whether a value of `len` bytes stays in the leaf next to `key`.
+    pub fn fits_inline(key: &[u8], len: usize) -> bool {
+        LEAF_ENTRY_HEADER_LEN + key.len() + len <= MAX_INLINE_LEN
+    }
+
+    /// `encoded_len` returns the bytes the entry takes in its leaf.
+    pub fn encoded_len(&self) -> usize {
+        LEAF_ENTRY_HEADER_LEN
+            + self.key.len()
+            + match &self.value {
+                Value::Inline(value) => value.len(),
+                Value::Overflow { .. } => 8,
+            }
+    }
+}
+
+/// Node is a page of the tree. In a branch `keys[i]` is the first key under
+/// `children[i + 1]`.
+#[derive(Debug, Clone)]
+pub(crate) enum Node {
+    Leaf(Vec<Entry>),
+    Branch {
+        keys: Vec<Vec<u8>>,
+        children: Vec<PageId>,
+    },
+}
+
+impl Node {
+    /// `encoded_len` returns the bytes the node takes in a page, which may be more than
+    /// a page before it is split.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => {
+                HEADER_LEN + entries.iter().map(Entry::encoded_len).sum::<usize>()
+            }
+            Node::Branch { keys, .. } => {
+                HEADER_LEN + 8 + keys.iter().map(|key| key.len() + 10).sum::<usize>()
+            }
+        }
+    }
+
+    /// `encode` returns the page of the node, which must fit in one.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Node::Leaf(entries) => {
+                header(&mut buf, KIND_LEAF, entries.len());
+                for entry in entries {
+                    buf.extend_from_slice(&(entry.key.len() as u16).to_le_bytes());
+                    let (flags, len) = match &entry.value {
+                        Value::Inline(value) => (0, value.len() as u32),
+                        Value::Overflow { len, .. } => (FLAG_OVERFLOW, *len),
+                    };
+                    buf.push(flags);
+                    buf.extend_from_slice(&len.to_le_bytes());
+                    buf.extend_from_slice(&entry.expire_at.unwrap_or(0).to_le_bytes());
+                    buf.extend_from_slice(&entry.seq.to_le_bytes());
+                    buf.extend_from_slice(&entry.key);
+                    match &entry.value {
+                        Value::Inline(value) => buf.extend_from_slice(value),
+                        Value::Overflow { page, .. } => buf.extend_from_slice(&page.to_le_bytes()),
+                    }
+                }
+            }
+            Node::Branch { keys, children } => {
+                header(&mut buf, KIND_BRANCH, keys.len());
+                buf.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        seal(buf)
+    }
+
+    /// `decode` parses the page `id` read from the file.
+    fn decode(path: &Path, id: PageId, page: &[u8]) -> Result<Self> {
+        let broken = || corrupted(path, id, "broken node");
+        let count = u16_at(page, 5) as usize;
+        let mut pos = HEADER_LEN;
+        match page[4] {
+            KIND_LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    if page.len() < pos + LEAF_ENTRY_HEADER_LEN {
+                        return Err(broken());
+                    }
+                    let key_len = u16_at(page, pos) as usize;
+                    let flags = page[pos + 2];
+                    let len = u32_at(page, pos + 3);
+                    let expire_at = Some(u64_at(page, pos + 7)).filter(|&at| at != 0);
+                    let seq = u64_at(page, pos + 15);
+                    pos += LEAF_ENTRY_HEADER_LEN;
+                    let key = page.get(pos..pos + key_len).ok_or_else(broken)?.to_vec();
+                    pos += key_len;
+                    let value = if flags & FLAG_OVERFLOW != 0 {
+                        page.get(pos..pos + 8).ok_or_else(broken)?;
+                        pos += 8;
+                        Value::Overflow {
+                            page: u64_at(page, pos - 8),
+                            len,
+                        }
+                    } else {
+                        let value = page.get(pos..pos + len as usize).ok_or_else(broken)?;
+                        pos += len as usize;
+                        Value::Inline(value.to_vec())
+                    };
+                    entries.push(Entry {
+                        key,
+                        value,
+                        expire_at,
+                        seq,
+                    });
+                }
+                Ok(Node::Leaf(entries))
+            }
+            KIND_BRANCH => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = vec![u64_at(page, pos)];
+                pos += 8;
+                for _ in 0..count {
+                    if page.len() < pos + 2 {
+                        return Err(broken());
+                    }
+                    let key_len = u16_at(page, pos) as usize;
+                    pos += 2;
+                    keys.push(page.get(pos..pos + key_len).ok_or_else(broken)?.to_vec());
+                    pos += key_len;
+                    page.get(pos..pos + 8).ok_or_else(broken)?;
+                    children.push(u64_at(page, pos));
+                    pos += 8;
+                }
+                Ok(Node::Branch { keys, children })
+            }
+            kind => Err(corrupted(
+                path,
+                id,
+                &format!("page of kind {} is no node", kind),
+            )),
+        }
+    }
+}
+
+impl Meta {
+    /// `encode` returns the meta page.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        header(&mut buf, KIND_META, 0);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&self.txid.to_le_bytes());
+        buf.extend_from_slice(&self.root.to_le_bytes());
+        buf.extend_from_slice(&self.pages.to_le_bytes());
+        buf.extend_from_slice(&self.free_list.to_le_bytes());
+        buf.extend_from_slice(&self.next_keyspace.to_le_bytes());
+        seal(buf)
+    }
+
+    // `decode` returns None for a meta page which is broken or never written.
+    fn decode(page: &[u8]) -> Option<Self> {
+        if !is_sealed(page) || page[4] != KIND_META || page[7..9] != MAGIC || page[9] != VERSION {
+            return None;
+        }
+        Some(Meta {
+            txid: u64_at(page, 10),
+            root: u64_at(page, 18),
+            pages: u64_at(page, 26),
+            free_list: u64_at(page, 34),
+            next_keyspace: u32_at(page, 42),
+        })
+    }
+}
+
+/// `overflow_page` returns an overflow page holding `data`, followed by the page `next`.
+pub(crate) fn overflow_page(next: PageId, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(PAGE_SIZE);
+    header(&mut buf, KIND_OVERFLOW, 0);
+    buf.extend_from_slice(&next.to_le_bytes());
+    buf.extend_from_slice(data);
+    seal(buf)
+}
+
+/// `free_page` returns a page of the free list holding `ids`, followed by the page `next`.
+pub(crate) fn free_page(next: PageId, ids: &[PageId]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(PAGE_SIZE);
+    header(&mut buf, KIND_FREE, ids.len());
+    buf.extend_from_slice(&next.to_le_bytes());
+    for id in ids {
+        buf.extend_from_slice(&id.to_le_bytes());
+    }
+    seal(buf)
+}
+
+/// Pager reads the pages of the file through pread, so readers never wait for each
+/// other. The nodes read are kept in a buffer pool of a fixed number of pages, which
+/// is what bounds the memory of the engine. Writes go through the writer's own handle
+/// of the file.
+pub(crate) struct Pager {
+    path: PathBuf,
+    file: File,
+    cache: Mutex<Cache>,
+}
+
+impl Pager {
+    /// `new` reads `file` at `path`, keeping up to `cache_pages` nodes in memory.
+    pub fn new(path: PathBuf, file: File, cache_pages: usize) -> Self {
+        Pager {
+            path,
+            file,
+            cache: Mutex::new(Cache::new(cache_pages.max(1))),
+        }
+    }
+
+    /// `meta` returns the last commit found in the meta pages.
+    pub fn meta(&self) -> Result<Meta> {
+        let mut meta: Option<Meta> = None;
+        for id in 0..2 {
+            let mut page = vec![0u8; PAGE_SIZE];
+            if self
+                .file
+                .read_exact_at(id * PAGE_SIZE as u64, &mut page)
+                .is_err()
+            {
+                continue;
+            }
+            if let Some(found) = Meta::decode(&page) {
+                if meta.map_or(true, |meta| meta.txid < found.txid) {
+                    meta = Some(found);
+                }
+            }
+        }
+        meta.ok_or_else(|| corrupted(&self.path, 0, "no valid meta page"))
+    }
+
+    /// `node` returns the node in page `id`, from the buffer pool if it is there.
+    pub fn node(&self, id: PageId) -> Result<Arc<Node>> {
+        if let Some(node) = self.cache.lock().unwrap().get(id) {
+            return Ok(node);
+        }
+        let page = self.read(id)?;
+        let node = Arc::new(Node::decode(&self.path, id, &page)?);
+        self.cache.lock().unwrap().insert(id, node.clone());
+        Ok(node)
+    }
+
+    /// `cache` puts the node just written to page `id` into the buffer pool, in place of
+    /// what the page held before.
+    pub fn cache(&self, id: PageId, node: Arc<Node>) {
+        self.cache.lock().unwrap().insert(id, node);
+    }
+
+    /// `read_overflow` returns the next page and the data of the overflow page `id`.
+    /// Overflow pages bypass the buffer pool.
+    pub fn read_overflow(&self, id: PageId) -> Result<(PageId, Vec<u8>)> {
+        let page = self.read_chain(id, KIND_OVERFLOW)?;
+        Ok((u64_at(&page, HEADER_LEN), page[CHAIN_HEADER_LEN..].to_vec()))
+    }
+
+    /// `read_free` returns the next page and the ids held by the free list page `id`.
+    pub fn read_free(&self, id: PageId) -> Result<(PageId, Vec<PageId>)> {
+        let page = self.read_chain(id, KIND_FREE)?;
+        let count = u16_at(&page, 5) as usize;
+        if count > FREE_IDS_PER_PAGE {
+            return Err(corrupted(&self.path, id, "broken free list"));
+        }
+        let ids = (0..count)
+            .map(|i| u64_at(&page, CHAIN_HEADER_LEN + i * 8))
+            .collect();
+        Ok((u64_at(&page, HEADER_LEN), ids))
+    }
+
+    fn read_chain(&self, id: PageId, kind: u8) -> Result<Vec<u8>> {
+        let page = self.read(id)?;
+        if page[4] != kind {
+            return Err(corrupted(&self.path, id, "broken page chain"));
+        }
+        Ok(page)
+    }
+
+    /// `copy` copies the pages `pages` into the same place of `file` as they are, with
+    /// no check, since a free page may be written meanwhile.
+    pub fn copy(&self, file: &mut File, pages: Range<PageId>) -> Result<()> {
+        let mut buf = vec![0u8; COPY_PAGES * PAGE_SIZE];
+        let mut id = pages.start;
+        while id < pages.end {
+            let n = (pages.end - id).min(COPY_PAGES as u64) as usize;
+            let buf = &mut buf[..n * PAGE_SIZE];
+            self.file.read_exact_at(id * PAGE_SIZE as u64, buf)?;
+            file.write_all_at(id * PAGE_SIZE as u64, buf)?;
+            id += n as u64;
+        }
+        Ok(())
+    }
+
+    /// `read` reads page `id` and checks its crc32.
+    pub fn read(&self, id: PageId) -> Result<Vec<u8>> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        // the read_exact_at call pread under the hood.
+        self.file.read_exact_at(id * PAGE_SIZE as u64, &mut page)?;
+        if !is_sealed(&page) {
+            return Err(corrupted(&self.path, id, "checksum mismatch"));
+        }
+        Ok(page)
+    }
+}
+
+/// `write_page` writes `page` as page `id` through the writer's handle of the file.
+pub(crate) fn write_page(file: &mut File, id: PageId, page: &[u8]) -> Result<()> {
+    file.write_all_at(id * PAGE_SIZE as u64, page)?;
+    Ok(())
+}
+
+// Cache keeps the nodes read last and evicts with the clock algorithm: a node read
+// since the hand passed it last is spared once.
+struct Cache {
+    capacity: usize,
+    slots: Vec<Slot>,
+    // page id to its slot.
+    map: HashMap<PageId, usize>,
+    hand: usize,
+}
+
+struct Slot {
+    id: PageId,
+    node: Arc<Node>,
+    referenced: bool,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Cache {
+            capacity,
+            slots: Vec::with_capacity(capacity),
+            map: HashMap::with_capacity(capacity),
+            hand: 0,
+        }
+    }
+
+    fn get(&mut self, id: PageId) -> Option<Arc<Node>> {
+        let slot = &mut self.slots[*self.map.get(&id)?];
+        slot.referenced = true;
+        Some(slot.node.clone())
+    }
+
+    fn insert(&mut self, id: PageId, node: Arc<Node>) {
+        if let Some(&i) = self.map.get(&id) {
+            self.slots[i].node = node;
+            return;
+        }
+        let slot = Slot {
+            id,
+            node,
+            referenced: false,
+        };
+        if self.slots.len() < self.capacity {
+            self.map.insert(id, self.slots.len());
+            self.slots.push(slot);
+            return;
+        }
+        loop {
+            let victim = &mut self.slots[self.hand];
+            if !victim.referenced {
+                break;
+            }
+            victim.referenced = false;
+            self.hand = (self.hand + 1) % self.capacity;
+        }
+        self.map.remove(&self.slots[self.hand].id);
+        self.map.insert(id, self.hand);
+        self.slots[self.hand] = slot;
+        self.hand = (self.hand + 1) % self.capacity;
+    }
+}
+
+fn header(buf: &mut Vec<u8>, kind: u8, count: usize) {
+    // the crc32 is filled in by `seal`.
+    buf.extend_from_slice(&[0u8; 4]);
+    buf.push(kind);
+    buf.extend_from_slice(&(count as u16).to_le_bytes());
+}
+
+// seal pads `buf` to a page and fills in the crc32 of the header.
+fn seal(mut buf: Vec<u8>) -> Vec<u8> {
+    debug_assert!(
+        buf.len() <= PAGE_SIZE,
+        "page overflows by {}",
+        buf.len() - PAGE_SIZE
+    );
+    buf.resize(PAGE_SIZE, 0);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn is_sealed(page: &[u8]) -> bool {
+    crc32fast::hash(&page[4..]) == u32_at(page, 0)
+}
+
+fn corrupted(path: &Path, id: PageId, reason: &str) -> KvsError {
+    KvsError::CorruptedRecordError {
+        offset: id * PAGE_SIZE as u64,
+        reason: format!("{}: {}", path.display(), reason),
+    }
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,874 @@
+//! # Log record format
+//!
+//! Every command is written to the log as a binary record:
+//!
+//! ```text
+//! +-------+---------+------+---------+-----------+-------+-----+-----+-------+
+//! | magic | version | kind | key len | value len | crc32 | seq | key | value |
+//! |  2B   |   1B    |  1B  |   4B    |    4B     |  4B   | 8B  |     |       |
+//! +-------+---------+------+---------+-----------+-------+-----+-----+-------+
+//! ```
+//!
+//! Integers are little endian. The crc32 covers the header fields in front of it
+//! plus the sequence number, key and value bytes, so a torn write or a flipped bit
+//! is detected on a per record basis.
+//!
+//! Every write takes the next sequence number of the store, which grows through the
+//! log. Version 1 records come without it and read as sequence number 0.
+//!
+//! A write batch is framed as a single record of its own kind, with no key and the
+//! records of the batch as the value. Its crc32 covers all of them, so replay applies
+//! a batch as a whole or drops it as a whole. The index points to the records inside
+//! the frame, which are complete records themselves. They carry the sequence number
+//! of their batch.
+//!
+//! A set with an expiry is a record of its own kind, whose value starts with the
+//! expire time in milliseconds since the unix epoch as 8 bytes.
+//!
+//! The value of a set may be compressed. The high 4 bits of the kind name the codec
+//! it is compressed with, 0 for none, and the expire time stays in front of it as it
+//! is. The crc32 covers the compressed bytes, which are checked before they are
+//! decompressed. A value is only compressed if it is long enough and comes out
+//! shorter, so records of the same log may differ in their codec.
+
+use super::options::Compression;
+use crate::{KvsError, Result};
+use positioned_io::ReadAt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 2] = [0xb7, 0x4b];
+const VERSION: u8 = 2;
+const V1: u8 = 1;
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_SET_EX: u8 = 4;
+const KIND_MASK: u8 = 0x0f;
+const CODEC_SHIFT: u8 = 4;
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+const ZSTD_LEVEL: i32 = 3;
+const EXPIRE_AT_LEN: usize = 8;
+const CRC_OFFSET: usize = 12;
+const V1_HEADER_LEN: usize = 16;
+pub(crate) const HEADER_LEN: usize = 24;
+
+/// Command defines command
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Command {
+    Get {
+        #[serde(with = "json_str")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "json_str")]
+        key: Vec<u8>,
+        #[serde(with = "json_str")]
+        value: Vec<u8>,
+        #[serde(default)]
+        expire_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "json_str")]
+        key: Vec<u8>,
+    },
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Get { key } => {
+                write!(f, "get {}", String::from_utf8_lossy(key))?;
+            }
+            Command::Set { key, value, .. } => {
+                let (key, value) = (String::from_utf8_lossy(key), String::from_utf8_lossy(value));
+                write!(f, "set {}:{}", key, value)?;
+            }
+            Command::Remove { key } => {
+                write!(f, "rm {}", String::from_utf8_lossy(key))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// json_str keeps keys and values as strings in the old json log, which only held utf-8.
+mod json_str {
+    use serde::{ser, Deserialize, Deserializer, Serializer};
+    use std::str;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(str::from_utf8(bytes).map_err(ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        Ok(String::deserialize(deserializer)?.into_bytes())
+    }
+}
+
+/// `encode` turns a command written with sequence number `seq` into a single log record.
+pub(crate) fn encode(seq: u64, cmd: &Command) -> Result<Vec<u8>> {
+    encode_compressed(seq, cmd, Compression::None, 0)
+}
+
+/// `encode_compressed` works like `encode`, but compresses a value of at least
+/// `threshold` bytes with `compression`, if that makes it shorter.
+pub(crate) fn encode_compressed(
+    seq: u64,
+    cmd: &Command,
+    compression: Compression,
+    threshold: usize,
+) -> Result<Vec<u8>> {
+    let buf = match cmd {
+        Command::Set {
+            key,
+            value,
+            expire_at,
+        } => {
+            let (codec, compressed) = if value.len() >= threshold {
+                compress(compression, value)?
+            } else {
+                (CODEC_NONE, None)
+            };
+            let value = compressed.as_deref().unwrap_or(value);
+            let kind = codec << CODEC_SHIFT;
+            match expire_at {
+                None => frame(kind | KIND_SET, seq, key, value)?,
+                Some(at) => {
+                    let mut payload = at.to_le_bytes().to_vec();
+                    payload.extend_from_slice(value);
+                    frame(kind | KIND_SET_EX, seq, key, &payload)?
+                }
+            }
+        }
+        Command::Remove { key } => frame(KIND_REMOVE, seq, key, &[])?,
+        Command::Get { .. } => return Err(KvsError::InvalidCommandError),
+    };
+    Ok(buf)
+}
+
+/// `recode` rewrites the whole record read from `offset` with `compression`, as
+/// `encode_compressed` would write it now. Returns None if the record is to be kept
+/// as it is.
+pub(crate) fn recode(
+    offset: u64,
+    buf: &[u8],
+    compression: Compression,
+    threshold: usize,
+) -> Result<Option<Vec<u8>>> {
+    let header = Header::parse(offset, buf)?;
+    let target = codec_of(compression);
+    if header.codec == target || header.kind == KIND_REMOVE {
+        return Ok(None);
+    }
+    let payload = header.value_len as usize
+        - if header.kind == KIND_SET_EX {
+            EXPIRE_AT_LEN
+        } else {
+            0
+        };
+    // a value written as it is because it is too short stays so.
+    if header.codec == CODEC_NONE && payload < threshold {
+        return Ok(None);
+    }
+    let cmd = decode(offset, buf)?;
+    encode_compressed(header.seq, &cmd, compression, threshold).map(Some)
+}
+
+// compress returns the codec `value` is written with, and the compressed value if it
+// is any shorter.
+fn compress(compression: Compression, value: &[u8]) -> Result<(u8, Option<Vec<u8>>)> {
+    let compressed = match compression {
+        Compression::None => return Ok((CODEC_NONE, None)),
+        Compression::Lz4 => lz4_flex::compress_prepend_size(value),
+        Compression::Zstd => zstd::encode_all(value, ZSTD_LEVEL)?,
+    };
+    if compressed.len() >= value.len() {
+        return Ok((CODEC_NONE, None));
+    }
+    Ok((codec_of(compression), Some(compressed)))
+}
+
+// decompress restores a value compressed with `codec` in the record at `offset`.
+fn decompress(offset: u64, codec: u8, value: &[u8]) -> Result<Vec<u8>> {
+    let value = match codec {
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(value).ok(),
+        CODEC_ZSTD => zstd::decode_all(value).ok(),
+        _ => Some(value.to_vec()),
+    };
+    value.ok_or_else(|| corrupted(offset, "broken compressed value"))
+}
+
+fn codec_of(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => CODEC_NONE,
+        Compression::Lz4 => CODEC_LZ4,
+        Compression::Zstd => CODEC_ZSTD,
+    }
+}
+
+/// `encode_batch` frames the records encoded by `encode` with sequence number `seq`
+/// into a single batch record. The record at index `i` starts at `HEADER_LEN` plus the
+/// lengths of the ones before it.
+pub(crate) fn encode_batch(seq: u64, records: &[Vec<u8>]) -> Result<Vec<u8>> {
+    frame(KIND_BATCH, seq, &[], &records.concat())
+}
+
+// frame writes a record, whose key and value lengths have to fit in their 4 bytes.
+fn frame(kind: u8, seq: u64, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    let max = u32::MAX as usize;
+    let key_len = u32::try_from(key.len()).map_err(|_| KvsError::KeyTooLargeError(max))?;
+    let value_len = u32::try_from(value.len()).map_err(|_| KvsError::ValueTooLargeError(max))?;
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(&key_len.to_le_bytes());
+    buf.extend_from_slice(&value_len.to_le_bytes());
+    // reserve the crc field, it is filled once the payload is in place.
+    buf.extend_from_slice(&[0u8; 4]);
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = checksum(&buf);
+    buf[CRC_OFFSET..V1_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    Ok(buf)
+}
+
+/// `decode` parses a whole record read from `offset` of the log. A batch record is
+/// rejected, the records inside it have to be decoded one by one.
+pub(crate) fn decode(offset: u64, buf: &[u8]) -> Result<Command> {
+    let header = Header::parse(offset, buf)?;
+    if header.kind == KIND_BATCH {
+        return Err(corrupted(offset, "unexpected batch record"));
+    }
+    if buf.len() as u64 != header.record_len() {
+        return Err(corrupted(offset, "record length mismatch"));
+    }
+    header.verify(offset, buf)?;
+    header.to_command(offset, &buf[header.len()..])
+}
+
+// decode_batch splits the payload of a verified batch record read from `offset` into
+// its records, along with their position, length and sequence number.
+fn decode_batch(offset: u64, header_len: usize, payload: &[u8]) -> Result<Vec<Item>> {
+    let mut cmds = Vec::new();
+    let mut start = 0;
+    while start < payload.len() {
+        let pos = offset + (header_len + start) as u64;
+        let header = Header::parse(pos, &payload[start..])?;
+        let len = header.record_len();
+        if ((payload.len() - start) as u64) < len {
+            return Err(corrupted(pos, "record runs over its batch"));
+        }
+        let end = start + len as usize;
+        cmds.push((pos, len, header.seq, decode(pos, &payload[start..end])?));
+        start = end;
+    }
+    Ok(cmds)
+}
+
+fn checksum(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..CRC_OFFSET]);
+    hasher.update(&record[V1_HEADER_LEN..]);
+    hasher.finalize()
+}
+
+fn corrupted(offset: u64, reason: &str) -> KvsError {
+    KvsError::CorruptedRecordError {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+struct Header {
+    version: u8,
+    kind: u8,
+    codec: u8,
+    key_len: u32,
+    value_len: u32,
+    crc: u32,
+    seq: u64,
+}
+
+impl Header {
+    // `parse` reads the header at the start of `buf`, whose length depends on the
+    // version of the record.
+    fn parse(offset: u64, buf: &[u8]) -> Result<Self> {
+        if buf.len() < V1_HEADER_LEN {
+            return Err(corrupted(offset, "record shorter than header"));
+        }
+        if buf[0..2] != MAGIC {
+            return Err(corrupted(offset, "bad magic"));
+        }
+        let version = buf[2];
+        if version != VERSION && version != V1 {
+            return Err(corrupted(offset, "unknown version"));
+        }
+        if buf.len() < header_len(version) {
+            return Err(corrupted(offset, "record shorter than header"));
+        }
+        let mut seq = [0u8; 8];
+        if version == VERSION {
+            seq.copy_from_slice(&buf[V1_HEADER_LEN..HEADER_LEN]);
+        }
+        let (kind, codec) = (buf[3] & KIND_MASK, buf[3] >> CODEC_SHIFT);
+        if ![KIND_SET, KIND_REMOVE, KIND_BATCH, KIND_SET_EX].contains(&kind) {
+            return Err(corrupted(offset, "unknown record kind"));
+        }
+        if ![CODEC_NONE, CODEC_LZ4, CODEC_ZSTD].contains(&codec)
+            || (codec != CODEC_NONE && kind != KIND_SET && kind != KIND_SET_EX)
+        {
+            return Err(corrupted(offset, "unknown codec"));
+        }
+        let header = Header {
+            version,
+            kind,
+            codec,
+            key_len: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            value_len: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            crc: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            seq: u64::from_le_bytes(seq),
+        };
+        if kind == KIND_SET_EX && (header.value_len as usize) < EXPIRE_AT_LEN {
+            return Err(corrupted(offset, "expiring record without expire time"));
+        }
+        Ok(header)
+    }
+
+    fn len(&self) -> usize {
+        header_len(self.version)
+    }
+
+    fn record_len(&self) -> u64 {
+        self.len() as u64 + self.key_len as u64 + self.value_len as u64
+    }
+
+    fn verify(&self, offset: u64, record: &[u8]) -> Result<()> {
+        if checksum(record) != self.crc {
+            return Err(corrupted(offset, "checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    // `to_command` turns the verified payload of the record at `offset` into its
+    // command, a compressed value comes out decompressed.
+    fn to_command(&self, offset: u64, payload: &[u8]) -> Result<Command> {
+        let (key, value) = payload.split_at(self.key_len as usize);
+        let key = key.to_vec();
+        match self.kind {
+            KIND_SET => Ok(Command::Set {
+                key,
+                value: decompress(offset, self.codec, value)?,
+                expire_at: None,
+            }),
+            KIND_SET_EX => {
+                let (at, value) = value.split_at(EXPIRE_AT_LEN);
+                let mut expire_at = [0u8; EXPIRE_AT_LEN];
+                expire_at.copy_from_slice(at);
+                Ok(Command::Set {
+                    key,
+                    value: decompress(offset, self.codec, value)?,
+                    expire_at: Some(u64::from_le_bytes(expire_at)),
+                })
+            }
+            _ => Ok(Command::Remove { key }),
+        }
+    }
+}
+
+fn header_len(version: u8) -> usize {
+    if version == V1 {
+        V1_HEADER_LEN
+    } else {
+        HEADER_LEN
+    }
+}
+
+/// Item is a record read from the log: its position, its length, its sequence number
+/// and the command in it.
+pub(crate) type Item = (u64, u64, u64, Command);
+
+/// RecordReader iterates the records of a log from the beginning of `reader`.
+/// Each item carries the position, the length and the sequence number of the record
+/// within the log. A batch record yields the records inside it.
+pub(crate) struct RecordReader<R: Read> {
+    reader: R,
+    offset: u64,
+    failed: bool,
+    // records of the last batch not yielded yet.
+    pending: VecDeque<Item>,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(reader: R) -> Self {
+        RecordReader {
+            reader,
+            offset: 0,
+            failed: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<Item>> {
+        if let Some(item) = self.pending.pop_front() {
+            return Ok(Some(item));
+        }
+        let offset = self.offset;
+        let mut header = [0u8; HEADER_LEN];
+        let n = read_full(&mut self.reader, &mut header[..V1_HEADER_LEN])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        // the version tells how long the header is, read the rest of it.
+        if n < V1_HEADER_LEN
+            || (header[2] == VERSION
+                && read_full(&mut self.reader, &mut header[V1_HEADER_LEN..])?
+                    < HEADER_LEN - V1_HEADER_LEN)
+        {
+            return Err(corrupted(offset, "incomplete header"));
+        }
+        let parsed = Header::parse(offset, &header)?;
+        let len = parsed.record_len();
+        // a broken length field must not make us allocate a huge buffer,
+        // so only take what the log really has.
+        let mut record = header[..parsed.len()].to_vec();
+        (&mut self.reader)
+            .take(len - parsed.len() as u64)
+            .read_to_end(&mut record)?;
+        if (record.len() as u64) < len {
+            return Err(corrupted(offset, "incomplete payload"));
+        }
+        parsed.verify(offset, &record)?;
+        if parsed.kind == KIND_BATCH {
+            // a batch is dropped as a whole, so is one with a broken record inside.
+            self.pending = decode_batch(offset, parsed.len(), &record[parsed.len()..])
+                .map_err(|e| corrupted(offset, &format!("broken batch: {}", e)))?
+                .into();
+            self.offset += len;
+            // an empty batch is never written, but it is no reason to stop either.
+            return self.read_record();
+        }
+        let cmd = parsed.to_command(offset, &record[parsed.len()..])?;
+        self.offset += len;
+        Ok(Some((offset, len, parsed.seq, cmd)))
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let ret = self.read_record();
+        if ret.is_err() {
+            self.failed = true;
+        }
+        ret.transpose()
+    }
+}
+
+// read_full reads until `buf` is full or the reader hits eof, and returns the bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(l) => n += l,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// `is_trailing` tells whether the broken record at `offset` is the last one in the
+/// log, i.e. it is what a crash in the middle of an append leaves behind. That is
+/// the case if no valid record follows it. A broken length field may make a record
+/// in the middle of the log look as if it ran to the end of the file, so the rest of
+/// the file is searched for a record whose magic and checksum hold.
+pub(crate) fn is_trailing(file: &File, offset: u64, file_len: u64) -> Result<bool> {
+    let mut rest = vec![0u8; (file_len - offset) as usize];
+    file.read_exact_at(offset, &mut rest)?;
+    // the records inside a torn batch are complete records of their own, they carry
+    // the sequence number of the batch though.
+    let batch_seq = Header::parse(offset, &rest)
+        .ok()
+        .filter(|header| header.kind == KIND_BATCH)
+        .map(|header| header.seq);
+    Ok(!(1..rest.len()).any(|pos| {
+        let buf = &rest[pos..];
+        let header = match Header::parse(offset + pos as u64, buf) {
+            Ok(header) => header,
+            Err(_) => return false,
+        };
+        let len = header.record_len();
+        (buf.len() as u64) >= len
+            && Some(header.seq) != batch_seq
+            && header.verify(0, &buf[..len as usize]).is_ok()
+    }))
+}
+
+/// `migrate_json_log` rewrites a log written by the old `serde_json` format into
+/// the binary record format. The new log is built next to the old one and
+/// renamed over it, so a crash during migration leaves the old log untouched.
+/// Returns false if the log is already in the binary format.
+pub(crate) fn migrate_json_log(path: &Path) -> Result<bool> {
+    if !is_json_log(path)? {
+        return Ok(false);
+    }
+    let tmp_path = path.with_extension("migrate");
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?,
+    );
+    let reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    for cmd in serde_json::Deserializer::from_reader(reader).into_iter::<Command>() {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // a torn tail of the old log, nothing after it can be read anyway.
+            Err(e) if e.is_eof() => {
+                warn!("drop torn json record of {}: {}", path.display(), e);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Command::Get { .. } = cmd {
+            continue;
+        }
+        // the old log has no sequence numbers, the records are numbered in order.
+        count += 1;
+        writer.write_all(&encode(count, &cmd)?)?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    info!(
+        "migrated {} records of {} to binary format",
+        count,
+        path.display()
+    );
+    Ok(true)
+}
+
+// the old log is a stream of json objects, which always starts with `{`.
+fn is_json_log(path: &Path) -> Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mut first = [0u8; 1];
+    Ok(read_full(&mut file, &mut first)? == 1 && first[0] == b'{')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            expire_at: None,
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let buf = encode(1, &set("key", "value")).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 8);
+        match decode(0, &buf).unwrap() {
+            Command::Set {
+                key,
+                value,
+                expire_at: None,
+            } => {
+                assert_eq!(key, b"key");
+                assert_eq!(value, b"value");
+            }
+            cmd => panic!("wrong command {}", cmd),
+        }
+        let buf = encode(
+            1,
+            &Command::Set {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+                expire_at: Some(1_600_000_000_000),
+            },
+        )
+        .unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 16);
+        assert!(matches!(
+            decode(0, &buf).unwrap(),
+            Command::Set {
+                expire_at: Some(1_600_000_000_000),
+                ..
+            }
+        ));
+        let buf = encode(
+            1,
+            &Command::Set {
+                key: vec![0xff, 0x00],
+                value: b"\r\n\xfe".to_vec(),
+                expire_at: None,
+            },
+        )
+        .unwrap();
+        match decode(0, &buf).unwrap() {
+            Command::Set { key, value, .. } => {
+                assert_eq!(key, [0xff, 0x00]);
+                assert_eq!(value, b"\r\n\xfe");
+            }
+            cmd => panic!("wrong command {}", cmd),
+        }
+        let buf = encode(
+            1,
+            &Command::Remove {
+                key: b"key".to_vec(),
+            },
+        )
+        .unwrap();
+        assert!(matches!(decode(0, &buf).unwrap(), Command::Remove { .. }));
+        assert!(encode(
+            1,
+            &Command::Get {
+                key: b"key".to_vec()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detect_flipped_bit() {
+        let buf = encode(1, &set("key", "value")).unwrap();
+        for i in 0..buf.len() {
+            let mut broken = buf.clone();
+            broken[i] ^= 0x01;
+            assert!(decode(7, &broken).is_err(), "flip at {} not detected", i);
+        }
+    }
+
+    #[test]
+    fn compressed_record() {
+        let value = "value".repeat(100);
+        let plain = encode(1, &set("key", &value)).unwrap();
+        for &compression in &[Compression::Lz4, Compression::Zstd] {
+            let buf = encode_compressed(1, &set("key", &value), compression, 64).unwrap();
+            assert!(buf.len() < plain.len());
+            assert_eq!(buf[3] >> CODEC_SHIFT, codec_of(compression));
+            match decode(0, &buf).unwrap() {
+                Command::Set { value: read, .. } => assert_eq!(read, value.as_bytes()),
+                cmd => panic!("wrong command {}", cmd),
+            }
+            let mut broken = buf.clone();
+            let last = broken.len() - 1;
+            broken[last] ^= 0x01;
+            assert!(decode(0, &broken).is_err());
+
+            // the expire time stays in front of the compressed value.
+            let cmd = Command::Set {
+                key: b"key".to_vec(),
+                value: value.as_bytes().to_vec(),
+                expire_at: Some(1_600_000_000_000),
+            };
+            let buf = encode_compressed(1, &cmd, compression, 64).unwrap();
+            match decode(0, &buf).unwrap() {
+                Command::Set {
+                    value: read,
+                    expire_at: Some(1_600_000_000_000),
+                    ..
+                } => assert_eq!(read, value.as_bytes()),
+                cmd => panic!("wrong command {}", cmd),
+            }
+
+            // a short value is written as it is.
+            let buf = encode_compressed(1, &set("key", &value), compression, 1024).unwrap();
+            assert_eq!(buf, plain);
+            let buf = encode_compressed(1, &set("key", "v"), compression, 0).unwrap();
+            assert_eq!(buf[3] >> CODEC_SHIFT, CODEC_NONE);
+        }
+    }
+
+    #[test]
+    fn recode_record() {
+        let value = "value".repeat(100);
+        let lz4 = encode_compressed(7, &set("key", &value), Compression::Lz4, 64).unwrap();
+        assert!(recode(0, &lz4, Compression::Lz4, 64).unwrap().is_none());
+        let zstd = recode(0, &lz4, Compression::Zstd, 64).unwrap().unwrap();
+        assert_eq!(zstd[3] >> CODEC_SHIFT, CODEC_ZSTD);
+        let plain = recode(0, &zstd, Compression::None, 64).unwrap().unwrap();
+        assert_eq!(plain, encode(7, &set("key", &value)).unwrap());
+        assert!(recode(0, &plain, Compression::Lz4, 1024).unwrap().is_none());
+        let remove = encode(
+            7,
+            &Command::Remove {
+                key: b"key".to_vec(),
+            },
+        )
+        .unwrap();
+        assert!(recode(0, &remove, Compression::Zstd, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_reports_offset() {
+        let mut log = encode(1, &set("key1", "value1")).unwrap();
+        let second = log.len() as u64;
+        log.extend(encode(1, &set("key2", "value2")).unwrap());
+        let last = log.len() - 1;
+        log[last] ^= 0xff;
+        let mut reader = RecordReader::new(&log[..]);
+        assert!(matches!(reader.next(), Some(Ok((0, _, _, _)))));
+        match reader.next() {
+            Some(Err(KvsError::CorruptedRecordError { offset, .. })) => {
+                assert_eq!(offset, second)
+            }
+            _ => panic!("corruption not detected"),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn batch_record() {
+        let first = encode(2, &set("key1", "value1")).unwrap();
+        let second = encode(
+            2,
+            &Command::Remove {
+                key: b"key2".to_vec(),
+            },
+        )
+        .unwrap();
+        let mut log = encode(1, &set("key0", "value0")).unwrap();
+        let offset = log.len();
+        let batch = encode_batch(2, &[first.clone(), second.clone()]).unwrap();
+        assert_eq!(batch.len(), HEADER_LEN + first.len() + second.len());
+        log.extend_from_slice(&batch);
+
+        let items: Vec<_> = RecordReader::new(&log[..]).collect::<Result<_>>().unwrap();
+        assert_eq!(items.len(), 3);
+        let pos = (offset + HEADER_LEN) as u64;
+        assert_eq!((items[1].0, items[1].1), (pos, first.len() as u64));
+        assert!(matches!(items[2].3, Command::Remove { .. }));
+        let seqs: Vec<_> = items.iter().map(|item| item.2).collect();
+        assert_eq!(seqs, [1, 2, 2]);
+        // the index reads the records inside the batch on their own.
+        let start = pos as usize + first.len();
+        assert!(decode(0, &log[start..start + second.len()]).is_ok());
+        assert!(decode(0, &batch).is_err());
+
+        // a torn batch is dropped as a whole.
+        log.pop();
+        let mut reader = RecordReader::new(&log[..]);
+        assert!(matches!(reader.next(), Some(Ok((0, _, _, _)))));
+        match reader.next() {
+            Some(Err(KvsError::CorruptedRecordError { offset: at, .. })) => {
+                assert_eq!(at, offset as u64)
+            }
+            _ => panic!("torn batch not detected"),
+        }
+    }
+
+    #[test]
+    fn read_v1_record() {
+        // a version 1 record is the same record without the sequence number.
+        let mut v1 = encode(7, &set("key1", "value1")).unwrap();
+        v1.drain(V1_HEADER_LEN..HEADER_LEN);
+        v1[2] = V1;
+        let crc = checksum(&v1);
+        v1[CRC_OFFSET..V1_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(decode(0, &v1).unwrap(), Command::Set { .. }));
+
+        let mut log = v1.clone();
+        log.extend(encode(8, &set("key2", "value2")).unwrap());
+        let items: Vec<_> = RecordReader::new(&log[..]).collect::<Result<_>>().unwrap();
+        assert_eq!((items[0].0, items[0].2), (0, 0));
+        assert_eq!((items[1].0, items[1].2), (v1.len() as u64, 8));
+    }
+
+    #[test]
+    fn trailing_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        let first = encode(1, &set("key1", "value1")).unwrap();
+        let second = encode(1, &set("key2", "value2")).unwrap();
+        let offset = first.len() as u64;
+
+        let mut log = first.clone();
+        log.extend_from_slice(&second[..second.len() - 1]);
+        fs::write(&path, &log).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(is_trailing(&file, offset, log.len() as u64).unwrap());
+
+        let mut log = first.clone();
+        log.extend_from_slice(&[0u8; 64]);
+        fs::write(&path, &log).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(is_trailing(&file, offset, log.len() as u64).unwrap());
+
+        let mut log = first.clone();
+        let mut broken = second.clone();
+        broken[0] ^= 0xff;
+        log.extend_from_slice(&broken);
+        log.extend_from_slice(&second);
+        fs::write(&path, &log).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(!is_trailing(&file, offset, log.len() as u64).unwrap());
+
+        // a flipped bit in the length field makes the record run past the end.
+        let mut log = first.clone();
+        let mut broken = first.clone();
+        broken[11] ^= 0x80;
+        log.extend_from_slice(&broken);
+        log.extend_from_slice(&second);
+        fs::write(&path, &log).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(!is_trailing(&file, offset, log.len() as u64).unwrap());
+
+        let inner = encode(2, &set("key2", "value2")).unwrap();
+        let batch = encode_batch(2, &[inner.clone(), inner]).unwrap();
+        let mut log = first.clone();
+        log.extend_from_slice(&batch[..batch.len() - 1]);
+        fs::write(&path, &log).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(is_trailing(&file, offset, log.len() as u64).unwrap());
+    }
+
+    #[test]
+    fn migrate_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        let mut json = Vec::new();
+        serde_json::to_writer(&mut json, &set("key1", "value1")).unwrap();
+        serde_json::to_writer(
+            &mut json,
+            &Command::Remove {
+                key: b"key1".to_vec(),
+            },
+        )
+        .unwrap();
+        fs::write(&path, json).unwrap();
+
+        assert!(migrate_json_log(&path).unwrap());
+        assert!(!migrate_json_log(&path).unwrap());
+        let cmds: Vec<_> = RecordReader::new(File::open(&path).unwrap())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(cmds.len(), 2);
+        assert!(matches!(cmds[1].3, Command::Remove { .. }));
+    }
+}
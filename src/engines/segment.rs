@@ -0,0 +1,63 @@
+//! # Segment readers
+//!
+//! A segment is read either with a pread per record, or through a memory map of the
+//! whole file which hands out the records as slices of it without copying them. Only
+//! a sealed segment is mapped, the active one grows past any map taken of it.
+//!
+//! A sealed segment never changes, and a removed one stays mapped until the last
+//! reader holding it is done, so a map never sees the file shrink under it.
+
+use crate::Result;
+use memmap2::Mmap;
+use positioned_io::ReadAt;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Segment reads the records of a segment file.
+pub(crate) enum Segment {
+    Pread(File),
+    Mmap(Mmap),
+}
+
+impl Segment {
+    /// `open` opens the segment in `path`, mapped if `mmap` is true and the segment is
+    /// `sealed`.
+    pub fn open(path: &Path, mmap: bool, sealed: bool) -> Result<Self> {
+        let file = File::open(path)?;
+        // a map of an empty file is refused on some platforms, and saves nothing.
+        if !mmap || !sealed || file.metadata()?.len() == 0 {
+            return Ok(Segment::Pread(file));
+        }
+        // Safety: a sealed segment is never written or truncated again, see above.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Segment::Mmap(map))
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Segment::Mmap(_))
+    }
+
+    /// `read` returns the `len` bytes at `pos`, borrowed from the map if there is one.
+    pub fn read(&self, pos: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Segment::Pread(file) => {
+                let mut buf = vec![0u8; len as usize];
+                file.read_exact_at(pos, &mut buf)?;
+                Ok(Cow::Owned(buf))
+            }
+            Segment::Mmap(map) => {
+                let end = pos.checked_add(len).filter(|end| *end <= map.len() as u64);
+                match end {
+                    Some(end) => Ok(Cow::Borrowed(&map[pos as usize..end as usize])),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "record runs over its segment",
+                    )
+                    .into()),
+                }
+            }
+        }
+    }
+}
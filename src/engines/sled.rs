@@ -1,49 +1,396 @@
-use crate::{KvsEngine, KvsError, Result};
-use std::path::PathBuf;
-use std::{fs, str};
+use super::lock::DirLock;
+use super::transaction::Transaction;
+use super::{check_keyspace, expiry, DEFAULT_KEYSPACE};
+use crate::{BatchOp, KvsEngine, KvsError, Result, WriteBatch};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+// the directory the db is kept in, under the directory given to `open`.
+const SLED_DATA: &str = "sled_data";
+
+// a value with an expiry is stored behind this byte and the time it expires at. A value
+// starting with either byte is stored behind the escape byte, other values are stored
+// as they are. Neither byte ever starts a utf-8 string, so values written before keys
+// and values were bytes read the same.
+const EXPIRING: u8 = 0xff;
+const ESCAPED: u8 = 0xfe;
+const EXPIRE_AT_LEN: usize = 8;
+
+// a value as it is stored, along with the value and the expire time in it.
+type Live = (sled::IVec, Vec<u8>, Option<u64>);
+
+/// SledKvsEngine keeps every keyspace in a tree of its own, the default keyspace is
+/// the default tree of the db.
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    tree: sled::Tree,
+    // the lock of the directory given to `open`, dropped once the db is closed.
+    _lock: Option<Arc<DirLock>>,
 }
 
 impl SledKvsEngine {
     pub fn new(db: sled::Db) -> Self {
-        Self { db }
+        let tree = (*db).clone();
+        Self {
+            db,
+            tree,
+            _lock: None,
+        }
     }
 
+    /// `open` opens the db in `path`, which is locked like the directory of a
+    /// `KvStore`.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let path: PathBuf = path.into();
         fs::create_dir_all(&path)?;
-        let db: sled::Db = sled::open(path.join("sled_data"))?;
-        Ok(SledKvsEngine { db })
+        let lock = DirLock::acquire(&path, true)?;
+        let db: sled::Db = sled::open(path.join(SLED_DATA))?;
+        Ok(SledKvsEngine {
+            _lock: Some(Arc::new(lock)),
+            ..SledKvsEngine::new(db)
+        })
+    }
+
+    /// `destroy` removes the db in `path`, the directory is locked meanwhile.
+    pub fn destroy(path: impl Into<PathBuf>) -> Result<()> {
+        let path: PathBuf = path.into();
+        let _lock = DirLock::acquire(&path, true)?;
+        match fs::remove_dir_all(path.join(SLED_DATA)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // `get_live` reads the stored value of `key` along with the value and expire time
+    // in it. An expired value is removed and read as None.
+    fn get_live(&self, key: &[u8]) -> Result<Option<Live>> {
+        let data = match self.tree.get(key)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        match decode_live(&data, expiry::now()) {
+            Some((value, expire_at)) => {
+                let value = value.to_vec();
+                Ok(Some((data, value, expire_at)))
+            }
+            None => {
+                // a failed swap means the key is written meanwhile, which is fine.
+                let _ = self
+                    .tree
+                    .compare_and_swap(key, Some(data), None::<Vec<u8>>)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // `set_expiry` rewrites the value of `key` to expire at `expire_at`, None makes it
+    // never expire.
+    fn set_expiry(&self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool> {
+        loop {
+            let (data, value, current) = match self.get_live(&key)? {
+                Some(live) => live,
+                None => return Ok(false),
+            };
+            if expire_at.is_none() && current.is_none() {
+                return Ok(false);
+            }
+            let new = encode_value(value, expire_at);
+            // the value may change between the get and the swap, then look again.
+            if self
+                .tree
+                .compare_and_swap(&key, Some(data), Some(new))?
+                .is_ok()
+            {
+                self.db.flush()?;
+                return Ok(true);
+            }
+        }
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.into_bytes())?;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.tree.insert(key, encode_value(value, None))?;
         // flush in every set opt will make the opt too slow.
         self.db.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let value = self.db.get(key)?;
-        let ret = match value {
-            Some(data) => {
-                let s = str::from_utf8(data.as_ref())?;
-                Ok(Some(s.to_string()))
-            }
-            None => Ok(None),
-        };
-        ret
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_live(&key)?.map(|(_, value, _)| value))
+    }
+
+    fn version_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self.get_live(&key)?.map(|(data, _, _)| version_of(&data)))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFoundError)?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let data = self.tree.remove(key)?.ok_or(KvsError::KeyNotFoundError)?;
         // flush in every remove opt will make the opt too slow.
         self.db.flush()?;
+        decode_live(&data, expiry::now()).ok_or(KvsError::KeyNotFoundError)?;
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        collect_pairs(self.tree.range(range), limit)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        collect_pairs(self.tree.scan_prefix(prefix), limit)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => sled_batch.insert(key, encode_value(value, None)),
+                BatchOp::Remove { key } => sled_batch.remove(key),
+            }
+        }
+        self.tree.apply_batch(sled_batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let new = new.map(|value| encode_value(value, None));
+        loop {
+            let (data, current) = match self.get_live(&key)? {
+                Some((data, value, _)) => (Some(data), Some(value)),
+                None => (None, None),
+            };
+            if current != expected {
+                return Ok(false);
+            }
+            // the stored value may carry an expiry, so swap it as it is.
+            if self.tree.compare_and_swap(&key, data, new.clone())?.is_ok() {
+                self.db.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = expiry::expire_at(ttl);
+        self.tree
+            .insert(key, encode_value(value, Some(expire_at)))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.set_expiry(key, Some(expiry::expire_at(ttl)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = expiry::now();
+        match self.get_live(&key)? {
+            Some((_, _, expire_at)) => Ok(expire_at.map(|at| expiry::time_left(at, now))),
+            None => Err(KvsError::KeyNotFoundError),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.set_expiry(key, None)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T>,
+    {
+        let ret = self.tree.transaction(|tree| {
+            let mut txn = SledTransaction { tree, failed: None };
+            f(&mut txn).map_err(|e| match txn.failed.take() {
+                // sled runs `f` again on a conflict, so the error it caused is no abort.
+                Some(failed) => failed.into(),
+                None => ConflictableTransactionError::Abort(e),
+            })
+        });
+        let ret = match ret {
+            Ok(ret) => ret,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+        self.db.flush()?;
+        Ok(ret)
+    }
+
+    fn open_keyspace(&self, name: &str) -> Result<Self> {
+        let tree = if name == DEFAULT_KEYSPACE {
+            (*self.db).clone()
+        } else {
+            check_keyspace(name)?;
+            self.db.open_tree(name)?
+        };
+        let db = self.db.clone();
+        let _lock = self._lock.clone();
+        Ok(Self { db, tree, _lock })
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<bool> {
+        check_keyspace(name)?;
+        Ok(self.db.drop_tree(name)?)
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        // the names of the trees include the one of the default tree.
+        let default = self.db.name();
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            if name != default {
+                names.push(super::into_string(name.to_vec())?);
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let _lock = DirLock::acquire(dir, true)?;
+        let db = sled::open(dir.join(SLED_DATA))?;
+        if db.was_recovered() {
+            let msg = format!("{} holds a store already", dir.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+        // every tree is exported on its own, there is no single point in time sled
+        // could export them at.
+        db.import(self.db.export());
+        db.flush()?;
         Ok(())
     }
 }
+
+// SledTransaction reads and writes through a sled transaction, which finds conflicts
+// on its own.
+struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+    // the error sled failed an operation with, which has to be handed back to it.
+    failed: Option<UnabortableTransactionError>,
+}
+
+impl<'a> SledTransaction<'a> {
+    fn check<T>(&mut self, ret: std::result::Result<T, UnabortableTransactionError>) -> Result<T> {
+        ret.map_err(|e| {
+            let err = match &e {
+                UnabortableTransactionError::Conflict => KvsError::TransactionConflict,
+                UnabortableTransactionError::Storage(e) => KvsError::SledError(e.clone()),
+            };
+            self.failed = Some(e);
+            err
+        })
+    }
+}
+
+impl<'a> Transaction for SledTransaction<'a> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let data = self.check(self.tree.get(key))?;
+        let now = expiry::now();
+        Ok(data.and_then(|data| decode_live(&data, now).map(|(value, _)| value.to_vec())))
+    }
+
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        let data = self.check(self.tree.get(key))?;
+        let now = expiry::now();
+        Ok(data
+            .filter(|data| decode_live(data, now).is_some())
+            .map(|data| version_of(&data)))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.check(self.tree.insert(key, encode_value(value, None)))?;
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let data = self.check(self.tree.remove(key))?;
+        let now = expiry::now();
+        match data {
+            Some(data) if decode_live(&data, now).is_some() => Ok(()),
+            _ => Err(KvsError::KeyNotFoundError),
+        }
+    }
+}
+
+fn encode_value(value: Vec<u8>, expire_at: Option<u64>) -> Vec<u8> {
+    let mut data = match (expire_at, value.first()) {
+        (Some(at), _) => {
+            let mut data = Vec::with_capacity(1 + EXPIRE_AT_LEN + value.len());
+            data.push(EXPIRING);
+            data.extend_from_slice(&at.to_be_bytes());
+            data
+        }
+        (None, Some(&EXPIRING)) | (None, Some(&ESCAPED)) => {
+            let mut data = Vec::with_capacity(1 + value.len());
+            data.push(ESCAPED);
+            data
+        }
+        (None, _) => return value,
+    };
+    data.extend_from_slice(&value);
+    data
+}
+
+// decode_live splits a stored value into the value and the time it expires at. It
+// returns None if the value is expired by `now`.
+// version_of stands in for the version of a key, which sled does not keep. It is a hash
+// of the stored value, so unlike the other engines a write of the value a key already
+// has, with the same expiry, keeps its version.
+fn version_of(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn decode_live(data: &[u8], now: u64) -> Option<(&[u8], Option<u64>)> {
+    let (value, expire_at) = match data.split_first() {
+        Some((&EXPIRING, rest)) if rest.len() >= EXPIRE_AT_LEN => {
+            let (at, value) = rest.split_at(EXPIRE_AT_LEN);
+            let mut expire_at = [0u8; EXPIRE_AT_LEN];
+            expire_at.copy_from_slice(at);
+            (value, Some(u64::from_be_bytes(expire_at)))
+        }
+        Some((&ESCAPED, value)) => (value, None),
+        // anything else has to be a plain value.
+        _ => (data, None),
+    };
+    if expiry::is_expired(expire_at, now) {
+        return None;
+    }
+    Some((value, expire_at))
+}
+
+fn collect_pairs(iter: sled::Iter, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let now = expiry::now();
+    let mut pairs = Vec::new();
+    for item in iter {
+        if pairs.len() >= limit {
+            break;
+        }
+        let (key, data) = item?;
+        if let Some((value, _)) = decode_live(&data, now) {
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+    }
+    Ok(pairs)
+}
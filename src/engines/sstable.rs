@@ -0,0 +1,532 @@
+//! # Sorted string tables
+//!
+//! The LSM engine flushes its memtable into a table `<id>.sst`, which is never changed
+//! once written. It holds a single version of every key, ordered by key:
+//!
+//! ```text
+//! data block:  | entry | entry | ... | crc32 4B |
+//! entry:       | key len 4B | value len 4B | kind 1B | expire at 8B | seq 8B | key | value |
+//! index block: | count 4B | (key len 4B | offset 8B | len 4B | key) * count | crc32 4B |
+//! bloom block: | hashes 1B | bits | crc32 4B |
+//! footer:      | index offset 8B | index len 4B | bloom offset 8B | bloom len 4B |
+//!              | count 8B | max seq 8B | magic 2B | version 1B | crc32 4B |
+//! ```
+//!
+//! Integers are little endian and every crc32 covers the bytes of its block in front of
+//! it. A data block is cut once it grows over the block size, the index holds the last
+//! key of every data block, so a lookup reads a single block. The bloom filter saves
+//! reading even that one for most keys the table does not have. An expire time of 0
+//! means the key never expires, a delete has kind 2 and no value.
+
+use crate::{KvsError, Result};
+use positioned_io::ReadAt;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: [u8; 2] = [0xb7, 0x53];
+// 2 derives the bloom filter bits from another hash than 1 does.
+const VERSION: u8 = 2;
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+const ENTRY_HEADER_LEN: usize = 25;
+const INDEX_ENTRY_HEADER_LEN: usize = 16;
+const FOOTER_LEN: usize = 47;
+const CRC_LEN: usize = 4;
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u8 = 7;
+
+/// Slot is a version of a key, a delete has no value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Slot {
+    pub value: Option<Vec<u8>>,
+    pub expire_at: Option<u64>,
+    pub seq: u64,
+}
+
+/// TableBuilder writes a table from keys added in ascending order.
+pub(crate) struct TableBuilder {
+    file: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    // the last key, offset and length of every data block written.
+    index: Vec<(Vec<u8>, u64, u32)>,
+    offset: u64,
+    hashes: Vec<(u32, u32)>,
+    max_seq: u64,
+}
+
+impl TableBuilder {
+    /// `create` starts a table at `path`, which must not exist.
+    pub fn create(path: &Path, block_size: usize) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        Ok(TableBuilder {
+            file: BufWriter::new(file),
+            block_size,
+            block: Vec::new(),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            offset: 0,
+            hashes: Vec::new(),
+            max_seq: 0,
+        })
+    }
+
+    /// `add` adds a key greater than all keys added before.
+    pub fn add(&mut self, key: &[u8], slot: &Slot) -> Result<()> {
+        let value = slot.value.as_deref().unwrap_or(&[]);
+        let kind = if slot.value.is_some() {
+            KIND_PUT
+        } else {
+            KIND_DELETE
+        };
+        self.block
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.block
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.block.push(kind);
+        self.block
+            .extend_from_slice(&slot.expire_at.unwrap_or(0).to_le_bytes());
+        self.block.extend_from_slice(&slot.seq.to_le_bytes());
+        self.block.extend_from_slice(key);
+        self.block.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.hashes.push(bloom_hashes(key));
+        self.max_seq = self.max_seq.max(slot.seq);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// `len` returns how many keys are added.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let len = self.write_block()?;
+        let key = std::mem::take(&mut self.last_key);
+        self.index.push((key, self.offset - len as u64, len));
+        Ok(())
+    }
+
+    // write_block appends the pending block along with its crc32, returns its length.
+    fn write_block(&mut self) -> Result<u32> {
+        let crc = crc32fast::hash(&self.block);
+        self.block.extend_from_slice(&crc.to_le_bytes());
+        self.file.write_all(&self.block)?;
+        let len = self.block.len() as u32;
+        self.offset += len as u64;
+        self.block.clear();
+        Ok(len)
+    }
+
+    /// `finish` writes the index, the bloom filter and the footer and syncs the table.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let index_offset = self.offset;
+        self.block
+            .extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for (key, offset, len) in &self.index {
+            self.block
+                .extend_from_slice(&(key.len() as u32).to_le_bytes());
+            self.block.extend_from_slice(&offset.to_le_bytes());
+            self.block.extend_from_slice(&len.to_le_bytes());
+            self.block.extend_from_slice(key);
+        }
+        let index_len = self.write_block()?;
+        let bloom_offset = self.offset;
+        self.block = Bloom::build(&self.hashes).encode();
+        let bloom_len = self.write_block()?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&index_offset.to_le_bytes());
+        footer.extend_from_slice(&index_len.to_le_bytes());
+        footer.extend_from_slice(&bloom_offset.to_le_bytes());
+        footer.extend_from_slice(&bloom_len.to_le_bytes());
+        footer.extend_from_slice(&(self.hashes.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&self.max_seq.to_le_bytes());
+        footer.extend_from_slice(&MAGIC);
+        footer.push(VERSION);
+        self.block = footer;
+        self.write_block()?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Table reads a table written by `TableBuilder`. The index and the bloom filter are
+/// kept in memory, the data blocks are read as needed.
+pub(crate) struct Table {
+    path: PathBuf,
+    file: File,
+    // the length of the file, no block runs past it.
+    len: u64,
+    // the last key, offset and length of every data block.
+    index: Vec<(Vec<u8>, u64, u32)>,
+    bloom: Bloom,
+    count: u64,
+    max_seq: u64,
+}
+
+impl Table {
+    /// `open` opens the table at `path` and checks its footer, index and bloom filter.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN as u64 {
+            return Err(corrupted(path, 0, "table too short"));
+        }
+        let footer_offset = len - FOOTER_LEN as u64;
+        let footer = read_block(&file, path, len, footer_offset, FOOTER_LEN as u32)?;
+        if footer[40..42] != MAGIC {
+            return Err(corrupted(path, footer_offset, "not a table"));
+        }
+        if footer[42] != VERSION {
+            let reason = format!("unknown version {}", footer[42]);
+            return Err(corrupted(path, footer_offset, &reason));
+        }
+        let index_offset = u64_at(&footer, 0);
+        let index_len = u32_at(&footer, 8);
+        let bloom_offset = u64_at(&footer, 12);
+        let bloom_len = u32_at(&footer, 20);
+        let count = u64_at(&footer, 24);
+        let max_seq = u64_at(&footer, 32);
+
+        let buf = read_block(&file, path, len, index_offset, index_len)?;
+        let broken = || corrupted(path, index_offset, "broken index");
+        if buf.len() < 4 {
+            return Err(broken());
+        }
+        let mut index = Vec::new();
+        let mut pos = 4;
+        for _ in 0..u32_at(&buf, 0) {
+            if buf.len() < pos + INDEX_ENTRY_HEADER_LEN {
+                return Err(broken());
+            }
+            let key_len = u32_at(&buf, pos) as usize;
+            let offset = u64_at(&buf, pos + 4);
+            let len = u32_at(&buf, pos + 12);
+            pos += INDEX_ENTRY_HEADER_LEN;
+            let key = buf.get(pos..pos + key_len).ok_or_else(broken)?.to_vec();
+            pos += key_len;
+            index.push((key, offset, len));
+        }
+        let buf = read_block(&file, path, len, bloom_offset, bloom_len)?;
+        let bloom =
+            Bloom::decode(&buf).ok_or_else(|| corrupted(path, bloom_offset, "broken bloom"))?;
+        Ok(Table {
+            path: path.to_owned(),
+            file,
+            len,
+            index,
+            bloom,
+            count,
+            max_seq,
+        })
+    }
+
+    /// `count` returns how many keys the table holds.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// `max_seq` returns the highest sequence number in the table.
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    /// `get` returns the version of `key` in the table.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Slot>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.find_block(key) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let entries = self.read_entries(block)?;
+        Ok(entries
+            .into_iter()
+            .find(|(k, _)| k.as_slice() == key)
+            .map(|(_, slot)| slot))
+    }
+
+    /// `iter` iterates the keys of the table from `start` on.
+    pub fn iter(self: &Arc<Self>, start: Bound<Vec<u8>>) -> TableIter {
+        let block = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.find_block(key).unwrap_or_else(|| self.index.len())
+            }
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: self.clone(),
+            block,
+            start,
+            entries: VecDeque::new(),
+        }
+    }
+
+    // find_block returns the first data block that may hold `key`.
+    fn find_block(&self, key: &[u8]) -> Option<usize> {
+        let block = self
+            .index
+            .partition_point(|(last, _, _)| last.as_slice() < key);
+        Some(block).filter(|&block| block < self.index.len())
+    }
+
+    fn read_entries(&self, block: usize) -> Result<Vec<(Vec<u8>, Slot)>> {
+        let (_, offset, len) = &self.index[block];
+        let buf = read_block(&self.file, &self.path, self.len, *offset, *len)?;
+        let broken = || corrupted(&self.path, *offset, "broken block");
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            if buf.len() < pos + ENTRY_HEADER_LEN {
+                return Err(broken());
+            }
+            let key_len = u32_at(&buf, pos) as usize;
+            let value_len = u32_at(&buf, pos + 4) as usize;
+            let kind = buf[pos + 8];
+            let expire_at = Some(u64_at(&buf, pos + 9)).filter(|&at| at != 0);
+            let seq = u64_at(&buf, pos + 17);
+            pos += ENTRY_HEADER_LEN;
+            let key = buf.get(pos..pos + key_len).ok_or_else(broken)?.to_vec();
+            pos += key_len;
+            let value = buf.get(pos..pos + value_len).ok_or_else(broken)?.to_vec();
+            pos += value_len;
+            let value = match kind {
+                KIND_PUT => Some(value),
+                KIND_DELETE => None,
+                _ => return Err(broken()),
+            };
+            entries.push((
+                key,
+                Slot {
+                    value,
+                    expire_at,
+                    seq,
+                },
+            ));
+        }
+        Ok(entries)
+    }
+}
+
+/// TableIter iterates the keys of a table in order, reading a block at a time.
+pub(crate) struct TableIter {
+    table: Arc<Table>,
+    block: usize,
+    // the keys before it are skipped in the first block read.
+    start: Bound<Vec<u8>>,
+    entries: VecDeque<(Vec<u8>, Slot)>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Slot)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            let entries = match self.table.read_entries(self.block) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            };
+            self.block += 1;
+            let start = std::mem::replace(&mut self.start, Bound::Unbounded);
+            self.entries = entries
+                .into_iter()
+                .filter(|(key, _)| match &start {
+                    Bound::Included(start) => key >= start,
+                    Bound::Excluded(start) => key > start,
+                    Bound::Unbounded => true,
+                })
+                .collect();
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+// Bloom is the bloom filter of a table, which tells the keys it surely does not hold.
+struct Bloom {
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    fn build(keys: &[(u32, u32)]) -> Self {
+        let len = (keys.len() * BLOOM_BITS_PER_KEY).max(64) / 8;
+        let mut bloom = Bloom {
+            hashes: BLOOM_HASHES,
+            bits: vec![0u8; len],
+        };
+        for &hash in keys {
+            for bit in bloom.bits_of(hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(bloom_hashes(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // bits_of returns the bits of a key, by double hashing its two hashes.
+    fn bits_of(&self, (h1, h2): (u32, u32)) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64).map(move |i| ((h1 as u64 + i * h2 as u64) % len) as usize)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.hashes];
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (&hashes, bits) = buf.split_first()?;
+        if bits.is_empty() {
+            return None;
+        }
+        Some(Bloom {
+            hashes,
+            bits: bits.to_vec(),
+        })
+    }
+}
+
+// bloom_hashes returns the two hashes the bloom filter bits of `key` derive from, the
+// halves of a single 64 bit hash. They are written to disk, so they must not change
+// with the compiler like the std hasher may. A crc32 does not do here: it is linear,
+// two crc32 of a key with different seeds differ by a value only its length decides,
+// which leaves the bits of a key correlated.
+fn bloom_hashes(key: &[u8]) -> (u32, u32) {
+    // FNV-1a, finished with the mixer of MurmurHash3 for the high bits to depend on
+    // every byte of the key.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    (hash as u32, (hash >> 32) as u32 | 1)
+}
+
+// read_block reads the block of `len` bytes at `offset` of a file of `file_len` bytes,
+// checks its crc32 and returns the bytes in front of it. The offset and the length come
+// from the table itself, a broken one must not make for a huge allocation.
+fn read_block(file: &File, path: &Path, file_len: u64, offset: u64, len: u32) -> Result<Vec<u8>> {
+    let end = offset
+        .checked_add(len as u64)
+        .filter(|&end| end <= file_len);
+    if end.is_none() {
+        return Err(corrupted(path, offset, "block runs past the table"));
+    }
+    let len = len as usize;
+    if len < CRC_LEN {
+        return Err(corrupted(path, offset, "block too short"));
+    }
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(offset, &mut buf)?;
+    let crc = u32_at(&buf, len - CRC_LEN);
+    buf.truncate(len - CRC_LEN);
+    if crc32fast::hash(&buf) != crc {
+        return Err(corrupted(path, offset, "checksum mismatch"));
+    }
+    Ok(buf)
+}
+
+fn corrupted(path: &Path, offset: u64, reason: &str) -> KvsError {
+    KvsError::CorruptedRecordError {
+        offset,
+        reason: format!("{}: {}", path.display(), reason),
+    }
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_false_positive_rate() {
+        let keys: Vec<(u32, u32)> = (0..10_000)
+            .map(|i| bloom_hashes(format!("key{}", i).as_bytes()))
+            .collect();
+        let bloom = Bloom::build(&keys);
+        for i in 0..10_000 {
+            assert!(bloom.may_contain(format!("key{}", i).as_bytes()));
+        }
+        // 10 bits a key and 7 hashes make for a rate of about 0.8%.
+        let probes = 100_000;
+        let false_positives = (0..probes)
+            .filter(|i| bloom.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        let rate = false_positives as f64 / probes as f64;
+        assert!(rate < 0.012, "false positive rate {}", rate);
+    }
+
+    #[test]
+    fn block_past_the_end() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("1.sst");
+        let mut builder = TableBuilder::create(&path, 64)?;
+        let slot = Slot {
+            value: Some(b"value".to_vec()),
+            expire_at: None,
+            seq: 1,
+        };
+        for i in 0..100u32 {
+            builder.add(&i.to_be_bytes(), &slot)?;
+        }
+        builder.finish()?;
+        let table = Table::open(&path)?;
+        assert!(table.get(&7u32.to_be_bytes())?.is_some());
+
+        // an index length of nearly 4 GiB.
+        let mut buf = std::fs::read(&path)?;
+        let footer = buf.len() - FOOTER_LEN;
+        buf[footer + 8..footer + 12].copy_from_slice(&(u32::MAX - 1).to_le_bytes());
+        let crc = crc32fast::hash(&buf[footer..buf.len() - CRC_LEN]);
+        let end = buf.len();
+        buf[end - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&path, &buf)?;
+        match Table::open(&path) {
+            Err(KvsError::CorruptedRecordError { .. }) => Ok(()),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("a broken table is opened"),
+        }
+    }
+}
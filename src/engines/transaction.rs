@@ -0,0 +1,42 @@
+use crate::Result;
+
+/// Transaction is what the closure of `KvsEngine::transaction` reads and writes through.
+/// Reads see the writes made by the transaction before them, the writes reach the engine
+/// only once the closure returns.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("db")?;
+/// // move the value of key1 to key2, unless key1 changes meanwhile.
+/// store.transaction(|txn| {
+///     if let Some(value) = txn.get("key1".to_owned())? {
+///         txn.remove("key1".to_owned())?;
+///         txn.set("key2".to_owned(), value)?;
+///     }
+///     Ok(())
+/// })?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub trait Transaction {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// `version_bytes` returns the version of `key` like `KvsEngine::version_bytes`, and
+    /// counts as a read of the key. The writes of the transaction leave it as it is.
+    fn version_bytes(&mut self, key: Vec<u8>) -> Result<Option<u64>>;
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// `remove_bytes` fails with `KvsError::KeyNotFoundError` if the key does not exist.
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(super::into_string)
+            .transpose()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+}
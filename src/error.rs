@@ -16,6 +16,33 @@ pub enum KvsError {
     RayonError(rayon::ThreadPoolBuildError),
     NixError(nix::Error),
     SerdeRespError(serde_resp::Error),
+    /// CorruptedRecordError reports a log record failing its sanity checks.
+    CorruptedRecordError {
+        offset: u64,
+        reason: String,
+    },
+    /// ReadOnlyError reports a write to a store opened read-only.
+    ReadOnlyError,
+    /// InvalidOptionError reports an option value that can not be parsed.
+    InvalidOptionError(String),
+    /// TransactionConflict reports a transaction whose keys are changed by another
+    /// write before it commits.
+    TransactionConflict,
+    /// InvalidKeyspaceError reports a keyspace name that can not be opened or dropped.
+    InvalidKeyspaceError(String),
+    /// Locked reports a store another process holds the lock of, `pid` is that process
+    /// if it is a writer.
+    Locked {
+        pid: Option<u32>,
+    },
+    /// KeyTooLargeError reports a key longer than the engine takes, which is the given
+    /// bytes.
+    KeyTooLargeError(usize),
+    /// ValueTooLargeError reports a value longer than the engine takes, which is the
+    /// given bytes.
+    ValueTooLargeError(usize),
+    /// InvalidPathError reports a checkpoint directory a client may not write to.
+    InvalidPathError(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -90,6 +117,36 @@ impl Display for KvsError {
             KvsError::SerdeRespError(e) => {
                 write!(f, "Serde resp error: {}", e)
             }
+            KvsError::CorruptedRecordError { offset, reason } => {
+                write!(f, "Corrupted record at offset {}: {}", offset, reason)
+            }
+            KvsError::ReadOnlyError => {
+                write!(f, "Store is read-only")
+            }
+            KvsError::InvalidOptionError(s) => {
+                write!(f, "Invalid option: {}", s)
+            }
+            KvsError::TransactionConflict => {
+                write!(f, "Transaction conflict")
+            }
+            KvsError::InvalidKeyspaceError(s) => {
+                write!(f, "Invalid keyspace: {}", s)
+            }
+            KvsError::Locked { pid: Some(pid) } => {
+                write!(f, "Store is locked by process {}", pid)
+            }
+            KvsError::Locked { pid: None } => {
+                write!(f, "Store is locked by readers")
+            }
+            KvsError::KeyTooLargeError(max) => {
+                write!(f, "Key is longer than {} bytes", max)
+            }
+            KvsError::ValueTooLargeError(max) => {
+                write!(f, "Value is longer than {} bytes", max)
+            }
+            KvsError::InvalidPathError(s) => {
+                write!(f, "Invalid path: {}", s)
+            }
         }
     }
 }
@@ -5,9 +5,14 @@ extern crate log;
 extern crate nom;
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    BTreeKvsEngine, BTreeOptions, BatchOp, CacheStats, CompactionTrigger, Compression, KvStore,
+    KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, MemoryKvsEngine, RecoveryMode,
+    SledKvsEngine, Snapshot, SyncPolicy, Transaction, WriteBatch, DEFAULT_KEYSPACE,
+};
 pub use error::{KvsError, Result};
 pub use proto::{parse_reply, parse_request, Reply, Request};
+pub use serde_resp::Bytes;
 pub use server::KvsServer;
 
 mod client;
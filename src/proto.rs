@@ -1,11 +1,21 @@
+use crate::{Bytes, KvsError, WriteBatch};
 use nom::bytes::complete::tag;
 use nom::bytes::complete::{take, take_while, take_while1};
 use nom::combinator::map_res;
 use nom::error::{Error, ErrorKind};
+use nom::multi::count;
 use nom::sequence::{delimited, terminated, tuple};
 use nom::{Err, IResult};
 use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
 use std::fmt::{self, Display};
+use std::io::BufRead;
+use std::iter;
+use std::ops::Bound;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use std::time::Duration;
 use structopt::StructOpt;
 
 // TODO: impl Serialize and Deserialize for Request
@@ -13,13 +23,97 @@ use structopt::StructOpt;
 #[derive(StructOpt, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Request {
     #[structopt(name = "get")]
-    Get { key: String },
+    Get { key: Bytes },
 
     #[structopt(name = "set")]
-    Set { key: String, value: String },
+    Set { key: Bytes, value: Bytes },
 
     #[structopt(name = "rm")]
-    Remove { key: String },
+    Remove { key: Bytes },
+
+    /// Scan keys from `start` up to `end` excluded, an empty `end` means no upper bound.
+    /// A `limit` of 0 means no limit.
+    #[structopt(name = "scan")]
+    Scan {
+        start: Bytes,
+        end: Bytes,
+        limit: u64,
+    },
+
+    /// Scan keys starting with `prefix`. A `limit` of 0 means no limit.
+    #[structopt(name = "scan-prefix")]
+    ScanPrefix { prefix: Bytes, limit: u64 },
+
+    /// Apply the set and rm requests read from stdin, one per line, as one batch.
+    #[structopt(name = "batch")]
+    Batch {
+        #[structopt(skip)]
+        requests: Vec<Request>,
+    },
+
+    /// Set `key` to `new` only if its value is `expected`. A missing `expected` stands
+    /// for a missing key, a missing `new` removes the key.
+    #[structopt(name = "cas")]
+    CompareAndSwap {
+        key: Bytes,
+        #[structopt(long)]
+        expected: Option<Bytes>,
+        #[structopt(long)]
+        new: Option<Bytes>,
+    },
+
+    /// Set `key` only if it does not exist.
+    #[structopt(name = "setnx")]
+    SetIfAbsent { key: Bytes, value: Bytes },
+
+    /// Set `key` only if it exists.
+    #[structopt(name = "setxx")]
+    SetIfPresent { key: Bytes, value: Bytes },
+
+    /// Set `key` to `value`, which expires after `ex` seconds.
+    #[structopt(name = "setex")]
+    SetEx {
+        key: Bytes,
+        value: Bytes,
+        #[structopt(long)]
+        ex: u64,
+    },
+
+    /// Make `key` expire after `seconds`.
+    #[structopt(name = "expire")]
+    Expire { key: Bytes, seconds: u64 },
+
+    /// Show the seconds left until `key` expires, -1 if it never does.
+    #[structopt(name = "ttl")]
+    Ttl { key: Bytes },
+
+    /// Make `key` never expire.
+    #[structopt(name = "persist")]
+    Persist { key: Bytes },
+
+    /// Watch `keys` for the next batch on the connection, which then fails with a
+    /// transaction conflict if any of them has another value by the time it is applied.
+    /// It is no command of the command line, a watch ends with the connection.
+    #[structopt(skip)]
+    Watch { keys: Vec<Bytes> },
+
+    /// Switch the connection to the keyspace `keyspace`, which is created if it does not
+    /// exist. It is no command of the command line, like `Request::Watch`.
+    #[structopt(skip)]
+    Select { keyspace: Bytes },
+
+    /// Drop the keyspace `keyspace` along with all its keys.
+    #[structopt(name = "drop-keyspace")]
+    DropKeyspace { keyspace: Bytes },
+
+    /// List the keyspaces next to the default one.
+    #[structopt(name = "keyspaces")]
+    Keyspaces {},
+
+    /// Write a checkpoint of the store into `dir` under the checkpoint root of the server.
+    /// The command line has the backup command instead.
+    #[structopt(skip)]
+    Checkpoint { dir: Bytes },
 }
 
 impl Display for Request {
@@ -34,22 +128,249 @@ impl Display for Request {
             Request::Remove { key } => {
                 write!(f, "remove {}", key)?;
             }
+            Request::Scan { start, end, limit } => {
+                write!(f, "scan {}..{} limit {}", start, end, limit)?;
+            }
+            Request::ScanPrefix { prefix, limit } => {
+                write!(f, "scan prefix {} limit {}", prefix, limit)?;
+            }
+            Request::Batch { requests } => {
+                write!(f, "batch of {} requests", requests.len())?;
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                write!(f, "cas {}:{:?}->{:?}", key, expected, new)?;
+            }
+            Request::SetIfAbsent { key, value } => {
+                write!(f, "setnx {}:{}", key, value)?;
+            }
+            Request::SetIfPresent { key, value } => {
+                write!(f, "setxx {}:{}", key, value)?;
+            }
+            Request::SetEx { key, value, ex } => {
+                write!(f, "set {}:{} ex {}", key, value, ex)?;
+            }
+            Request::Expire { key, seconds } => {
+                write!(f, "expire {} {}", key, seconds)?;
+            }
+            Request::Ttl { key } => {
+                write!(f, "ttl {}", key)?;
+            }
+            Request::Persist { key } => {
+                write!(f, "persist {}", key)?;
+            }
+            Request::Watch { keys } => {
+                write!(f, "watch {} keys", keys.len())?;
+            }
+            Request::Select { keyspace } => {
+                write!(f, "select {}", keyspace)?;
+            }
+            Request::DropKeyspace { keyspace } => {
+                write!(f, "drop keyspace {}", keyspace)?;
+            }
+            Request::Keyspaces {} => {
+                write!(f, "keyspaces")?;
+            }
+            Request::Checkpoint { dir } => {
+                write!(f, "checkpoint {}", dir)?;
+            }
         }
         Ok(())
     }
 }
 
 impl Request {
-    // simply format using REdis Serialization Protocol
-    pub fn to_resp(&self) -> String {
-        let s = match self {
-            Request::Get { key } => format!("*2\r\n{}\r\n{}\r\n", "GET", key),
+    // simply format using REdis Serialization Protocol, keys and values are written as
+    // bulk strings.
+    pub fn to_resp(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Request::Get { key } => {
+                buf.extend_from_slice(b"*2\r\nGET\r\n");
+                bulk_to_resp(&mut buf, key);
+            }
             Request::Set { key, value } => {
-                format!("*3\r\n{}\r\n{}\r\n{}\r\n", "SET", key, value)
+                buf.extend_from_slice(b"*3\r\nSET\r\n");
+                bulk_to_resp(&mut buf, key);
+                bulk_to_resp(&mut buf, value);
+            }
+            Request::Remove { key } => {
+                buf.extend_from_slice(b"*2\r\nDEL\r\n");
+                bulk_to_resp(&mut buf, key);
+            }
+            Request::Scan { start, end, limit } => {
+                buf.extend_from_slice(b"*4\r\nSCAN\r\n");
+                bulk_to_resp(&mut buf, start);
+                bulk_to_resp(&mut buf, end);
+                buf.extend_from_slice(format!("{}\r\n", limit).as_bytes());
+            }
+            Request::ScanPrefix { prefix, limit } => {
+                buf.extend_from_slice(b"*3\r\nSCANPREFIX\r\n");
+                bulk_to_resp(&mut buf, prefix);
+                buf.extend_from_slice(format!("{}\r\n", limit).as_bytes());
+            }
+            // MULTI carries the number of requests following it up to EXEC.
+            Request::Batch { requests } => {
+                let multi = format!("*2\r\nMULTI\r\n{}\r\n", requests.len());
+                buf.extend_from_slice(multi.as_bytes());
+                for request in requests {
+                    buf.extend_from_slice(&request.to_resp());
+                }
+                buf.extend_from_slice(b"*1\r\nEXEC\r\n");
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                buf.extend_from_slice(b"*4\r\nCAS\r\n");
+                bulk_to_resp(&mut buf, key);
+                option_to_resp(&mut buf, expected);
+                option_to_resp(&mut buf, new);
+            }
+            Request::SetIfAbsent { key, value } => {
+                buf.extend_from_slice(b"*3\r\nSETNX\r\n");
+                bulk_to_resp(&mut buf, key);
+                bulk_to_resp(&mut buf, value);
+            }
+            Request::SetIfPresent { key, value } => {
+                buf.extend_from_slice(b"*3\r\nSETXX\r\n");
+                bulk_to_resp(&mut buf, key);
+                bulk_to_resp(&mut buf, value);
+            }
+            Request::SetEx { key, value, ex } => {
+                buf.extend_from_slice(b"*5\r\nSET\r\n");
+                bulk_to_resp(&mut buf, key);
+                bulk_to_resp(&mut buf, value);
+                buf.extend_from_slice(format!("EX\r\n{}\r\n", ex).as_bytes());
+            }
+            Request::Expire { key, seconds } => {
+                buf.extend_from_slice(b"*3\r\nEXPIRE\r\n");
+                bulk_to_resp(&mut buf, key);
+                buf.extend_from_slice(format!("{}\r\n", seconds).as_bytes());
+            }
+            Request::Ttl { key } => {
+                buf.extend_from_slice(b"*2\r\nTTL\r\n");
+                bulk_to_resp(&mut buf, key);
+            }
+            Request::Persist { key } => {
+                buf.extend_from_slice(b"*2\r\nPERSIST\r\n");
+                bulk_to_resp(&mut buf, key);
+            }
+            // WATCH carries the number of keys in front of them, like MULTI.
+            Request::Watch { keys } => {
+                let watch = format!("*2\r\nWATCH\r\n{}\r\n", keys.len());
+                buf.extend_from_slice(watch.as_bytes());
+                for key in keys {
+                    bulk_to_resp(&mut buf, key);
+                }
+            }
+            Request::Select { keyspace } => {
+                buf.extend_from_slice(b"*2\r\nSELECT\r\n");
+                bulk_to_resp(&mut buf, keyspace);
+            }
+            Request::DropKeyspace { keyspace } => {
+                buf.extend_from_slice(b"*2\r\nDROPKEYSPACE\r\n");
+                bulk_to_resp(&mut buf, keyspace);
+            }
+            Request::Keyspaces {} => {
+                buf.extend_from_slice(b"*1\r\nKEYSPACES\r\n");
             }
-            Request::Remove { key } => format!("*2\r\n{}\r\n{}\r\n", "DEL", key),
+            Request::Checkpoint { dir } => {
+                buf.extend_from_slice(b"*2\r\nCHECKPOINT\r\n");
+                bulk_to_resp(&mut buf, dir);
+            }
+        }
+        buf
+    }
+
+    /// `keyspace` turns the keyspace of a request into the name `KvsEngine::open_keyspace`
+    /// takes.
+    pub fn keyspace(keyspace: Bytes) -> crate::Result<String> {
+        String::from_utf8(keyspace.into_vec()).map_err(|e| KvsError::Utf8Error(e.utf8_error()))
+    }
+
+    /// `checkpoint_dir` turns the directory of a checkpoint request, which need not be
+    /// utf-8, into a path under `root`. A directory that is absolute or climbs out with
+    /// `..` is rejected.
+    pub fn checkpoint_dir(root: &Path, dir: Bytes) -> crate::Result<PathBuf> {
+        let dir = PathBuf::from(OsString::from_vec(dir.into_vec()));
+        let inside = dir
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if dir.as_os_str().is_empty() || !inside {
+            return Err(KvsError::InvalidPathError(dir.display().to_string()));
+        }
+        Ok(root.join(dir))
+    }
+
+    /// `scan_range` turns the bounds of a scan request into the range of `KvsEngine::scan`.
+    pub fn scan_range(start: Vec<u8>, end: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let end = if end.is_empty() {
+            Bound::Unbounded
+        } else {
+            Bound::Excluded(end)
         };
-        s
+        (Bound::Included(start), end)
+    }
+
+    /// `scan_limit` turns the limit of a scan request into the one of `KvsEngine::scan`.
+    pub fn scan_limit(limit: u64) -> usize {
+        if limit == 0 {
+            usize::MAX
+        } else {
+            limit as usize
+        }
+    }
+
+    /// `read_batch` reads the requests of a batch request from `reader`, one per line in
+    /// the syntax of the command line, e.g. `set key value` or `rm key`.
+    pub fn read_batch(reader: impl BufRead) -> crate::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let args = iter::once("batch").chain(line.split_whitespace());
+            match Request::from_iter_safe(args) {
+                Ok(request) => requests.push(request),
+                Err(e) => {
+                    error!("invalid batch request {:?}: {}", line, e.message);
+                    return Err(KvsError::InvalidCommandError);
+                }
+            }
+        }
+        Ok(requests)
+    }
+
+    /// `into_batch` turns the requests of a batch request into the batch of
+    /// `KvsEngine::write_batch`. Only set and remove requests may be batched.
+    pub fn into_batch(requests: Vec<Request>) -> crate::Result<WriteBatch> {
+        let mut batch = WriteBatch::new();
+        for request in requests {
+            match request {
+                Request::Set { key, value } => batch.set(key, value),
+                Request::Remove { key } => batch.remove(key),
+                _ => return Err(KvsError::InvalidCommandError),
+            }
+        }
+        Ok(batch)
+    }
+}
+
+// bulk_to_resp formats a key or value as a bulk string, whose length in front of it
+// lets it hold any byte.
+fn bulk_to_resp(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    buf.extend_from_slice(bytes);
+    buf.extend_from_slice(b"\r\n");
+}
+
+// option_to_resp formats an optional argument as an array of no or a single element,
+// which tells a missing value from an empty one.
+fn option_to_resp(buf: &mut Vec<u8>, value: &Option<Bytes>) {
+    match value {
+        Some(value) => {
+            buf.extend_from_slice(b"1\r\n");
+            bulk_to_resp(buf, value);
+        }
+        None => buf.extend_from_slice(b"0\r\n"),
     }
 }
 
@@ -59,6 +380,9 @@ pub enum Reply {
     SingleLine(String),
     Err(String),
     Int(i64),
+    Array(Vec<Reply>),
+    /// Bulk carries a value, which may hold any byte.
+    Bulk(Bytes),
 }
 
 impl Display for Reply {
@@ -73,105 +397,279 @@ impl Display for Reply {
             Reply::Int(s) => {
                 write!(f, "{}", s)?;
             }
+            Reply::Array(replies) => {
+                for (i, reply) in replies.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", reply)?;
+                }
+            }
+            Reply::Bulk(bytes) => {
+                write!(f, "{}", bytes)?;
+            }
         }
         Ok(())
     }
 }
 
 impl Reply {
-    pub fn to_resp(&self) -> String {
-        let s = match self {
-            Reply::SingleLine(data) => format!("+{}\r\n", data),
-            Reply::Err(data) => format!("-{}\r\n", data),
-            Reply::Int(data) => format!(":{}\r\n", data),
-        };
-        s
+    pub fn to_resp(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Reply::SingleLine(data) => buf.extend_from_slice(format!("+{}\r\n", data).as_bytes()),
+            Reply::Err(data) => buf.extend_from_slice(format!("-{}\r\n", data).as_bytes()),
+            Reply::Int(data) => buf.extend_from_slice(format!(":{}\r\n", data).as_bytes()),
+            Reply::Array(replies) => {
+                buf.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    buf.extend_from_slice(&reply.to_resp());
+                }
+            }
+            Reply::Bulk(bytes) => bulk_to_resp(&mut buf, bytes),
+        }
+        buf
     }
     pub fn should_println(&self) -> bool {
         match self {
             Reply::SingleLine(data) => !data.is_empty(),
             Reply::Err(_) => true,
             Reply::Int(_) => true,
+            Reply::Array(replies) => !replies.is_empty(),
+            Reply::Bulk(_) => true,
         }
     }
+
+    // `swapped` turns the result of a conditional write into 1 if the value is written
+    // and 0 if not.
+    pub fn swapped(ret: crate::Result<bool>) -> Reply {
+        match ret {
+            Ok(swapped) => Reply::Int(swapped as i64),
+            Err(e) => Reply::Err(e.to_string()),
+        }
+    }
+
+    // `ttl` turns the result of a ttl request into the seconds left, rounded up, -1 if
+    // the key never expires and -2 if it does not exist.
+    pub fn ttl(ret: crate::Result<Option<Duration>>) -> Reply {
+        match ret {
+            Ok(Some(ttl)) => Reply::Int(((ttl.as_millis() + 999) / 1000) as i64),
+            Ok(None) => Reply::Int(-1),
+            Err(KvsError::KeyNotFoundError) => Reply::Int(-2),
+            Err(e) => Reply::Err(e.to_string()),
+        }
+    }
+
+    // `pairs` turns the pairs returned by a scan into an array reply of keys and values.
+    pub fn pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Reply {
+        let mut replies = Vec::with_capacity(pairs.len() * 2);
+        for (key, value) in pairs {
+            replies.push(Reply::Bulk(key.into()));
+            replies.push(Reply::Bulk(value.into()));
+        }
+        Reply::Array(replies)
+    }
 }
 
 // norm impl
-fn parse_single_line(input: &str) -> IResult<&str, Reply> {
-    let (remain, res) = terminated(take_while(|c| c != '\r' && c != '\n'), tag("\r\n"))(input)?;
+fn parse_single_line(input: &[u8]) -> IResult<&[u8], Reply> {
+    let (remain, res) = terminated(
+        map_res(take_while(|c| c != b'\r' && c != b'\n'), str::from_utf8),
+        tag("\r\n"),
+    )(input)?;
     Ok((remain, Reply::SingleLine(res.to_string())))
 }
 
-fn parse_err(input: &str) -> IResult<&str, Reply> {
-    let (remain, res) = terminated(take_while1(|c| c != '\r' && c != '\n'), tag("\r\n"))(input)?;
+fn parse_err(input: &[u8]) -> IResult<&[u8], Reply> {
+    let (remain, res) = terminated(
+        map_res(take_while1(|c| c != b'\r' && c != b'\n'), str::from_utf8),
+        tag("\r\n"),
+    )(input)?;
     Ok((remain, Reply::Err(res.to_string())))
 }
 
-fn to_i64(input: &str) -> Result<i64, std::num::ParseIntError> {
-    input.parse::<i64>()
+// the number parsers only see the digits taken by the parser in front of them.
+fn to_i64(input: &[u8]) -> Result<i64, std::num::ParseIntError> {
+    String::from_utf8_lossy(input).parse::<i64>()
 }
 
-fn parse_int(input: &str) -> IResult<&str, Reply> {
+fn parse_int(input: &[u8]) -> IResult<&[u8], Reply> {
     let (remain, res) = terminated(
-        map_res(take_while1(|c: char| c.is_digit(10) || c == '-'), to_i64),
+        map_res(take_while1(|c: u8| c.is_ascii_digit() || c == b'-'), to_i64),
         tag("\r\n"),
     )(input)?;
     Ok((remain, Reply::Int(res)))
 }
 
-pub fn parse_reply(input: &str) -> IResult<&str, Reply> {
+fn to_u64(input: &[u8]) -> Result<u64, std::num::ParseIntError> {
+    String::from_utf8_lossy(input).parse::<u64>()
+}
+
+fn to_usize(input: &[u8]) -> Result<usize, std::num::ParseIntError> {
+    String::from_utf8_lossy(input).parse::<usize>()
+}
+
+fn parse_array(input: &[u8]) -> IResult<&[u8], Reply> {
+    let (remain, len) = terminated(
+        map_res(take_while1(|c: u8| c.is_ascii_digit()), to_usize),
+        tag("\r\n"),
+    )(input)?;
+    let (remain, replies) = count(parse_reply, len)(remain)?;
+    Ok((remain, Reply::Array(replies)))
+}
+
+// parse_bulk parses a bulk string, which takes as many bytes as its length says.
+fn parse_bulk(input: &[u8]) -> IResult<&[u8], Bytes> {
+    let (remain, len) = delimited(
+        tag("$"),
+        map_res(take_while1(|c: u8| c.is_ascii_digit()), to_usize),
+        tag("\r\n"),
+    )(input)?;
+    let (remain, bytes) = terminated(take(len), tag("\r\n"))(remain)?;
+    Ok((remain, Bytes::from(bytes)))
+}
+
+pub fn parse_reply(input: &[u8]) -> IResult<&[u8], Reply> {
     let (remain, prefix) = take(1usize)(input)?;
     match prefix {
-        ":" => Ok(parse_int(remain)?),
-        "+" => Ok(parse_single_line(remain)?),
-        "-" => Ok(parse_err(remain)?),
+        b":" => Ok(parse_int(remain)?),
+        b"+" => Ok(parse_single_line(remain)?),
+        b"-" => Ok(parse_err(remain)?),
+        b"*" => Ok(parse_array(remain)?),
+        b"$" => {
+            let (remain, bytes) = parse_bulk(input)?;
+            Ok((remain, Reply::Bulk(bytes)))
+        }
         content => Err(Err::Error(Error::new(content, ErrorKind::Switch))),
     }
 }
 
-fn parse_arg(input: &str) -> IResult<&str, &str> {
-    let (remain, res) = terminated(take_while1(|c| c != '\r' && c != '\n'), tag("\r\n"))(input)?;
+fn parse_arg(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (remain, res) = terminated(take_while1(|c| c != b'\r' && c != b'\n'), tag("\r\n"))(input)?;
     Ok((remain, res))
 }
 
-fn parse_get(input: &str) -> IResult<&str, Request> {
-    let (remain, key) = parse_arg(input)?;
-    Ok((
-        remain,
-        Request::Get {
-            key: key.to_string(),
-        },
-    ))
+fn parse_get(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, key) = parse_bulk(input)?;
+    Ok((remain, Request::Get { key }))
+}
+
+fn parse_set(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (key, value)) = tuple((parse_bulk, parse_bulk))(input)?;
+    Ok((remain, Request::Set { key, value }))
 }
 
-fn parse_set(input: &str) -> IResult<&str, Request> {
-    let (remain, (key, value)) = tuple((parse_arg, parse_arg))(input)?;
-    Ok((
-        remain,
-        Request::Set {
-            key: key.to_string(),
-            value: value.to_string(),
-        },
-    ))
+fn parse_set_ex(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (key, value, _, ex)) =
+        tuple((parse_bulk, parse_bulk, tag("EX\r\n"), parse_limit))(input)?;
+    Ok((remain, Request::SetEx { key, value, ex }))
 }
 
-fn parse_remove(input: &str) -> IResult<&str, Request> {
-    let (remain, key) = parse_arg(input)?;
-    Ok((
-        remain,
-        Request::Remove {
-            key: key.to_string(),
-        },
-    ))
+fn parse_expire(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (key, seconds)) = tuple((parse_bulk, parse_limit))(input)?;
+    Ok((remain, Request::Expire { key, seconds }))
 }
 
-pub fn parse_request(input: &str) -> IResult<&str, Request> {
-    let (remain, _) = delimited(tag("*"), map_res(take(1usize), to_i64), tag("\r\n"))(input)?;
+fn parse_remove(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, key) = parse_bulk(input)?;
+    Ok((remain, Request::Remove { key }))
+}
+
+fn parse_limit(input: &[u8]) -> IResult<&[u8], u64> {
+    terminated(
+        map_res(take_while1(|c: u8| c.is_ascii_digit()), to_u64),
+        tag("\r\n"),
+    )(input)
+}
+
+fn parse_scan(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (start, end, limit)) = tuple((parse_bulk, parse_bulk, parse_limit))(input)?;
+    Ok((remain, Request::Scan { start, end, limit }))
+}
+
+fn parse_batch(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, len) = parse_limit(input)?;
+    let (remain, requests) =
+        terminated(count(parse_request, len as usize), tag("*1\r\nEXEC\r\n"))(remain)?;
+    Ok((remain, Request::Batch { requests }))
+}
+
+fn parse_watch(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, len) = parse_limit(input)?;
+    let (remain, keys) = count(parse_bulk, len as usize)(remain)?;
+    Ok((remain, Request::Watch { keys }))
+}
+
+fn parse_option(input: &[u8]) -> IResult<&[u8], Option<Bytes>> {
+    let (remain, len) = parse_limit(input)?;
+    match len {
+        0 => Ok((remain, None)),
+        1 => {
+            let (remain, value) = parse_bulk(remain)?;
+            Ok((remain, Some(value)))
+        }
+        _ => Err(Err::Error(Error::new(input, ErrorKind::Count))),
+    }
+}
+
+fn parse_compare_and_swap(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (key, expected, new)) = tuple((parse_bulk, parse_option, parse_option))(input)?;
+    Ok((remain, Request::CompareAndSwap { key, expected, new }))
+}
+
+fn parse_set_if(input: &[u8], absent: bool) -> IResult<&[u8], Request> {
+    let (remain, (key, value)) = tuple((parse_bulk, parse_bulk))(input)?;
+    let request = if absent {
+        Request::SetIfAbsent { key, value }
+    } else {
+        Request::SetIfPresent { key, value }
+    };
+    Ok((remain, request))
+}
+
+fn parse_scan_prefix(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, (prefix, limit)) = tuple((parse_bulk, parse_limit))(input)?;
+    Ok((remain, Request::ScanPrefix { prefix, limit }))
+}
+
+pub fn parse_request(input: &[u8]) -> IResult<&[u8], Request> {
+    let (remain, len) = delimited(tag("*"), map_res(take(1usize), to_i64), tag("\r\n"))(input)?;
     let (remain, command) = parse_arg(remain)?;
     match command {
-        "GET" => Ok(parse_get(remain)?),
-        "SET" => Ok(parse_set(remain)?),
-        "DEL" => Ok(parse_remove(remain)?),
+        b"GET" => Ok(parse_get(remain)?),
+        // a SET with an expiry carries the EX option.
+        b"SET" if len == 5 => Ok(parse_set_ex(remain)?),
+        b"SET" => Ok(parse_set(remain)?),
+        b"DEL" => Ok(parse_remove(remain)?),
+        b"SCAN" => Ok(parse_scan(remain)?),
+        b"SCANPREFIX" => Ok(parse_scan_prefix(remain)?),
+        b"MULTI" => Ok(parse_batch(remain)?),
+        b"WATCH" => Ok(parse_watch(remain)?),
+        b"CAS" => Ok(parse_compare_and_swap(remain)?),
+        b"SETNX" => Ok(parse_set_if(remain, true)?),
+        b"SETXX" => Ok(parse_set_if(remain, false)?),
+        b"EXPIRE" => Ok(parse_expire(remain)?),
+        b"TTL" => {
+            let (remain, key) = parse_bulk(remain)?;
+            Ok((remain, Request::Ttl { key }))
+        }
+        b"PERSIST" => {
+            let (remain, key) = parse_bulk(remain)?;
+            Ok((remain, Request::Persist { key }))
+        }
+        b"SELECT" => {
+            let (remain, keyspace) = parse_bulk(remain)?;
+            Ok((remain, Request::Select { keyspace }))
+        }
+        b"DROPKEYSPACE" => {
+            let (remain, keyspace) = parse_bulk(remain)?;
+            Ok((remain, Request::DropKeyspace { keyspace }))
+        }
+        b"KEYSPACES" => Ok((remain, Request::Keyspaces {})),
+        b"CHECKPOINT" => {
+            let (remain, dir) = parse_bulk(remain)?;
+            Ok((remain, Request::Checkpoint { dir }))
+        }
         content => Err(Err::Error(Error::new(content, ErrorKind::Switch))),
     }
 }
@@ -182,29 +680,25 @@ mod tests {
     #[test]
     fn request_to_resp() {
         {
-            let cmd = Request::Get {
-                key: "key".to_string(),
-            };
-            assert_eq!(cmd.to_resp(), "*2\r\nGET\r\nkey\r\n".to_string())
+            let cmd = Request::Get { key: "key".into() };
+            assert_eq!(cmd.to_resp(), b"*2\r\nGET\r\n$3\r\nkey\r\n")
         }
         {
             let cmd = Request::Set {
-                key: "key".to_string(),
-                value: "value".to_string(),
+                key: "key".into(),
+                value: "value".into(),
             };
-            assert_eq!(cmd.to_resp(), "*3\r\nSET\r\nkey\r\nvalue\r\n".to_string())
+            assert_eq!(cmd.to_resp(), b"*3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n")
         }
         {
-            let cmd = Request::Remove {
-                key: "key".to_string(),
-            };
-            assert_eq!(cmd.to_resp(), "*2\r\nDEL\r\nkey\r\n".to_string())
+            let cmd = Request::Remove { key: "key".into() };
+            assert_eq!(cmd.to_resp(), b"*2\r\nDEL\r\n$3\r\nkey\r\n")
         }
     }
 
     #[test]
     fn parse_reply() {
-        let check = |ret: IResult<&str, Reply>, target: Reply| match ret {
+        let check = |ret: IResult<&[u8], Reply>, target: Reply| match ret {
             Err(_) => {
                 panic!("wrong reply");
             }
@@ -216,30 +710,49 @@ mod tests {
             }
         };
         {
-            let ret = super::parse_reply("+OK\r\n");
+            let ret = super::parse_reply(b"+OK\r\n");
             check(ret, Reply::SingleLine("OK".to_string()));
         }
         {
-            let ret = super::parse_reply("-ERROR\r\n");
+            let ret = super::parse_reply(b"-ERROR\r\n");
             check(ret, Reply::Err("ERROR".to_string()));
         }
         {
-            let ret = super::parse_reply(":10\r\n");
+            let ret = super::parse_reply(b":10\r\n");
             check(ret, Reply::Int(10));
         }
         {
-            let ret = super::parse_reply("OK\r\n");
+            let ret = super::parse_reply(b"*2\r\n$3\r\nkey\r\n$5\r\nvalue\r\n");
+            check(
+                ret,
+                Reply::pairs(vec![(b"key".to_vec(), b"value".to_vec())]),
+            );
+        }
+        {
+            let ret = super::parse_reply(b"$4\r\n\r\n\xff\xfe\r\n");
+            check(ret, Reply::Bulk(Bytes::new(b"\r\n\xff\xfe".to_vec())));
+        }
+        {
+            let ret = super::parse_reply(b"$4\r\nkey\r\n");
+            assert!(ret.is_err());
+        }
+        {
+            let ret = super::parse_reply(b"*0\r\n");
+            check(ret, Reply::Array(vec![]));
+        }
+        {
+            let ret = super::parse_reply(b"OK\r\n");
             assert!(matches!(ret, Err(_)));
         }
         {
-            let ret = super::parse_reply(":OK\r\n");
+            let ret = super::parse_reply(b":OK\r\n");
             assert!(matches!(ret, Err(_)));
         }
     }
 
     #[test]
     fn parse_request() {
-        let check = |ret: IResult<&str, Request>, target: Request| match ret {
+        let check = |ret: IResult<&[u8], Request>, target: Request| match ret {
             Err(_) => {
                 panic!("wrong request");
             }
@@ -251,30 +764,175 @@ mod tests {
             }
         };
         {
-            let req = Request::Get {
-                key: "key".to_string(),
-            };
+            let req = Request::Get { key: "key".into() };
             let input = req.to_resp();
-            let ret = super::parse_request(input.as_str());
+            let ret = super::parse_request(&input);
             check(ret, req)
         }
         {
             let req = Request::Set {
-                key: "key".to_string(),
-                value: "value".to_string(),
+                key: "key".into(),
+                value: "value".into(),
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        {
+            let req = Request::Remove { key: "key".into() };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        {
+            let req = Request::Scan {
+                start: "a".into(),
+                end: "".into(),
+                limit: 10,
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        {
+            let req = Request::ScanPrefix {
+                prefix: "key".into(),
+                limit: 0,
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        {
+            let req = Request::Batch {
+                requests: vec![
+                    Request::Set {
+                        key: "key".into(),
+                        value: "value".into(),
+                    },
+                    Request::Remove { key: "key".into() },
+                ],
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        for (expected, new) in [(None, Some("value".into())), (Some("".into()), None)] {
+            let req = Request::CompareAndSwap {
+                key: "key".into(),
+                expected,
+                new,
             };
             let input = req.to_resp();
-            let ret = super::parse_request(input.as_str());
+            let ret = super::parse_request(&input);
             check(ret, req)
         }
         {
-            let req = Request::Remove {
-                key: "key".to_string(),
+            let req = Request::SetIfAbsent {
+                key: "key".into(),
+                value: "value".into(),
             };
             let input = req.to_resp();
-            let ret = super::parse_request(input.as_str());
+            let ret = super::parse_request(&input);
             check(ret, req)
         }
+        {
+            let req = Request::SetIfPresent {
+                key: "key".into(),
+                value: "value".into(),
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        {
+            let req = Request::Set {
+                key: Bytes::new(vec![0xff, 0x00]),
+                value: Bytes::new(b"$3\r\n\r\n".to_vec()),
+            };
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+        let requests = vec![
+            Request::SetEx {
+                key: "key".into(),
+                value: "value".into(),
+                ex: 10,
+            },
+            Request::Expire {
+                key: "key".into(),
+                seconds: 10,
+            },
+            Request::Ttl { key: "key".into() },
+            Request::Persist { key: "key".into() },
+            Request::Watch {
+                keys: vec!["key1".into(), "key2".into()],
+            },
+            Request::Select {
+                keyspace: "name".into(),
+            },
+            Request::DropKeyspace {
+                keyspace: "name".into(),
+            },
+            Request::Keyspaces {},
+            Request::Checkpoint {
+                dir: "/tmp/dir".into(),
+            },
+        ];
+        for req in requests {
+            let input = req.to_resp();
+            let ret = super::parse_request(&input);
+            check(ret, req)
+        }
+    }
+
+    #[test]
+    fn read_batch() {
+        let input = "set key1 value1\n\nrm key2\n";
+        let requests = Request::read_batch(input.as_bytes()).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                Request::Set {
+                    key: "key1".into(),
+                    value: "value1".into(),
+                },
+                Request::Remove { key: "key2".into() },
+            ]
+        );
+        assert_eq!(Request::into_batch(requests).unwrap().len(), 2);
+        for input in &["set key1\n", "foo key1\n"] {
+            assert!(Request::read_batch(input.as_bytes()).is_err());
+        }
+        let requests = Request::read_batch("get key1\n".as_bytes()).unwrap();
+        assert!(Request::into_batch(requests).is_err());
+    }
+
+    #[test]
+    fn ttl_reply() {
+        assert_eq!(Reply::ttl(Ok(None)), Reply::Int(-1));
+        assert_eq!(Reply::ttl(Err(KvsError::KeyNotFoundError)), Reply::Int(-2));
+        assert_eq!(
+            Reply::ttl(Ok(Some(Duration::from_millis(1)))),
+            Reply::Int(1)
+        );
+        assert_eq!(
+            Reply::ttl(Ok(Some(Duration::from_secs(10)))),
+            Reply::Int(10)
+        );
+    }
+
+    #[test]
+    fn checkpoint_dir() {
+        let root = Path::new("/var/backups");
+        assert_eq!(
+            Request::checkpoint_dir(root, "daily/1".into()).unwrap(),
+            root.join("daily/1")
+        );
+        for dir in &["", "/tmp/dir", "..", "daily/../../etc"] {
+            assert!(Request::checkpoint_dir(root, (*dir).into()).is_err());
+        }
     }
 
     #[test]
@@ -1,11 +1,12 @@
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Reply, Request, Result};
+use crate::{BatchOp, Bytes, KvsEngine, KvsError, Reply, Request, Result, WriteBatch};
 use nix::unistd::close;
 use serde_resp::SimpleDeserializer;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::sync::atomic;
 use std::time::Duration;
 
@@ -15,6 +16,9 @@ pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     pool: P,
     socket: Socket,
     close: atomic::AtomicBool,
+    // the directory checkpoints requested by clients are written under, none if they
+    // are not allowed.
+    checkpoint_root: Option<PathBuf>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -31,20 +35,32 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             pool,
             socket,
             close: atomic::AtomicBool::new(false),
+            checkpoint_root: None,
         })
     }
 
+    /// `checkpoint_root` lets clients write checkpoints, into directories under `root`.
+    pub fn checkpoint_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.checkpoint_root = Some(root.into());
+        self
+    }
+
     pub fn run(&self, addr: SocketAddr) -> Result<()> {
         self.socket.bind(&SockAddr::from(addr))?;
         self.socket.listen(128)?;
+        // the port is picked by the system if `addr` asks for port 0.
+        if let Some(addr) = self.socket.local_addr()?.as_std() {
+            info!("listening on {}", addr);
+        }
         {
             loop {
                 match self.socket.accept() {
                     Ok((s, _)) => {
                         let stream = s.into_tcp_stream();
                         let engine = self.engine.clone();
+                        let checkpoint_root = self.checkpoint_root.clone();
                         self.pool.spawn(move || {
-                            if let Err(e) = handle_serde(engine, stream) {
+                            if let Err(e) = handle_serde(engine, stream, checkpoint_root) {
                                 error!("handle failed: {}", e);
                             }
                         })
@@ -76,18 +92,25 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     }
 }
 
-// Option1: use serde_resp to process the stream
-fn handle_serde<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
+// Option1: use serde_resp to process the stream. A connection starts in the default
+// keyspace, a select request switches it to another one.
+fn handle_serde<T: KvsEngine>(
+    mut engine: T,
+    stream: TcpStream,
+    checkpoint_root: Option<PathBuf>,
+) -> Result<()> {
     let mut reader = BufReader::new(&stream);
     let req_reader = SimpleDeserializer::from_buf_reader(&mut reader).into_iter::<Request>();
     let mut writer = BufWriter::new(&stream);
+    // the keys watched for the next batch along with the versions they are watched with.
+    let mut watched: Vec<(Vec<u8>, Option<u64>)> = Vec::new();
     for req in req_reader {
         let req = req?;
         match req {
-            Request::Get { key } => match engine.get(key) {
+            Request::Get { key } => match engine.get_bytes(key.into()) {
                 Ok(res) => {
-                    if let Some(s) = res {
-                        writer.write_all(Reply::SingleLine(s).to_resp().as_ref())?;
+                    if let Some(value) = res {
+                        writer.write_all(Reply::Bulk(value.into()).to_resp().as_ref())?;
                     } else {
                         writer.write_all(
                             Reply::SingleLine("Key not found".to_string())
@@ -102,7 +125,7 @@ fn handle_serde<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
                     writer.flush()?;
                 }
             },
-            Request::Set { key, value } => match engine.set(key, value) {
+            Request::Set { key, value } => match engine.set_bytes(key.into(), value.into()) {
                 Ok(_) => {
                     writer.write_all(Reply::SingleLine("".to_string()).to_resp().as_ref())?;
                     writer.flush()?;
@@ -113,7 +136,7 @@ fn handle_serde<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
                     writer.flush()?;
                 }
             },
-            Request::Remove { key } => match engine.remove(key) {
+            Request::Remove { key } => match engine.remove_bytes(key.into()) {
                 Ok(_) => {
                     writer.write_all(Reply::SingleLine("".to_string()).to_resp().as_ref())?;
                     writer.flush()?;
@@ -123,11 +146,182 @@ fn handle_serde<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
                     writer.flush()?;
                 }
             },
+            Request::Scan { start, end, limit } => {
+                let range = Request::scan_range(start.into(), end.into());
+                let reply = match engine.scan_bytes(range, Request::scan_limit(limit)) {
+                    Ok(pairs) => Reply::pairs(pairs),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::ScanPrefix { prefix, limit } => {
+                let limit = Request::scan_limit(limit);
+                let reply = match engine.scan_prefix_bytes(prefix.into(), limit) {
+                    Ok(pairs) => Reply::pairs(pairs),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Batch { requests } => {
+                // a batch clears the watch, whether it is applied or not.
+                let watched = std::mem::take(&mut watched);
+                let ret = Request::into_batch(requests).and_then(|batch| {
+                    if watched.is_empty() {
+                        engine.write_batch(batch)
+                    } else {
+                        exec_watched(&engine, &watched, batch)
+                    }
+                });
+                let reply = match ret {
+                    Ok(_) => Reply::SingleLine("".to_string()),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                let (expected, new) = (expected.map(Bytes::into_vec), new.map(Bytes::into_vec));
+                let reply =
+                    Reply::swapped(engine.compare_and_swap_bytes(key.into(), expected, new));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::SetIfAbsent { key, value } => {
+                let reply = Reply::swapped(engine.set_if_absent_bytes(key.into(), value.into()));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::SetIfPresent { key, value } => {
+                let reply = Reply::swapped(engine.set_if_present_bytes(key.into(), value.into()));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::SetEx { key, value, ex } => {
+                let ttl = Duration::from_secs(ex);
+                let reply = match engine.set_with_ttl_bytes(key.into(), value.into(), ttl) {
+                    Ok(_) => Reply::SingleLine("".to_string()),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Expire { key, seconds } => {
+                let ttl = Duration::from_secs(seconds);
+                let reply = Reply::swapped(engine.expire_bytes(key.into(), ttl));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Ttl { key } => {
+                let reply = Reply::ttl(engine.ttl_bytes(key.into()));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Persist { key } => {
+                let reply = Reply::swapped(engine.persist_bytes(key.into()));
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Watch { keys } => {
+                let mut reply = Reply::SingleLine("".to_string());
+                for key in keys {
+                    let key = key.into_vec();
+                    match engine.version_bytes(key.clone()) {
+                        Ok(version) => watched.push((key, version)),
+                        Err(e) => {
+                            reply = Reply::Err(e.to_string());
+                            break;
+                        }
+                    }
+                }
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Select { keyspace } => {
+                // the watched keys are read in the keyspace the connection is in.
+                let ret = if watched.is_empty() {
+                    Request::keyspace(keyspace).and_then(|name| engine.open_keyspace(&name))
+                } else {
+                    Err(KvsError::InvalidCommandError)
+                };
+                let reply = match ret {
+                    Ok(keyspace) => {
+                        engine = keyspace;
+                        Reply::SingleLine("".to_string())
+                    }
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::DropKeyspace { keyspace } => {
+                let ret = Request::keyspace(keyspace).and_then(|name| engine.drop_keyspace(&name));
+                let reply = Reply::swapped(ret);
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Checkpoint { dir } => {
+                let ret = match &checkpoint_root {
+                    Some(root) => {
+                        Request::checkpoint_dir(root, dir).and_then(|dir| engine.checkpoint(&dir))
+                    }
+                    None => Err(KvsError::InvalidPathError(
+                        "checkpoints are not enabled".to_string(),
+                    )),
+                };
+                let reply = match ret {
+                    Ok(()) => Reply::SingleLine("".to_string()),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
+            Request::Keyspaces {} => {
+                let reply = match engine.keyspaces() {
+                    Ok(names) => Reply::Array(
+                        names
+                            .into_iter()
+                            .map(|name| Reply::Bulk(name.into()))
+                            .collect(),
+                    ),
+                    Err(e) => Reply::Err(e.to_string()),
+                };
+                writer.write_all(reply.to_resp().as_ref())?;
+                writer.flush()?;
+            }
         }
     }
     Ok(())
 }
 
+// exec_watched applies a batch in a transaction, which fails with a conflict if a
+// watched key is written after it is watched, even with the value it had.
+fn exec_watched<T: KvsEngine>(
+    engine: &T,
+    watched: &[(Vec<u8>, Option<u64>)],
+    batch: WriteBatch,
+) -> Result<()> {
+    engine.transaction(|txn| {
+        for (key, version) in watched {
+            if txn.version_bytes(key.clone())? != *version {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        for op in batch.clone() {
+            match op {
+                BatchOp::Set { key, value } => txn.set_bytes(key, value)?,
+                // a remove of a missing key is fine in a batch.
+                BatchOp::Remove { key } => match txn.remove_bytes(key) {
+                    Err(KvsError::KeyNotFoundError) => (),
+                    ret => ret?,
+                },
+            }
+        }
+        Ok(())
+    })
+}
+
 // TODO: the loop never loop from `cargo clippy`?
 // Option2: use nom parser to process the stream.
 // fn handle_norm<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
@@ -205,6 +399,7 @@ mod tests {
     use nix::unistd::close;
     use std::net::Shutdown;
     use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
     use std::sync::Arc;
     use std::thread;
 
@@ -151,6 +151,25 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` should refuse the flags the engine has no use for
+#[test]
+fn server_cli_unsupported_flags() {
+    for args in &[
+        ["--engine", "sled", "--segment-size", "4096"],
+        ["--engine", "lsm", "--cache-size", "4096"],
+        ["--engine", "memory", "--read-only", "--addr=127.0.0.1:4000"],
+        ["--engine", "kvs", "--capacity", "4096"],
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("is not supported by the"));
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -171,6 +190,125 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs backup` should write a checkpoint that `kvs restore` restores elsewhere.
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let (store, restored) = (
+        temp_dir.path().join("store"),
+        temp_dir.path().join("restored"),
+    );
+    let backup = temp_dir.path().join("backup");
+    fs::create_dir_all(&store).unwrap();
+    fs::create_dir_all(&restored).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&store)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["backup", backup.to_str().unwrap()])
+        .current_dir(&store)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", backup.to_str().unwrap()])
+        .current_dir(&restored)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&restored)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", backup.to_str().unwrap()])
+        .current_dir(&restored)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().to_str().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    File::create(temp_dir.path().join("kvs.engine")).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(temp_dir.path().join("sled.engine").exists());
+    assert!(!temp_dir.path().join("kvs.engine").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", dir])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(temp_dir.path().join("kvs.engine").exists());
+    assert!(!temp_dir.path().join("sled_data").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "lsm", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(temp_dir.path().join("lsm.engine").exists());
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "lsm", "--to", "kvs", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(!temp_dir.path().join("lsm.engine").exists());
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "btree", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(temp_dir.path().join("btree.engine").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", "--from", "btree", "--to", "kvs", dir])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(!temp_dir.path().join("btree.db").exists());
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -283,6 +421,137 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\nvalue2\nkey2\nvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\nvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\nvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "foo", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3 value4\nrm key3\nset key4 value5\n")
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key4\nvalue5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key4\n")
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setnx", "key4", "value6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key4", "--expected", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setxx", "key4", "value7", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key5", "value8", "--ex", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["persist", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["expire", "key4", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2\n");
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key1", "--addr", addr])
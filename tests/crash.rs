@@ -0,0 +1,98 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, Reply, Request};
+use std::io::{BufRead, BufReader, Read};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Every key the server acknowledged before it is killed should survive. The page cache
+// survives the kill, so this does not tell whether the writes were synced, the group
+// commit has its own test for that.
+#[test]
+fn acknowledged_writes_survive_kill() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:0",
+            "--sync",
+            "every-write",
+            "--segment-size",
+            "65536",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = listening_addr(server.stderr.take().unwrap());
+
+    let killed = Arc::new(AtomicBool::new(false));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let killed = killed.clone();
+            thread::spawn(move || {
+                let mut acked = Vec::new();
+                let mut client = KvsClient::new(addr).unwrap();
+                for i in 0.. {
+                    let key = format!("key{}-{}", t, i);
+                    let value = format!("value{}-{}", t, i);
+                    let req = Request::Set {
+                        key: key.clone().into(),
+                        value: value.clone().into(),
+                    };
+                    match client.process(&req) {
+                        Ok(Reply::SingleLine(_)) => acked.push((key, value)),
+                        Ok(reply) if !killed.load(Ordering::SeqCst) => {
+                            panic!("unexpected reply {}", reply)
+                        }
+                        _ => break,
+                    }
+                }
+                acked
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(500));
+    killed.store(true, Ordering::SeqCst);
+    server.kill().unwrap();
+    server.wait().unwrap();
+    let acked: Vec<_> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    assert!(!acked.is_empty());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for (key, value) in acked {
+        assert_eq!(
+            store.get(key.clone()).unwrap(),
+            Some(value),
+            "{} is lost",
+            key
+        );
+    }
+}
+
+// listening_addr reads the address the server listens on from its log, and keeps
+// draining the log so the server never blocks on it.
+fn listening_addr(stderr: impl Read + Send + 'static) -> SocketAddr {
+    let mut lines = BufReader::new(stderr).lines();
+    let addr = lines
+        .by_ref()
+        .map(|line| line.unwrap())
+        .find_map(|line| {
+            let (_, addr) = line.split_once("listening on ")?;
+            addr.trim().parse().ok()
+        })
+        .expect("server exited before listening");
+    thread::spawn(move || lines.for_each(drop));
+    addr
+}
@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BTreeKvsEngine, BTreeOptions, CacheStats, CompactionTrigger, Compression, KvStore,
+    KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MemoryKvsEngine, RecoveryMode,
+    Result, SledKvsEngine, SyncPolicy, WriteBatch, DEFAULT_KEYSPACE,
+};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +88,121 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should read a log written in the old json format and migrate it in place.
+#[test]
+fn migrate_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("data"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should drop a corrupted record at the end of the log.
+#[test]
+fn drop_corrupted_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_len = fs::metadata(&data_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut data = fs::read(&data_path)?;
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    fs::write(&data_path, data)?;
+    let store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict)?;
+    assert_eq!(fs::metadata(&data_path)?.len(), first_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should open after a torn write at any byte of the log, keeping every complete record.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut boundaries = vec![0];
+    for i in 0..4 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        boundaries.push(fs::metadata(&data_path)?.len());
+    }
+    store.remove("key0".to_owned())?;
+    boundaries.push(fs::metadata(&data_path)?.len());
+    drop(store);
+    let data = fs::read(&data_path)?;
+
+    for cut in 0..data.len() {
+        let torn_dir = TempDir::new().expect("unable to create temporary working directory");
+        let torn_path = torn_dir.path().join("1.log");
+        fs::write(&torn_path, &data[..cut])?;
+
+        let store = KvStore::open_with_recovery(torn_dir.path(), RecoveryMode::Strict)?;
+        // the log is truncated to the last complete record.
+        let good = boundaries.iter().filter(|b| **b <= cut as u64).count() - 1;
+        assert_eq!(fs::metadata(&torn_path)?.len(), boundaries[good]);
+        for i in 0..4 {
+            let expect = if i < good && !(i == 0 && good == 5) {
+                Some(format!("value{}", i))
+            } else {
+                None
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expect, "cut at {}", cut);
+        }
+
+        // the store keeps working after the recovery.
+        store.set("key".to_owned(), "value".to_owned())?;
+        drop(store);
+        let store = KvStore::open_with_recovery(torn_dir.path(), RecoveryMode::Strict)?;
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Strict recovery should refuse corruption in the middle of the log,
+// the default one should drop everything from the broken record.
+#[test]
+fn recover_mid_file_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_len = fs::metadata(&data_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut data = fs::read(&data_path)?;
+    data[first_len as usize + 20] ^= 0x01;
+    fs::write(&data_path, data)?;
+
+    match KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict) {
+        Err(KvsError::CorruptedRecordError { offset, .. }) => assert_eq!(offset, first_len),
+        _ => panic!("corruption not detected"),
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&data_path)?.len(), first_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,20 +249,1255 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should seal full segments and keep the data of every segment.
+#[test]
+fn multiple_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let segments = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension() == Some("log".as_ref())
+            })
+            .count()
+    };
+    assert!(segments() > 1);
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Should write a hint file at compaction and load the segment from it on open.
+#[test]
+fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    for i in 0..1000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    for _ in 0..3 {
+        for i in 0..1000 {
+            store.set(format!("other{}", i), value.clone())?;
+        }
+    }
+    drop(store);
+
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert!(!hints.is_empty(), "no hint file written at compaction");
+    let hint = hints
+        .into_iter()
+        .max_by_key(|path| fs::metadata(path.with_extension("log")).unwrap().len())
+        .unwrap();
+    let segment = hint.with_extension("log");
+
+    // The hint file spares reading the values, so a corrupted one goes unnoticed.
+    let data = fs::read(&segment)?;
+    let mut corrupted = data.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0x01;
+    fs::write(&segment, &corrupted)?;
+    let store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict)?;
+    assert_eq!(store.get("key0".to_owned())?, Some(value.clone()));
+    drop(store);
+    fs::write(&segment, &data)?;
+
+    // A broken hint file falls back to replaying the segment.
+    let mut broken = fs::read(&hint)?;
+    broken[0] ^= 0x01;
+    fs::write(&hint, &broken)?;
+    let store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict)?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Should compress long values, read them back whatever codec they are written with
+// and rewrite them with the codec of the store at compaction.
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            // a segment may be removed by a compaction in between.
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+    let value = |i: usize| {
+        let field = format!("\"field\": \"{}\"", "x".repeat(40));
+        format!("{{\"id\": {}, {}}}", i, vec![field; 50].join(", "))
+    };
+    let raw_size: u64 = (0..200).map(|i| value(i).len() as u64).sum();
+    let opts = || {
+        KvStoreOptions::new()
+            .segment_size(16 * 1024)
+            .compaction(CompactionTrigger::DanglingRatio(0.0))
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+        assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), opts().compression(Compression::Lz4))?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    store.set("short".to_owned(), "value".to_owned())?;
+    assert!(log_size() < raw_size / 4);
+    check(&store)?;
+    drop(store);
+
+    // Switching the codec keeps the old records readable until compaction rewrites
+    // them, so the log grows back to the size of the raw values.
+    let recompress = |compression: Compression, done: &dyn Fn(u64) -> bool| -> Result<()> {
+        let store = KvStore::open_with(temp_dir.path(), opts().compression(compression))?;
+        check(&store)?;
+        let mut tick = 0;
+        while !done(log_size()) {
+            assert!(tick < 100_000, "no {:?} compaction detected", compression);
+            store.set("tick".to_owned(), tick.to_string())?;
+            tick += 1;
+        }
+        check(&store)?;
+        drop(store);
+        check(&KvStore::open_with(temp_dir.path(), opts())?)
+    };
+    recompress(Compression::None, &|size| size > raw_size)?;
+    recompress(Compression::Zstd, &|size| size < raw_size / 4)
+}
+
+// Should keep the writes made while a compaction runs in the background.
+#[test]
+fn compaction_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let padding = "p".repeat(100);
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            let padding = padding.clone();
+            thread::spawn(move || {
+                for round in 0..40 {
+                    for i in 0..250 {
+                        let key = format!("key{}-{}", t, i);
+                        let value = format!("{}{}", round, padding);
+                        store.set(key.clone(), value.clone()).unwrap();
+                        assert_eq!(store.get(key).unwrap(), Some(value));
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let log_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    // more than 5 MiB is written, most of it is compacted away.
+    assert!(log_size < 4 * 1024 * 1024);
+    let expected = format!("{}{}", 39, padding);
+    for t in 0..4 {
+        for i in 0..250 {
+            let key = format!("key{}-{}", t, i);
+            assert_eq!(store.get(key)?, Some(expected.clone()));
+        }
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..4 {
+        for i in 0..250 {
+            let key = format!("key{}-{}", t, i);
+            assert_eq!(store.get(key)?, Some(expected.clone()));
+        }
+    }
+    Ok(())
+}
+
+// Should read the sealed segments through memory maps, which are swapped under
+// concurrent readers as segments roll and get compacted.
+#[test]
+fn mmap_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new()
+        .segment_size(16 * 1024)
+        .compaction(CompactionTrigger::DanglingRatio(1.0))
+        .mmap(true);
+    let store = KvStore::open_with(temp_dir.path(), opts.clone())?;
+    let padding = "p".repeat(100);
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("0{}", padding))?;
+    }
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", i)).unwrap().unwrap();
+                        assert!(value.ends_with("p"));
+                    }
+                }
+            })
+        })
+        .collect();
+    for round in 1..40 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{}{}", round, padding))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let expected = format!("{}{}", 39, padding);
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected.clone()));
+        }
+        assert_eq!(store.scan(.., 1000)?.len(), 100);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), opts)?)?;
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Should never fail a read or go back to an older value while segments roll and get
+// compacted under the readers, with pread and with memory maps.
+#[test]
+fn reads_racing_compaction() -> Result<()> {
+    for &mmap in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let opts = KvStoreOptions::new()
+            .segment_size(4 * 1024)
+            .compaction(CompactionTrigger::DanglingRatio(0.5))
+            .mmap(mmap);
+        let store = KvStore::open_with(temp_dir.path(), opts)?;
+        let padding = "p".repeat(50);
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("0-{}", padding))?;
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let done = done.clone();
+                let padding = padding.clone();
+                thread::spawn(move || {
+                    let mut last = vec![0u64; 50];
+                    while !done.load(Ordering::Acquire) {
+                        for (i, last) in last.iter_mut().enumerate() {
+                            let value = store.get(format!("key{}", i)).unwrap().unwrap();
+                            let (round, rest) = value.split_at(value.find('-').unwrap());
+                            let round: u64 = round.parse().unwrap();
+                            assert_eq!(&rest[1..], padding);
+                            assert!(round >= *last, "key{} goes back to {}", i, round);
+                            *last = round;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 1..200 {
+            for i in 0..50 {
+                store.set(format!("key{}", i), format!("{}-{}", round, padding))?;
+            }
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("199-{}", padding))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Should serve reads and refuse writes without touching the files.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log_path = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log_path)?;
+    data.extend_from_slice(b"torn");
+    fs::write(&log_path, &data)?;
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnlyError)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnlyError)
+    ));
+    drop(store);
+    assert_eq!(fs::read(&log_path)?, data);
+    Ok(())
+}
+
+// Should keep a store from being opened by a writer and any other opener at once.
+#[test]
+fn lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = || KvStoreOptions::new().read_only(true);
+    let store = KvStore::open(temp_dir.path())?;
+    let pid = Some(std::process::id());
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { pid: p }) if p == pid));
+    let ret = KvStore::open_with(temp_dir.path(), read_only());
+    assert!(matches!(ret, Err(KvsError::Locked { pid: p }) if p == pid));
+    // a keyspace shares the lock of its store.
+    store
+        .open_keyspace("first")?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let first = KvStore::open_with(temp_dir.path(), read_only())?;
+    let second = KvStore::open_with(temp_dir.path(), read_only())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { pid: None })
+    ));
+    drop((first, second));
+    KvStore::open(temp_dir.path())?;
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path())?;
+    let ret = SledKvsEngine::open(sled_dir.path());
+    assert!(matches!(ret, Err(KvsError::Locked { pid: p }) if p == pid));
+    drop(engine);
+    SledKvsEngine::open(sled_dir.path())?;
+    Ok(())
+}
+
+// Should write a consistent copy of the store, which can be verified and restored.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new().segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path().join("store"), opts)?;
+    let keyspace = store.open_keyspace("first")?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    keyspace.set("key1".to_owned(), "value1".to_owned())?;
+    let backup = temp_dir.path().join("backup");
+    store.checkpoint(&backup)?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert!(store.checkpoint(&backup).is_err());
+    drop((store, keyspace));
+    KvStore::verify(&backup)?;
+
+    let restored = temp_dir.path().join("restored");
+    KvStore::restore(&backup, &restored)?;
+    assert!(KvStore::restore(&backup, &restored).is_err());
+    let store = KvStore::open(&restored)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let keyspace = store.open_keyspace("first")?;
+    assert_eq!(keyspace.get("key1".to_owned())?, Some("value1".to_owned()));
+    // the restored store is written to on its own.
+    store.set("key1".to_owned(), "value".to_owned())?;
+    drop((store, keyspace));
+    KvStore::verify(&backup)?;
+
+    let log_path = backup.join("1.log");
+    let mut data = fs::read(&log_path)?;
+    data[20] ^= 0xff;
+    fs::remove_file(&log_path)?;
+    fs::write(&log_path, &data)?;
+    assert!(matches!(
+        KvStore::verify(&backup),
+        Err(KvsError::CorruptedRecordError { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path().join("engine"))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine
+        .open_keyspace("first")?
+        .set("key1".to_owned(), "value2".to_owned())?;
+    let backup = temp_dir.path().join("backup");
+    engine.checkpoint(&backup)?;
+    assert!(engine.checkpoint(&backup).is_err());
+
+    let engine = SledKvsEngine::open(&backup)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    let keyspace = engine.open_keyspace("first")?;
+    assert_eq!(keyspace.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should seal segments and compact them as the options say.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new()
+        .segment_size(16 * 1024)
+        .compaction(CompactionTrigger::DanglingRatio(1.0))
+        .sync(SyncPolicy::EveryNBytes(4096));
+    let store = KvStore::open_with(temp_dir.path(), opts.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("{}{}", iter, "v".repeat(100)),
+            )?;
+        }
+    }
+    drop(store);
+
+    // 20 rounds of about 13 KiB are written, most of them are compacted away.
+    let log_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(log_size < 100 * 1024, "log size {}", log_size);
+    let store = KvStore::open_with(temp_dir.path(), opts.sync(SyncPolicy::EveryWrite))?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", 19, "v".repeat(100)))
+        );
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Should serve hot values from the cache and never hand out one replaced by a write
+// or moved by a compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new()
+        .segment_size(16 * 1024)
+        .compaction(CompactionTrigger::DanglingRatio(0.0))
+        .cache_size(1024 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), opts)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(store.cache_stats(), CacheStats { hits: 2, misses: 1 });
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(snapshot);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Overwrites roll the segments and get them compacted under the cached values.
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}{}", iter, "v".repeat(100)))?;
+            assert_eq!(
+                store.get(key)?,
+                Some(format!("{}{}", iter, "v".repeat(100)))
+            );
+        }
+    }
+    for key_id in 0..100 {
+        for _ in 0..3 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}{}", 19, "v".repeat(100)))
+            );
+        }
+    }
+    assert!(store.cache_stats().hits >= 100);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), CacheStats::default());
+    Ok(())
+}
+
+fn check_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in &["a", "ab", "abc", "b", "ba", "c"] {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+    engine.remove("ba".to_owned())?;
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value, format!("value-{}", key));
+                key
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        keys(engine.scan(.., usize::MAX)?),
+        vec!["a", "ab", "abc", "b", "c"]
+    );
+    assert_eq!(
+        keys(engine.scan("ab".to_owned().."b".to_owned(), usize::MAX)?),
+        vec!["ab", "abc"]
+    );
+    assert_eq!(
+        keys(engine.scan("ab".to_owned()..="b".to_owned(), 2)?),
+        vec!["ab", "abc"]
+    );
+    assert_eq!(
+        keys(engine.scan("b".to_owned().., 0)?),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("a".to_owned(), usize::MAX)?),
+        vec!["a", "ab", "abc"]
+    );
+    assert_eq!(keys(engine.scan_prefix("ab".to_owned(), 1)?), vec!["ab"]);
+    assert_eq!(
+        keys(engine.scan_prefix("b".to_owned(), usize::MAX)?),
+        vec!["b"]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("d".to_owned(), usize::MAX)?),
+        Vec::<String>::new()
+    );
+    Ok(())
+}
+
+// Should return the pairs in a range or with a prefix, ordered by key.
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)
+}
+
+// engine_tests! runs the engine checks of this file against every engine other than
+// KvStore, each in a module named after it. An engine is listed with the expression
+// opening it in `dir`, a temporary directory kept until the test is done.
+macro_rules! engine_tests {
+    ($($name:ident: |$dir:pat| $open:expr;)*) => {$(
+        mod $name {
+            use super::*;
+
+            fn open() -> Result<(impl KvsEngine, TempDir)> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let engine = {
+                    let $dir = temp_dir.path();
+                    $open
+                };
+                Ok((engine, temp_dir))
+            }
+
+            #[test]
+            fn scan() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_scan(engine)
+            }
+
+            #[test]
+            fn write_batch() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_write_batch(engine)
+            }
+
+            #[test]
+            fn compare_and_swap() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_compare_and_swap(engine)
+            }
+
+            #[test]
+            fn expiry() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_expiry(engine)
+            }
+
+            #[test]
+            fn binary_values() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_binary(engine)
+            }
+
+            #[test]
+            fn transaction() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_transaction(engine)
+            }
+
+            #[test]
+            fn keyspaces() -> Result<()> {
+                let (engine, _temp_dir) = open()?;
+                check_keyspaces(engine)
+            }
+
+            #[test]
+            fn export_import() -> Result<()> {
+                let ((from, _from_dir), (to, _to_dir)) = (open()?, open()?);
+                check_export_import(from, to)
+            }
+        }
+    )*};
+}
+
+engine_tests! {
+    sled: |dir| SledKvsEngine::open(dir)?;
+    memory: |_| MemoryKvsEngine::new();
+    lsm: |dir| LsmKvsEngine::open_with(dir, small_lsm())?;
+    btree: |dir| BTreeKvsEngine::open(dir)?;
+}
+
+fn check_write_batch<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "value4".to_owned());
+    batch.remove("key3".to_owned());
+    batch.set("key3".to_owned(), "value5".to_owned());
+    // a missing key is no error in a batch.
+    batch.remove("key4".to_owned());
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Should apply every operation of a batch in order.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(KvStore::open(temp_dir.path())?)?;
+
+    // the batch survives a reopen and a compaction.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+    let value = "v".repeat(1000);
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let key = format!("filler{}", key_id);
+            store.set(key, format!("{}-{}", iter, value))?;
+        }
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Should drop a batch torn by a crash as a whole.
+#[test]
+fn torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_len = fs::metadata(&data_path)?.len();
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let data = fs::read(&data_path)?;
+    for len in first_len as usize..data.len() {
+        fs::write(&data_path, &data[..len])?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+    fs::write(&data_path, &data)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "key1".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+    assert!(!engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert!(engine.compare_and_swap(key(), None, value("value1"))?);
+    assert!(!engine.compare_and_swap(key(), None, value("value2"))?);
+    assert!(!engine.compare_and_swap(key(), value("value2"), value("value3"))?);
+    assert_eq!(engine.get(key())?, value("value1"));
+    assert!(engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert_eq!(engine.get(key())?, value("value2"));
+    assert!(engine.compare_and_swap(key(), value("value2"), None)?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+
+    assert!(!engine.set_if_present(key(), "value1".to_owned())?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.set_if_absent(key(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent(key(), "value2".to_owned())?);
+    assert_eq!(engine.get(key())?, value("value1"));
+    assert!(engine.set_if_present(key(), "value3".to_owned())?);
+    assert_eq!(engine.get(key())?, value("value3"));
+
+    // concurrent increments through compare_and_swap lose no update.
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        if engine
+                            .compare_and_swap("counter".to_owned(), Some(current), Some(next))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter".to_owned())?, value("400"));
+    Ok(())
+}
+
+// Should only write if the current value is the expected one.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_transaction<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = |k: &str| k.to_owned();
+    let value = |v: &str| Some(v.to_owned());
+    engine.set(key("key1"), "value1".to_owned())?;
+    let moved = engine.transaction(|txn| {
+        let value = txn.get(key("key1"))?.unwrap();
+        txn.remove(key("key1"))?;
+        assert_eq!(txn.get(key("key1"))?, None);
+        txn.set(key("key2"), value)?;
+        txn.get(key("key2"))
+    })?;
+    assert_eq!(moved, value("value1"));
+    assert_eq!(engine.get(key("key1"))?, None);
+    assert_eq!(engine.get(key("key2"))?, value("value1"));
+
+    // an error aborts the transaction without writing anything.
+    let ret = engine.transaction(|txn| {
+        txn.set(key("key3"), "value3".to_owned())?;
+        txn.remove(key("key1"))
+    });
+    assert!(matches!(ret, Err(KvsError::KeyNotFoundError)));
+    assert_eq!(engine.get(key("key3"))?, None);
+
+    // a write gives the key another version, which a transaction reads as well.
+    let version = engine.version_bytes(b"key2".to_vec())?;
+    assert!(version.is_some());
+    assert_eq!(engine.version_bytes(b"key1".to_vec())?, None);
+    engine.set(key("key2"), "value2".to_owned())?;
+    let current = engine.transaction(|txn| txn.version_bytes(b"key2".to_vec()))?;
+    assert!(current.is_some() && current != version);
+    Ok(())
+}
+
+// Should apply the writes of a transaction all at once, or none of them.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_transaction(store.clone())?;
+
+    // a write of the value a key already has gives it another version all the same.
+    let version = store.version_bytes(b"key2".to_vec())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_ne!(store.version_bytes(b"key2".to_vec())?, version);
+
+    // a write between the read and the commit fails the transaction.
+    let ret = store.transaction(|txn| {
+        let value = txn.get("key2".to_owned())?.unwrap();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        txn.set("key1".to_owned(), value)
+    });
+    assert!(matches!(ret, Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn check_keyspaces<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "key1".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+    engine.set(key(), "value0".to_owned())?;
+    let first = engine.open_keyspace("first")?;
+    assert_eq!(first.get(key())?, None);
+    first.set(key(), "value1".to_owned())?;
+    let second = first.open_keyspace("second")?;
+    second.set(key(), "value2".to_owned())?;
+    assert_eq!(engine.get(key())?, value("value0"));
+    assert_eq!(engine.open_keyspace("first")?.get(key())?, value("value1"));
+    assert_eq!(
+        second.open_keyspace(DEFAULT_KEYSPACE)?.get(key())?,
+        value("value0")
+    );
+    assert_eq!(second.get(key())?, value("value2"));
+    assert_eq!(
+        engine.keyspaces()?,
+        vec!["first".to_owned(), "second".to_owned()]
+    );
+
+    assert!(engine.drop_keyspace("first")?);
+    assert!(!engine.drop_keyspace("first")?);
+    assert_eq!(engine.keyspaces()?, vec!["second".to_owned()]);
+    assert_eq!(engine.open_keyspace("first")?.get(key())?, None);
+    assert_eq!(engine.get(key())?, value("value0"));
+
+    for name in &["", DEFAULT_KEYSPACE, "../first", "a b"] {
+        let ret = engine.drop_keyspace(name);
+        assert!(matches!(ret, Err(KvsError::InvalidKeyspaceError(_))));
+    }
+    for name in &["", "../first"] {
+        let ret = engine.open_keyspace(name);
+        assert!(matches!(ret, Err(KvsError::InvalidKeyspaceError(_))));
+    }
+    Ok(())
+}
+
+// Should keep the keys of every keyspace apart, also after a reopen.
+#[test]
+fn keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_keyspaces(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.keyspaces()?,
+        vec!["first".to_owned(), "second".to_owned()]
+    );
+    let second = store.open_keyspace("second")?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value0".to_owned()));
+    drop((store, second));
+
+    let opts = KvStoreOptions::new().read_only(true);
+    let store = KvStore::open_with(temp_dir.path(), opts)?;
+    let second = store.open_keyspace("second")?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        store.drop_keyspace("second"),
+        Err(KvsError::ReadOnlyError)
+    ));
+    Ok(())
+}
+
+fn check_export_import<A: KvsEngine, B: KvsEngine>(from: A, to: B) -> Result<()> {
+    for i in 0..1500 {
+        from.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    from.set_bytes(vec![0xff, 0], vec![0xfe, 0])?;
+    from.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    from.open_keyspace("first")?
+        .set("key1".to_owned(), "first".to_owned())?;
+    let mut dump = Vec::new();
+    assert_eq!(from.export(&mut dump)?, 1503);
+
+    // a broken dump fails the import, what is written before is overwritten below.
+    let ret = to.import(&mut &dump[..dump.len() - 1]);
+    assert!(matches!(ret, Err(KvsError::CorruptedRecordError { .. })));
+    let mut flipped = dump.clone();
+    flipped[dump.len() / 2] ^= 0x01;
+    let ret = to.import(&mut flipped.as_slice());
+    assert!(matches!(ret, Err(KvsError::CorruptedRecordError { .. })));
+
+    assert_eq!(to.import(&mut dump.as_slice())?, 1503);
+    for i in 0..1500 {
+        assert_eq!(to.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(to.get_bytes(vec![0xff, 0])?, Some(vec![0xfe, 0]));
+    assert_eq!(to.get("ttl".to_owned())?, Some("value".to_owned()));
+    let ttl = to.ttl("ttl".to_owned())?.expect("the ttl is exported");
+    assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+    assert_eq!(to.ttl("key1".to_owned())?, None);
+    assert_eq!(to.keyspaces()?, vec!["first".to_owned()]);
+    assert_eq!(
+        to.open_keyspace("first")?.get("key1".to_owned())?,
+        Some("first".to_owned())
+    );
+    Ok(())
+}
+
+// Should move every key, its expiry and its keyspace from one engine to the other.
+#[test]
+fn export_import() -> Result<()> {
+    let (from, to) = (TempDir::new()?, TempDir::new()?);
+    check_export_import(KvStore::open(from.path())?, SledKvsEngine::open(to.path())?)?;
+    let (from, to) = (TempDir::new()?, TempDir::new()?);
+    check_export_import(SledKvsEngine::open(from.path())?, KvStore::open(to.path())?)
+}
+
+// Should evict the least recently used keys of every keyspace once over capacity.
+#[test]
+fn memory_capacity() -> Result<()> {
+    let engine = MemoryKvsEngine::with_capacity(100);
+    let keyspace = engine.open_keyspace("first")?;
+    // every key and value takes 20 bytes.
+    for key_id in 0..4 {
+        engine.set(format!("key{}", key_id), "v".repeat(16))?;
+    }
+    keyspace.set("key4".to_owned(), "v".repeat(16))?;
+    assert_eq!(engine.size(), 100);
+    assert!(engine.get("key0".to_owned())?.is_some());
+    engine.set("key5".to_owned(), "v".repeat(16))?;
+    assert_eq!(engine.size(), 100);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.get("key0".to_owned())?.is_some());
+
+    // an overwrite is a use, a scan is not.
+    engine.set("key2".to_owned(), "v".repeat(16))?;
+    assert_eq!(engine.scan_prefix("key".to_owned(), 10)?.len(), 4);
+    keyspace.set("key6".to_owned(), "v".repeat(36))?;
+    assert_eq!(engine.size(), 100);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(keyspace.get("key4".to_owned())?, None);
+    assert!(engine.get("key2".to_owned())?.is_some());
+
+    assert!(engine.drop_keyspace("first")?);
+    assert_eq!(engine.size(), 60);
+    Ok(())
+}
+
+fn check_expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = |k: &str| k.to_owned();
+    let value = |v: &str| v.to_owned();
+    engine.set_with_ttl(key("key1"), value("value1"), Duration::from_secs(100))?;
+    assert_eq!(engine.get(key("key1"))?, Some(value("value1")));
+    let ttl = engine.ttl(key("key1"))?.unwrap();
+    assert!(ttl <= Duration::from_secs(100) && ttl > Duration::from_secs(90));
+    assert!(engine.persist(key("key1"))?);
+    assert!(!engine.persist(key("key1"))?);
+    assert_eq!(engine.ttl(key("key1"))?, None);
+    assert!(engine.expire(key("key1"), Duration::from_secs(100))?);
+    assert!(engine.ttl(key("key1"))?.is_some());
+    // a plain write drops the expiry.
+    engine.set(key("key1"), value("value2"))?;
+    assert_eq!(engine.ttl(key("key1"))?, None);
+    assert!(engine.expire(key("key1"), Duration::from_secs(100))?);
+
+    assert!(!engine.expire(key("key2"), Duration::from_secs(100))?);
+    assert!(!engine.persist(key("key2"))?);
+    assert!(matches!(
+        engine.ttl(key("key2")),
+        Err(KvsError::KeyNotFoundError)
+    ));
+
+    // an expired key is gone for every operation.
+    engine.set_with_ttl(key("key2"), value("value1"), Duration::from_millis(50))?;
+    engine.set(key("key3"), value("value1"))?;
+    assert!(engine.expire(key("key3"), Duration::from_millis(50))?);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.get(key("key2"))?, None);
+    assert!(matches!(
+        engine.ttl(key("key2")),
+        Err(KvsError::KeyNotFoundError)
+    ));
+    assert_eq!(engine.scan_prefix(key("key"), 10)?.len(), 1);
+    assert!(matches!(
+        engine.remove(key("key3")),
+        Err(KvsError::KeyNotFoundError)
+    ));
+    assert!(!engine.set_if_present(key("key2"), value("value2"))?);
+    assert!(engine.set_if_absent(key("key3"), value("value2"))?);
+    assert_eq!(engine.ttl(key("key3"))?, None);
+    Ok(())
+}
+
+// Should hide a key once it expires, also after a reopen and a compaction.
+#[test]
+fn expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new().sweep_interval(Duration::from_secs(3600));
+    check_expiry(KvStore::open_with(temp_dir.path(), opts.clone())?)?;
+
+    let store = KvStore::open_with(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    let value = "v".repeat(1000);
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let key = format!("filler{}", key_id);
+            store.set(key, format!("{}-{}", iter, value))?;
+        }
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(store.ttl("key1".to_owned())?.is_some());
+    Ok(())
+}
+
+// Should drop expired keys in the background and compact them away.
+#[test]
+fn expiry_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new()
+        .segment_size(4 * 1024)
+        .compaction(CompactionTrigger::DanglingRatio(1.0))
+        .sweep_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(temp_dir.path(), opts)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let value = "v".repeat(100);
+    for key_id in 0..200 {
+        let key = format!("key{}", key_id);
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(100))?;
+    }
+    let dir_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            // a segment may be removed by a compaction in between.
+            .filter_map(|entry| entry.unwrap().metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    assert!(dir_size() > 20 * 1024);
+    let mut retries = 0;
+    while dir_size() > 8 * 1024 {
+        assert!(retries < 100, "expired keys not compacted: {}", dir_size());
+        retries += 1;
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(store.scan_prefix("key".to_owned(), 10)?.len(), 1);
+    Ok(())
+}
+
+fn check_binary<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0xff, 0x00, b'\r', b'\n'];
+    let value = vec![0xfe, 0x00, 0xff, b'\r', b'\n'];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert!(matches!(
+        engine.get(String::from_utf8_lossy(&key).into_owned()),
+        Ok(None)
+    ));
+    engine.set_bytes(b"text".to_vec(), value.clone())?;
+    assert!(matches!(
+        engine.get("text".to_owned()),
+        Err(KvsError::Utf8Error(_))
+    ));
+
+    let pairs = engine.scan_prefix_bytes(vec![0xff], 10)?;
+    assert_eq!(pairs, vec![(key.clone(), value.clone())]);
+    assert!(engine.compare_and_swap_bytes(key.clone(), Some(value.clone()), Some(vec![0xff]))?);
+    assert_eq!(engine.get_bytes(key.clone())?, Some(vec![0xff]));
+    engine.set_with_ttl_bytes(b"ttl".to_vec(), value.clone(), Duration::from_secs(100))?;
+    assert_eq!(engine.get_bytes(b"ttl".to_vec())?, Some(value.clone()));
+
+    let mut batch = WriteBatch::new();
+    batch.set(vec![0x80], vec![0xfe]);
+    batch.remove(b"text".to_vec());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get_bytes(vec![0x80])?, Some(vec![0xfe]));
+    assert_eq!(engine.get_bytes(b"text".to_vec())?, None);
+    Ok(())
+}
+
+// Should store keys and values which are not utf-8.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes(vec![0xff, 0x00, b'\r', b'\n'])?,
+        Some(vec![0xff])
+    );
+    assert_eq!(store.get_bytes(vec![0x80])?, Some(vec![0xfe]));
+    Ok(())
+}
+
+// Should read the store as it was when the snapshot is taken.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    let snapshot = store.snapshot();
+
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key5".to_owned(), "new".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "new");
+    batch.set("key2", "newer");
+    batch.remove("key3");
+    store.write_batch(batch)?;
+    thread::sleep(Duration::from_millis(150));
+
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key5".to_owned())?, None);
+    assert_eq!(snapshot.get("ttl".to_owned())?, Some("old".to_owned()));
+    let old: Vec<_> = (0..5)
+        .map(|key_id| (format!("key{}", key_id), "old".to_owned()))
+        .collect();
+    assert_eq!(snapshot.scan_prefix("key".to_owned(), 10)?, old);
+    assert_eq!(
+        snapshot.scan("key1".to_owned()..="key3".to_owned(), 10)?,
+        old[1..4].to_vec()
+    );
+    assert_eq!(snapshot.scan("key1".to_owned().., 2)?, old[1..3].to_vec());
+
+    assert_eq!(store.get("ttl".to_owned())?, None);
+    let new = vec![
+        ("key0".to_owned(), "new".to_owned()),
+        ("key2".to_owned(), "newer".to_owned()),
+        ("key4".to_owned(), "old".to_owned()),
+        ("key5".to_owned(), "new".to_owned()),
+    ];
+    assert_eq!(store.scan_prefix("key".to_owned(), 10)?, new);
+    drop(snapshot);
+    assert_eq!(store.snapshot().scan_prefix("key".to_owned(), 10)?, new);
+    Ok(())
+}
+
+// Should keep the segments a snapshot reads until it is dropped.
+#[test]
+fn snapshot_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions::new()
+        .segment_size(4 * 1024)
+        .compaction(CompactionTrigger::DanglingRatio(1.0))
+        .sweep_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(temp_dir.path(), opts)?;
+    let value = "v".repeat(100);
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("0{}", value))?;
+    }
+    let snapshot = store.snapshot();
+    for iter in 1..20 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    let dir_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            // a segment may be removed by a compaction in between.
+            .filter_map(|entry| entry.unwrap().metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    // 20 rounds of about 2.5 KiB are written.
+    assert!(dir_size() > 40 * 1024);
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id);
+        assert_eq!(snapshot.get(key.clone())?, Some(format!("0{}", value)));
+        assert_eq!(store.get(key)?, Some(format!("19{}", value)));
+    }
+
+    drop(snapshot);
+    let mut retries = 0;
+    while dir_size() > 16 * 1024 {
+        assert!(retries < 100, "merged segments not removed: {}", dir_size());
+        retries += 1;
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("19{}", value)));
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +1505,10 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once no thread holds the lock.
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +1569,172 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// small_lsm makes the LSM engine flush and merge its tables after a few writes.
+fn small_lsm() -> LsmOptions {
+    LsmOptions::new()
+        .memtable_size(1024)
+        .block_size(256)
+        .tier_width(2)
+}
+
+// Should keep every key through flushes, merges and a reopen, and drop what is removed.
+#[test]
+fn lsm_flush_compact_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open_with(temp_dir.path(), small_lsm())?;
+    let keyspace = engine.open_keyspace("first")?;
+    for round in 0..4 {
+        for key_id in 0..200 {
+            engine.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+    }
+    for key_id in (0..200).step_by(2) {
+        engine.remove(format!("key{}", key_id))?;
+    }
+    keyspace.set("key1".to_owned(), "other".to_owned())?;
+    let tables = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    assert!(tables() > 0);
+    drop(engine);
+    drop(keyspace);
+
+    let engine = LsmKvsEngine::open_with(temp_dir.path(), small_lsm())?;
+    for key_id in 0..200 {
+        let expected = Some("value3".to_owned()).filter(|_| key_id % 2 == 1);
+        assert_eq!(engine.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(engine.scan_prefix("key".to_owned(), 1000)?.len(), 100);
+    let keyspace = engine.open_keyspace("first")?;
+    assert_eq!(
+        keyspace.scan(.., 10)?,
+        vec![("key1".to_owned(), "other".to_owned())]
+    );
+    // the tiers are merged down to fewer tables than were flushed.
+    assert!(tables() < 20);
+    Ok(())
+}
+
+// Should write through the log under every sync policy and read it back on reopen.
+#[test]
+fn lsm_sync_policies() -> Result<()> {
+    for &policy in &[
+        SyncPolicy::EveryWrite,
+        SyncPolicy::EveryNBytes(256),
+        SyncPolicy::EveryNms(1),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let opts = small_lsm().memtable_size(64 * 1024).sync(policy);
+        let engine = LsmKvsEngine::open_with(temp_dir.path(), opts.clone())?;
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        engine.remove("key0".to_owned())?;
+        drop(engine);
+
+        let engine = LsmKvsEngine::open_with(temp_dir.path(), opts)?;
+        assert_eq!(engine.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                engine.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Should keep every key, large values included, through splits, removes and a reopen
+// with a buffer pool of a few pages, and reuse the pages freed meanwhile.
+#[test]
+fn btree_split_remove_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = BTreeOptions::new().cache_size(4 * 4096);
+    let engine = BTreeKvsEngine::open_with(temp_dir.path(), opts.clone())?;
+    let keyspace = engine.open_keyspace("first")?;
+    let value = |key_id: u32| format!("value{}", key_id).repeat(key_id as usize % 700);
+    for key_id in 0..2000 {
+        engine.set(format!("key{:04}", key_id), value(key_id))?;
+    }
+    for key_id in (0..2000).filter(|key_id| key_id % 3 != 0) {
+        engine.remove(format!("key{:04}", key_id))?;
+    }
+    keyspace.set("key0001".to_owned(), "other".to_owned())?;
+    let size = fs::metadata(temp_dir.path().join("btree.db"))?.len();
+    drop(engine);
+    drop(keyspace);
+
+    let engine = BTreeKvsEngine::open_with(temp_dir.path(), opts)?;
+    for key_id in 0..2000 {
+        let expected = Some(value(key_id)).filter(|_| key_id % 3 == 0);
+        assert_eq!(engine.get(format!("key{:04}", key_id))?, expected);
+    }
+    let keys: Vec<String> = engine
+        .scan(.., 1000)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let expected: Vec<String> = (0..2000)
+        .filter(|key_id| key_id % 3 == 0)
+        .map(|key_id| format!("key{:04}", key_id))
+        .collect();
+    assert_eq!(keys, expected);
+    let keyspace = engine.open_keyspace("first")?;
+    assert_eq!(
+        keyspace.get("key0001".to_owned())?,
+        Some("other".to_owned())
+    );
+
+    // writing the removed keys again takes the pages they are removed from.
+    for key_id in (0..2000).filter(|key_id| key_id % 3 != 0) {
+        engine.set(format!("key{:04}", key_id), value(key_id))?;
+    }
+    assert!(fs::metadata(temp_dir.path().join("btree.db"))?.len() <= size * 2);
+    assert!(engine.drop_keyspace("first")?);
+    assert!(engine.keyspaces()?.is_empty());
+    Ok(())
+}
+
+// Should never show a reader a page reused by a write going on meanwhile.
+#[test]
+fn btree_concurrent_read_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = BTreeOptions::new().cache_size(4 * 4096);
+    let engine = BTreeKvsEngine::open_with(temp_dir.path(), opts)?;
+    for key_id in 0..500 {
+        engine.set(format!("key{:03}", key_id), "0".repeat(100))?;
+    }
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            for round in 1..10 {
+                for key_id in 0..500 {
+                    let value = round.to_string().repeat(100);
+                    engine.set(format!("key{:03}", key_id), value).unwrap();
+                }
+            }
+        })
+    };
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || {
+            for key_id in 0..500 {
+                let value = engine.get(format!("key{:03}", key_id)).unwrap().unwrap();
+                assert_eq!(value.len(), 100);
+                let pairs = engine.scan_prefix("key".to_owned(), 1000).unwrap();
+                assert_eq!(pairs.len(), 500);
+            }
+        }));
+    }
+    writer.join().unwrap();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("key499".to_owned())?, Some("9".repeat(100)));
+    Ok(())
+}